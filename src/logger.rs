@@ -2,6 +2,7 @@ use env_logger::fmt::Color;
 use env_logger::{Builder, Env};
 use log::Level;
 use std::io::Write;
+use std::thread;
 
 /// Configures the logger with the desired log level and format.
 ///
@@ -11,6 +12,8 @@ use std::io::Write;
 ///
 /// The logger format includes the timestamp, log level, target module, and log message.
 /// Log levels are color-coded for better readability.
+/// Lines logged by the threads of parallel backups are prefixed with the name of the thread,
+/// like `[/dev/sdc1]`, see `run_jobs`.
 pub fn configure_logger() {
     Builder::from_env(Env::default().filter_or("RUST_LOG", "info"))
         .format(|buf, record| {
@@ -40,12 +43,18 @@ pub fn configure_logger() {
                 .set_color(Color::Rgb(255, 165, 0))
                 .set_bold(false);
 
+            let prefix = match thread::current().name() {
+                Some("main") | None => String::new(),
+                Some(name) => format!("[{}] ", name),
+            };
+
             writeln!(
                 buf,
-                "[{} {} - {}]: {}",
+                "[{} {} - {}]: {}{}",
                 buf.timestamp(),
                 level_style.value(level_str),
                 target_style.value(target),
+                prefix,
                 record.args()
             )
         })
@@ -1,6 +1,7 @@
 use std::process;
 
 use crate::logger::configure_logger;
+use crate::run::backup_run::interrupt;
 mod logger;
 mod run;
 
@@ -14,6 +15,9 @@ fn main() {
     if let Err(e) = run::run() {
         error!("Application error: {}", e);
 
+        if interrupt::is_interrupted() {
+            process::exit(interrupt::EXIT_INTERRUPTED);
+        }
         process::exit(1);
     }
     debug!("Application ran successfully");
@@ -0,0 +1,785 @@
+use std::io::{Read, Seek};
+
+use super::{
+    extent::{complement, merge, Extent},
+    partition_table::{read_at, u16_le, u32_le, u64_le},
+};
+
+/// The filesystems whose allocated blocks can be read natively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemKind {
+    Ext,
+    Xfs,
+    Fat,
+    Ntfs,
+}
+
+impl FilesystemKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilesystemKind::Ext => "ext",
+            FilesystemKind::Xfs => "xfs",
+            FilesystemKind::Fat => "vfat",
+            FilesystemKind::Ntfs => "ntfs",
+        }
+    }
+}
+
+/// Reads the allocated blocks of the filesystem in the given range of `reader`.
+///
+/// # Arguments
+///
+/// * `reader` - The device or image holding the filesystem.
+/// * `offset` - The byte offset of the filesystem, like the start of a partition.
+/// * `length` - The number of bytes of the partition, extents are clipped to it.
+///
+/// # Returns
+///
+/// - `Ok(Some((FilesystemKind, Vec<Extent>)))`: The merged extents allocated by the filesystem,
+///   with offsets relative to the start of `reader`.
+/// - `Ok(None)`: If no supported filesystem was found.
+/// - `Err(String)`: If the filesystem was found but its allocation information is unreadable or unsupported.
+pub fn allocated_extents<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: u64,
+) -> Result<Option<(FilesystemKind, Vec<Extent>)>, String> {
+    let bounds = Extent::new(offset, length);
+    let readers: [(FilesystemKind, ExtentReader<R>); 4] = [
+        (FilesystemKind::Ntfs, ntfs_extents),
+        (FilesystemKind::Fat, fat_extents),
+        (FilesystemKind::Ext, ext_extents),
+        (FilesystemKind::Xfs, xfs_extents),
+    ];
+    for (kind, read_extents) in readers {
+        if let Some(extents) = read_extents(reader, offset)
+            .map_err(|e| format!("Failed to read {} allocation: {}", kind.name(), e))?
+        {
+            let extents = merge(extents)
+                .iter()
+                .filter_map(|extent| extent.clip(&bounds))
+                .collect();
+            return Ok(Some((kind, extents)));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the allocated extents of one kind of filesystem at the given offset,
+/// `None` if the filesystem is not of that kind.
+type ExtentReader<R> = fn(&mut R, u64) -> Result<Option<Vec<Extent>>, String>;
+
+fn read_exact_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: usize,
+) -> Result<Vec<u8>, String> {
+    read_at(reader, offset, length)?.ok_or(format!(
+        "Unexpected end of device at {}",
+        offset + length as u64
+    ))
+}
+
+fn is_bit_set(bitmap: &[u8], bit: u64) -> bool {
+    bitmap
+        .get((bit / 8) as usize)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+/// Collects the runs of set bits of a bitmap as extents of `unit` bytes starting at `base`.
+fn bitmap_extents(bitmap: &[u8], bits: u64, base: u64, unit: u64) -> Vec<Extent> {
+    let mut extents = Vec::new();
+    let mut run_start = None;
+    for bit in 0..=bits {
+        let is_set = bit < bits && is_bit_set(bitmap, bit);
+        match (is_set, run_start) {
+            (true, None) => run_start = Some(bit),
+            (false, Some(start)) => {
+                extents.push(Extent::new(base + start * unit, (bit - start) * unit));
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    extents
+}
+
+/// ext2/3/4: the block bitmap of each block group, groups with uninitialized bitmaps only
+/// hold their superblock backup and the metadata placed in them.
+fn ext_extents<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Option<Vec<Extent>>, String> {
+    let superblock = match read_at(reader, offset + 1024, 1024)? {
+        Some(superblock) if u16_le(&superblock[0x38..]) == 0xEF53 => superblock,
+        _ => return Ok(None),
+    };
+    let feature_compat = u32_le(&superblock[0x5C..]);
+    let feature_incompat = u32_le(&superblock[0x60..]);
+    let feature_ro_compat = u32_le(&superblock[0x64..]);
+    if feature_incompat & 0x10 != 0 {
+        return Err("meta_bg is not supported".to_string());
+    }
+    if feature_ro_compat & 0x200 != 0 {
+        return Err("bigalloc is not supported".to_string());
+    }
+
+    let is_64bit = feature_incompat & 0x80 != 0;
+    let blocks_count = u32_le(&superblock[0x04..]) as u64
+        | match is_64bit {
+            true => (u32_le(&superblock[0x150..]) as u64) << 32,
+            false => 0,
+        };
+    let invalid = || "invalid superblock".to_string();
+    let first_data_block = u32_le(&superblock[0x14..]) as u64;
+    // block sizes range from 1 KiB to 64 KiB
+    let log_block_size = u32_le(&superblock[0x18..]);
+    if log_block_size > 6 {
+        return Err(invalid());
+    }
+    let block_size = 1024u64 << log_block_size;
+    let blocks_per_group = u32_le(&superblock[0x20..]) as u64;
+    let inodes_per_group = u32_le(&superblock[0x28..]) as u64;
+    let inode_size = match u32_le(&superblock[0x4C..]) {
+        0 => 128,
+        _ => u16_le(&superblock[0x58..]) as u64,
+    };
+    let desc_size = match is_64bit {
+        true => (u16_le(&superblock[0xFE..]) as u64).max(32),
+        false => 32,
+    };
+    let reserved_gdt_blocks = u16_le(&superblock[0xCE..]) as u64;
+    // the block bitmap of a group is a single block
+    if blocks_per_group == 0 || blocks_per_group > block_size * 8 {
+        return Err(invalid());
+    }
+
+    let group_count = blocks_count
+        .checked_sub(first_data_block)
+        .ok_or_else(invalid)?
+        .div_ceil(blocks_per_group);
+    let gdt_blocks = group_count
+        .checked_mul(desc_size)
+        .ok_or_else(invalid)?
+        .div_ceil(block_size);
+    // without meta_bg the group descriptors follow the superblock in the first group
+    if gdt_blocks >= blocks_per_group {
+        return Err(invalid());
+    }
+    let inode_table_blocks = inodes_per_group
+        .checked_mul(inode_size)
+        .ok_or_else(invalid)?
+        .div_ceil(block_size);
+    let gdt = read_exact_at(
+        reader,
+        offset + (first_data_block + 1) * block_size,
+        (gdt_blocks * block_size) as usize,
+    )?;
+
+    let descriptor_block = |descriptor: &[u8], at: usize| -> u64 {
+        let low = u32_le(&descriptor[at..]) as u64;
+        match desc_size >= 64 {
+            true => low | (u32_le(&descriptor[at + 0x20..]) as u64) << 32,
+            false => low,
+        }
+    };
+    let descriptors: Vec<&[u8]> = gdt
+        .chunks(desc_size as usize)
+        .take(group_count as usize)
+        .collect();
+    // block bitmaps, inode bitmaps and inode tables, which may be placed in other groups with flex_bg
+    let metadata: Vec<(u64, u64)> = descriptors
+        .iter()
+        .flat_map(|descriptor| {
+            [
+                (descriptor_block(descriptor, 0x0), 1),
+                (descriptor_block(descriptor, 0x4), 1),
+                (descriptor_block(descriptor, 0x8), inode_table_blocks),
+            ]
+        })
+        .collect();
+    let is_sparse_super = feature_ro_compat & 0x1 != 0;
+    let is_sparse_super2 = feature_compat & 0x200 != 0;
+    let backup_groups = [
+        u32_le(&superblock[0x24C..]) as u64,
+        u32_le(&superblock[0x250..]) as u64,
+    ];
+    let has_superblock = |group: u64| -> bool {
+        if group <= 1 && !is_sparse_super2 {
+            return true;
+        }
+        if is_sparse_super2 {
+            return group == 0 || backup_groups.contains(&group);
+        }
+        if !is_sparse_super {
+            return true;
+        }
+        [3u64, 5, 7].iter().any(|&base| {
+            let mut power = base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    };
+
+    let mut blocks: Vec<Extent> = vec![Extent::new(0, first_data_block)];
+    for (group, descriptor) in descriptors.iter().enumerate() {
+        let group = group as u64;
+        let group_start = first_data_block + group * blocks_per_group;
+        let group_blocks = blocks_per_group.min(blocks_count - group_start);
+        let is_block_bitmap_uninit = u16_le(&descriptor[0x12..]) & 0x2 != 0;
+        if is_block_bitmap_uninit {
+            if has_superblock(group) {
+                blocks.push(Extent::new(
+                    group_start,
+                    1 + gdt_blocks + reserved_gdt_blocks,
+                ));
+            }
+            let group_extent = Extent::new(group_start, group_blocks);
+            blocks.extend(
+                metadata
+                    .iter()
+                    .filter_map(|&(block, length)| Extent::new(block, length).clip(&group_extent)),
+            );
+        } else {
+            let bitmap_offset = descriptor_block(descriptor, 0x0)
+                .checked_mul(block_size)
+                .and_then(|bitmap_offset| bitmap_offset.checked_add(offset))
+                .ok_or_else(invalid)?;
+            let bitmap = read_exact_at(reader, bitmap_offset, block_size as usize)?;
+            blocks.extend(bitmap_extents(&bitmap, group_blocks, group_start, 1));
+        }
+    }
+
+    Ok(Some(
+        blocks
+            .into_iter()
+            .map(|extent| {
+                Extent::new(
+                    offset + extent.offset * block_size,
+                    extent.length * block_size,
+                )
+            })
+            .collect(),
+    ))
+}
+
+/// FAT12/16/32: everything up to the first cluster, and the clusters with a non-zero FAT entry.
+fn fat_extents<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Option<Vec<Extent>>, String> {
+    let Some(boot_sector) = read_at(reader, offset, 512)? else {
+        return Ok(None);
+    };
+    let bytes_per_sector = u16_le(&boot_sector[0x0B..]) as u64;
+    let sectors_per_cluster = boot_sector[0x0D] as u64;
+    let reserved_sectors = u16_le(&boot_sector[0x0E..]) as u64;
+    let fat_count = boot_sector[0x10] as u64;
+    let is_fat = boot_sector[510..512] == [0x55, 0xAA]
+        && (&boot_sector[0x36..0x39] == b"FAT" || &boot_sector[0x52..0x55] == b"FAT")
+        && [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors > 0
+        && fat_count > 0;
+    if !is_fat {
+        return Ok(None);
+    }
+
+    let root_entries = u16_le(&boot_sector[0x11..]) as u64;
+    let total_sectors = match u16_le(&boot_sector[0x13..]) {
+        0 => u32_le(&boot_sector[0x20..]) as u64,
+        sectors => sectors as u64,
+    };
+    let fat_sectors = match u16_le(&boot_sector[0x16..]) {
+        0 => u32_le(&boot_sector[0x24..]) as u64,
+        sectors => sectors as u64,
+    };
+    let root_dir_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved_sectors + fat_count * fat_sectors + root_dir_sectors;
+    let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+    let fat = read_exact_at(
+        reader,
+        offset + reserved_sectors * bytes_per_sector,
+        (fat_sectors * bytes_per_sector) as usize,
+    )?;
+
+    let entry = |cluster: u64| -> u32 {
+        match clusters {
+            0..=4084 => {
+                let at = (cluster + cluster / 2) as usize;
+                let value = u16_le(&fat[at..]) as u32;
+                match cluster % 2 {
+                    0 => value & 0x0FFF,
+                    _ => value >> 4,
+                }
+            }
+            4085..=65524 => u16_le(&fat[(cluster * 2) as usize..]) as u32,
+            _ => u32_le(&fat[(cluster * 4) as usize..]) & 0x0FFF_FFFF,
+        }
+    };
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+    let data_offset = offset + data_start * bytes_per_sector;
+    let mut extents = vec![Extent::new(offset, data_start * bytes_per_sector)];
+    extents.extend(
+        (2..clusters + 2)
+            .filter(|&cluster| entry(cluster) != 0)
+            .map(|cluster| Extent::new(data_offset + (cluster - 2) * cluster_size, cluster_size)),
+    );
+    Ok(Some(extents))
+}
+
+/// NTFS: the clusters marked in the `$Bitmap` file, and the backup boot sector.
+fn ntfs_extents<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+) -> Result<Option<Vec<Extent>>, String> {
+    let boot_sector = match read_at(reader, offset, 512)? {
+        Some(boot_sector) if &boot_sector[3..11] == b"NTFS    " => boot_sector,
+        _ => return Ok(None),
+    };
+    let bytes_per_sector = u16_le(&boot_sector[0x0B..]) as u64;
+    let cluster_size = match boot_sector[0x0D] {
+        raw if raw > 0x80 => bytes_per_sector << (256 - raw as u64),
+        raw => bytes_per_sector * raw as u64,
+    };
+    let total_sectors = u64_le(&boot_sector[0x28..]);
+    let mft_cluster = u64_le(&boot_sector[0x30..]);
+    let record_size = match boot_sector[0x40] as i8 {
+        raw if raw < 0 => 1u64 << (-raw as u32),
+        raw => raw as u64 * cluster_size,
+    };
+    if cluster_size == 0 || record_size < 512 {
+        return Err("invalid boot sector".to_string());
+    }
+
+    // $Bitmap is the file record 6, in the first extent of the MFT
+    let mut record = read_exact_at(
+        reader,
+        offset + mft_cluster * cluster_size + 6 * record_size,
+        record_size as usize,
+    )?;
+    if &record[0..4] != b"FILE" {
+        return Err("$Bitmap file record not found".to_string());
+    }
+    apply_fixups(&mut record)?;
+
+    let mut attribute_offset = u16_le(&record[0x14..]) as usize;
+    let (runs, data_size) = loop {
+        if attribute_offset + 8 > record.len() {
+            return Err("$Bitmap data attribute not found".to_string());
+        }
+        let attribute = &record[attribute_offset..];
+        let attribute_type = u32_le(attribute);
+        let attribute_length = u32_le(&attribute[4..]) as usize;
+        if attribute_type == 0xFFFF_FFFF || attribute_length == 0 {
+            return Err("$Bitmap data attribute not found".to_string());
+        }
+        let is_non_resident = attribute[8] != 0;
+        let has_name = attribute[9] != 0;
+        if attribute_type == 0x80 && is_non_resident && !has_name {
+            let runs_offset = u16_le(&attribute[0x20..]) as usize;
+            break (
+                data_runs(&attribute[runs_offset..attribute_length])?,
+                u64_le(&attribute[0x30..]),
+            );
+        }
+        attribute_offset += attribute_length;
+    };
+
+    let mut bitmap = Vec::with_capacity(data_size as usize);
+    for (cluster, clusters) in runs {
+        let run = read_exact_at(
+            reader,
+            offset + cluster * cluster_size,
+            (clusters * cluster_size) as usize,
+        )?;
+        bitmap.extend(run);
+    }
+    bitmap.truncate(data_size as usize);
+
+    let total_clusters = total_sectors * bytes_per_sector / cluster_size;
+    let mut extents = bitmap_extents(&bitmap, total_clusters, offset, cluster_size);
+    extents.push(Extent::new(
+        offset + total_sectors * bytes_per_sector,
+        bytes_per_sector,
+    ));
+    Ok(Some(extents))
+}
+
+/// Restores the last two bytes of each 512 byte stride of an NTFS record from its update sequence array.
+fn apply_fixups(record: &mut [u8]) -> Result<(), String> {
+    let array_offset = u16_le(&record[4..]) as usize;
+    let array_count = u16_le(&record[6..]) as usize;
+    if array_count == 0 || array_offset + array_count * 2 > record.len() {
+        return Err("invalid update sequence array".to_string());
+    }
+    let sequence_number = [record[array_offset], record[array_offset + 1]];
+    for i in 1..array_count {
+        let end = i * 512;
+        if end > record.len() {
+            break;
+        }
+        if record[end - 2..end] != sequence_number {
+            return Err("torn file record".to_string());
+        }
+        let fixup = [
+            record[array_offset + i * 2],
+            record[array_offset + i * 2 + 1],
+        ];
+        record[end - 2..end].copy_from_slice(&fixup);
+    }
+    Ok(())
+}
+
+/// Decodes NTFS data runs to (first cluster, number of clusters), sparse runs are skipped.
+fn data_runs(bytes: &[u8]) -> Result<Vec<(u64, u64)>, String> {
+    let mut runs = Vec::new();
+    let mut position = 0;
+    let mut cluster: i64 = 0;
+    while position < bytes.len() && bytes[position] != 0 {
+        let length_size = (bytes[position] & 0x0F) as usize;
+        let offset_size = (bytes[position] >> 4) as usize;
+        position += 1;
+        if length_size == 0
+            || length_size > 8
+            || offset_size > 8
+            || position + length_size + offset_size > bytes.len()
+        {
+            return Err("invalid data run".to_string());
+        }
+        let mut length = 0u64;
+        for (i, byte) in bytes[position..position + length_size].iter().enumerate() {
+            length |= (*byte as u64) << (8 * i);
+        }
+        position += length_size;
+        if offset_size > 0 {
+            let mut relative: i64 = 0;
+            for (i, byte) in bytes[position..position + offset_size].iter().enumerate() {
+                relative |= (*byte as i64) << (8 * i);
+            }
+            // sign extension
+            let shift = 64 - 8 * offset_size as u32;
+            relative = (relative << shift) >> shift;
+            position += offset_size;
+            cluster += relative;
+            runs.push((cluster as u64, length));
+        }
+    }
+    Ok(runs)
+}
+
+pub fn u16_be(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+pub fn u32_be(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub fn u64_be(bytes: &[u8]) -> u64 {
+    (u32_be(bytes) as u64) << 32 | u32_be(&bytes[4..]) as u64
+}
+
+/// XFS: the complement of the free extents in the by-block-number free space B+tree of each allocation group.
+fn xfs_extents<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Option<Vec<Extent>>, String> {
+    let superblock = match read_at(reader, offset, 512)? {
+        Some(superblock) if &superblock[0..4] == b"XFSB" => superblock,
+        _ => return Ok(None),
+    };
+    let block_size = u32_be(&superblock[0x04..]) as u64;
+    let data_blocks = u64_be(&superblock[0x08..]);
+    let ag_blocks = u32_be(&superblock[0x54..]) as u64;
+    let ag_count = u32_be(&superblock[0x58..]) as u64;
+    let sector_size = u16_be(&superblock[0x66..]) as u64;
+    let is_v5 = u16_be(&superblock[0x64..]) & 0xF == 5;
+    if block_size == 0 || ag_blocks == 0 || sector_size == 0 {
+        return Err("invalid superblock".to_string());
+    }
+    let header_size = match is_v5 {
+        true => 56,
+        false => 16,
+    };
+
+    let mut extents = Vec::new();
+    for ag in 0..ag_count {
+        let ag_start = ag * ag_blocks;
+        let ag_extent = Extent::new(ag_start, ag_blocks.min(data_blocks - ag_start));
+        let agf = read_exact_at(
+            reader,
+            offset + ag_start * block_size + sector_size,
+            sector_size as usize,
+        )?;
+        if &agf[0..4] != b"XAGF" {
+            return Err(format!("AGF of allocation group {} not found", ag));
+        }
+
+        let mut free = Vec::new();
+        let mut pending = vec![u32_be(&agf[0x10..]) as u64];
+        while let Some(agbno) = pending.pop() {
+            if agbno >= ag_extent.length {
+                return Err(format!(
+                    "invalid free space btree block {} in allocation group {}",
+                    agbno, ag
+                ));
+            }
+            let block = read_exact_at(
+                reader,
+                offset + (ag_start + agbno) * block_size,
+                block_size as usize,
+            )?;
+            if &block[0..4] != b"ABTB" && &block[0..4] != b"AB3B" {
+                return Err(format!(
+                    "invalid free space btree block {} in allocation group {}",
+                    agbno, ag
+                ));
+            }
+            let level = u16_be(&block[4..]);
+            let records = u16_be(&block[6..]) as usize;
+            match level {
+                0 => free.extend((0..records).map(|i| {
+                    let record = &block[header_size + i * 8..];
+                    Extent::new(
+                        ag_start + u32_be(record) as u64,
+                        u32_be(&record[4..]) as u64,
+                    )
+                })),
+                _ => {
+                    let max_records = (block_size as usize - header_size) / 12;
+                    let pointers = header_size + max_records * 8;
+                    pending.extend((0..records).map(|i| u32_be(&block[pointers + i * 4..]) as u64));
+                }
+            }
+        }
+        extents.extend(complement(&merge(free), &ag_extent));
+    }
+
+    Ok(Some(
+        extents
+            .into_iter()
+            .map(|extent| {
+                Extent::new(
+                    offset + extent.offset * block_size,
+                    extent.length * block_size,
+                )
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{io::Cursor, path::Path, process::Command};
+
+    use super::*;
+    use crate::run::extent::total_length;
+
+    /// Builds a FAT16 filesystem of 16 MiB with 2 KiB clusters, of which `used` clusters are allocated.
+    pub fn fat_image(used: &[u64]) -> Vec<u8> {
+        let mut image = vec![0u8; 16 * 1024 * 1024];
+        image[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+        image[0x0D] = 4;
+        image[0x0E..0x10].copy_from_slice(&4u16.to_le_bytes());
+        image[0x10] = 2;
+        image[0x11..0x13].copy_from_slice(&512u16.to_le_bytes());
+        image[0x13..0x15].copy_from_slice(&32768u16.to_le_bytes());
+        image[0x16..0x18].copy_from_slice(&32u16.to_le_bytes());
+        image[0x36..0x3E].copy_from_slice(b"FAT16   ");
+        image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let fat = 4 * 512;
+        image[fat..fat + 4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+        for cluster in used {
+            let at = fat + *cluster as usize * 2;
+            image[at..at + 2].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn test_fat_extents() {
+        let image = fat_image(&[2, 3, 10]);
+        let (kind, extents) = allocated_extents(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+
+        // reserved sectors, 2 FATs of 32 sectors and 32 sectors of root directory
+        let data_start = (4 + 2 * 32 + 32) * 512;
+        assert_eq!(kind, FilesystemKind::Fat);
+        assert_eq!(
+            extents,
+            vec![
+                Extent::new(0, data_start + 2 * 2048),
+                Extent::new(data_start + 8 * 2048, 2048),
+            ]
+        );
+
+        // an offset moves the extents, no filesystem is found in zeros
+        let mut disk = vec![0u8; 1024 * 1024];
+        disk.extend(&image);
+        let (_, extents) =
+            allocated_extents(&mut Cursor::new(&disk), 1024 * 1024, image.len() as u64)
+                .unwrap()
+                .unwrap();
+        assert_eq!(extents[0], Extent::new(1024 * 1024, data_start + 2 * 2048));
+        assert!(allocated_extents(&mut Cursor::new(&disk), 0, 1024 * 1024)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_ntfs_extents() {
+        let cluster_size = 4096;
+        let mut image = vec![0u8; 64 * cluster_size];
+        image[3..11].copy_from_slice(b"NTFS    ");
+        image[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+        image[0x0D] = 8;
+        image[0x28..0x30].copy_from_slice(&(63 * 8u64).to_le_bytes());
+        image[0x30..0x38].copy_from_slice(&4u64.to_le_bytes());
+        image[0x40] = (-10i8) as u8;
+
+        // $Bitmap record with a non-resident $DATA attribute in cluster 10
+        let record = 4 * cluster_size + 6 * 1024;
+        image[record..record + 4].copy_from_slice(b"FILE");
+        image[record + 4..record + 6].copy_from_slice(&0x30u16.to_le_bytes());
+        image[record + 6..record + 8].copy_from_slice(&3u16.to_le_bytes());
+        image[record + 0x30..record + 0x32].copy_from_slice(&[7, 0]);
+        for end in [512, 1024] {
+            image[record + end - 2..record + end].copy_from_slice(&[7, 0]);
+        }
+        image[record + 0x14..record + 0x16].copy_from_slice(&0x38u16.to_le_bytes());
+        let attribute = record + 0x38;
+        image[attribute..attribute + 4].copy_from_slice(&0x80u32.to_le_bytes());
+        image[attribute + 4..attribute + 8].copy_from_slice(&0x48u32.to_le_bytes());
+        image[attribute + 8] = 1;
+        image[attribute + 0x20..attribute + 0x22].copy_from_slice(&0x40u16.to_le_bytes());
+        image[attribute + 0x30..attribute + 0x38].copy_from_slice(&8u64.to_le_bytes());
+        image[attribute + 0x40..attribute + 0x43].copy_from_slice(&[0x11, 0x01, 10]);
+        image[attribute + 0x48..attribute + 0x4C].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+
+        // clusters 0-4 and 10 are in use
+        image[10 * cluster_size] = 0b0001_1111;
+        image[10 * cluster_size + 1] = 0b0000_0100;
+
+        let (kind, extents) = allocated_extents(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(kind, FilesystemKind::Ntfs);
+        assert_eq!(
+            extents,
+            vec![
+                Extent::new(0, 5 * cluster_size as u64),
+                Extent::new(10 * cluster_size as u64, cluster_size as u64),
+                Extent::new(63 * cluster_size as u64, 512),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_xfs_extents() {
+        let block_size = 4096;
+        let mut image = vec![0u8; 2 * 256 * block_size];
+        image[0..4].copy_from_slice(b"XFSB");
+        image[0x04..0x08].copy_from_slice(&(block_size as u32).to_be_bytes());
+        image[0x08..0x10].copy_from_slice(&512u64.to_be_bytes());
+        image[0x54..0x58].copy_from_slice(&256u32.to_be_bytes());
+        image[0x58..0x5C].copy_from_slice(&2u32.to_be_bytes());
+        image[0x64..0x66].copy_from_slice(&5u16.to_be_bytes());
+        image[0x66..0x68].copy_from_slice(&512u16.to_be_bytes());
+
+        // each allocation group has a single leaf with free extents
+        for (ag, free) in [(0, vec![(16u32, 200u32)]), (1, vec![(8, 100), (200, 56)])] {
+            let ag_start = ag * 256 * block_size;
+            let agf = ag_start + 512;
+            image[agf..agf + 4].copy_from_slice(b"XAGF");
+            image[agf + 0x10..agf + 0x14].copy_from_slice(&4u32.to_be_bytes());
+            let leaf = ag_start + 4 * block_size;
+            image[leaf..leaf + 4].copy_from_slice(b"AB3B");
+            image[leaf + 6..leaf + 8].copy_from_slice(&(free.len() as u16).to_be_bytes());
+            for (i, (start, count)) in free.iter().enumerate() {
+                let record = leaf + 56 + i * 8;
+                image[record..record + 4].copy_from_slice(&start.to_be_bytes());
+                image[record + 4..record + 8].copy_from_slice(&count.to_be_bytes());
+            }
+        }
+
+        let (kind, extents) = allocated_extents(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+        let block = |n: u64| n * block_size as u64;
+        assert_eq!(kind, FilesystemKind::Xfs);
+        assert_eq!(
+            extents,
+            vec![
+                Extent::new(0, block(16)),
+                Extent::new(block(216), block(40 + 8)),
+                Extent::new(block(364), block(92)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ext_extents_rejects_corrupt_superblock() {
+        let ext_image = |log_block_size: u32, blocks_count: u32, first_data_block: u32| {
+            let mut image = vec![0u8; 4096];
+            let superblock = &mut image[1024..2048];
+            superblock[0x04..0x08].copy_from_slice(&blocks_count.to_le_bytes());
+            superblock[0x14..0x18].copy_from_slice(&first_data_block.to_le_bytes());
+            superblock[0x18..0x1C].copy_from_slice(&log_block_size.to_le_bytes());
+            superblock[0x20..0x24].copy_from_slice(&8192u32.to_le_bytes());
+            superblock[0x38..0x3A].copy_from_slice(&0xEF53u16.to_le_bytes());
+            image
+        };
+
+        for image in [ext_image(54, 1024, 1), ext_image(0, 1, 2)] {
+            assert_eq!(
+                ext_extents(&mut Cursor::new(&image), 0),
+                Err("invalid superblock".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_ext_extents() {
+        if Command::new("mkfs.ext4").arg("-V").output().is_err() {
+            eprintln!("mkfs.ext4 not found, skipping");
+            return;
+        }
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_allocation_test_{}.img",
+            std::process::id()
+        ));
+        std::fs::write(&path, vec![0u8; 64 * 1024 * 1024]).unwrap();
+        let status = Command::new("mkfs.ext4")
+            .args([
+                "-q",
+                "-F",
+                "-b",
+                "1024",
+                "-E",
+                "lazy_itable_init=1",
+                "-L",
+                "test",
+            ])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(Path::new(&path)).unwrap();
+        let (kind, extents) = allocated_extents(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(kind, FilesystemKind::Ext);
+        // the superblock and the journal are allocated, most of the filesystem isn't
+        assert_eq!(extents[0].offset, 0);
+        assert!(extents[0].length > 2048);
+        let used = total_length(&extents);
+        assert!(
+            used > 4 * 1024 * 1024 && used < 16 * 1024 * 1024,
+            "used {}",
+            used
+        );
+        // everything outside of the extents is zero on a fresh filesystem
+        for gap in complement(&extents, &Extent::new(0, image.len() as u64)) {
+            let range = gap.offset as usize..gap.end() as usize;
+            assert!(image[range].iter().all(|&b| b == 0), "data in {:?}", gap);
+        }
+    }
+}
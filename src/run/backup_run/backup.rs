@@ -1,12 +1,50 @@
-use std::path::Path;
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    path::Path,
+};
 
 use chrono::Local;
 use chrono_humanize::Humanize;
 use relative_path::RelativePath;
 
-use crate::run::utils::current_date;
+use crate::run::{
+    config::ImageFormat,
+    convert::{write_image, DiskReader},
+    extent::{from_offset, offset_after, total_length, Extent},
+    image_metadata::{ImageMetadata, Region},
+    partition_table::{relocate_gpt, PartitionTable},
+    utils::current_date,
+};
 
-use super::{command_output::command_output, device::Device, filesystem::Filesystem, BackupArgs};
+use super::{
+    command_output::CommandRunner,
+    destination_file,
+    device::Device,
+    filesystem::Filesystem,
+    interrupt::{self, InterruptibleReader},
+    journal::{partial_path, verify_tail, Journal, JournalWriter},
+    progress::{parse_dd_progress, CopyTracker, Phase, Progress},
+    rescue::{self, RescueReader},
+    tee::{copy_teed, TeeTarget},
+    throttle::{self, Scheduling, ThrottledReader},
+    used_blocks, BackupArgs,
+};
+
+/// The planned copy of a device into its image, see `Backup::plan`.
+struct CopyPlan {
+    format: ImageFormat,
+    /// The size of the image, if it is trimmed to the end of the last partition.
+    trimmed_size: Option<u64>,
+    /// The planned image, if it is copied natively.
+    native_copy: Option<ImageMetadata>,
+    /// The number of bytes to copy from the start of the device, if not the whole device.
+    length: Option<u64>,
+    /// The size of the image in bytes.
+    size: u64,
+    /// The extents of the device copied into the image.
+    extents: Vec<Extent>,
+}
 
 #[derive(Debug)]
 pub struct Backup<'a> {
@@ -16,6 +54,10 @@ pub struct Backup<'a> {
     pub backup_device: &'a Device,
     /// The command line arguments for the backup operation.
     pub backup_args: &'a BackupArgs,
+    /// The receiver of progress events.
+    pub progress: &'a Progress,
+    /// The runner executing the dd and chown commands.
+    pub runner: &'a dyn CommandRunner,
 }
 
 impl<'a> Backup<'a> {
@@ -25,72 +67,845 @@ impl<'a> Backup<'a> {
     ///
     /// * `dst_filesystem` - The destination filesystem for the backup.
     /// * `backup_device` - The device to be backed up.
+    /// * `backup_args` - The command-line arguments for the backup operation.
+    /// * `progress` - The receiver of progress events.
+    /// * `runner` - The runner executing the dd and chown commands.
     pub fn new(
         dst_filesystem: &'a Filesystem,
         backup_device: &'a Device,
         backup_args: &'a BackupArgs,
+        progress: &'a Progress,
+        runner: &'a dyn CommandRunner,
     ) -> Backup<'a> {
         let backup = Backup {
             dst_filesystem,
             backup_device,
             backup_args,
+            progress,
+            runner,
         };
         debug!("{:?}", backup);
         backup
     }
 
-    /// Runs the backup process using the `dd` command.
+    /// Runs the backup process using the `dd` command, or natively if `used_blocks_only`, `rescue`
+    /// or `bandwidth_limit` is set or partitions are excluded.
+    ///
+    /// With `trim_to_last_partition` the device is copied up to the end of its last partition only.
+    /// With an `output_format` other than raw the image is written natively in that format.
     ///
     /// # Returns
     ///
     /// * `Ok(())` if the backup process is successful.
     /// * `Err` with an error message if the backup process encounters an error.
     pub fn run(&self) -> Result<(), String> {
-        self.validate_state()?;
+        let CopyPlan {
+            format,
+            trimmed_size,
+            native_copy,
+            length,
+            size,
+            extents,
+        } = self.plan()?;
+        let from = self.validate_state(format, size, &extents)?.unwrap_or(0);
 
-        let input_file_arg = format!("if={}", self.backup_device.device_path.clone());
-        let output_file_arg = format!("of={}", self.backup_file_path());
-        let command_parts = vec!["dd", &input_file_arg, &output_file_arg, "status=progress"];
-        let description = format!("run dd command: {:?}", &command_parts.join(" "));
+        let command_parts = self.dd_command_parts(length, from);
         match self.backup_args.dry_run {
             true => {
-                info!(
-                    "[DRY RUN] backup would run with command: {}",
-                    &command_parts.join(" "),
-                );
+                match &native_copy {
+                    Some(metadata) => info!(
+                        "[DRY RUN] backup would copy {} of {} bytes of {} to {}",
+                        metadata.data_bytes(),
+                        metadata.size,
+                        self.backup_device.device_path,
+                        self.backup_file_path()
+                    ),
+                    None => info!(
+                        "[DRY RUN] backup would run with command: {}",
+                        &command_parts.join(" "),
+                    ),
+                }
+                if format != ImageFormat::Raw {
+                    info!(
+                        "[DRY RUN] backup would write a {:?} image to {}",
+                        format,
+                        self.backup_file_path()
+                    );
+                }
+                if self.saves_layout() {
+                    info!(
+                        "[DRY RUN] would save the partition table and the first and last {} bytes next to {}",
+                        Device::PARTITION_TABLE_LENGTH,
+                        self.backup_file_path()
+                    );
+                }
+                self.delete_surplus_backup();
                 Ok(())
             }
             false => {
-                let time_before_dd = Local::now();
-                let output =
-                    command_output(command_parts.clone(), description.as_str(), Some(true))?;
+                self.progress
+                    .phase(&self.backup_device.device_path, Phase::Copy);
+                let copied = match (format, &native_copy) {
+                    (ImageFormat::Raw, native_copy) => JournalWriter::start(
+                        self.runner,
+                        &self.backup_file_path(),
+                        Journal {
+                            source: self.backup_device.device_path.clone(),
+                            size,
+                            data_bytes: total_length(&extents),
+                            confirmed_offset: from,
+                        },
+                    )
+                    .and_then(|mut journal| match native_copy {
+                        Some(metadata) => self.copy_natively(metadata, from, &mut journal),
+                        None => self.copy_with_dd(&command_parts, size, from, &mut journal),
+                    }),
+                    (format, native_copy) => {
+                        self.copy_converted(format, native_copy.as_ref(), trimmed_size)
+                    }
+                };
+                if let Err(e) = copied {
+                    return Err(self.copy_failed(format, e));
+                }
+                self.complete(format, trimmed_size, native_copy.is_none())
+            }
+        }
+    }
 
-                if output.status.success() {
-                    let time_after_dd = Local::now();
-                    let diff = time_after_dd - time_before_dd;
-                    info!(
-                        "Success running backup with dd command {} for {}: {}",
-                        &command_parts.join(" "),
-                        diff.humanize(),
-                        String::from_utf8_lossy(&output.stdout).to_string()
+    /// Backs up the same device to several destinations with a single read, teeing it into all images.
+    ///
+    /// The backups need to have the same copy plan, which holds for a device configured with the same
+    /// options on several destinations, and write raw images. The partial backup files are written natively
+    /// like in `copy_natively`, an interrupted backup is resumed from the lowest offset confirmed on all
+    /// destinations. A backup failing on its destination, like one without enough space, is logged and
+    /// the others continue, see `copy_teed`.
+    pub fn run_teed(backups: &[Backup]) {
+        let Some(first) = backups.first() else {
+            return;
+        };
+        let device_path = &first.backup_device.device_path;
+        let plan = match first.plan() {
+            Ok(plan) => plan,
+            Err(e) => {
+                error!("Error performing backup: {}", e);
+                return;
+            }
+        };
+        let started: Vec<(&Backup, u64)> = backups
+            .iter()
+            .filter_map(|backup| {
+                match backup.validate_state(plan.format, plan.size, &plan.extents) {
+                    Ok(from) => Some((backup, from.unwrap_or(0))),
+                    Err(e) => {
+                        error!("Error performing backup: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        let Some(from) = started.iter().map(|(_, from)| *from).min() else {
+            return;
+        };
+
+        if first.backup_args.dry_run {
+            for (backup, _) in &started {
+                info!(
+                    "[DRY RUN] backup would copy {} of {} bytes of {} to {}, reading it once for {} destinations",
+                    total_length(&plan.extents),
+                    plan.size,
+                    device_path,
+                    backup.backup_file_path(),
+                    started.len()
+                );
+                backup.delete_surplus_backup();
+            }
+            return;
+        }
+
+        first.progress.phase(device_path, Phase::Copy);
+        let journal = Journal {
+            source: device_path.clone(),
+            size: plan.size,
+            data_bytes: total_length(&plan.extents),
+            confirmed_offset: from,
+        };
+        let (teed, mut targets): (Vec<&Backup>, Vec<TeeTarget>) = started
+            .into_iter()
+            .filter_map(|(backup, _)| {
+                match TeeTarget::open(backup.runner, &backup.backup_file_path(), journal.clone()) {
+                    Ok(target) => Some((backup, target)),
+                    Err(e) => {
+                        error!("Error performing backup: {}", e);
+                        None
+                    }
+                }
+            })
+            .unzip();
+        if teed.is_empty() {
+            return;
+        }
+
+        let images: Vec<String> = targets.iter().map(|target| target.image.clone()).collect();
+        let mut tracker = CopyTracker::start(
+            first.progress,
+            device_path,
+            &images.join(", "),
+            Some(journal.data_bytes),
+        );
+        let extents = from_offset(&plan.extents, from);
+        let copied_before = journal.data_bytes - total_length(&extents);
+        let time_before_copy = Local::now();
+        let copied = first.read_device(|device| {
+            copy_teed(device, &mut targets, &extents, &mut |bytes| {
+                tracker.update(copied_before + bytes)
+            })
+        });
+        let unreadable = match copied {
+            Ok((copied, unreadable)) => {
+                tracker.finish(true);
+                info!(
+                    "Success copying {} of {} bytes of {} to {} destinations for {}",
+                    copied,
+                    plan.size,
+                    device_path,
+                    images.len(),
+                    (Local::now() - time_before_copy).humanize()
+                );
+                unreadable
+            }
+            Err(e) => {
+                tracker.finish(false);
+                for (backup, target) in teed.iter().zip(targets) {
+                    let e = target
+                        .error
+                        .unwrap_or(format!("Error copying {}: {}", device_path, e));
+                    error!(
+                        "Error performing backup: {}",
+                        backup.copy_failed(plan.format, e)
                     );
+                }
+                return;
+            }
+        };
 
-                    self.chown()
-                } else {
-                    Err(format!(
-                        "Error running dd command {}: {}",
-                        &command_parts.join(" "),
-                        String::from_utf8_lossy(&output.stderr)
-                    ))
+        for (backup, target) in teed.iter().zip(targets) {
+            let completed = match (target.error, &plan.native_copy) {
+                (Some(e), _) => Err(e),
+                (None, Some(metadata)) => backup
+                    .write_metadata(metadata.clone(), unreadable.clone())
+                    .and_then(|_| backup.complete(plan.format, plan.trimmed_size, false)),
+                (None, None) => backup.complete(plan.format, plan.trimmed_size, true),
+            };
+            if let Err(e) = completed {
+                error!("Error performing backup: {}", e);
+            }
+        }
+    }
+
+    /// Returns whether the backup can be teed with `other` by `run_teed`, which is the case if both
+    /// copy the same device with the same options into a raw image.
+    pub fn tees_with(&self, other: &Backup) -> bool {
+        let (device, other_device) = (self.backup_device, other.backup_device);
+        self.output_format() == ImageFormat::Raw
+            && device.device_path == other_device.device_path
+            && device.partition_suffix == other_device.partition_suffix
+            && device.length == other_device.length
+            && device.options == other_device.options
+            && device.excluded_partitions == other_device.excluded_partitions
+    }
+
+    /// Plans the copy of the device: the format and size of the image and the extents to copy.
+    fn plan(&self) -> Result<CopyPlan, String> {
+        let format = self.output_format();
+        let trimmed_size = self.trimmed_size()?;
+        let native_copy = self.native_copy_plan(trimmed_size)?;
+        let length = self.backup_device.length.or(trimmed_size);
+        let size = length.unwrap_or(self.backup_device.total_size());
+        let extents = match &native_copy {
+            Some(metadata) => metadata.data_extents(),
+            None => vec![Extent::new(0, size)],
+        };
+        Ok(CopyPlan {
+            format,
+            trimmed_size,
+            native_copy,
+            length,
+            size,
+            extents,
+        })
+    }
+
+    /// Completes a copied image: a trimmed image gets its backup GPT, the partial backup file is published,
+    /// a surplus copy is deleted, the layout of the device is saved next to it and its owner is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The format of the image.
+    /// * `trimmed_size` - The size of the image, if it is trimmed to the end of the last partition.
+    /// * `copied_completely` - Whether the device was copied completely, without metadata written by a native copy.
+    fn complete(
+        &self,
+        format: ImageFormat,
+        trimmed_size: Option<u64>,
+        copied_completely: bool,
+    ) -> Result<(), String> {
+        if let (ImageFormat::Raw, Some(trimmed_size)) = (format, trimmed_size) {
+            self.finish_trimmed_image(trimmed_size, copied_completely)?;
+        }
+        self.publish()?;
+        self.delete_surplus_backup();
+
+        if self.saves_layout() {
+            if let Err(e) = self.save_layout() {
+                warn!(
+                    "Failed to save the partition table of {}: {}",
+                    self.backup_device.device_path, e
+                );
+            }
+        }
+        self.chown()
+    }
+
+    /// Returns the `dd` command copying the device, or its first `length` bytes, into the partial backup file.
+    /// A resumed copy skips the first `from` bytes of the device and the file.
+    fn dd_command_parts(&self, length: Option<u64>, from: u64) -> Vec<String> {
+        let mut command_parts = throttle::command_prefix(&self.backup_device.options);
+        command_parts.extend([
+            "dd".to_string(),
+            format!("if={}", self.backup_device.device_path),
+            format!("of={}", self.partial_file_path()),
+            "status=progress".to_string(),
+        ]);
+        let input_flags: Vec<&str> = [(from > 0, "skip_bytes"), (length.is_some(), "count_bytes")]
+            .into_iter()
+            .filter_map(|(is_set, flag)| is_set.then_some(flag))
+            .collect();
+        if !input_flags.is_empty() {
+            command_parts.push(format!("iflag={}", input_flags.join(",")));
+        }
+        if from > 0 {
+            command_parts.extend([
+                "oflag=seek_bytes".to_string(),
+                format!("skip={}", from),
+                format!("seek={}", from),
+            ]);
+        }
+        if let Some(length) = length {
+            command_parts.push(format!("count={}", length - from));
+        }
+        command_parts
+    }
+
+    /// Copies the device into the partial backup file with `dd`, reporting its progress towards `total_bytes`
+    /// and confirming it in the journal. A resumed copy starts at `from`.
+    fn copy_with_dd(
+        &self,
+        command_parts: &[String],
+        total_bytes: u64,
+        from: u64,
+        journal: &mut JournalWriter,
+    ) -> Result<(), String> {
+        let command_parts: Vec<&str> = command_parts.iter().map(String::as_str).collect();
+        let description = format!("run dd command: {:?}", &command_parts.join(" "));
+        let mut tracker = CopyTracker::start(
+            self.progress,
+            &self.backup_device.device_path,
+            &self.backup_file_path(),
+            Some(total_bytes),
+        );
+        let time_before_dd = Local::now();
+        let output = self.runner.output_with_progress(
+            command_parts.clone(),
+            description.as_str(),
+            Some(true),
+            &mut |line| {
+                if let Some(bytes) = parse_dd_progress(line) {
+                    tracker.update(from + bytes);
+                    journal.update(from + bytes);
                 }
+            },
+        );
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                tracker.finish(false);
+                return Err(e);
+            }
+        };
+
+        if output.status.success() {
+            tracker.finish(true);
+            let time_after_dd = Local::now();
+            let diff = time_after_dd - time_before_dd;
+            info!(
+                "Success running backup with dd command {} for {}: {}",
+                &command_parts.join(" "),
+                diff.humanize(),
+                String::from_utf8_lossy(&output.stdout)
+            );
+            Ok(())
+        } else {
+            tracker.finish(false);
+            Err(format!(
+                "Error running dd command {}: {}",
+                &command_parts.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            ))
+        }
+    }
+
+    /// Returns the format of the image, partition table images are always raw.
+    fn output_format(&self) -> ImageFormat {
+        match self.backup_device.length {
+            Some(_) => ImageFormat::Raw,
+            None => self.backup_device.options.output_format.unwrap_or_default(),
+        }
+    }
+
+    /// Returns whether the backup device is a whole device, which may hold a partition table.
+    fn is_whole_device(&self) -> bool {
+        !self.backup_device.blockdevice.is_partition()
+            && self.backup_device.partition_suffix.is_none()
+    }
+
+    /// Returns the size of the image if `trim_to_last_partition` is set for a whole device,
+    /// its partitions followed by room for the backup GPT.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(u64))`: The size of the trimmed image.
+    /// - `Ok(None)`: If the device is copied completely, also if it has no partition table
+    ///   or its partitions reach its end.
+    /// - `Err(String)`: If the partition table is not readable, on a dry run the device is copied completely instead.
+    fn trimmed_size(&self) -> Result<Option<u64>, String> {
+        let is_enabled = self
+            .backup_device
+            .options
+            .trim_to_last_partition
+            .unwrap_or(false)
+            && self.backup_device.length.is_none()
+            && self.is_whole_device();
+        if !is_enabled {
+            return Ok(None);
+        }
+        let device_path = &self.backup_device.device_path;
+        let device_size = self.backup_device.blockdevice.size;
+        let table = File::open(device_path)
+            .map_err(|e| {
+                format!(
+                    "Failed to open {} to read its partition table (needs to run as root): {}",
+                    device_path, e
+                )
+            })
+            .and_then(|mut device| PartitionTable::read(&mut device));
+        match table {
+            Ok(Some(table)) if table.trimmed_size() < device_size => {
+                info!(
+                    "Trimming the image of {} to {} of {} bytes, up to the end of its last partition",
+                    device_path,
+                    table.trimmed_size(),
+                    device_size
+                );
+                Ok(Some(table.trimmed_size()))
+            }
+            Ok(Some(_)) => {
+                info!(
+                    "The partitions of {} reach its end, copying it completely",
+                    device_path
+                );
+                Ok(None)
+            }
+            Ok(None) => {
+                info!(
+                    "No partition table found on {}, copying it completely",
+                    device_path
+                );
+                Ok(None)
+            }
+            Err(e) if self.backup_args.dry_run => {
+                warn!("[DRY RUN] {}, assuming a full copy", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Plans the image copied natively if `used_blocks_only`, `rescue` or `bandwidth_limit` is set or partitions
+    /// are excluded, partition table images are always copied completely.
+    ///
+    /// # Arguments
+    ///
+    /// * `trimmed_size` - The size of the image, if it is trimmed to the end of the last partition.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(ImageMetadata))`: The planned image, if it is copied natively.
+    /// - `Ok(None)`: If the device is copied completely with `dd`.
+    /// - `Err(String)`: If the device is not readable, on a dry run it is copied completely instead.
+    fn native_copy_plan(&self, trimmed_size: Option<u64>) -> Result<Option<ImageMetadata>, String> {
+        let used_blocks_only = self.backup_device.options.used_blocks_only.unwrap_or(false);
+        let is_enabled = (used_blocks_only
+            || self.rescues()
+            || !self.backup_device.excluded_partitions.is_empty()
+            || self.backup_device.options.bandwidth_limit.is_some())
+            && self.backup_device.length.is_none();
+        if !is_enabled {
+            return Ok(None);
+        }
+        let device_size = self.backup_device.blockdevice.size;
+        match used_blocks::plan(
+            &self.backup_device.device_path,
+            trimmed_size.unwrap_or(device_size),
+            self.is_whole_device(),
+            used_blocks_only,
+            &self.backup_device.excluded_partitions,
+        ) {
+            Ok(metadata) => Ok(Some(ImageMetadata {
+                source_size: trimmed_size.map(|_| device_size),
+                ..metadata
+            })),
+            Err(e) if self.backup_args.dry_run => {
+                warn!("[DRY RUN] {}, assuming a full copy", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Completes an image trimmed to `size` bytes: a backup GPT is written to its end, matching the
+    /// primary GPT, and images copied with `dd` get metadata recording the size of the device.
+    fn finish_trimmed_image(&self, size: u64, writes_metadata: bool) -> Result<(), String> {
+        let partial_path = self.partial_file_path();
+        let mut image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&partial_path)
+            .map_err(|e| format!("{}: {}", partial_path, e))?;
+        if let Some(relocated) = relocate_gpt(&mut image, size)? {
+            relocated.write_to(&mut image)?;
+            image
+                .sync_all()
+                .map_err(|e| format!("{}: {}", partial_path, e))?;
+            debug!("Relocated the backup GPT of {} to its end", partial_path);
+        }
+        if writes_metadata {
+            ImageMetadata {
+                source: self.backup_device.device_path.clone(),
+                size,
+                source_size: Some(self.backup_device.blockdevice.size),
+                unreadable: Vec::new(),
+                regions: vec![Region::full(0, size)],
+            }
+            .write(&self.backup_file_path())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the image of the device in a virtual disk format, reporting its progress.
+    ///
+    /// Only the planned regions of a native copy are read, clusters holding only zeros stay unallocated.
+    /// A trimmed image gets the GPT of the device relocated to its end. The metadata of native copies
+    /// and trimmed images is written next to the image, like for raw images.
+    fn copy_converted(
+        &self,
+        format: ImageFormat,
+        native_copy: Option<&ImageMetadata>,
+        trimmed_size: Option<u64>,
+    ) -> Result<(), String> {
+        let device_path = &self.backup_device.device_path;
+        let image_path = self.backup_file_path();
+        let size = trimmed_size.unwrap_or(self.backup_device.total_size());
+        let metadata = native_copy
+            .cloned()
+            .or(trimmed_size.map(|size| ImageMetadata {
+                source: device_path.clone(),
+                size,
+                source_size: Some(self.backup_device.blockdevice.size),
+                unreadable: Vec::new(),
+                regions: vec![Region::full(0, size)],
+            }));
+        let extents = match &metadata {
+            Some(metadata) => metadata.data_extents(),
+            None => vec![Extent::new(0, size)],
+        };
+
+        let mut device = File::open(device_path).map_err(|e| format!("{}: {}", device_path, e))?;
+        let patches = match trimmed_size {
+            Some(size) => match relocate_gpt(&mut device, size)? {
+                Some(relocated) => vec![
+                    (relocated.header_offset, relocated.header),
+                    (relocated.tail_offset, relocated.tail),
+                ],
+                None => vec![],
+            },
+            None => vec![],
+        };
+
+        let mut tracker = CopyTracker::start(
+            self.progress,
+            device_path,
+            &image_path,
+            Some(total_length(&extents)),
+        );
+        let time_before_copy = Local::now();
+        let unreadable = match self.read_device(|device| {
+            write_image(
+                format,
+                device,
+                size,
+                &extents,
+                &patches,
+                &self.partial_file_path(),
+                &mut |bytes| tracker.update(bytes),
+            )
+        }) {
+            Ok((copied, unreadable)) => {
+                tracker.finish(true);
+                info!(
+                    "Success writing {:?} image of {} bytes of {} for {}",
+                    format,
+                    copied,
+                    device_path,
+                    (Local::now() - time_before_copy).humanize()
+                );
+                unreadable
+            }
+            Err(e) => {
+                tracker.finish(false);
+                return Err(format!("Error copying {}: {}", device_path, e));
+            }
+        };
+        match metadata {
+            Some(metadata) => self.write_metadata(metadata, unreadable),
+            None => Ok(()),
+        }
+    }
+
+    /// Copies the planned regions of the device natively into the sparse partial backup file,
+    /// reporting its progress and confirming it in the journal. A resumed copy starts at `from`.
+    fn copy_natively(
+        &self,
+        metadata: &ImageMetadata,
+        from: u64,
+        journal: &mut JournalWriter,
+    ) -> Result<(), String> {
+        let mut tracker = CopyTracker::start(
+            self.progress,
+            &self.backup_device.device_path,
+            &self.backup_file_path(),
+            Some(metadata.data_bytes()),
+        );
+        let extents = from_offset(&metadata.data_extents(), from);
+        let copied_before = metadata.data_bytes() - total_length(&extents);
+        let time_before_copy = Local::now();
+        match self.read_device(|device| {
+            used_blocks::copy(
+                metadata,
+                device,
+                &self.partial_file_path(),
+                from,
+                &mut |bytes| {
+                    tracker.update(copied_before + bytes);
+                    journal.update(offset_after(&extents, bytes));
+                },
+            )
+        }) {
+            Ok((copied, unreadable)) => {
+                tracker.finish(true);
+                info!(
+                    "Success copying {} of {} bytes of {} for {}",
+                    copied,
+                    metadata.size,
+                    self.backup_device.device_path,
+                    (Local::now() - time_before_copy).humanize()
+                );
+                self.write_metadata(metadata.clone(), unreadable)
+            }
+            Err(e) => {
+                tracker.finish(false);
+                Err(format!(
+                    "Error copying {}: {}",
+                    self.backup_device.device_path, e
+                ))
+            }
+        }
+    }
+
+    /// Handles a failed copy, returning its error. A copy stopped by SIGINT or SIGTERM leaves a raw partial
+    /// backup file with its journal, to be resumed by the next backup of the device today. Other partial
+    /// backup files can't be resumed and are removed.
+    fn copy_failed(&self, format: ImageFormat, error: String) -> String {
+        if !interrupt::is_interrupted() {
+            return error;
+        }
+        let partial_path = self.partial_file_path();
+        match format == ImageFormat::Raw && !self.rescues() {
+            true => info!(
+                "Keeping {} to resume the backup of {} today",
+                partial_path, self.backup_device.device_path
+            ),
+            false => match Journal::discard(self.runner, &self.backup_file_path()) {
+                Ok(()) => info!("Removed {}, it can't be resumed", partial_path),
+                Err(e) => warn!("Failed to remove {}: {}", partial_path, e),
+            },
+        }
+        format!("Backup of {} interrupted", self.backup_device.device_path)
+    }
+
+    /// Publishes the complete image: the partial backup file is renamed to the image and its journal is removed,
+    /// both through the command runner with sudo.
+    fn publish(&self) -> Result<(), String> {
+        let partial_path = self.partial_file_path();
+        let image_path = self.backup_file_path();
+        destination_file::rename(self.runner, &partial_path, &image_path)
+            .map_err(|e| format!("Failed to rename {} to {}: {}", partial_path, image_path, e))?;
+        Journal::discard(self.runner, &image_path)?;
+        debug!("Renamed {} to {}", partial_path, image_path);
+        Ok(())
+    }
+
+    /// Returns whether the device is copied around read errors, partition table images never are.
+    fn rescues(&self) -> bool {
+        self.backup_device.options.rescue.unwrap_or(false) && self.backup_device.length.is_none()
+    }
+
+    /// Opens the device and reads it with `read`, through a `RescueReader` if `rescue` is set.
+    /// Reading fails once SIGINT or SIGTERM was received. The device is read with the configured
+    /// I/O priority and nice level and at most `bandwidth_limit` MiB per second.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok((T, Vec<Extent>))`: The result of `read` and the extents of the device which could not be read.
+    /// - `Err(String)`: If the device is not readable, or `read` failed.
+    fn read_device<T>(
+        &self,
+        read: impl FnOnce(&mut dyn DiskReader) -> Result<T, String>,
+    ) -> Result<(T, Vec<Extent>), String> {
+        let device_path = &self.backup_device.device_path;
+        let device = File::open(device_path).map_err(|e| format!("{}: {}", device_path, e))?;
+        let mut device = ThrottledReader::new(
+            InterruptibleReader::new(device),
+            self.backup_device.options.bandwidth_limit,
+        );
+        let _scheduling = Scheduling::apply(&self.backup_device.options);
+        match self.rescues() {
+            true => {
+                let mut reader = RescueReader::new(device);
+                let result = read(&mut reader)?;
+                Ok((result, reader.unreadable()))
+            }
+            false => Ok((read(&mut device)?, Vec::new())),
+        }
+    }
+
+    /// Writes the metadata next to the image, with the extents of the device which could not be read.
+    ///
+    /// A rescued image gets its map file too, the number of unreadable bytes is reported.
+    fn write_metadata(
+        &self,
+        metadata: ImageMetadata,
+        unreadable: Vec<Extent>,
+    ) -> Result<(), String> {
+        let image_path = self.backup_file_path();
+        let device_path = &self.backup_device.device_path;
+        if self.rescues() {
+            rescue::write_map(
+                &image_path,
+                device_path,
+                metadata.size,
+                &metadata.data_extents(),
+                &unreadable,
+            )?;
+            match unreadable.is_empty() {
+                true => info!("Read {} without errors", device_path),
+                false => warn!(
+                    "{} bytes of {} in {} ranges could not be read and were filled with a marker, \
+                    the image {} is degraded, see {}",
+                    total_length(&unreadable),
+                    device_path,
+                    unreadable.len(),
+                    image_path,
+                    rescue::map_path(&image_path)
+                ),
+            }
+        }
+        ImageMetadata {
+            unreadable,
+            ..metadata
+        }
+        .write(&image_path)
+    }
+
+    /// Returns whether the layout of the device is saved next to its image,
+    /// which is the case for whole devices and partition table images, but not for partitions.
+    fn saves_layout(&self) -> bool {
+        match &self.backup_device.partition_suffix {
+            Some(suffix) => suffix == "ptable",
+            None => true,
+        }
+    }
+
+    /// Saves the layout of the device next to the backup file:
+    /// - `<image>.head.bin`: the first MiB, with the MBR and the primary GPT.
+    /// - `<image>.tail.bin`: the last MiB, with the backup GPT.
+    /// - `<image>.sfdisk`: the partition table like `sfdisk --dump` prints it, if there is one.
+    ///
+    /// The partition table is parsed from the written raw image, or from the saved head for other formats.
+    fn save_layout(&self) -> Result<(), String> {
+        let device_size = self.backup_device.blockdevice.size;
+        let length = Device::PARTITION_TABLE_LENGTH.min(device_size);
+        let image_path = self.backup_file_path();
+
+        for (sidecar, skip) in [("head.bin", 0), ("tail.bin", device_size - length)] {
+            let input_file_arg = format!("if={}", self.backup_device.device_path);
+            let output_file_arg = format!("of={}.{}", image_path, sidecar);
+            let skip_arg = format!("skip={}", skip);
+            let count_arg = format!("count={}", length);
+            let command_parts = vec![
+                "dd",
+                &input_file_arg,
+                &output_file_arg,
+                "iflag=skip_bytes,count_bytes",
+                &skip_arg,
+                &count_arg,
+            ];
+            let output = self.runner.output(
+                command_parts,
+                &format!("save {} of {}", sidecar, self.backup_device.device_path),
+                Some(true),
+            )?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).to_string());
             }
         }
+
+        let table_path = match self.output_format() {
+            ImageFormat::Raw => image_path.clone(),
+            _ => format!("{}.head.bin", image_path),
+        };
+        let mut image = File::open(&table_path).map_err(|e| format!("{}: {}", table_path, e))?;
+        match PartitionTable::read(&mut image)? {
+            Some(table) => {
+                let sfdisk_path = format!("{}.sfdisk", image_path);
+                fs::write(
+                    &sfdisk_path,
+                    table.to_sfdisk(&self.backup_device.device_path),
+                )
+                .map_err(|e| format!("{}: {}", sfdisk_path, e))?;
+                info!("Saved partition table to {}", sfdisk_path);
+            }
+            None => info!(
+                "No partition table found on {}",
+                self.backup_device.device_path
+            ),
+        }
+        Ok(())
     }
 
-    /// Sets the owner of the backup file to the current user ID and group ID.
+    /// Sets the owner of the backup file and the saved layout to the current user ID and group ID.
     ///
     /// This function changes the owner of the backup file specified by `output_file_path`
-    /// to the current user and group. It uses the `chown` command to perform the operation.
+    /// and its layout files to the current user and group. It uses the `chown` command to perform the operation.
     ///
     /// # Returns
     ///
@@ -104,8 +919,15 @@ impl<'a> Backup<'a> {
         let group_id = unsafe { libc::getgid() };
 
         let user_group_id_arg = format!("{}:{}", user_id, group_id);
-        let command_parts = vec!["chown", &user_group_id_arg, &output_file_path];
-        command_output(
+        let layout_file_paths: Vec<String> = ["head.bin", "tail.bin", "sfdisk", "json", "map"]
+            .iter()
+            .map(|sidecar| format!("{}.{}", output_file_path, sidecar))
+            .filter(|path| Path::new(path).exists())
+            .collect();
+        let mut command_parts = vec!["chown", &user_group_id_arg];
+        command_parts.extend(layout_file_paths.iter().map(String::as_str));
+        command_parts.push(&output_file_path);
+        self.runner.output(
             command_parts,
             "change owner of backup file to $UID",
             Some(true),
@@ -114,7 +936,7 @@ impl<'a> Backup<'a> {
     }
 
     /// Returns the output dir path for the backup.
-    fn backup_dir_path(&self) -> String {
+    pub fn backup_dir_path(&self) -> String {
         let relative_path =
             RelativePath::new(&self.dst_filesystem.blockdevice.mountpoint.clone().unwrap())
                 .join_normalized(self.backup_device.destination_path.clone())
@@ -132,110 +954,258 @@ impl<'a> Backup<'a> {
         format!("/{}", relative_path)
     }
 
-    /// Generates the file name for the backup image.
+    /// Returns the path the backup is written to until it is complete, see `publish`.
+    fn partial_file_path(&self) -> String {
+        partial_path(&self.backup_file_path())
+    }
+
+    /// Generates the file name for the backup image, with the extension of its format.
     fn file_name(&self) -> String {
         format!(
-            "{}_{}",
+            "{}_{}.{}",
             current_date(),
-            self.suffix_file_name_pattern().replace(' ', "-")
+            self.suffix_file_name_pattern().replace(' ', "-"),
+            self.output_format().extension()
         )
     }
 
     /// Generates the stable postfix file name for the backup image.
     ///
-    /// The stable postfix file name is generated by combining the model and serial
+    /// The stable postfix file name is generated by combining the name, the model and serial
     /// number of the block device associated with the backup. Any spaces in the
-    /// names are replaced with hyphens.
+    /// names are replaced with hyphens. Devices without serial number use their identifier instead.
+    /// Images of partitions and partition tables end with the partition suffix, like `part2` or `ptable`.
+    /// The extension is not part of it, so that copies in every image format are found.
     ///
     /// # Returns
     ///
     /// The stable postfix file name as a string.
     fn suffix_file_name_pattern(&self) -> String {
-        format!(
-            "{}.img",
-            vec![
-                self.backup_device.name.clone(),
-                self.backup_device.blockdevice.model.clone(),
-                self.backup_device.blockdevice.serial.clone(),
-            ]
-            .into_iter()
-            .flatten()
-            .collect::<Vec<String>>()
-            .join("_")
-            .replace(' ', "-")
-        )
+        vec![
+            self.backup_device.name.clone(),
+            self.backup_device.blockdevice.model.clone(),
+            Some(self.backup_device.identifier_suffix()),
+            self.backup_device.partition_suffix.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>()
+        .join("_")
+        .replace(' ', "-")
     }
 
-    /// Checks if the number of existing backups exceeds the specified number of copies.
+    /// Checks if the number of existing backups, together with the new backup unless it is `published`
+    /// already, exceeds the specified number of copies.
     /// If the copies is `None` then return false
-    fn needs_deletion(&self) -> bool {
+    fn needs_deletion(&self, published: bool) -> bool {
         let present_number_of_copies = self
             .dst_filesystem
             .present_number_of_copies(&self.suffix_file_name_pattern(), &self.backup_dir_path());
+        let number_of_copies = match published {
+            true => present_number_of_copies,
+            false => present_number_of_copies + 1,
+        };
         match self.backup_device.copies {
-            Some(copies) => present_number_of_copies >= copies,
+            Some(copies) => number_of_copies > copies,
             None => false,
         }
     }
 
     /// Validates the state of the backup process by performing the following checks:
-    /// 1. Checks if the target file is already present. If it is, an error is returned.
-    /// 2. Checks if the oldest backup needs to be deleted based on the configured number of copies.
-    ///    If a deletion is required, the oldest backup is deleted.
-    /// 3. If no deletion is needed, checks if the target filesystem has enough space to accommodate
-    ///    the new backup. If there is insufficient space, an error is returned.
-    /// If all checks pass, `Ok(())` is returned indicating that the state is valid and the backup
-    /// process can proceed.
-    fn validate_state(&self) -> Result<(), String> {
+    /// 1. Checks if the device got mounted since it was found, like a disk holding a destination filesystem
+    ///    which is still mounted, see `Device::is_in_use`. If it is, an error is returned.
+    /// 2. Checks if the target file is already present. If it is, an error is returned.
+    /// 3. Checks if a partial backup file of an interrupted backup of the device on the same day is present.
+    ///    It is resumed if possible, otherwise it is discarded.
+    /// 4. Checks if the target filesystem has enough space to accommodate the rest of the backup next to
+    ///    the present copies. If it hasn't, an error is returned: the oldest backup is only deleted after
+    ///    the new one was published, see `delete_surplus_backup`, so a failed copy never costs a good image.
+    /// 5. Unless `delete_oldest_when_short` is set for the device: then the oldest backup is deleted right away,
+    ///    if it needs to be deleted based on the configured number of copies, and the space is checked again.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The format of the image.
+    /// * `size` - The size of the image in bytes.
+    /// * `extents` - The extents of the device copied into the image.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(u64))`: The offset to resume the interrupted backup from.
+    /// - `Ok(None)`: If the backup starts from the beginning.
+    /// - `Err(String)`: If the backup can't proceed.
+    fn validate_state(
+        &self,
+        format: ImageFormat,
+        size: u64,
+        extents: &[Extent],
+    ) -> Result<Option<u64>, String> {
+        if self.backup_device.is_in_use()? {
+            return Err(format!(
+                "Device {} is mounted, it is not backed up",
+                self.backup_device.device_path
+            ));
+        }
         self.target_file_is_present()?;
-        let needed_deletion = self.delete_oldest_backup_if_needed()?;
-        if !needed_deletion {
-            self.target_filesystem_has_enough_space()?;
+        let resume_offset = self.resume_offset(format, size, extents)?;
+        let remaining = total_length(&from_offset(extents, resume_offset.unwrap_or(0)));
+        match self.target_filesystem_has_enough_space(remaining) {
+            Ok(()) => Ok(resume_offset),
+            Err(_)
+                if self.backup_device.delete_oldest_when_short.unwrap_or(false)
+                    && self.needs_deletion(false) =>
+            {
+                warn!(
+                    "Not enough space for {} next to the present copies, deleting the oldest copy before the backup is complete",
+                    self.backup_file_path()
+                );
+                self.delete_oldest_backup(None)?;
+                if !self.backup_args.dry_run {
+                    self.target_filesystem_has_enough_space(remaining)?;
+                }
+                Ok(resume_offset)
+            }
+            Err(e) => Err(e),
         }
-        Ok(())
     }
 
-    /// Side-Effect: Deletes the oldest backup file if the number of existing backups exceeds the specified number of copies.
-    fn delete_oldest_backup_if_needed(&self) -> Result<bool, String> {
-        let needs_deletion = self.needs_deletion();
-        if needs_deletion {
-            if self.backup_args.dry_run {
+    /// Returns the offset to resume an interrupted backup from, if its partial backup file is present.
+    ///
+    /// The journal needs to match the device and the plan of this backup, and the last bytes before the
+    /// confirmed offset need to equal the device. Otherwise the partial backup file is discarded.
+    /// Images in other formats than raw and rescued images are always restarted.
+    fn resume_offset(
+        &self,
+        format: ImageFormat,
+        size: u64,
+        extents: &[Extent],
+    ) -> Result<Option<u64>, String> {
+        let image_path = self.backup_file_path();
+        let partial_path = self.partial_file_path();
+        if !Path::new(&partial_path).exists() {
+            return Ok(None);
+        }
+        let journal = Journal::read(&image_path).unwrap_or_else(|e| {
+            warn!("{}", e);
+            None
+        });
+        let confirmed_offset = match journal {
+            Some(journal)
+                if format == ImageFormat::Raw
+                    && !self.rescues()
+                    && journal.confirmed_offset > 0
+                    && journal.matches(&self.backup_device.device_path, size, extents) =>
+            {
+                Some(journal.confirmed_offset)
+                    .filter(|offset| self.verify_partial(&partial_path, extents, *offset))
+            }
+            _ => None,
+        };
+
+        match (confirmed_offset, self.backup_args.dry_run) {
+            (Some(offset), true) => info!(
+                "[DRY RUN] would resume the interrupted backup {} from byte {}",
+                partial_path, offset
+            ),
+            (Some(offset), false) => info!(
+                "Resuming the interrupted backup {} from byte {} of {}",
+                partial_path, offset, size
+            ),
+            (None, true) => info!(
+                "[DRY RUN] would discard the interrupted backup {} and start over",
+                partial_path
+            ),
+            (None, false) => {
                 info!(
-                    "[DRY RUN] Would delete oldest backup file with suffix: {} in {}",
-                    self.suffix_file_name_pattern(),
-                    self.backup_dir_path()
+                    "Discarding the interrupted backup {}, it can't be resumed, starting over",
+                    partial_path
+                );
+                Journal::discard(self.runner, &image_path)?;
+            }
+        }
+        Ok(confirmed_offset)
+    }
+
+    /// Returns whether the partial backup file equals the device before `confirmed_offset`.
+    fn verify_partial(
+        &self,
+        partial_path: &str,
+        extents: &[Extent],
+        confirmed_offset: u64,
+    ) -> bool {
+        let device_path = &self.backup_device.device_path;
+        let verified = File::open(device_path)
+            .map_err(|e| format!("{}: {}", device_path, e))
+            .and_then(|mut device| {
+                let mut partial =
+                    File::open(partial_path).map_err(|e| format!("{}: {}", partial_path, e))?;
+                verify_tail(&mut device, &mut partial, extents, confirmed_offset)
+            });
+        match verified {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!(
+                    "The end of {} differs from {}, the device changed since the backup was interrupted",
+                    partial_path, device_path
                 );
-            } else {
-                self.dst_filesystem.delete_oldest_backup(
-                    &self.suffix_file_name_pattern(),
-                    &self.backup_dir_path(),
-                )?;
+                false
+            }
+            Err(e) => {
+                warn!("Failed to verify {}: {}", partial_path, e);
+                false
             }
         }
-        Ok(needs_deletion)
+    }
+
+    /// Side-Effect: Deletes the oldest backup file if the published image exceeds the specified number of copies.
+    ///
+    /// Deleting the oldest backup only after the new one is complete keeps it if the copy fails.
+    /// A failed deletion is logged only, since the backup itself succeeded.
+    fn delete_surplus_backup(&self) {
+        if !self.needs_deletion(!self.backup_args.dry_run) {
+            return;
+        }
+        if let Err(e) = self.delete_oldest_backup(Some(&self.file_name())) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Deletes the oldest backup file of the device besides `keep`, or logs it on a dry run.
+    fn delete_oldest_backup(&self, keep: Option<&str>) -> Result<(), String> {
+        if self.backup_args.dry_run {
+            info!(
+                "[DRY RUN] Would delete oldest backup file with suffix: {} in {}",
+                self.suffix_file_name_pattern(),
+                self.backup_dir_path()
+            );
+            Ok(())
+        } else {
+            self.dst_filesystem.delete_oldest_backup(
+                &self.suffix_file_name_pattern(),
+                &self.backup_dir_path(),
+                keep,
+            )
+        }
     }
 
     /// Checks if the target filesystem has enough space to accommodate the backup of the device.
-    /// It compares the available space on the filesystem with the total size of the device to be backed up.
+    /// It compares the available space on the filesystem with the number of bytes to be written,
+    /// the total size of the device or the used blocks of a used-blocks-only image.
     /// If there is sufficient space, `Ok(())` is returned, indicating that the backup can proceed.
     /// If there is not enough space or if it couldn't be read, an error is returned with a descriptive message.
-    /// If either available_space or needed_space is None then proceed with an Ok as well.
-    fn target_filesystem_has_enough_space(&self) -> Result<(), String> {
+    fn target_filesystem_has_enough_space(&self, needed_space: u64) -> Result<(), String> {
         let available_space = self.dst_filesystem.available_space()?.ok_or(format!(
             "Available space on {} not readable",
             self.dst_filesystem.device_path
         ))?;
-        let needed_space = self.backup_device.total_size()?.ok_or(format!(
-            "Needed space on {} not readable",
-            self.backup_device.device_path
-        ))?;
 
         let remaining_space: i64 = available_space as i64 - needed_space as i64;
         if remaining_space > 0 {
             Ok(())
         } else {
             Err(format!(
-                "Not enough space on destination filesystem {}, to backup device {}",
+                "Not enough space on destination filesystem {}, to backup device {} next to the present copies, \
+                 lower its copies, free space or set delete_oldest_when_short",
                 self.dst_filesystem.device_path, self.backup_device.device_path
             ))
         }
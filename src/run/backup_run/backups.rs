@@ -1,11 +1,29 @@
+use std::{path::PathBuf, sync::Arc};
+
+use chrono::{DateTime, Local};
+
 use crate::run::backup_run::backup::Backup;
 use crate::run::config::{BackupConfig, Config};
+use crate::run::utils::next_time_of_day;
 
+use super::command_output::CommandRunner;
 use super::device::Device;
+use super::executor::Job;
 use super::filesystem::Filesystem;
-use super::lsblk::Lsblk;
+use super::interrupt;
+use super::lock::{DestinationLock, HostLock};
+use super::lsblk::{BlockDevice, Lsblk};
+use super::progress::{Phase, Progress};
+use super::quarantine::quarantine_stale_files;
 use super::BackupArgs;
 
+/// The locks of a destination filesystem opened for backups, see `Backups::open`.
+#[derive(Debug)]
+pub struct Session {
+    _host_lock: HostLock,
+    destination_lock: DestinationLock,
+}
+
 #[derive(Debug)]
 pub struct Backups<'a> {
     /// The destination filesystem for the backup.
@@ -15,6 +33,14 @@ pub struct Backups<'a> {
     /// The command line arguments for the backup operation.
     pub backup_args: &'a BackupArgs,
     pub skip_mount: bool,
+    /// The receiver of progress events.
+    pub progress: &'a Progress,
+    /// The runner executing all external commands.
+    pub runner: Arc<dyn CommandRunner>,
+    /// The directory of the lock files of this host, see `HostLock`, `locks` in the config home by default.
+    pub lock_dir: Option<PathBuf>,
+    /// The point in time after which no further backups are started, see `start_before`.
+    pub start_deadline: Option<DateTime<Local>>,
 }
 
 impl<'a> Backups<'a> {
@@ -27,6 +53,8 @@ impl<'a> Backups<'a> {
     /// * `lsblk` - The `Lsblk` instance containing available filesystems and devices.
     /// * `backup_args` - The command-line arguments for the backup operation.
     /// * `config` - The global configuration.
+    /// * `progress` - The receiver of progress events.
+    /// * `runner` - The runner executing all external commands.
     ///
     /// # Returns
     ///
@@ -38,11 +66,14 @@ impl<'a> Backups<'a> {
         lsblk: &Lsblk,
         backup_args: &'a BackupArgs,
         config: &'a Config,
+        progress: &'a Progress,
+        runner: Arc<dyn CommandRunner>,
     ) -> Result<Option<Backups<'a>>, String> {
         let dst_filesystem = Filesystem::new(
             backup_config,
             &lsblk.available_filesystems,
-            config.mountpath.clone(),
+            backup_args.mountpath.clone().or(config.mountpath.clone()),
+            Arc::clone(&runner),
         )?;
 
         if let Some(dst_filesystem) = dst_filesystem {
@@ -61,18 +92,28 @@ impl<'a> Backups<'a> {
                 })
                 .collect();
 
-            // Unwrap the `Result<Vec<Device>, String>` and filter out any `None` values using `filter_map`
+            // Unwrap the `Result<Vec<Vec<Device>>, String>` and flatten the devices of each backup device
             let backup_devices: Vec<Device> = backup_devices_result
                 .map_err(|e| format!("Failed to create Device object: {}", e))?
                 .into_iter()
                 .flatten()
                 .collect();
 
+            let start_deadline = backup_args
+                .start_before
+                .as_ref()
+                .or(config.start_before.as_ref())
+                .map(|start_before| next_time_of_day(start_before, Local::now()))
+                .transpose()?;
             let backups = Backups {
                 dst_filesystem,
                 backup_devices,
                 backup_args,
                 skip_mount: backup_config.skip_mount.unwrap_or(false),
+                progress,
+                runner,
+                lock_dir: None,
+                start_deadline,
             };
             debug!("{:?}", backups);
             Ok(Some(backups))
@@ -82,41 +123,932 @@ impl<'a> Backups<'a> {
     }
 
     /// Executes the backup process.
+    /// Locks the filesystem against other runs, see `HostLock` and `DestinationLock`, skipping it if it is locked.
     /// Checks filesystem with `fsck` before mounting it (eventually unmount first).
     /// If fsck was successfull, do backups pairs matching the conditions, unmount
+    /// Stale temporary files of interrupted backups are quarantined after mounting.
     /// If fsck was not successfull, dst_filesystem will be skipped
+    /// Once the time of `start_before` has passed, no further backups are started, an unopened filesystem is skipped.
+    /// Once SIGINT or SIGTERM was received, no further backups are started, the written data is synced
+    /// and the filesystem is unmounted, before an error is returned.
     /// Returns `Ok(())` if the backup process is successful, otherwise returns an error message.
     pub fn run(mut self) -> Result<(), String> {
-        if !self.skip_mount && self.dst_filesystem.is_mounted() {
-            self.dst_filesystem.unmount()?;
+        if self.is_past_start_deadline() {
+            return Ok(());
         }
+        let Some(session) = self.open()? else {
+            return interrupt::check();
+        };
+        for backup_device in &self.backup_devices {
+            if interrupt::is_interrupted() || self.is_past_start_deadline() {
+                break;
+            }
+            self.back_up(backup_device);
+        }
+        self.close(session)
+    }
 
-        match self.dst_filesystem.validate_fsck_or_skip() {
-            Ok(()) => {
-                if !self.skip_mount {
-                    self.dst_filesystem.mount()?;
-                }
-
-                for backup_device in &self.backup_devices {
-                    if let Err(err) =
-                        Backup::new(&self.dst_filesystem, backup_device, self.backup_args).run()
-                    {
-                        error!("Error performing backup: {}", err);
-                    }
-                }
-
-                if !self.skip_mount {
-                    self.dst_filesystem.unmount()?;
-                }
-                Ok(())
+    /// Prepares the destination filesystem for backups: it is locked, checked with `fsck` and mounted,
+    /// and stale temporary files of interrupted backups are quarantined, see `run`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(Session))`: The locks of the filesystem, to be passed to `close` after the backups.
+    /// - `Ok(None)`: If the filesystem is skipped, because it is locked or the check failed, which is logged.
+    /// - `Err(String)`: If the filesystem could not be mounted or unmounted.
+    pub fn open(&mut self) -> Result<Option<Session>, String> {
+        let host_lock = match self.host_lock() {
+            Ok(host_lock) => host_lock,
+            Err(e) => {
+                error!(
+                    "{}, skipping backups for filesystem {}",
+                    e, self.dst_filesystem.device_path
+                );
+                return Ok(None);
             }
+        };
+        if !self.skip_mount && self.dst_filesystem.is_mounted() {
+            self.unmount()?;
+        }
+
+        if !self.dst_filesystem.skip_fsck {
+            self.progress
+                .phase(&self.dst_filesystem.device_path, Phase::Fsck);
+        }
+        if let Err(e) = self.dst_filesystem.validate_fsck_or_skip() {
+            error!(
+                "{}, skipping backups for filesystem {}",
+                e, self.dst_filesystem.device_path
+            );
+            return Ok(None);
+        }
+        if !self.skip_mount {
+            self.progress
+                .phase(&self.dst_filesystem.device_path, Phase::Mount);
+            self.dst_filesystem.mount()?;
+        }
+        let destination_lock = match self.destination_lock() {
+            Ok(destination_lock) => destination_lock,
             Err(e) => {
                 error!(
                     "{}, skipping backups for filesystem {}",
                     e, self.dst_filesystem.device_path
                 );
-                Ok(())
+                if !self.skip_mount {
+                    self.unmount()?;
+                }
+                return Ok(None);
+            }
+        };
+        self.quarantine_stale_files();
+        Ok(Some(Session {
+            _host_lock: host_lock,
+            destination_lock,
+        }))
+    }
+
+    /// Returns whether no further backups are started, because the time of `start_before` has passed,
+    /// which is logged.
+    pub fn is_past_start_deadline(&self) -> bool {
+        match self.start_deadline {
+            Some(start_deadline) if Local::now() >= start_deadline => {
+                warn!(
+                    "It is past {}, not starting further backups to filesystem {}",
+                    start_deadline.format("%H:%M"),
+                    self.dst_filesystem.device_path
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Backs up a device to the opened filesystem, a failed backup is logged only.
+    pub fn back_up(&self, backup_device: &Device) {
+        if let Err(err) = self.backup(backup_device).run() {
+            error!("Error performing backup: {}", err);
+        }
+    }
+
+    /// Returns the backup of a device to the filesystem.
+    pub fn backup<'b>(&'b self, backup_device: &'b Device) -> Backup<'b> {
+        Backup::new(
+            &self.dst_filesystem,
+            backup_device,
+            self.backup_args,
+            self.progress,
+            self.runner.as_ref(),
+        )
+    }
+
+    /// Releases the filesystem opened by `open` after the backups: it is unmounted,
+    /// or synced if it stays mounted and the run was interrupted.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())`: If the backups ran to the end.
+    /// - `Err(String)`: If the filesystem could not be unmounted, or SIGINT or SIGTERM was received.
+    pub fn close(mut self, session: Session) -> Result<(), String> {
+        drop(session.destination_lock);
+        match self.skip_mount {
+            false => self.unmount()?,
+            true if interrupt::is_interrupted() => {
+                self.runner
+                    .output(vec!["sync"], "execute sync", Some(false))?;
+            }
+            true => {}
+        }
+        interrupt::check()
+    }
+
+    /// Turns the backups into a job of `run_jobs`, labeled with the destination filesystem.
+    ///
+    /// Its resources are the physical disk of the destination, the disks of all source devices
+    /// and the mount path, so that a source is never read by two jobs at once, writes to the
+    /// partitions of one disk don't compete and destinations sharing a mount path take turns.
+    /// It reads the source devices and writes the destination and its disk, so that the backup
+    /// of a disk holding another destination runs after the backups to it, see `order_jobs`.
+    pub fn job(self) -> Job<Backups<'a>> {
+        Job {
+            label: self.dst_filesystem.device_path.clone(),
+            resources: self.resources(),
+            reads: self.reads(),
+            writes: self.writes(),
+            task: self,
+        }
+    }
+
+    /// Returns the names of the source devices, read by the job of the backups, see `job`.
+    pub fn reads(&self) -> Vec<String> {
+        let mut reads: Vec<String> = self
+            .backup_devices
+            .iter()
+            .map(|backup_device| backup_device.blockdevice.name.clone())
+            .collect();
+        reads.sort();
+        reads.dedup();
+        reads
+    }
+
+    /// Returns the names of the destination filesystem and its disk, written by the job of the backups, see `job`.
+    pub fn writes(&self) -> Vec<String> {
+        let blockdevice = &self.dst_filesystem.blockdevice;
+        let mut writes = vec![
+            blockdevice.name.clone(),
+            whole_disk(blockdevice).to_string(),
+        ];
+        writes.dedup();
+        writes
+    }
+
+    /// Returns the resources of the job of the backups, see `job`.
+    pub fn resources(&self) -> Vec<String> {
+        let mut resources = vec![
+            format!("disk:{}", whole_disk(&self.dst_filesystem.blockdevice)),
+            format!("mountpath:{}", self.dst_filesystem.mountpath),
+        ];
+        for backup_device in &self.backup_devices {
+            resources.push(format!("disk:{}", whole_disk(&backup_device.blockdevice)));
+        }
+        resources.sort();
+        resources.dedup();
+        resources
+    }
+
+    /// Locks the destination filesystem against other runs on this host, see `HostLock`.
+    fn host_lock(&self) -> Result<HostLock, String> {
+        let lock_dir = match &self.lock_dir {
+            Some(lock_dir) => lock_dir.clone(),
+            None => Config::config_home_path()?.join("locks"),
+        };
+        let uuid = self
+            .dst_filesystem
+            .blockdevice
+            .uuid
+            .clone()
+            .unwrap_or_default();
+        HostLock::acquire(&lock_dir, &uuid, self.backup_args.wait)
+    }
+
+    /// Locks the mounted destination filesystem against runs on other hosts, see `DestinationLock`.
+    fn destination_lock(&self) -> Result<DestinationLock, String> {
+        let mountpoint = self
+            .dst_filesystem
+            .blockdevice
+            .mountpoint
+            .clone()
+            .ok_or(format!(
+                "Filesystem {} is not mounted",
+                self.dst_filesystem.device_path
+            ))?;
+        DestinationLock::acquire(self.runner.clone(), &mountpoint, self.backup_args.wait)
+    }
+
+    /// Moves the stale temporary files of interrupted backups out of the backup directories,
+    /// see `quarantine_stale_files`. Failures are logged only, they don't stop the backups.
+    fn quarantine_stale_files(&self) {
+        let mut backup_dirs: Vec<String> = self
+            .backup_devices
+            .iter()
+            .map(|backup_device| self.backup(backup_device).backup_dir_path())
+            .collect();
+        backup_dirs.sort();
+        backup_dirs.dedup();
+        for backup_dir in backup_dirs {
+            if let Err(e) = quarantine_stale_files(&backup_dir, self.backup_args.dry_run) {
+                warn!("Failed to quarantine stale files in {}: {}", backup_dir, e);
+            }
+        }
+    }
+
+    /// Unmounts the destination filesystem, reporting the `Phase::Unmount` phase.
+    fn unmount(&mut self) -> Result<(), String> {
+        self.progress
+            .phase(&self.dst_filesystem.device_path, Phase::Unmount);
+        self.dst_filesystem.unmount()
+    }
+}
+
+/// Returns the name of the disk holding a block device, like `sda` for the partition `sda1`.
+fn whole_disk(blockdevice: &BlockDevice) -> &str {
+    blockdevice.pkname.as_deref().unwrap_or(&blockdevice.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::*;
+    use crate::run::backup_run::command_output::fake::{FakeCommandRunner, FakeResponse};
+    use crate::run::backup_run::journal::{partial_path, Journal};
+    use crate::run::backup_run::lock::{LockOwner, DESTINATION_LOCK_FILE};
+    use crate::run::backup_run::quarantine::QUARANTINE_DIR;
+    use crate::run::config::{
+        BackupDevice, CopyOptions, DeviceIdentifier, IoPriority, PartitionSelector,
+    };
+    use crate::run::extent::Extent;
+    use crate::run::image_metadata::{ImageMetadata, Region};
+
+    const LSBLK_OUTPUT: &str = r#"{
+        "blockdevices": [
+            {"name": "fakesrc0", "model": "Model", "serial": "SRC1", "uuid": null,
+             "mountpoint": null, "size": "1M", "fsavail": null},
+            {"name": "fakedst0p1", "model": null, "serial": null, "uuid": "DST-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G"}
+        ]
+    }"#;
+
+    /// Returns the directory holding the files of the simulated destination filesystem of a test.
+    fn destination(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_backups_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_dir_all(mountpath(&path));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Returns the path the destination filesystem is mounted at, created by the mount.
+    fn mountpath(destination: &Path) -> PathBuf {
+        PathBuf::from(format!("{}_mnt", destination.to_string_lossy()))
+    }
+
+    fn backup_args(dry_run: bool) -> BackupArgs {
+        BackupArgs {
+            dry_run,
+            file_config_args: None,
+            single_backup_args: None,
+            mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
+        }
+    }
+
+    fn config(destination: &Path, copies: Option<usize>) -> Config {
+        Config {
+            mountpath: Some(mountpath(destination).to_string_lossy().to_string()),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
+            backups: vec![BackupConfig {
+                backup_devices: vec![BackupDevice {
+                    identifier: DeviceIdentifier::Serial("SRC1".to_string()),
+                    name: Some("desktop".to_string()),
+                    copies,
+                    delete_oldest_when_short: None,
+                    partitions: None,
+                    options: CopyOptions::default(),
+                }],
+                uuid: "DST-UUID".to_string(),
+                destination_path: None,
+                fsck_command: None,
+                skip_fsck: None,
+                skip_mount: None,
+            }],
+        }
+    }
+
+    /// Returns the directory of the simulated destination filesystem of a test configuration.
+    fn destination_of(config: &Config) -> PathBuf {
+        let mountpath = config.mountpath.as_ref().unwrap();
+        PathBuf::from(mountpath.strip_suffix("_mnt").unwrap())
+    }
+
+    /// Returns the directory of the lock files of a test, next to its destination.
+    fn lock_dir(config: &Config) -> PathBuf {
+        PathBuf::from(format!(
+            "{}_locks",
+            destination_of(config).to_string_lossy()
+        ))
+    }
+
+    fn run_backups(
+        runner: FakeCommandRunner,
+        config: &Config,
+        backup_args: &BackupArgs,
+    ) -> (Arc<FakeCommandRunner>, Result<(), String>) {
+        run_backups_with_lsblk(runner, LSBLK_OUTPUT, config, backup_args)
+    }
+
+    fn run_backups_with_lsblk(
+        runner: FakeCommandRunner,
+        lsblk_output: &str,
+        config: &Config,
+        backup_args: &BackupArgs,
+    ) -> (Arc<FakeCommandRunner>, Result<(), String>) {
+        let runner = Arc::new(
+            runner
+                .script("lsblk", FakeResponse::ok(lsblk_output))
+                .filesystem("/dev/fakedst0p1", &destination_of(config)),
+        );
+        let lsblk = Lsblk::from_lsblk(runner.as_ref()).unwrap();
+        let progress = Progress::new();
+        let mut backups = Backups::new(
+            &config.backups[0],
+            &lsblk,
+            backup_args,
+            config,
+            &progress,
+            runner.clone(),
+        )
+        .unwrap()
+        .unwrap();
+        backups.lock_dir = Some(lock_dir(config));
+        let result = backups.run();
+        (runner, result)
+    }
+
+    /// Returns the files in the destination besides those of the backup of today.
+    fn present_images(destination: &Path) -> Vec<String> {
+        let today = crate::run::utils::current_date();
+        let mut images: Vec<String> = fs::read_dir(destination)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|file_name| !file_name.starts_with(&today))
+            .collect();
+        images.sort();
+        images
+    }
+
+    /// Asserts that the partial backup file of today was renamed to the image.
+    fn assert_published(destination: &Path) {
+        let image = format!(
+            "{}_desktop_Model_SRC1.img",
+            crate::run::utils::current_date()
+        );
+        assert!(destination.join(&image).exists());
+        assert!(!destination.join(format!("{}.partial", image)).exists());
+        assert!(!destination
+            .join(format!("{}.partial.journal", image))
+            .exists());
+    }
+
+    #[test]
+    fn test_run_mounts_copies_and_unmounts() {
+        let destination = destination("success");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        let commands = runner.commands_of(&["fsck", "mount", "dd", "chown", "sync", "umount"]);
+        let image = format!(
+            "{}/{}_desktop_Model_SRC1.img",
+            mountpath(&destination).to_string_lossy(),
+            crate::run::utils::current_date()
+        );
+        assert_eq!(commands.len(), 8);
+        assert_eq!(commands[0], "fsck -n /dev/fakedst0p1");
+        assert_eq!(
+            commands[1],
+            format!(
+                "mount /dev/fakedst0p1 {}",
+                mountpath(&destination).to_string_lossy()
+            )
+        );
+        assert_eq!(
+            commands[2],
+            format!("dd if=/dev/fakesrc0 of={}.partial status=progress", image)
+        );
+        assert_eq!(
+            commands[3],
+            format!(
+                "dd if=/dev/fakesrc0 of={}.head.bin iflag=skip_bytes,count_bytes skip=0 count=1048576",
+                image
+            )
+        );
+        assert_eq!(
+            commands[4],
+            format!(
+                "dd if=/dev/fakesrc0 of={}.tail.bin iflag=skip_bytes,count_bytes skip=0 count=1048576",
+                image
+            )
+        );
+        assert!(commands[5].starts_with("chown ") && commands[5].ends_with(&image));
+        assert_eq!(commands[6], "sync");
+        assert!(!destination.join(DESTINATION_LOCK_FILE).exists());
+        // the mount path was created for the mount and removed afterwards
+        assert!(!mountpath(&destination).exists());
+        assert_eq!(
+            commands[7],
+            format!("umount {}", mountpath(&destination).to_string_lossy())
+        );
+    }
+
+    #[test]
+    fn test_run_deletes_oldest_copy() {
+        let destination = destination("rotation");
+        let config = config(&destination, Some(2));
+        let backup_args = backup_args(false);
+        // the oldest copy was converted, copies in any image format are counted
+        for file_name in [
+            "2023-01-01_desktop_Model_SRC1.qcow2",
+            "2023-01-02_desktop_Model_SRC1.img",
+        ] {
+            fs::write(destination.join(file_name), b"image").unwrap();
+        }
+        fs::write(
+            destination.join("2023-01-01_desktop_Model_SRC1.qcow2.sfdisk"),
+            b"label: gpt",
+        )
+        .unwrap();
+        fs::write(destination.join("unrelated.img"), b"image").unwrap();
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        assert_published(&destination);
+        let images = present_images(&destination);
+        assert_eq!(
+            images,
+            vec![
+                "2023-01-02_desktop_Model_SRC1.img".to_string(),
+                "unrelated.img".to_string()
+            ]
+        );
+        assert_eq!(runner.commands_of(&["dd"]).len(), 3);
+    }
+
+    #[test]
+    fn test_run_short_of_space_deletes_oldest_copy_only_if_enabled() {
+        // a source larger than any temporary directory, the destination is always short of space
+        let lsblk_output = LSBLK_OUTPUT.replace(r#""size": "1M""#, r#""size": "1000T""#);
+        let backup_args = backup_args(false);
+        for delete_oldest_when_short in [false, true] {
+            let destination = destination(&format!("short_{}", delete_oldest_when_short));
+            let mut config = config(&destination, Some(2));
+            config.backups[0].backup_devices[0].delete_oldest_when_short =
+                Some(delete_oldest_when_short);
+            for file_name in [
+                "2023-01-01_desktop_Model_SRC1.img",
+                "2023-01-02_desktop_Model_SRC1.img",
+            ] {
+                fs::write(destination.join(file_name), b"image").unwrap();
             }
+
+            let (runner, _) = run_backups_with_lsblk(
+                FakeCommandRunner::new(),
+                &lsblk_output,
+                &config,
+                &backup_args,
+            );
+
+            let expected = match delete_oldest_when_short {
+                false => vec![
+                    "2023-01-01_desktop_Model_SRC1.img".to_string(),
+                    "2023-01-02_desktop_Model_SRC1.img".to_string(),
+                ],
+                true => vec!["2023-01-02_desktop_Model_SRC1.img".to_string()],
+            };
+            assert_eq!(present_images(&destination), expected);
+            assert!(runner.commands_of(&["dd"]).is_empty());
         }
     }
+
+    #[test]
+    fn test_run_keeps_last_good_copy() {
+        let destination = destination("degraded");
+        let config = config(&destination, Some(2));
+        let backup_args = backup_args(false);
+        for file_name in [
+            "2023-01-01_desktop_Model_SRC1.img",
+            "2023-01-02_desktop_Model_SRC1.img",
+        ] {
+            fs::write(destination.join(file_name), b"image").unwrap();
+        }
+        // the newer copy was read from a failing device
+        ImageMetadata {
+            source: "/dev/fakesrc0".to_string(),
+            size: 5,
+            source_size: None,
+            regions: vec![Region::full(0, 5)],
+            unreadable: vec![Extent::new(0, 5)],
+        }
+        .write(
+            &destination
+                .join("2023-01-02_desktop_Model_SRC1.img")
+                .to_string_lossy(),
+        )
+        .unwrap();
+
+        let (_, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        assert_published(&destination);
+        assert_eq!(
+            present_images(&destination),
+            vec!["2023-01-01_desktop_Model_SRC1.img".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_run_discards_partial_image_of_other_device() {
+        let destination = destination("partial");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+        let image = destination
+            .join(format!(
+                "{}_desktop_Model_SRC1.img",
+                crate::run::utils::current_date()
+            ))
+            .to_string_lossy()
+            .to_string();
+        fs::write(partial_path(&image), b"partial").unwrap();
+        Journal {
+            source: "/dev/other0".to_string(),
+            size: 1024 * 1024,
+            data_bytes: 1024 * 1024,
+            confirmed_offset: 512,
+        }
+        .write(&FakeCommandRunner::new(), &image)
+        .unwrap();
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        assert_published(&destination);
+        assert_eq!(
+            runner.commands_of(&["dd"])[0],
+            format!(
+                "dd if=/dev/fakesrc0 of={}/{}.partial status=progress",
+                mountpath(&destination).to_string_lossy(),
+                Path::new(&image).file_name().unwrap().to_string_lossy()
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_dry_run_keeps_copies_and_skips_dd() {
+        let destination = destination("dry_run");
+        let config = config(&destination, Some(1));
+        let backup_args = backup_args(true);
+        fs::write(
+            destination.join("2023-01-01_desktop_Model_SRC1.img"),
+            b"image",
+        )
+        .unwrap();
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(present_images(&destination).len(), 1);
+        assert!(runner.commands_of(&["dd", "chown"]).is_empty());
+    }
+
+    #[test]
+    fn test_run_skips_filesystem_on_failed_fsck() {
+        let destination = destination("fsck");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+
+        let (runner, result) = run_backups(
+            FakeCommandRunner::new().script("fsck", FakeResponse::fail("corrupted")),
+            &config,
+            &backup_args,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert!(runner.commands_of(&["mount", "dd", "umount"]).is_empty());
+    }
+
+    #[test]
+    fn test_run_skips_filesystem_past_start_deadline() {
+        let destination = destination("deadline");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+        let runner =
+            Arc::new(FakeCommandRunner::new().script("lsblk", FakeResponse::ok(LSBLK_OUTPUT)));
+        let lsblk = Lsblk::from_lsblk(runner.as_ref()).unwrap();
+        let progress = Progress::new();
+        let mut backups = Backups::new(
+            &config.backups[0],
+            &lsblk,
+            &backup_args,
+            &config,
+            &progress,
+            runner.clone(),
+        )
+        .unwrap()
+        .unwrap();
+        backups.lock_dir = Some(lock_dir(&config));
+        backups.start_deadline = Some(Local::now() - chrono::Duration::minutes(1));
+
+        let result = backups.run();
+
+        assert_eq!(result, Ok(()));
+        assert!(runner
+            .commands_of(&["fsck", "mount", "dd", "umount"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_run_runs_dd_with_priority() {
+        let destination = destination("priority");
+        let mut config = config(&destination, None);
+        config.backups[0].backup_devices[0].options = CopyOptions {
+            io_priority: Some(IoPriority::Idle),
+            nice: Some(19),
+            ..CopyOptions::default()
+        };
+        let backup_args = backup_args(false);
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(result, Ok(()));
+        let commands = runner.commands_of(&["ionice"]);
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with("ionice -c 3 nice -n 19 dd if=/dev/fakesrc0 of="));
+    }
+
+    #[test]
+    fn test_run_fails_on_failed_mount() {
+        let destination = destination("mount");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+
+        let (runner, result) = run_backups(
+            FakeCommandRunner::new().script("mount", FakeResponse::fail("busy")),
+            &config,
+            &backup_args,
+        );
+
+        assert!(result.unwrap_err().contains("busy"));
+        assert!(runner.commands_of(&["dd", "umount"]).is_empty());
+    }
+
+    #[test]
+    fn test_run_refuses_to_mount_over_files() {
+        let destination = destination("shadow");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+        fs::create_dir_all(mountpath(&destination)).unwrap();
+        fs::write(mountpath(&destination).join("data"), b"data").unwrap();
+
+        let (runner, result) = run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert!(result.unwrap_err().contains("non-empty directory"));
+        assert!(runner.commands_of(&["mount", "dd", "umount"]).is_empty());
+        assert!(mountpath(&destination).join("data").exists());
+    }
+
+    #[test]
+    fn test_run_unmounts_after_failed_copy() {
+        let destination = destination("dd");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+
+        let (runner, result) = run_backups(
+            FakeCommandRunner::new().script("dd", FakeResponse::fail("Input/output error")),
+            &config,
+            &backup_args,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert!(runner.commands_of(&["chown"]).is_empty());
+        assert_eq!(runner.commands_of(&["umount"]).len(), 1);
+    }
+
+    #[test]
+    fn test_run_quarantines_stale_partial_image() {
+        let destination = destination("quarantine");
+        let config = config(&destination, Some(1));
+        let backup_args = backup_args(false);
+        fs::write(
+            destination.join("2023-01-01_desktop_Model_SRC1.img"),
+            b"image",
+        )
+        .unwrap();
+        // the backup of the next day was interrupted and a failed copy today leaves its partial image only
+        fs::write(
+            destination.join("2023-01-02_desktop_Model_SRC1.img.partial"),
+            b"partial",
+        )
+        .unwrap();
+
+        let (_, result) = run_backups(
+            FakeCommandRunner::new().script("dd", FakeResponse::fail("Input/output error")),
+            &config,
+            &backup_args,
+        );
+
+        assert_eq!(result, Ok(()));
+        // the last good copy isn't replaced by a partial image
+        assert_eq!(
+            present_images(&destination),
+            vec![
+                "2023-01-01_desktop_Model_SRC1.img".to_string(),
+                QUARANTINE_DIR.to_string()
+            ]
+        );
+        assert!(destination
+            .join(QUARANTINE_DIR)
+            .join("2023-01-02_desktop_Model_SRC1.img.partial")
+            .exists());
+    }
+
+    #[test]
+    fn test_run_skips_locked_filesystem() {
+        let destination = destination("locked");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+        let host_lock = HostLock::acquire(&lock_dir(&config), "DST-UUID", false).unwrap();
+
+        let (host_locked_runner, host_locked) =
+            run_backups(FakeCommandRunner::new(), &config, &backup_args);
+        drop(host_lock);
+        // a run of another host left its lock on the destination
+        let owner = LockOwner {
+            host: "otherhost".to_string(),
+            ..LockOwner::current()
+        };
+        fs::write(
+            destination.join(DESTINATION_LOCK_FILE),
+            serde_json::to_string(&owner).unwrap(),
+        )
+        .unwrap();
+        let (destination_locked_runner, destination_locked) =
+            run_backups(FakeCommandRunner::new(), &config, &backup_args);
+
+        assert_eq!(host_locked, Ok(()));
+        assert!(host_locked_runner
+            .commands_of(&["fsck", "mount", "dd", "umount"])
+            .is_empty());
+        assert_eq!(destination_locked, Ok(()));
+        let commands = destination_locked_runner.commands_of(&["mount", "dd", "umount"]);
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].starts_with("mount ") && commands[1].starts_with("umount "));
+        assert!(destination.join(DESTINATION_LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn test_run_skips_copy_without_enough_space() {
+        let destination = destination("space");
+        let config = config(&destination, None);
+        let backup_args = backup_args(false);
+        let huge_device = LSBLK_OUTPUT.replace("\"1M\"", "\"1000T\"");
+
+        let (runner, result) = run_backups(
+            FakeCommandRunner::new().script("lsblk", FakeResponse::ok(&huge_device)),
+            &config,
+            &backup_args,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert!(runner.commands_of(&["dd"]).is_empty());
+        assert_eq!(runner.commands_of(&["umount"]).len(), 1);
+    }
+
+    #[test]
+    fn test_run_backs_up_selected_partitions() {
+        let destination = destination("partitions");
+        let mut config = config(&destination, None);
+        config.backups[0].backup_devices[0].partitions = Some(vec![PartitionSelector::Number(2)]);
+        let backup_args = backup_args(false);
+        let lsblk_output = LSBLK_OUTPUT.replace(
+            r#""fsavail": null},"#,
+            r#""fsavail": null},
+            {"name": "fakesrc0p1", "model": null, "serial": null, "uuid": null, "mountpoint": null,
+             "size": "512K", "fsavail": null, "type": "part", "pkname": "fakesrc0"},
+            {"name": "fakesrc0p2", "model": null, "serial": null, "uuid": null, "mountpoint": null,
+             "size": "512K", "fsavail": null, "type": "part", "pkname": "fakesrc0"},"#,
+        );
+
+        let (runner, result) = run_backups_with_lsblk(
+            FakeCommandRunner::new(),
+            &lsblk_output,
+            &config,
+            &backup_args,
+        );
+
+        assert_eq!(result, Ok(()));
+        let image = format!(
+            "{}/{}_desktop_Model_SRC1",
+            mountpath(&destination).to_string_lossy(),
+            crate::run::utils::current_date()
+        );
+        assert_eq!(
+            runner.commands_of(&["dd"])[..2],
+            vec![
+                format!(
+                    "dd if=/dev/fakesrc0p2 of={}_part2.img.partial status=progress",
+                    image
+                ),
+                format!(
+                    "dd if=/dev/fakesrc0 of={}_ptable.img.partial status=progress iflag=count_bytes count=1048576",
+                    image
+                ),
+            ]
+        );
+        // the layout is saved next to the partition table image only
+        assert!(runner.commands_of(&["dd"])[2..]
+            .iter()
+            .all(|command| command.contains("_ptable.img.")));
+    }
+
+    #[test]
+    fn test_job_resources() {
+        let destination = destination("job");
+        let mut config = config(&destination, None);
+        config.backups[0].backup_devices[0].partitions = Some(vec![PartitionSelector::Number(1)]);
+        let lsblk_output = LSBLK_OUTPUT
+            .replace(
+                r#""fsavail": "50G"}"#,
+                r#""fsavail": "50G", "type": "part", "pkname": "fakedst0"}"#,
+            )
+            .replace(
+                r#""fsavail": null},"#,
+                r#""fsavail": null},
+            {"name": "fakesrc0p1", "model": null, "serial": null, "uuid": null, "mountpoint": null,
+             "size": "512K", "fsavail": null, "type": "part", "pkname": "fakesrc0"},"#,
+            );
+        let runner =
+            Arc::new(FakeCommandRunner::new().script("lsblk", FakeResponse::ok(&lsblk_output)));
+        let lsblk = Lsblk::from_lsblk(runner.as_ref()).unwrap();
+        let progress = Progress::new();
+        let backup_args = backup_args(false);
+
+        let job = Backups::new(
+            &config.backups[0],
+            &lsblk,
+            &backup_args,
+            &config,
+            &progress,
+            runner,
+        )
+        .unwrap()
+        .unwrap()
+        .job();
+
+        assert_eq!(job.label, "/dev/fakedst0p1");
+        // the partition and the partition table image read the same disk
+        assert_eq!(
+            job.resources,
+            vec![
+                "disk:fakedst0".to_string(),
+                "disk:fakesrc0".to_string(),
+                format!("mountpath:{}", mountpath(&destination).to_string_lossy()),
+            ]
+        );
+        assert_eq!(
+            job.reads,
+            vec!["fakesrc0".to_string(), "fakesrc0p1".to_string()]
+        );
+        assert_eq!(
+            job.writes,
+            vec!["fakedst0p1".to_string(), "fakedst0".to_string()]
+        );
+    }
 }
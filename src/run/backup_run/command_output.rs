@@ -1,4 +1,64 @@
-use std::process::{Command, Output, Stdio};
+use std::{
+    fmt::Debug,
+    io::Read,
+    process::{Command, Output, Stdio},
+    thread,
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+use super::interrupt;
+
+/// Executes external commands on behalf of `Lsblk`, `Filesystem`, `Backup` and `Backups`.
+///
+/// `SystemCommandRunner` runs the commands on the machine, tests inject a `FakeCommandRunner`
+/// returning scripted outputs.
+pub trait CommandRunner: Debug + Send + Sync {
+    /// Executes a command and captures its output, see `command_output`.
+    fn output(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+    ) -> Result<Output, String>;
+
+    /// Executes a command and streams its stderr line by line, see `command_output_with_progress`.
+    fn output_with_progress(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+        on_stderr_line: &mut dyn FnMut(&str),
+    ) -> Result<Output, String>;
+}
+
+/// Runs commands on the machine, prepending `sudo` if needed and available.
+#[derive(Debug, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn output(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+    ) -> Result<Output, String> {
+        command_output(command_parts, description, is_sudo_needed)
+    }
+
+    fn output_with_progress(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+        on_stderr_line: &mut dyn FnMut(&str),
+    ) -> Result<Output, String> {
+        command_output_with_progress(command_parts, description, is_sudo_needed, on_stderr_line)
+    }
+}
 
 /// Executes a command and captures its output.
 /// Command output is still printed to stdout and stderr.
@@ -13,38 +73,135 @@ use std::process::{Command, Output, Stdio};
 ///
 /// * `Ok(output)` if the command executes successfully and captures the output.
 /// * `Err` with an error message if the command encounters an error.
-pub fn command_output(
+fn command_output(
     command_parts: Vec<&str>,
     description: &str,
     is_sudo_needed: Option<bool>,
 ) -> Result<Output, String> {
-    let command_parts = {
-        if is_sudo_needed.unwrap_or(false) {
-            append_sudo_if_available(command_parts, Some(description))
-        } else {
-            command_parts
-        }
-    };
+    let command_parts = prepare_command(command_parts, description, is_sudo_needed);
+    let output = Command::new(command_parts[0])
+        .args(&command_parts[1..])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("{}: {}", err, command_parts.join(" ")))?
+        .wait_with_output()
+        .map_err(|e| e.to_string())?;
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    check_status(output, &command_parts, &stderr)
+}
 
-    trace!("Command: {}", command_parts.join(" "));
-    match Command::new(command_parts[0])
+/// Executes a command like `command_output`, but captures stderr and hands it over
+/// line by line (split at `\n` and `\r`) to `on_stderr_line` while the command is running.
+/// Used for commands reporting progress on stderr, like `dd status=progress`.
+/// The command is terminated once SIGINT or SIGTERM was received, see `interrupt`.
+///
+/// # Returns
+///
+/// * `Ok(output)` if the command executes successfully, `output.stderr` contains the whole stderr.
+/// * `Err` with an error message if the command encounters an error, with the last `ERROR_LINES` lines
+///   of stderr only, since the progress lines of a long copy add up.
+fn command_output_with_progress(
+    command_parts: Vec<&str>,
+    description: &str,
+    is_sudo_needed: Option<bool>,
+    on_stderr_line: &mut dyn FnMut(&str),
+) -> Result<Output, String> {
+    let command_parts = prepare_command(command_parts, description, is_sudo_needed);
+    let mut child = Command::new(command_parts[0])
         .args(&command_parts[1..])
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
-    {
-        Ok(child) => {
-            let output = child.wait_with_output().map_err(|e| e.to_string())?;
-            match output.status.success() {
-                true => Ok(output),
-                false => Err(format!(
-                    "Error running {}: {}",
-                    &command_parts.join(" "),
-                    String::from_utf8_lossy(&output.stderr)
-                )),
+        .map_err(|err| format!("{}: {}", err, command_parts.join(" ")))?;
+
+    let mut stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
+    let stdout_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stdout.read_to_end(&mut buffer);
+        buffer
+    });
+
+    let mut stderr = Vec::new();
+    let mut terminated = false;
+    if let Some(mut child_stderr) = child.stderr.take() {
+        let mut line = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let read = child_stderr.read(&mut chunk).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            // a copy reports its progress every second, it is stopped once SIGINT or SIGTERM was received
+            if interrupt::is_interrupted() && !terminated {
+                info!("Stopping {}", command_parts.join(" "));
+                // SIGTERM instead of SIGKILL, so that `sudo` passes it on
+                let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+                terminated = true;
+            }
+            for &byte in &chunk[..read] {
+                stderr.push(byte);
+                if byte == b'\n' || byte == b'\r' {
+                    on_stderr_line(&String::from_utf8_lossy(&line));
+                    line.clear();
+                } else {
+                    line.push(byte);
+                }
             }
         }
-        Err(err) => Err(format!("{}: {}", err, command_parts.join(" "))),
+        if !line.is_empty() {
+            on_stderr_line(&String::from_utf8_lossy(&line));
+        }
     }
+
+    let status = child.wait().map_err(|e| e.to_string())?;
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_| "Failed to read stdout".to_string())?;
+    let last_lines = last_lines(&String::from_utf8_lossy(&stderr), ERROR_LINES);
+    let output = Output {
+        status,
+        stdout,
+        stderr,
+    };
+    check_status(output, &command_parts, &last_lines)
+}
+
+/// The number of the last lines of stderr in the error of a failed command reporting its progress.
+const ERROR_LINES: usize = 5;
+
+/// Prepends `sudo` to the command if it is needed and available, and traces the command.
+fn prepare_command<'a>(
+    command_parts: Vec<&'a str>,
+    description: &str,
+    is_sudo_needed: Option<bool>,
+) -> Vec<&'a str> {
+    let command_parts = match is_sudo_needed.unwrap_or(false) {
+        true => append_sudo_if_available(command_parts, Some(description)),
+        false => command_parts,
+    };
+    trace!("Command: {}", command_parts.join(" "));
+    command_parts
+}
+
+/// Returns the output of a successful command, otherwise an error naming the command and its `stderr`.
+fn check_status(output: Output, command_parts: &[&str], stderr: &str) -> Result<Output, String> {
+    match output.status.success() {
+        true => Ok(output),
+        false => Err(format!(
+            "Error running {}: {}",
+            command_parts.join(" "),
+            stderr
+        )),
+    }
+}
+
+/// Returns the last `count` non-empty lines of the output, split at `\n` and `\r` like the progress lines.
+fn last_lines(output: &str, count: usize) -> String {
+    let lines: Vec<&str> = output
+        .split(['\n', '\r'])
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    lines[lines.len().saturating_sub(count)..].join("\n")
 }
 
 fn append_sudo_if_available<'a>(
@@ -69,3 +226,269 @@ fn append_sudo_if_available<'a>(
 fn is_sudo_available() -> bool {
     Command::new("sudo").arg("--version").output().is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_lines() {
+        let mut stderr = String::new();
+        for seconds in 1..=3600u64 {
+            stderr.push_str(&format!(
+                "{} bytes copied, {} s, 1 MB/s\r",
+                seconds * 1_000_000,
+                seconds
+            ));
+        }
+        stderr.push_str("\ndd: error reading '/dev/sdb': Input/output error\n");
+        stderr.push_str("3600+0 records in\n3600+0 records out\n");
+
+        assert_eq!(
+            last_lines(&stderr, 3),
+            "dd: error reading '/dev/sdb': Input/output error\n3600+0 records in\n3600+0 records out"
+        );
+        assert_eq!(last_lines("", 3), "");
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::{
+        fs,
+        os::unix::process::ExitStatusExt,
+        path::{Path, PathBuf},
+        process::{ExitStatus, Output},
+        sync::Mutex,
+    };
+
+    use super::CommandRunner;
+
+    /// A scripted response of the `FakeCommandRunner`.
+    #[derive(Debug, Clone)]
+    pub struct FakeResponse {
+        pub success: bool,
+        pub stdout: String,
+        pub stderr: String,
+    }
+
+    impl FakeResponse {
+        pub fn ok(stdout: &str) -> FakeResponse {
+            FakeResponse {
+                success: true,
+                stdout: stdout.to_string(),
+                stderr: String::new(),
+            }
+        }
+
+        pub fn fail(stderr: &str) -> FakeResponse {
+            FakeResponse {
+                success: false,
+                stdout: String::new(),
+                stderr: stderr.to_string(),
+            }
+        }
+    }
+
+    /// Records every executed command and answers with scripted responses.
+    ///
+    /// A response is picked by the first script whose prefix the command line starts with,
+    /// commands without a matching script succeed with empty output.
+    /// A successful `dd` creates its output file, if its directory exists.
+    /// A successful `mount` of a device with a simulated filesystem moves the filesystem's files into the
+    /// mount path, `umount` moves them back. File operations like `mkdir -p`, `cp`, `mv -f` and `rm -f` are applied,
+    /// see `simulate_files`.
+    #[derive(Debug, Default)]
+    pub struct FakeCommandRunner {
+        scripts: Mutex<Vec<(String, FakeResponse)>>,
+        /// The executed command lines and whether they needed sudo.
+        commands: Mutex<Vec<(String, bool)>>,
+        /// The simulated filesystems by device path, directories holding their files while unmounted.
+        filesystems: Mutex<Vec<(String, PathBuf)>>,
+        /// The mount paths of the mounted simulated filesystems and their directories.
+        mounted: Mutex<Vec<(String, PathBuf)>>,
+    }
+
+    impl FakeCommandRunner {
+        pub fn new() -> FakeCommandRunner {
+            FakeCommandRunner::default()
+        }
+
+        /// Scripts the response for all commands starting with `prefix`.
+        pub fn script(self, prefix: &str, response: FakeResponse) -> FakeCommandRunner {
+            self.scripts
+                .lock()
+                .unwrap()
+                .push((prefix.to_string(), response));
+            self
+        }
+
+        /// Simulates the filesystem of `device`, its files are stored in `dir` while it isn't mounted.
+        pub fn filesystem(self, device: &str, dir: &Path) -> FakeCommandRunner {
+            self.filesystems
+                .lock()
+                .unwrap()
+                .push((device.to_string(), dir.to_path_buf()));
+            self
+        }
+
+        /// Returns all executed command lines in order.
+        pub fn commands(&self) -> Vec<String> {
+            self.commands
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(command, _)| command.clone())
+                .collect()
+        }
+
+        /// Returns all executed command lines in order, prefixed with `sudo` if they needed it,
+        /// like `SystemCommandRunner` runs them if sudo is available.
+        pub fn sudo_commands(&self) -> Vec<String> {
+            self.commands
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(command, sudo)| match sudo {
+                    true => format!("sudo {}", command),
+                    false => command.clone(),
+                })
+                .collect()
+        }
+
+        /// Returns the executed command lines starting with one of the given programs.
+        pub fn commands_of(&self, programs: &[&str]) -> Vec<String> {
+            self.commands()
+                .into_iter()
+                .filter(|command| {
+                    programs
+                        .iter()
+                        .any(|program| command.split(' ').next() == Some(program))
+                })
+                .collect()
+        }
+
+        fn respond(
+            &self,
+            command_parts: Vec<&str>,
+            is_sudo_needed: Option<bool>,
+        ) -> Result<Output, String> {
+            let command = command_parts.join(" ");
+            self.commands
+                .lock()
+                .unwrap()
+                .push((command.clone(), is_sudo_needed.unwrap_or(false)));
+            let response = self
+                .scripts
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(prefix, _)| command.starts_with(prefix.as_str()))
+                .map(|(_, response)| response.clone())
+                .unwrap_or(FakeResponse::ok(""));
+
+            if response.success {
+                self.simulate_mounts(&command_parts);
+                simulate_files(&command_parts)
+                    .map_err(|e| format!("Error running {}: {}", command, e))?;
+                if command_parts.first() == Some(&"dd") {
+                    for output_file in command_parts
+                        .iter()
+                        .filter_map(|part| part.strip_prefix("of="))
+                    {
+                        let _ = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(output_file);
+                    }
+                }
+                Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: response.stdout.into_bytes(),
+                    stderr: response.stderr.into_bytes(),
+                })
+            } else {
+                Err(format!("Error running {}: {}", command, response.stderr))
+            }
+        }
+    }
+
+    impl FakeCommandRunner {
+        fn simulate_mounts(&self, command_parts: &[&str]) {
+            match command_parts {
+                ["mount", device, mountpath] => {
+                    let filesystems = self.filesystems.lock().unwrap();
+                    if let Some((_, dir)) = filesystems.iter().find(|(d, _)| d == device) {
+                        move_entries(dir, Path::new(mountpath));
+                        let mut mounted = self.mounted.lock().unwrap();
+                        mounted.push((mountpath.to_string(), dir.clone()));
+                    }
+                }
+                ["umount", mountpath] => {
+                    let mut mounted = self.mounted.lock().unwrap();
+                    if let Some(index) = mounted.iter().position(|(m, _)| m == mountpath) {
+                        let (_, dir) = mounted.remove(index);
+                        move_entries(Path::new(mountpath), &dir);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Applies the file operations of successful commands, which need sudo on real destinations.
+    /// A failing operation fails the command, like creating a present file with `noclobber`.
+    fn simulate_files(command_parts: &[&str]) -> std::io::Result<()> {
+        match command_parts {
+            ["mkdir", "-p", path] => fs::create_dir_all(path),
+            ["rmdir", path] => fs::remove_dir(path),
+            ["cp", "--", from, to] => fs::copy(from, to).map(|_| ()),
+            ["mv", "-f", "--", from, to] => fs::rename(from, to),
+            ["rm", "-f", "--", path] => match fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                removed => removed,
+            },
+            ["sh", "-c", script, "sh", from, to] if script.starts_with("set -C;") => {
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(to)?;
+                std::io::Write::write_all(&mut file, &fs::read(from)?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves all entries of the directory `from` into the directory `to`.
+    fn move_entries(from: &Path, to: &Path) {
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            fs::rename(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+
+    impl CommandRunner for FakeCommandRunner {
+        fn output(
+            &self,
+            command_parts: Vec<&str>,
+            _description: &str,
+            is_sudo_needed: Option<bool>,
+        ) -> Result<Output, String> {
+            self.respond(command_parts, is_sudo_needed)
+        }
+
+        fn output_with_progress(
+            &self,
+            command_parts: Vec<&str>,
+            _description: &str,
+            is_sudo_needed: Option<bool>,
+            on_stderr_line: &mut dyn FnMut(&str),
+        ) -> Result<Output, String> {
+            let output = self.respond(command_parts, is_sudo_needed)?;
+            for line in String::from_utf8_lossy(&output.stderr).split(['\n', '\r']) {
+                on_stderr_line(line);
+            }
+            Ok(output)
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::command_output::CommandRunner;
+
+/// Creates the file `path` with `content` through `runner` with sudo, since the root directory of
+/// a destination filesystem is usually owned by root. The file is created exclusively, with the
+/// `noclobber` option of the shell.
+///
+/// # Returns
+///
+/// - `Ok(true)`: If the file was created.
+/// - `Ok(false)`: If it exists already.
+/// - `Err(String)`: If it couldn't be created.
+pub fn create_new(runner: &dyn CommandRunner, path: &str, content: &[u8]) -> Result<bool, String> {
+    let staged = stage(content)?;
+    let staged_path = staged.0.to_string_lossy().to_string();
+    let created = runner.output(
+        vec![
+            "sh",
+            "-c",
+            "set -C; cat -- \"$1\" > \"$2\" && sync -- \"$2\"",
+            "sh",
+            &staged_path,
+            path,
+        ],
+        &format!("create {}", path),
+        Some(true),
+    );
+    match created {
+        Ok(_) => Ok(true),
+        Err(_) if Path::new(path).exists() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `content` into the file `path` through `runner` with sudo, replacing the present file
+/// atomically by renaming `<path>.tmp`.
+pub fn write(runner: &dyn CommandRunner, path: &str, content: &[u8]) -> Result<(), String> {
+    let staged = stage(content)?;
+    let staged_path = staged.0.to_string_lossy().to_string();
+    let temporary_path = format!("{}.tmp", path);
+    runner.output(
+        vec!["cp", "--", &staged_path, &temporary_path],
+        &format!("write {}", path),
+        Some(true),
+    )?;
+    rename(runner, &temporary_path, path)
+}
+
+/// Renames the file `from` to `to` through `runner` with sudo, replacing `to` if present.
+pub fn rename(runner: &dyn CommandRunner, from: &str, to: &str) -> Result<(), String> {
+    runner
+        .output(
+            vec!["mv", "-f", "--", from, to],
+            &format!("rename {} to {}", from, to),
+            Some(true),
+        )
+        .map(|_| ())
+}
+
+/// Removes the file `path` through `runner` with sudo, if present.
+pub fn remove(runner: &dyn CommandRunner, path: &str) -> Result<(), String> {
+    runner
+        .output(
+            vec!["rm", "-f", "--", path],
+            &format!("remove {}", path),
+            Some(true),
+        )
+        .map(|_| ())
+}
+
+/// A file in the temporary directory of the invoking user, holding content until it is copied
+/// to a destination filesystem, removed when it is dropped.
+struct Staged(PathBuf);
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Writes `content` into a new file in the temporary directory, unique across the backups running in parallel.
+fn stage(content: &[u8]) -> Result<Staged, String> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "dd_backup_{}_{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Staged(path))
+}
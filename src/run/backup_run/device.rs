@@ -1,122 +1,436 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-};
+use std::{fs, os::unix::fs::FileTypeExt, path::Path};
 
-use crate::run::{config::BackupDevice, utils::convert_to_byte_size};
+use crate::run::config::{BackupDevice, CopyOptions, DeviceIdentifier, PartitionSelector};
 
 use super::lsblk::BlockDevice;
 
-/// Represents a device identified by its serial number.
+/// Represents a source device identified by a `DeviceIdentifier`.
 #[derive(Debug)]
 pub struct Device {
     /// The underlying block device information.
     pub blockdevice: BlockDevice,
     /// The path to the device.
     pub device_path: String,
+    /// The identifier the device was found by.
+    pub identifier: DeviceIdentifier,
     /// The name of the device.
     pub name: Option<String>,
     /// The destination path for the device.
     pub destination_path: String,
     /// The number of copies to be kept for this device.
     pub copies: Option<usize>,
+    /// Deletes the oldest copy before the backup if the destination is short of space, see `BackupDevice`.
+    pub delete_oldest_when_short: Option<bool>,
+    /// Distinguishes the images of a device backed up by partitions, like `part2` or `ptable`.
+    pub partition_suffix: Option<String>,
+    /// The number of bytes to copy from the start of the device, if not the whole device.
+    pub length: Option<u64>,
+    /// The options on how the device is copied.
+    pub options: CopyOptions,
+    /// The numbers of the partitions left out of the image of a whole device, see `exclude_partitions`.
+    pub excluded_partitions: Vec<u32>,
 }
 
 impl Device {
-    /// Creates a new `Device` instance with the specified serial number and optional name.
+    /// The number of bytes at the start of a disk saved as partition table image.
+    /// It covers the MBR and the primary GPT with up to 128 entries.
+    pub const PARTITION_TABLE_LENGTH: u64 = 1024 * 1024;
+
+    /// Creates the `Device` instances to back up for the specified identifier and optional name.
     ///
-    /// It validates the uniqueness of the serial number among the available devices
-    /// and returns `Some(Device)` if a unique match is found, or `None` otherwise.
-    /// Additionally, it checks if the device is currently mounted and filters out mounted devices.
+    /// It validates the uniqueness of the identifier among the available devices
+    /// and returns the found device if a unique match is found, or nothing otherwise.
+    /// If partitions are selected, the selected partitions of the device and its partition table
+    /// are returned instead.
+    /// Additionally, it checks if the devices are currently mounted and filters out mounted devices.
     ///
     /// # Arguments
     ///
-    /// * `serial` - The serial number of the device.
-    /// * `name` - The optional name of the device.
+    /// * `backup_device` - The configured device, with identifier, name and copies.
     /// * `available_devices` - The list of available block devices.
     /// * `destination_path` - The optional destination path for the device from the configuration.
     ///
     /// # Returns
     ///
-    /// - `Ok(Some(Device))`: If a unique device is found matching the serial number and if it isn't mounted.
-    /// - `Ok(None)`: If no device is found matching the serial number or all matching devices are mounted.
-    /// - `Err(String)`: If the serial number is not unique among the available devices.
+    /// - `Ok(Vec<Device>)`: The devices found matching the identifier which aren't mounted, may be empty.
+    /// - `Err(String)`: If an error occurred while checking the mount state.
     pub fn new(
         backup_device: &BackupDevice,
         available_devices: &[BlockDevice],
         destination_path: String,
-    ) -> Result<Option<Device>, String> {
-        match Self::validate_serial(&backup_device.serial, available_devices) {
-            Ok(blockdevice) => {
-                if !Self::is_device_mounted(&format!("/dev/{}", &blockdevice.name))? {
-                    Ok(Some(Device {
-                        blockdevice: blockdevice.clone(),
-                        device_path: format!("/dev/{}", &blockdevice.name),
-                        name: backup_device.name.clone(),
-                        copies: backup_device.copies,
-                        destination_path,
-                    }))
-                } else {
-                    Ok(None)
+    ) -> Result<Vec<Device>, String> {
+        match Self::validate_identifier(&backup_device.identifier, available_devices) {
+            Ok((blockdevice, device_path)) => {
+                let mut device = Device {
+                    blockdevice,
+                    device_path,
+                    identifier: backup_device.identifier.clone(),
+                    name: backup_device.name.clone(),
+                    copies: backup_device.copies,
+                    delete_oldest_when_short: backup_device.delete_oldest_when_short,
+                    destination_path,
+                    partition_suffix: None,
+                    length: None,
+                    options: backup_device.options.clone(),
+                    excluded_partitions: Vec::new(),
+                };
+                if let Some(selectors) = &backup_device.options.exclude_partitions {
+                    device.excluded_partitions = device
+                        .partitions(available_devices)
+                        .into_iter()
+                        .filter(|partition| {
+                            selectors
+                                .iter()
+                                .any(|selector| Self::matches_selector(partition, selector))
+                        })
+                        .filter_map(|partition| partition.partn)
+                        .collect();
+                    info!(
+                        "Excluding partitions {:?} of {} from its image",
+                        device.excluded_partitions, device.device_path
+                    );
+                }
+                match &backup_device.partitions {
+                    Some(selectors) => device.partition_devices(selectors, available_devices),
+                    None if !Self::is_device_or_partition_mounted(&device.device_path)? => {
+                        Ok(vec![device])
+                    }
+                    None => Ok(vec![]),
                 }
             }
             Err(e) => {
                 warn!("{}, skipping it", e);
-                Ok(None)
+                Ok(vec![])
             }
         }
     }
 
-    /// Filters the available devices to those with the specified serial number,
-    /// ensuring uniqueness and presence of device
-    fn validate_serial<'a>(
-        serial: &str,
-        available_devices: &'a [BlockDevice],
-    ) -> Result<&'a BlockDevice, String> {
-        let serial_filtered_lsblk: Vec<&BlockDevice> = available_devices
+    /// Returns the partitions of this device.
+    fn partitions<'a>(&self, available_devices: &'a [BlockDevice]) -> Vec<&'a BlockDevice> {
+        available_devices
             .iter()
-            .filter(|blockdevice| blockdevice.serial.clone().unwrap() == serial)
-            .collect();
+            .filter(|blockdevice| {
+                blockdevice.is_partition()
+                    && blockdevice.pkname.as_deref() == Some(&self.blockdevice.name)
+            })
+            .collect()
+    }
+
+    /// Returns a device per partition of this device matching any of the selectors, which isn't mounted,
+    /// followed by the partition table of this device.
+    fn partition_devices(
+        self,
+        selectors: &[PartitionSelector],
+        available_devices: &[BlockDevice],
+    ) -> Result<Vec<Device>, String> {
+        let partitions = self.partitions(available_devices);
+
+        for selector in selectors {
+            if !partitions
+                .iter()
+                .any(|partition| Self::matches_selector(partition, selector))
+            {
+                warn!(
+                    "No partition of {} matches {:?}, skipping it",
+                    self.device_path, selector
+                );
+            }
+        }
+
+        let mut devices = Vec::new();
+        for partition in partitions.into_iter().filter(|partition| {
+            selectors
+                .iter()
+                .any(|selector| Self::matches_selector(partition, selector))
+        }) {
+            let device_path = format!("/dev/{}", partition.name);
+            if Self::is_device_mounted(&device_path)? {
+                continue;
+            }
+            let partition_suffix = match partition.partn {
+                Some(partn) => format!("part{}", partn),
+                None => partition.name.clone(),
+            };
+            devices.push(Device {
+                // model and serial of the disk, to name the images after the disk
+                blockdevice: BlockDevice {
+                    model: self.blockdevice.model.clone(),
+                    serial: self.blockdevice.serial.clone(),
+                    ..partition.clone()
+                },
+                device_path,
+                identifier: self.identifier.clone(),
+                name: self.name.clone(),
+                destination_path: self.destination_path.clone(),
+                copies: self.copies,
+                delete_oldest_when_short: self.delete_oldest_when_short,
+                partition_suffix: Some(partition_suffix),
+                length: None,
+                options: self.options.clone(),
+                excluded_partitions: Vec::new(),
+            });
+        }
+
+        if !devices.is_empty() {
+            devices.push(Device {
+                partition_suffix: Some("ptable".to_string()),
+                length: Some(Self::PARTITION_TABLE_LENGTH.min(self.blockdevice.size)),
+                ..self
+            });
+        }
+        Ok(devices)
+    }
+
+    /// Returns whether the partition is selected by the selector.
+    fn matches_selector(partition: &BlockDevice, selector: &PartitionSelector) -> bool {
+        match selector {
+            PartitionSelector::Number(number) => partition.partn == Some(*number),
+            PartitionSelector::Partuuid(partuuid) => partition
+                .partuuid
+                .as_deref()
+                .is_some_and(|p| p.eq_ignore_ascii_case(partuuid)),
+            PartitionSelector::Label(label) => {
+                partition.partlabel.as_deref() == Some(label)
+                    || partition.label.as_deref() == Some(label)
+            }
+            PartitionSelector::Fstype(fstype) => partition.fstype.as_deref() == Some(fstype),
+            PartitionSelector::Type(type_id) => {
+                partition.parttype.as_deref().is_some_and(|parttype| {
+                    parttype
+                        .trim_start_matches("0x")
+                        .eq_ignore_ascii_case(type_id.trim_start_matches("0x"))
+                })
+            }
+        }
+    }
+
+    /// Finds the device matching the identifier, ensuring uniqueness and presence of device.
+    /// Returns the block device with the path to read it from.
+    ///
+    /// Serial and wwn only match whole devices, a path to a regular file is returned as
+    /// block device of kind `file` with the size of the file.
+    pub fn validate_identifier(
+        identifier: &DeviceIdentifier,
+        available_devices: &[BlockDevice],
+    ) -> Result<(BlockDevice, String), String> {
+        let value = identifier.value();
+        let filtered_lsblk: Vec<&BlockDevice> = match identifier {
+            DeviceIdentifier::Serial(serial) => available_devices
+                .iter()
+                .filter(|blockdevice| Self::is_whole_device(blockdevice))
+                .filter(|blockdevice| blockdevice.serial.as_deref() == Some(serial))
+                .collect(),
+            DeviceIdentifier::Wwn(wwn) => available_devices
+                .iter()
+                .filter(|blockdevice| Self::is_whole_device(blockdevice))
+                .filter(|blockdevice| {
+                    blockdevice
+                        .wwn
+                        .as_deref()
+                        .is_some_and(|device_wwn| device_wwn.eq_ignore_ascii_case(wwn))
+                })
+                .collect(),
+            DeviceIdentifier::Partuuid(partuuid) => available_devices
+                .iter()
+                .filter(|blockdevice| {
+                    blockdevice
+                        .partuuid
+                        .as_deref()
+                        .is_some_and(|device_partuuid| {
+                            device_partuuid.eq_ignore_ascii_case(partuuid)
+                        })
+                })
+                .collect(),
+            DeviceIdentifier::FsUuid(uuid) => available_devices
+                .iter()
+                .filter(|blockdevice| blockdevice.uuid.as_deref() == Some(uuid))
+                .collect(),
+            DeviceIdentifier::ById(name) => {
+                let device_name =
+                    Self::resolve_device_name(&Path::new("/dev/disk/by-id").join(name))?;
+                Self::filter_by_name(available_devices, &device_name)
+            }
+            DeviceIdentifier::ByPath(name) => {
+                let device_name =
+                    Self::resolve_device_name(&Path::new("/dev/disk/by-path").join(name))?;
+                Self::filter_by_name(available_devices, &device_name)
+            }
+            DeviceIdentifier::Path(path) => {
+                let metadata = fs::metadata(path)
+                    .map_err(|e| format!("Device not found: {}, {}", identifier, e))?;
+                if metadata.file_type().is_block_device() {
+                    let device_name = Self::resolve_device_name(Path::new(path))?;
+                    Self::filter_by_name(available_devices, &device_name)
+                } else if metadata.is_file() {
+                    let blockdevice = BlockDevice {
+                        name: value.to_string(),
+                        size: metadata.len(),
+                        kind: Some("file".to_string()),
+                        ..Default::default()
+                    };
+                    return Ok((blockdevice, value.to_string()));
+                } else {
+                    return Err(format!(
+                        "Device is neither a block device nor a file: {}",
+                        path
+                    ));
+                }
+            }
+        };
 
-        let is_device_serial_uniq = serial_filtered_lsblk.len() <= 1;
-        let is_device_available = !serial_filtered_lsblk.is_empty();
+        let is_device_identifier_uniq = filtered_lsblk.len() <= 1;
+        let is_device_available = !filtered_lsblk.is_empty();
 
         if is_device_available {
-            if is_device_serial_uniq {
-                return Ok(serial_filtered_lsblk[0]);
+            if is_device_identifier_uniq {
+                let blockdevice = filtered_lsblk[0].clone();
+                let device_path = format!("/dev/{}", &blockdevice.name);
+                return Ok((blockdevice, device_path));
             } else {
-                return Err(format!("Device has not a unique serial: {}", serial));
+                return Err(format!("Device has not a unique {}", identifier));
             }
         }
-        Err(format!("Device not found: {}", serial))
+        Err(format!("Device not found: {}", value))
+    }
+
+    /// Returns whether the block device is a whole device and not a partition.
+    fn is_whole_device(blockdevice: &BlockDevice) -> bool {
+        blockdevice.kind.as_deref() != Some("part")
+    }
+
+    /// Resolves a device node or a symlink to it, like `/dev/disk/by-id/x`, to the kernel name, like `sda`.
+    fn resolve_device_name(path: &Path) -> Result<String, String> {
+        let device_path = fs::canonicalize(path)
+            .map_err(|e| format!("Device not found: {}, {}", path.to_string_lossy(), e))?;
+        device_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(format!("Device not found: {}", path.to_string_lossy()))
+    }
+
+    fn filter_by_name<'a>(
+        available_devices: &'a [BlockDevice],
+        device_name: &str,
+    ) -> Vec<&'a BlockDevice> {
+        available_devices
+            .iter()
+            .filter(|blockdevice| blockdevice.name == device_name)
+            .collect()
+    }
+
+    /// Returns the part of the backup file name identifying the device:
+    /// the serial if present, otherwise the identifier value with `/` replaced.
+    pub fn identifier_suffix(&self) -> String {
+        self.blockdevice.serial.clone().unwrap_or_else(|| {
+            self.identifier
+                .value()
+                .trim_start_matches('/')
+                .replace('/', "-")
+        })
     }
 
     /// Checks if the specified device is currently mounted by querying `/proc/mounts`.
+    /// Only the device itself counts, a disk isn't mounted if one of its partitions is,
+    /// see `is_device_or_partition_mounted`.
     ///
     /// Returns `Ok(true)` if the device is mounted, `Ok(false)` if it is not mounted,
     /// or `Err(String)` if an error occurred while checking.
-    fn is_device_mounted(device_path: &str) -> Result<bool, String> {
-        let file = File::open("/proc/mounts")
-            .map_err(|e| format!("Failed to open /proc/mounts: {}", e))?;
-        let reader = BufReader::new(file);
-
-        for line in reader.lines().flatten() {
-            let fields: Vec<&str> = line.split(' ').collect();
-            if fields.len() >= 2 && fields[0].contains(device_path) {
-                error!("Device {} is mounted, skipping it", device_path);
-                return Ok(true);
-            }
+    pub fn is_device_mounted(device_path: &str) -> Result<bool, String> {
+        if !device_path.starts_with("/dev/") {
+            return Ok(false);
+        }
+        let is_mounted = is_any_mounted(&read_mounts()?, &[device_path.to_string()]);
+        if is_mounted {
+            error!("Device {} is mounted, skipping it", device_path);
+        }
+        Ok(is_mounted)
+    }
+
+    /// Checks if the specified device or one of its partitions, listed in sysfs, is mounted,
+    /// before a whole disk is read or written.
+    pub fn is_device_or_partition_mounted(device_path: &str) -> Result<bool, String> {
+        if !device_path.starts_with("/dev/") {
+            return Ok(false);
+        }
+        let mut device_paths = partition_paths(device_path);
+        device_paths.push(device_path.to_string());
+        let is_mounted = is_any_mounted(&read_mounts()?, &device_paths);
+        if is_mounted {
+            error!(
+                "Device {} or one of its partitions is mounted, skipping it",
+                device_path
+            );
+        }
+        Ok(is_mounted)
+    }
+
+    /// Checks if the device got mounted since it was found, like a disk holding a destination filesystem,
+    /// see `is_device_or_partition_mounted`. A partition table image is never in use, the partition table
+    /// stays consistent while partitions are mounted, like those not backed up on a dual boot disk.
+    pub fn is_in_use(&self) -> Result<bool, String> {
+        if self.length.is_some() || !self.device_path.starts_with("/dev/") {
+            return Ok(false);
+        }
+        let is_in_use = self.is_in_use_by(&read_mounts()?, &partition_paths(&self.device_path));
+        if is_in_use {
+            error!(
+                "Device {} or one of its partitions is mounted, skipping it",
+                self.device_path
+            );
         }
-        Ok(false)
+        Ok(is_in_use)
     }
 
-    /// Returns the total size of the block device, converted to bytes, or None if the size is unavailable.
+    /// Returns whether the device or one of its `partitions` is mounted according to `mounts`,
+    /// the content of `/proc/mounts`, see `is_in_use`.
+    fn is_in_use_by(&self, mounts: &str, partitions: &[String]) -> bool {
+        let mut device_paths = partitions.to_vec();
+        device_paths.push(self.device_path.clone());
+        self.length.is_none() && is_any_mounted(mounts, &device_paths)
+    }
+
+    /// Returns the number of bytes to back up, the total size of the block device if not limited by `length`.
     /// This value is static in one run
-    pub fn total_size(&self) -> Result<Option<u64>, String> {
-        convert_to_byte_size(&self.blockdevice.size)
+    pub fn total_size(&self) -> u64 {
+        self.length.unwrap_or(self.blockdevice.size)
     }
 }
 
+/// Returns the content of `/proc/mounts`.
+fn read_mounts() -> Result<String, String> {
+    fs::read_to_string("/proc/mounts").map_err(|e| format!("Failed to read /proc/mounts: {}", e))
+}
+
+/// Returns whether one of the device paths is the mounted device of a line of `/proc/mounts`.
+/// Paths are compared exactly, `/dev/sda` is not mounted if `/dev/sda2` is.
+fn is_any_mounted(mounts: &str, device_paths: &[String]) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| line.split(' ').next())
+        .any(|mounted| {
+            device_paths
+                .iter()
+                .any(|device_path| device_path == mounted)
+        })
+}
+
+/// Returns the paths of the partitions of the disk, the entries of `/sys/class/block/<disk>` with a
+/// `partition` file, none for partitions and other devices.
+fn partition_paths(device_path: &str) -> Vec<String> {
+    let Some(name) = Path::new(device_path).file_name() else {
+        return Vec::new();
+    };
+    fs::read_dir(Path::new("/sys/class/block").join(name))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join("partition").exists())
+                .map(|entry| format!("/dev/{}", entry.file_name().to_string_lossy()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,8 +443,9 @@ mod tests {
                 serial: Some("serial1".to_string()),
                 uuid: Some("uuid1".to_string()),
                 mountpoint: Some("/mnt/sda1".to_string()),
-                size: "100GB".to_string(),
-                fsavail: Some("50GB".to_string()),
+                size: 100_000_000_000,
+                fsavail: Some(50_000_000_000),
+                ..Default::default()
             },
             BlockDevice {
                 name: "sdb1".to_string(),
@@ -138,8 +453,9 @@ mod tests {
                 serial: Some("serial2".to_string()),
                 uuid: Some("uuid2".to_string()),
                 mountpoint: Some("/mnt/sdb1".to_string()),
-                size: "200GB".to_string(),
-                fsavail: Some("100GB".to_string()),
+                size: 200_000_000_000,
+                fsavail: Some(100_000_000_000),
+                ..Default::default()
             },
             BlockDevice {
                 name: "sdc1".to_string(),
@@ -147,32 +463,210 @@ mod tests {
                 serial: Some("serial2".to_string()), // Duplicate serial
                 uuid: Some("uuid3".to_string()),
                 mountpoint: Some("/mnt/sdc1".to_string()),
-                size: "300GB".to_string(),
-                fsavail: Some("150GB".to_string()),
+                size: 300_000_000_000,
+                fsavail: Some(150_000_000_000),
+                ..Default::default()
             },
         ]
     }
 
+    #[test]
+    fn test_is_any_mounted() {
+        let mounts = "/dev/sda2 / ext4 rw,relatime 0 0\nproc /proc proc rw 0 0\n";
+        let paths = |paths: &[&str]| {
+            paths
+                .iter()
+                .map(|path| path.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(is_any_mounted(mounts, &paths(&["/dev/sda2"])));
+        // a disk isn't mounted because its partition is
+        assert!(!is_any_mounted(mounts, &paths(&["/dev/sda"])));
+        assert!(is_any_mounted(
+            mounts,
+            &paths(&["/dev/sda1", "/dev/sda2", "/dev/sda"])
+        ));
+        assert!(!is_any_mounted(mounts, &paths(&["/dev/sda1", "/dev/sda"])));
+    }
+
+    #[test]
+    fn test_ptable_of_disk_with_mounted_partition_is_not_in_use() {
+        let device = |partition_suffix: Option<&str>, length: Option<u64>| Device {
+            blockdevice: BlockDevice {
+                name: "sda".to_string(),
+                size: 100_000_000_000,
+                ..Default::default()
+            },
+            device_path: "/dev/sda".to_string(),
+            identifier: DeviceIdentifier::Serial("serial1".to_string()),
+            name: Some("dualboot".to_string()),
+            destination_path: "/backups".to_string(),
+            copies: None,
+            delete_oldest_when_short: None,
+            partition_suffix: partition_suffix.map(str::to_string),
+            length,
+            options: CopyOptions::default(),
+            excluded_partitions: Vec::new(),
+        };
+        let mounts = "/dev/sda2 / ext4 rw,relatime 0 0\n";
+        let partitions = vec!["/dev/sda1".to_string(), "/dev/sda2".to_string()];
+
+        let ptable = device(Some("ptable"), Some(Device::PARTITION_TABLE_LENGTH));
+        assert!(!ptable.is_in_use_by(mounts, &partitions));
+        // the whole disk is in use through its mounted partition
+        assert!(device(None, None).is_in_use_by(mounts, &partitions));
+        assert!(!device(None, None).is_in_use_by(mounts, &partitions[..1]));
+    }
+
     #[test]
     fn test_validate_serial() {
         let devices = generate_test_devices();
+        let serial = |serial: &str| DeviceIdentifier::Serial(serial.to_string());
 
         // Serial exists and is unique
-        match Device::validate_serial("serial1", &devices) {
-            Ok(device) => assert_eq!(device.serial.clone().unwrap(), "serial1"),
+        match Device::validate_identifier(&serial("serial1"), &devices) {
+            Ok((device, device_path)) => {
+                assert_eq!(device.serial.clone().unwrap(), "serial1");
+                assert_eq!(device_path, "/dev/sda1");
+            }
             Err(msg) => panic!("Error: {:?}", msg),
         }
 
         // Serial exists but is not unique
-        match Device::validate_serial("serial2", &devices) {
+        match Device::validate_identifier(&serial("serial2"), &devices) {
             Ok(_) => panic!("Should have failed due to non-unique serial"),
             Err(msg) => assert!(msg.contains("not a unique serial")),
         }
 
         // Serial does not exist
-        match Device::validate_serial("serial3", &devices) {
+        match Device::validate_identifier(&serial("serial3"), &devices) {
             Ok(_) => panic!("Should have failed due to non-existent serial"),
             Err(msg) => assert!(msg.contains("Device not found")),
         }
     }
+
+    #[test]
+    fn test_validate_identifier() {
+        let mut devices = generate_test_devices();
+        devices[0].wwn = Some("0x5002538E40A12345".to_string());
+        devices.push(BlockDevice {
+            name: "nvme0n1p2".to_string(),
+            serial: Some("serial1".to_string()),
+            partuuid: Some("0b1c6f3e-02".to_string()),
+            kind: Some("part".to_string()),
+            size: 1_000_000,
+            ..Default::default()
+        });
+
+        // wwn matches case-insensitively
+        let (device, _) = Device::validate_identifier(
+            &DeviceIdentifier::Wwn("0x5002538e40a12345".to_string()),
+            &devices,
+        )
+        .unwrap();
+        assert_eq!(device.name, "sda1");
+
+        // partitions are found by PARTUUID, but never by the serial of their disk
+        let (device, device_path) = Device::validate_identifier(
+            &DeviceIdentifier::Partuuid("0B1C6F3E-02".to_string()),
+            &devices,
+        )
+        .unwrap();
+        assert_eq!(device_path, "/dev/nvme0n1p2");
+        assert_eq!(device.size, 1_000_000);
+        assert!(Device::validate_identifier(
+            &DeviceIdentifier::Serial("serial1".to_string()),
+            &devices
+        )
+        .is_ok());
+
+        // partitions are selected by number, label or fstype, followed by the partition table
+        let partitioned = |partn: u32, partlabel: &str, fstype: &str| BlockDevice {
+            name: format!("fakedisk0p{}", partn),
+            pkname: Some("fakedisk0".to_string()),
+            kind: Some("part".to_string()),
+            partn: Some(partn),
+            partlabel: Some(partlabel.to_string()),
+            fstype: Some(fstype.to_string()),
+            size: 1_000_000,
+            ..Default::default()
+        };
+        devices.push(BlockDevice {
+            name: "fakedisk0".to_string(),
+            serial: Some("dualboot".to_string()),
+            kind: Some("disk".to_string()),
+            size: 512_000_000_000,
+            ..Default::default()
+        });
+        devices.push(partitioned(1, "EFI", "vfat"));
+        devices.push(partitioned(2, "windows", "ntfs"));
+        devices.push(BlockDevice {
+            parttype: Some("0FC63DAF-8483-4772-8E79-3D69D8477DE4".to_string()),
+            ..partitioned(3, "root", "ext4")
+        });
+        let backup_device = BackupDevice {
+            identifier: DeviceIdentifier::Serial("dualboot".to_string()),
+            name: None,
+            copies: None,
+            delete_oldest_when_short: None,
+            partitions: Some(vec![
+                PartitionSelector::Number(1),
+                PartitionSelector::Label("root".to_string()),
+                PartitionSelector::Fstype("btrfs".to_string()),
+            ]),
+            options: CopyOptions::default(),
+        };
+        let partition_devices = Device::new(&backup_device, &devices, "./".to_string()).unwrap();
+        let selected: Vec<(&str, Option<&str>, u64)> = partition_devices
+            .iter()
+            .map(|device| {
+                (
+                    device.device_path.as_str(),
+                    device.partition_suffix.as_deref(),
+                    device.total_size(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            selected,
+            vec![
+                ("/dev/fakedisk0p1", Some("part1"), 1_000_000),
+                ("/dev/fakedisk0p3", Some("part3"), 1_000_000),
+                (
+                    "/dev/fakedisk0",
+                    Some("ptable"),
+                    Device::PARTITION_TABLE_LENGTH
+                ),
+            ]
+        );
+
+        // excluded partitions are matched by fstype or partition type, ignoring case
+        let backup_device = BackupDevice {
+            partitions: None,
+            options: CopyOptions {
+                exclude_partitions: Some(vec![
+                    PartitionSelector::Fstype("ntfs".to_string()),
+                    PartitionSelector::Type("0fc63daf-8483-4772-8e79-3d69d8477de4".to_string()),
+                ]),
+                ..CopyOptions::default()
+            },
+            ..backup_device
+        };
+        let whole_devices = Device::new(&backup_device, &devices, "./".to_string()).unwrap();
+        assert_eq!(whole_devices.len(), 1);
+        assert_eq!(whole_devices[0].excluded_partitions, vec![2, 3]);
+
+        // regular files are read directly
+        let file =
+            std::env::temp_dir().join(format!("dd_backup_device_test_{}.raw", std::process::id()));
+        fs::write(&file, [0u8; 4096]).unwrap();
+        let path = file.to_string_lossy().to_string();
+        let (device, device_path) =
+            Device::validate_identifier(&DeviceIdentifier::Path(path.clone()), &devices).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(device_path, path);
+        assert_eq!(device.size, 4096);
+        assert_eq!(device.kind.as_deref(), Some("file"));
+    }
 }
@@ -0,0 +1,491 @@
+//! End-to-end tests running full backups between file-backed loop devices.
+//!
+//! The tests are opt-in, since they need root to set up loop devices and to mount:
+//!
+//! ```shell
+//! sudo DD_BACKUP_E2E=1 cargo test e2e
+//! ```
+//!
+//! `Lsblk` is fed from `fixtures/lsblk_e2e.json`, which maps a fake serial and a fixed
+//! filesystem UUID to the loop devices created for the test run.
+
+use std::{
+    fs,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output},
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    backups::Backups,
+    command_output::{CommandRunner, SystemCommandRunner},
+    journal::{partial_path, Journal},
+    lsblk::Lsblk,
+    progress::Progress,
+    BackupArgs,
+};
+use crate::run::{
+    config::{BackupConfig, BackupDevice, Config, CopyOptions, DeviceIdentifier, ImageFormat},
+    image_metadata::ImageMetadata,
+    partition_table::{tests::gpt_disk, PartitionTable},
+    qcow2::tests::read_qcow2,
+    utils::current_date,
+};
+
+const LSBLK_FIXTURE: &str = include_str!("fixtures/lsblk_e2e.json");
+const SOURCE_SERIAL: &str = "E2E-SOURCE-SERIAL";
+const DESTINATION_UUID: &str = "d0d0e2e0-0000-4000-8000-00000000e2e0";
+const SOURCE_SIZE: usize = 8 * 1024 * 1024;
+const DESTINATION_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Answers `lsblk` with the fixture, all other commands are run on the machine.
+#[derive(Debug)]
+struct FixtureCommandRunner {
+    lsblk_output: String,
+    /// The command lines run with progress, like `dd`.
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl CommandRunner for FixtureCommandRunner {
+    fn output(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+    ) -> Result<Output, String> {
+        if command_parts[0] == "lsblk" {
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: self.lsblk_output.clone().into_bytes(),
+                stderr: Vec::new(),
+            })
+        } else {
+            SystemCommandRunner.output(command_parts, description, is_sudo_needed)
+        }
+    }
+
+    fn output_with_progress(
+        &self,
+        command_parts: Vec<&str>,
+        description: &str,
+        is_sudo_needed: Option<bool>,
+        on_stderr_line: &mut dyn FnMut(&str),
+    ) -> Result<Output, String> {
+        self.commands.lock().unwrap().push(command_parts.join(" "));
+        SystemCommandRunner.output_with_progress(
+            command_parts,
+            description,
+            is_sudo_needed,
+            on_stderr_line,
+        )
+    }
+}
+
+/// Loop devices and directories of one test run, torn down on drop.
+struct Harness {
+    dir: PathBuf,
+    source: String,
+    destination: String,
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl Harness {
+    /// Returns `None` if the e2e tests are not enabled or not run as root.
+    fn new(name: &str) -> Option<Harness> {
+        if std::env::var("DD_BACKUP_E2E").is_err() {
+            eprintln!("skipping {}, set DD_BACKUP_E2E=1 to run it", name);
+            return None;
+        }
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipping {}, needs to be run as root", name);
+            return None;
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("dd_backup_e2e_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+
+        let source_file = dir.join("source.img");
+        fs::write(&source_file, pattern(1)).unwrap();
+        let destination_file = dir.join("destination.img");
+        fs::File::create(&destination_file)
+            .unwrap()
+            .set_len(DESTINATION_SIZE)
+            .unwrap();
+
+        let mut harness = Harness {
+            dir,
+            source: String::new(),
+            destination: String::new(),
+            commands: Arc::default(),
+        };
+        harness.source = sh(&["losetup", "--find", "--show", &path(&source_file)]);
+        harness.destination = sh(&["losetup", "--find", "--show", &path(&destination_file)]);
+        sh(&[
+            "mkfs.ext4",
+            "-q",
+            "-U",
+            DESTINATION_UUID,
+            &harness.destination,
+        ]);
+        Some(harness)
+    }
+
+    fn mountpath(&self) -> PathBuf {
+        self.dir.join("mnt")
+    }
+
+    fn runner(&self) -> Arc<dyn CommandRunner> {
+        let lsblk_output = LSBLK_FIXTURE
+            .replace("{{SOURCE}}", self.source.trim_start_matches("/dev/"))
+            .replace(
+                "{{DESTINATION}}",
+                self.destination.trim_start_matches("/dev/"),
+            );
+        Arc::new(FixtureCommandRunner {
+            lsblk_output,
+            commands: Arc::clone(&self.commands),
+        })
+    }
+
+    fn config(&self, copies: Option<usize>, options: CopyOptions) -> Config {
+        Config {
+            mountpath: Some(path(&self.mountpath())),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
+            backups: vec![BackupConfig {
+                backup_devices: vec![BackupDevice {
+                    identifier: DeviceIdentifier::Serial(SOURCE_SERIAL.to_string()),
+                    name: Some("e2e".to_string()),
+                    copies,
+                    delete_oldest_when_short: None,
+                    partitions: None,
+                    options,
+                }],
+                uuid: DESTINATION_UUID.to_string(),
+                destination_path: None,
+                fsck_command: None,
+                skip_fsck: None,
+                skip_mount: None,
+            }],
+        }
+    }
+
+    /// Runs the configured backups like `dd_backup run`.
+    fn run(&self, copies: Option<usize>) -> Result<(), String> {
+        self.run_with_options(copies, CopyOptions::default())
+    }
+
+    /// Runs the configured backups with the given copy options.
+    fn run_with_options(&self, copies: Option<usize>, options: CopyOptions) -> Result<(), String> {
+        let config = self.config(copies, options);
+        let backup_args = BackupArgs {
+            dry_run: false,
+            file_config_args: None,
+            single_backup_args: None,
+            mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
+        };
+        let runner = self.runner();
+        let lsblk = Lsblk::from_lsblk(runner.as_ref())?;
+        let progress = Progress::new();
+        for backup_config in &config.backups {
+            if let Some(mut backups) = Backups::new(
+                backup_config,
+                &lsblk,
+                &backup_args,
+                &config,
+                &progress,
+                Arc::clone(&runner),
+            )? {
+                backups.lock_dir = Some(self.dir.join("locks"));
+                backups.run()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mounts the destination, hands its mountpath to `f` and unmounts it again.
+    fn with_destination<T>(&self, f: impl FnOnce(&Path) -> T) -> T {
+        sh(&["mount", &self.destination, &path(&self.mountpath())]);
+        let result = f(&self.mountpath());
+        sh(&["umount", &path(&self.mountpath())]);
+        result
+    }
+
+    fn write_source(&self, seed: u64) {
+        fs::write(&self.source, pattern(seed)).unwrap();
+        sh(&["sync"]);
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(self.mountpath()).output();
+        for device in [&self.source, &self.destination] {
+            if !device.is_empty() {
+                let _ = Command::new("losetup").args(["-d", device]).output();
+            }
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn sh(command_parts: &[&str]) -> String {
+    let output = Command::new(command_parts[0])
+        .args(&command_parts[1..])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}: {}",
+        command_parts.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+fn path(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// Pseudo random content of the source device, different for every seed.
+fn pattern(seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..SOURCE_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Returns the image file names on the destination, sorted.
+fn images(mountpath: &Path) -> Vec<String> {
+    let mut images: Vec<String> = fs::read_dir(mountpath)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".img"))
+        .collect();
+    images.sort();
+    images
+}
+
+fn image_name(date: &str) -> String {
+    format!("{}_e2e_E2E-Disk_{}.img", date, SOURCE_SERIAL)
+}
+
+#[test]
+fn e2e_backup_is_byte_identical() {
+    let Some(harness) = Harness::new("identical") else {
+        return;
+    };
+
+    assert_eq!(harness.run(None), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        assert_eq!(images(mountpath), vec![image_name(&current_date())]);
+        let image = fs::read(mountpath.join(image_name(&current_date()))).unwrap();
+        assert!(image == pattern(1), "image differs from source device");
+    });
+}
+
+#[test]
+fn e2e_backup_rotates_copies() {
+    let Some(harness) = Harness::new("rotation") else {
+        return;
+    };
+    let today = image_name(&current_date());
+
+    // two runs on "previous days", renamed to older dates after each run
+    for (seed, date) in [(1, "2000-01-01"), (2, "2000-01-02")] {
+        harness.write_source(seed);
+        assert_eq!(harness.run(Some(2)), Ok(()));
+        harness.with_destination(|mountpath| {
+            fs::rename(mountpath.join(&today), mountpath.join(image_name(date))).unwrap();
+        });
+    }
+
+    harness.write_source(3);
+    assert_eq!(harness.run(Some(2)), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        assert_eq!(
+            images(mountpath),
+            vec![image_name("2000-01-02"), today.clone()]
+        );
+        assert!(fs::read(mountpath.join(image_name("2000-01-02"))).unwrap() == pattern(2));
+        assert!(fs::read(mountpath.join(&today)).unwrap() == pattern(3));
+    });
+}
+
+#[test]
+fn e2e_backup_used_blocks_only() {
+    let Some(harness) = Harness::new("used_blocks") else {
+        return;
+    };
+    sh(&["mkfs.ext4", "-q", "-F", "-E", "nodiscard", &harness.source]);
+
+    let options = CopyOptions {
+        used_blocks_only: Some(true),
+        ..CopyOptions::default()
+    };
+    assert_eq!(harness.run_with_options(None, options), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        let image = mountpath.join(image_name(&current_date()));
+        let metadata = fs::read_to_string(format!("{}.json", path(&image))).unwrap();
+        assert!(
+            metadata.contains("\"copy\":\"used_blocks\""),
+            "{}",
+            metadata
+        );
+        assert!(metadata.contains("\"filesystem\":\"ext\""), "{}", metadata);
+        // the free blocks still hold the random pattern on the source, but are holes in the image
+        let image_content = fs::read(&image).unwrap();
+        assert_eq!(image_content.len(), SOURCE_SIZE);
+        assert!(image_content != fs::read(&harness.source).unwrap());
+        sh(&["e2fsck", "-fn", &path(&image)]);
+    });
+}
+
+#[test]
+fn e2e_backup_trimmed_to_last_partition() {
+    let Some(harness) = Harness::new("trimmed") else {
+        return;
+    };
+    // a GPT with one partition in the first half of the source
+    let disk_sectors = SOURCE_SIZE as u64 / 512;
+    let mut disk = gpt_disk(disk_sectors, &[([0xAF; 16], 2048, 4095, "data")]);
+    disk[2048 * 512..4096 * 512].copy_from_slice(&pattern(2)[2048 * 512..4096 * 512]);
+    fs::write(&harness.source, &disk).unwrap();
+    sh(&["sync"]);
+
+    let options = CopyOptions {
+        trim_to_last_partition: Some(true),
+        ..CopyOptions::default()
+    };
+    assert_eq!(harness.run_with_options(None, options), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        let image = mountpath.join(image_name(&current_date()));
+        let image_content = fs::read(&image).unwrap();
+        // the partition followed by the backup GPT, the primary GPT header points to it
+        assert_eq!(image_content.len(), 4129 * 512);
+        assert!(image_content[..512] == disk[..512]);
+        assert!(image_content[1024..4096 * 512] == disk[1024..4096 * 512]);
+        let table = PartitionTable::read(&mut fs::File::open(&image).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.disk_size, Some(4129 * 512));
+        let metadata = fs::read_to_string(format!("{}.json", path(&image))).unwrap();
+        assert!(
+            metadata.contains(&format!("\"source_size\":{}", SOURCE_SIZE)),
+            "{}",
+            metadata
+        );
+    });
+}
+
+#[test]
+fn e2e_backup_output_format_qcow2() {
+    let Some(harness) = Harness::new("qcow2") else {
+        return;
+    };
+
+    let options = CopyOptions {
+        output_format: Some(ImageFormat::Qcow2),
+        ..CopyOptions::default()
+    };
+    assert_eq!(harness.run_with_options(None, options), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        let image = mountpath.join(image_name(&current_date()).replace(".img", ".qcow2"));
+        assert!(images(mountpath).is_empty());
+        let converted = read_qcow2(&fs::read(&image).unwrap());
+        assert!(converted == pattern(1), "image differs from source device");
+    });
+}
+
+#[test]
+fn e2e_backup_rescue() {
+    let Some(harness) = Harness::new("rescue") else {
+        return;
+    };
+
+    let options = CopyOptions {
+        rescue: Some(true),
+        ..CopyOptions::default()
+    };
+    assert_eq!(harness.run_with_options(None, options), Ok(()));
+
+    harness.with_destination(|mountpath| {
+        let image = mountpath.join(image_name(&current_date()));
+        assert!(
+            fs::read(&image).unwrap() == pattern(1),
+            "image differs from source device"
+        );
+        let metadata = ImageMetadata::read(&path(&image)).unwrap().unwrap();
+        assert!(!metadata.is_degraded());
+        // a healthy device is read completely
+        let map = fs::read_to_string(format!("{}.map", path(&image))).unwrap();
+        assert!(
+            map.contains(&format!("0x00000000  0x{:08X}  +", SOURCE_SIZE)),
+            "{}",
+            map
+        );
+    });
+}
+
+#[test]
+fn e2e_backup_resumes_partial_image() {
+    let Some(harness) = Harness::new("resume") else {
+        return;
+    };
+    let confirmed_offset = 4 * 1024 * 1024;
+    // an interrupted backup of today, confirmed up to 4 MiB, followed by bytes that never reached the disk
+    harness.with_destination(|mountpath| {
+        let image = path(&mountpath.join(image_name(&current_date())));
+        let mut partial = pattern(1)[..confirmed_offset as usize].to_vec();
+        partial.extend(vec![0xEE; 1024 * 1024]);
+        fs::write(partial_path(&image), partial).unwrap();
+        Journal {
+            source: harness.source.clone(),
+            size: SOURCE_SIZE as u64,
+            data_bytes: SOURCE_SIZE as u64,
+            confirmed_offset,
+        }
+        .write(&SystemCommandRunner, &image)
+        .unwrap();
+    });
+
+    assert_eq!(harness.run(None), Ok(()));
+
+    let commands = harness.commands.lock().unwrap().clone();
+    assert!(
+        commands[0].contains(&format!("skip={0} seek={0}", confirmed_offset)),
+        "{:?}",
+        commands
+    );
+    harness.with_destination(|mountpath| {
+        assert_eq!(images(mountpath), vec![image_name(&current_date())]);
+        let image = fs::read(mountpath.join(image_name(&current_date()))).unwrap();
+        assert!(image == pattern(1), "image differs from source device");
+        assert!(!Path::new(&partial_path(&path(
+            &mountpath.join(image_name(&current_date()))
+        )))
+        .exists());
+    });
+}
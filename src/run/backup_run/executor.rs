@@ -0,0 +1,346 @@
+use std::{
+    collections::HashSet,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::mpsc::channel,
+    thread,
+};
+
+use super::interrupt;
+
+/// A unit of work run by `run_jobs`, like the backups to one destination filesystem.
+#[derive(Debug)]
+pub struct Job<T> {
+    /// Names the job, used as the name of its thread, which prefixes its log lines.
+    pub label: String,
+    /// The resources used by the job, like `disk:sda`. Jobs sharing a resource never run at once.
+    pub resources: Vec<String>,
+    /// The devices read by the job, like `sdb`. It starts after the jobs writing one of them finished.
+    pub reads: Vec<String>,
+    /// The devices written by the job, like the partition `sdb1` and its disk `sdb`.
+    pub writes: Vec<String>,
+    /// The work passed to the run function.
+    pub task: T,
+}
+
+impl<T> Job<T> {
+    /// Returns whether the job reads a device written by `other`, so that it has to run after it.
+    fn depends_on(&self, other: &Job<T>) -> bool {
+        self.reads
+            .iter()
+            .any(|device| other.writes.contains(device))
+    }
+}
+
+/// Orders the jobs so that every job comes after the jobs writing the devices it reads,
+/// like the backup of a disk after the backups to a filesystem on it. Independent jobs keep their order.
+///
+/// # Returns
+///
+/// - `Ok(Vec<Job<T>>)`: The ordered jobs.
+/// - `Err(String)`: If jobs depend on each other in a cycle, naming them, or a job reads a device it writes.
+pub fn order_jobs<T>(jobs: Vec<Job<T>>) -> Result<Vec<Job<T>>, String> {
+    let mut pending = jobs;
+    let mut ordered = Vec::new();
+    while !pending.is_empty() {
+        let Some(index) = pending
+            .iter()
+            .position(|job| !pending.iter().any(|other| job.depends_on(other)))
+        else {
+            return Err(format!(
+                "Cyclic backup configuration, each of these destinations gets a backup of \
+                the disk holding the next one: {}",
+                find_cycle(&pending).join(" -> ")
+            ));
+        };
+        ordered.push(pending.remove(index));
+    }
+    Ok(ordered)
+}
+
+/// Returns the labels of a cycle of jobs depending on each other, given jobs which all depend on one of them.
+/// The first label is repeated at the end.
+fn find_cycle<T>(jobs: &[Job<T>]) -> Vec<&str> {
+    let mut path: Vec<usize> = vec![0];
+    while let Some(next) = path
+        .last()
+        .and_then(|&last| jobs.iter().position(|other| jobs[last].depends_on(other)))
+    {
+        if let Some(start) = path.iter().position(|&index| index == next) {
+            return path[start..]
+                .iter()
+                .chain([&next])
+                .map(|&index| jobs[index].label.as_str())
+                .collect();
+        }
+        path.push(next);
+    }
+    path.iter()
+        .map(|&index| jobs[index].label.as_str())
+        .collect()
+}
+
+/// Runs the jobs concurrently in threads named by their label, at most `max_parallel` at once.
+///
+/// The jobs are ordered first, see `order_jobs`. A job is started as soon as a slot is free, none of
+/// its resources is used by a running job and no waiting or running job writes a device it reads.
+/// Waiting jobs are started in their order, a later job may start before an earlier one whose
+/// resources are still in use. After a job failed or SIGINT or SIGTERM was received, no further
+/// jobs are started, the running ones are waited for.
+///
+/// # Arguments
+///
+/// * `jobs` - The jobs in the order they should be started.
+/// * `max_parallel` - The maximum number of jobs running at once, at least 1.
+/// * `run` - Runs the task of a job.
+///
+/// # Returns
+///
+/// - `Ok(())`: If all jobs were run successfully.
+/// - `Err(String)`: The error of the first failed job, if the jobs depend on each other in a cycle,
+///   or if the run was interrupted before all jobs were started.
+pub fn run_jobs<T: Send>(
+    jobs: Vec<Job<T>>,
+    max_parallel: usize,
+    run: impl Fn(T) -> Result<(), String> + Sync,
+) -> Result<(), String> {
+    let run = &run;
+    let mut pending = order_jobs(jobs)?;
+    let mut used: HashSet<String> = HashSet::new();
+    let mut written: HashSet<String> = HashSet::new();
+    let mut running = 0;
+    let mut result = Ok(());
+    let (sender, receiver) = channel();
+
+    thread::scope(|scope| loop {
+        while result.is_ok() && !interrupt::is_interrupted() && running < max_parallel {
+            let Some(index) = pending.iter().position(|job| {
+                job.resources
+                    .iter()
+                    .all(|resource| !used.contains(resource))
+                    && job.reads.iter().all(|device| !written.contains(device))
+                    && !pending.iter().any(|other| job.depends_on(other))
+            }) else {
+                break;
+            };
+            let job = pending.remove(index);
+            let sender = sender.clone();
+            let resources = job.resources.clone();
+            let writes = job.writes.clone();
+            let label = job.label.clone();
+            let spawned = thread::Builder::new()
+                .name(job.label)
+                .spawn_scoped(scope, move || {
+                    let result = catch_unwind(AssertUnwindSafe(|| run(job.task)))
+                        .unwrap_or_else(|_| Err(format!("Backup job {} panicked", label)));
+                    let _ = sender.send((resources, writes, result));
+                });
+            match spawned {
+                Ok(_) => {
+                    used.extend(job.resources);
+                    written.extend(job.writes);
+                    running += 1;
+                }
+                Err(e) => result = Err(format!("Failed to start backup job: {}", e)),
+            }
+        }
+        if running == 0 {
+            break;
+        }
+        let Ok((resources, writes, job_result)) = receiver.recv() else {
+            break;
+        };
+        running -= 1;
+        for resource in resources {
+            used.remove(&resource);
+        }
+        for device in writes {
+            written.remove(&device);
+        }
+        if let (Ok(()), Err(e)) = (&result, job_result) {
+            result = Err(e);
+        }
+    });
+
+    match pending.is_empty() {
+        true => result,
+        false => result.and_then(|()| interrupt::check()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+
+    fn job(label: &str, resources: &[&str]) -> Job<(String, Vec<String>)> {
+        let resources: Vec<String> = resources.iter().map(|r| r.to_string()).collect();
+        Job {
+            label: label.to_string(),
+            resources: resources.clone(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            task: (label.to_string(), resources),
+        }
+    }
+
+    fn backup_job(label: &str, reads: &[&str], writes: &[&str]) -> Job<(String, Vec<String>)> {
+        Job {
+            reads: reads.iter().map(|d| d.to_string()).collect(),
+            writes: writes.iter().map(|d| d.to_string()).collect(),
+            ..job(label, &[])
+        }
+    }
+
+    fn labels<T>(jobs: &[Job<T>]) -> Vec<&str> {
+        jobs.iter().map(|job| job.label.as_str()).collect()
+    }
+
+    #[test]
+    fn test_order_jobs() {
+        // the backup of sdb runs after the backup to sdb1, the one of sdc after the one of sdb to sdc1
+        let jobs = vec![
+            backup_job("/dev/sdd1", &["sdc"], &["sdd1", "sdd"]),
+            backup_job("/dev/sdc1", &["sdb"], &["sdc1", "sdc"]),
+            backup_job("/dev/sde1", &["sda"], &["sde1", "sde"]),
+            backup_job("/dev/sdb1", &["sda"], &["sdb1", "sdb"]),
+        ];
+        let ordered = order_jobs(jobs).unwrap();
+        assert_eq!(
+            labels(&ordered),
+            vec!["/dev/sde1", "/dev/sdb1", "/dev/sdc1", "/dev/sdd1"]
+        );
+
+        // a partition next to the destination doesn't depend on it
+        let jobs = vec![
+            backup_job("/dev/sdc1", &["sdb2"], &["sdc1", "sdc"]),
+            backup_job("/dev/sdb1", &["sda"], &["sdb1", "sdb"]),
+        ];
+        assert_eq!(
+            labels(&order_jobs(jobs).unwrap()),
+            vec!["/dev/sdc1", "/dev/sdb1"]
+        );
+
+        let jobs = vec![
+            backup_job("/dev/sde1", &["sda"], &["sde1", "sde"]),
+            backup_job("/dev/sdb1", &["sdc"], &["sdb1", "sdb"]),
+            backup_job("/dev/sdc1", &["sdb"], &["sdc1", "sdc"]),
+        ];
+        assert_eq!(
+            order_jobs(jobs).map(|jobs| jobs.len()),
+            Err(
+                "Cyclic backup configuration, each of these destinations gets a backup of \
+                the disk holding the next one: /dev/sdb1 -> /dev/sdc1 -> /dev/sdb1"
+                    .to_string()
+            )
+        );
+
+        let jobs = vec![backup_job("/dev/sdb1", &["sdb"], &["sdb1", "sdb"])];
+        assert_eq!(
+            order_jobs(jobs).map(|jobs| jobs.len()),
+            Err(
+                "Cyclic backup configuration, each of these destinations gets a backup of \
+                the disk holding the next one: /dev/sdb1 -> /dev/sdb1"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_jobs_waits_for_dependencies() {
+        let jobs = vec![
+            backup_job("/dev/sdc1", &["sdb"], &["sdc1", "sdc"]),
+            backup_job("/dev/sdb1", &["sda"], &["sdb1", "sdb"]),
+        ];
+        let events: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let result = run_jobs(jobs, 2, |(label, _)| {
+            events.lock().unwrap().push(format!("start {}", label));
+            thread::sleep(Duration::from_millis(20));
+            events.lock().unwrap().push(format!("finish {}", label));
+            Ok(())
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            events.into_inner().unwrap(),
+            vec![
+                "start /dev/sdb1",
+                "finish /dev/sdb1",
+                "start /dev/sdc1",
+                "finish /dev/sdc1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_jobs() {
+        let jobs = vec![
+            job("a", &["disk:sda", "disk:sdc"]),
+            job("b", &["disk:sda", "disk:sdd"]),
+            job("c", &["disk:sdb", "disk:sde"]),
+            job("d", &["disk:sdb", "disk:sdf"]),
+            job("e", &["disk:sdg"]),
+        ];
+        let used: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        let running: Mutex<(usize, usize)> = Mutex::new((0, 0));
+        let conflicts: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        let names: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+
+        let result = run_jobs(jobs, 2, |(label, resources)| {
+            {
+                let mut used = used.lock().unwrap();
+                conflicts
+                    .lock()
+                    .unwrap()
+                    .extend(resources.iter().filter(|r| used.contains(r)).cloned());
+                used.extend(resources.clone());
+                let mut running = running.lock().unwrap();
+                running.0 += 1;
+                running.1 = running.1.max(running.0);
+                names
+                    .lock()
+                    .unwrap()
+                    .push((label, thread::current().name().map(|n| n.to_string())));
+            }
+            thread::sleep(Duration::from_millis(20));
+            used.lock().unwrap().retain(|r| !resources.contains(r));
+            running.lock().unwrap().0 -= 1;
+            Ok(())
+        });
+
+        let mut names = names.into_inner().unwrap();
+        let mut first: Vec<String> = names[..2].iter().map(|(label, _)| label.clone()).collect();
+        first.sort();
+        names.sort();
+        assert_eq!(result, Ok(()));
+        assert_eq!(conflicts.into_inner().unwrap(), Vec::<String>::new());
+        assert_eq!(running.into_inner().unwrap().1, 2);
+        // b and d wait for the disks used by a and c
+        assert_eq!(first, vec!["a", "c"]);
+        assert_eq!(
+            names,
+            ["a", "b", "c", "d", "e"]
+                .iter()
+                .map(|label| (label.to_string(), Some(label.to_string())))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_run_jobs_stops_after_failure() {
+        let jobs = vec![job("a", &[]), job("b", &[]), job("c", &[])];
+        let started: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let result = run_jobs(jobs, 1, |(label, _)| {
+            started.lock().unwrap().push(label.clone());
+            match label.as_str() {
+                "b" => Err("Backup of b failed".to_string()),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(result, Err("Backup of b failed".to_string()));
+        assert_eq!(started.into_inner().unwrap(), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashSet;
+
+use crate::run::backup_run::backup::Backup;
+
+use super::backups::Backups;
+use super::executor::Job;
+use super::interrupt;
+
+/// The backups to several destination filesystems sharing source devices, run in one job
+/// so that a device configured on several destinations is read once for all of them.
+#[derive(Debug)]
+pub struct FanOut<'a> {
+    /// The backups to each destination filesystem, in their configured order.
+    pub backups: Vec<Backups<'a>>,
+}
+
+impl<'a> FanOut<'a> {
+    /// Groups the backups of all destinations by their source devices: destinations sharing a source device,
+    /// directly or through another destination, end up in the same `FanOut`, the others in one of their own.
+    /// The groups keep the order of their first destination.
+    ///
+    /// Only destinations at the same depth of the order of `order_jobs` are grouped, see `depths`:
+    /// a destination backing up the disk of another one runs in a later group, after the backups to it.
+    /// Destinations in a cycle are never grouped, `order_jobs` rejects them.
+    pub fn plan(all_backups: Vec<Backups<'a>>) -> Vec<FanOut<'a>> {
+        let depths = depths(&all_backups);
+        let mut fan_outs: Vec<(FanOut<'a>, Option<usize>)> = Vec::new();
+        for (backups, depth) in all_backups.into_iter().zip(depths) {
+            let sharing: Vec<usize> = fan_outs
+                .iter()
+                .enumerate()
+                .filter(|(_, (fan_out, fan_out_depth))| {
+                    depth.is_some()
+                        && *fan_out_depth == depth
+                        && fan_out
+                            .backups
+                            .iter()
+                            .any(|other| shares_source(other, &backups))
+                })
+                .map(|(index, _)| index)
+                .collect();
+            match sharing.split_first() {
+                Some((&first, rest)) => {
+                    for &index in rest.iter().rev() {
+                        let (merged, _) = fan_outs.remove(index);
+                        fan_outs[first].0.backups.extend(merged.backups);
+                    }
+                    fan_outs[first].0.backups.push(backups);
+                }
+                None => fan_outs.push((
+                    FanOut {
+                        backups: vec![backups],
+                    },
+                    depth,
+                )),
+            }
+        }
+        fan_outs.into_iter().map(|(fan_out, _)| fan_out).collect()
+    }
+
+    /// Turns the fan-out into a job of `run_jobs`, labeled with its destination filesystems
+    /// and holding the resources, reads and writes of the jobs of all of them, see `Backups::job`.
+    pub fn job(self) -> Job<FanOut<'a>> {
+        let label = self
+            .backups
+            .iter()
+            .map(|backups| backups.dst_filesystem.device_path.as_str())
+            .collect::<Vec<&str>>()
+            .join("+");
+        let mut resources: Vec<String> = self.backups.iter().flat_map(Backups::resources).collect();
+        resources.sort();
+        resources.dedup();
+        let mut reads: Vec<String> = self.backups.iter().flat_map(Backups::reads).collect();
+        reads.sort();
+        reads.dedup();
+        let writes: Vec<String> = self.backups.iter().flat_map(Backups::writes).collect();
+        Job {
+            label,
+            resources,
+            reads,
+            writes,
+            task: self,
+        }
+    }
+
+    /// Executes the backups to all destination filesystems.
+    ///
+    /// All filesystems are opened first, see `Backups::open`. A device is then backed up to every opened
+    /// filesystem it is configured on with the same options by a single read, see `Backup::run_teed`,
+    /// other devices are backed up like by `Backups::run`. A filesystem failing to mount is skipped,
+    /// the others are backed up, its error is returned after all filesystems were closed.
+    /// Once SIGINT or SIGTERM was received or the time of `start_before` has passed, no further backups are started.
+    pub fn run(self) -> Result<(), String> {
+        let mut backups_list = self.backups;
+        if backups_list.len() == 1 {
+            return backups_list.remove(0).run();
+        }
+        if backups_list
+            .iter()
+            .any(|backups| backups.is_past_start_deadline())
+        {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut opened = Vec::new();
+        for mut backups in backups_list {
+            match backups.open() {
+                Ok(Some(session)) => opened.push((backups, session)),
+                Ok(None) => {}
+                Err(e) => {
+                    error!(
+                        "{}, skipping backups for filesystem {}",
+                        e, backups.dst_filesystem.device_path
+                    );
+                    result = result.and(Err(e));
+                }
+            }
+        }
+
+        let mut started: HashSet<(usize, usize)> = HashSet::new();
+        'backups: for (index, (backups, _)) in opened.iter().enumerate() {
+            for (device_index, backup_device) in backups.backup_devices.iter().enumerate() {
+                if interrupt::is_interrupted() || backups.is_past_start_deadline() {
+                    break 'backups;
+                }
+                if !started.insert((index, device_index)) {
+                    continue;
+                }
+                let mut teed = vec![backups.backup(backup_device)];
+                for (other_index, (others, _)) in opened.iter().enumerate().skip(index + 1) {
+                    let peer = others.backup_devices.iter().enumerate().find(
+                        |(other_device_index, other_device)| {
+                            !started.contains(&(other_index, *other_device_index))
+                                && teed[0].tees_with(&others.backup(other_device))
+                        },
+                    );
+                    if let Some((other_device_index, other_device)) = peer {
+                        started.insert((other_index, other_device_index));
+                        teed.push(others.backup(other_device));
+                    }
+                }
+                match teed.len() {
+                    1 => backups.back_up(backup_device),
+                    destinations => {
+                        info!(
+                            "Reading {} once for {} destinations",
+                            backup_device.device_path, destinations
+                        );
+                        Backup::run_teed(&teed);
+                    }
+                }
+            }
+        }
+
+        for (backups, session) in opened {
+            result = result.and(backups.close(session));
+        }
+        result.and_then(|()| interrupt::check())
+    }
+}
+
+/// Returns the depth of each destination in the order of `order_jobs`: 0 for destinations whose sources
+/// are written by no other destination, otherwise one more than the deepest destination writing one of them.
+/// Destinations in a dependency cycle, or depending on one, have no depth.
+///
+/// Dependencies are those between single destinations, like `Job::depends_on`, so that the depth of
+/// a destination doesn't depend on the destinations it is grouped with.
+fn depths(all_backups: &[Backups]) -> Vec<Option<usize>> {
+    let reads: Vec<Vec<String>> = all_backups.iter().map(Backups::reads).collect();
+    let writes: Vec<Vec<String>> = all_backups.iter().map(Backups::writes).collect();
+    let count = all_backups.len();
+    let mut depths = vec![0; count];
+    // a depth reaches `count` only through a cycle, after as many rounds as there are destinations
+    for _ in 0..count {
+        depths = (0..count)
+            .map(|index| {
+                (0..count)
+                    .filter(|&other| {
+                        reads[index]
+                            .iter()
+                            .any(|device| writes[other].contains(device))
+                    })
+                    .map(|other| depths[other] + 1)
+                    .max()
+                    .unwrap_or(0)
+                    .min(count)
+            })
+            .collect();
+    }
+    depths
+        .into_iter()
+        .map(|depth| (depth < count).then_some(depth))
+        .collect()
+}
+
+/// Returns whether two destinations have a source device in common.
+fn shares_source(backups: &Backups, other: &Backups) -> bool {
+    backups.backup_devices.iter().any(|backup_device| {
+        other
+            .backup_devices
+            .iter()
+            .any(|other_device| other_device.device_path == backup_device.device_path)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::run::backup_run::command_output::fake::{FakeCommandRunner, FakeResponse};
+    use crate::run::backup_run::executor::order_jobs;
+    use crate::run::backup_run::lsblk::Lsblk;
+    use crate::run::backup_run::progress::Progress;
+    use crate::run::backup_run::BackupArgs;
+    use crate::run::config::{BackupConfig, BackupDevice, Config, CopyOptions, DeviceIdentifier};
+
+    const LSBLK_OUTPUT: &str = r#"{
+        "blockdevices": [
+            {"name": "fakesrc0", "model": "Model", "serial": "SRC1", "uuid": null,
+             "mountpoint": null, "size": "1M", "fsavail": null},
+            {"name": "fakesrc1", "model": "Model", "serial": "SRC2", "uuid": null,
+             "mountpoint": null, "size": "1M", "fsavail": null},
+            {"name": "fakedst0p1", "model": null, "serial": null, "uuid": "ONSITE-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G"},
+            {"name": "fakedst1p1", "model": null, "serial": null, "uuid": "OFFSITE-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G"},
+            {"name": "fakedst2p1", "model": null, "serial": null, "uuid": "OTHER-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G"}
+        ]
+    }"#;
+
+    fn backup_config(uuid: &str, serial: &str) -> BackupConfig {
+        BackupConfig {
+            backup_devices: vec![backup_device(serial)],
+            uuid: uuid.to_string(),
+            destination_path: None,
+            fsck_command: None,
+            skip_fsck: None,
+            skip_mount: None,
+        }
+    }
+
+    fn backup_device(serial: &str) -> BackupDevice {
+        BackupDevice {
+            identifier: DeviceIdentifier::Serial(serial.to_string()),
+            name: Some("desktop".to_string()),
+            copies: None,
+            delete_oldest_when_short: None,
+            partitions: None,
+            options: CopyOptions::default(),
+        }
+    }
+
+    /// Returns the backups of all destinations of the configuration, as found in the lsblk output.
+    fn all_backups<'a>(
+        config: &'a Config,
+        lsblk: &'a Lsblk,
+        backup_args: &'a BackupArgs,
+        progress: &'a Progress,
+        runner: Arc<FakeCommandRunner>,
+    ) -> Vec<Backups<'a>> {
+        config
+            .backups
+            .iter()
+            .map(|backup_config| {
+                Backups::new(
+                    backup_config,
+                    lsblk,
+                    backup_args,
+                    config,
+                    progress,
+                    runner.clone(),
+                )
+                .unwrap()
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn config(backups: Vec<BackupConfig>) -> Config {
+        Config {
+            mountpath: None,
+            max_parallel: None,
+            fan_out: Some(true),
+            start_before: None,
+            finish_before: None,
+            backups,
+        }
+    }
+
+    fn backup_args() -> BackupArgs {
+        BackupArgs {
+            dry_run: true,
+            file_config_args: None,
+            single_backup_args: None,
+            mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let config = config(vec![
+            backup_config("ONSITE-UUID", "SRC1"),
+            backup_config("OTHER-UUID", "SRC2"),
+            backup_config("OFFSITE-UUID", "SRC1"),
+        ]);
+        let backup_args = backup_args();
+        let runner =
+            Arc::new(FakeCommandRunner::new().script("lsblk", FakeResponse::ok(LSBLK_OUTPUT)));
+        let lsblk = Lsblk::from_lsblk(runner.as_ref()).unwrap();
+        let progress = Progress::new();
+        let all_backups = all_backups(&config, &lsblk, &backup_args, &progress, runner);
+
+        let fan_outs = FanOut::plan(all_backups);
+
+        assert_eq!(fan_outs.len(), 2);
+        let onsite = &fan_outs[0].backups[0];
+        let offsite = &fan_outs[0].backups[1];
+        let other = &fan_outs[1].backups[0];
+        let backup = onsite.backup(&onsite.backup_devices[0]);
+        assert!(backup.tees_with(&offsite.backup(&offsite.backup_devices[0])));
+        assert!(!backup.tees_with(&other.backup(&other.backup_devices[0])));
+
+        let jobs: Vec<Job<FanOut>> = fan_outs.into_iter().map(FanOut::job).collect();
+        assert_eq!(jobs[0].label, "/dev/fakedst0p1+/dev/fakedst1p1");
+        // the source is read by a single job
+        assert_eq!(
+            jobs[0].resources,
+            vec![
+                "disk:fakedst0p1".to_string(),
+                "disk:fakedst1p1".to_string(),
+                "disk:fakesrc0".to_string(),
+                "mountpath:/run/dd_backup/OFFSITE-UUID".to_string(),
+                "mountpath:/run/dd_backup/ONSITE-UUID".to_string(),
+            ]
+        );
+        assert_eq!(jobs[1].label, "/dev/fakedst2p1");
+    }
+
+    #[test]
+    fn test_plan_backs_up_destination_disk_after_backups_to_it() {
+        let lsblk_output = LSBLK_OUTPUT.replace(
+            r#""uuid": "ONSITE-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G"}"#,
+            r#""uuid": "ONSITE-UUID",
+             "mountpoint": null, "size": "100G", "fsavail": "50G", "pkname": "fakedst0"},
+            {"name": "fakedst0", "model": "Model", "serial": "DST0", "uuid": null,
+             "mountpoint": null, "size": "100G", "fsavail": null}"#,
+        );
+        let mut offsite = backup_config("OFFSITE-UUID", "SRC1");
+        offsite.backup_devices.push(backup_device("DST0"));
+        let config = config(vec![backup_config("ONSITE-UUID", "SRC1"), offsite]);
+        let backup_args = backup_args();
+        let runner =
+            Arc::new(FakeCommandRunner::new().script("lsblk", FakeResponse::ok(&lsblk_output)));
+        let lsblk = Lsblk::from_lsblk(runner.as_ref()).unwrap();
+        let progress = Progress::new();
+        let all_backups = all_backups(&config, &lsblk, &backup_args, &progress, runner);
+
+        let jobs: Vec<Job<FanOut>> = FanOut::plan(all_backups)
+            .into_iter()
+            .map(FanOut::job)
+            .rev()
+            .collect();
+        let jobs = order_jobs(jobs).unwrap();
+
+        // the offsite destination reads the disk of the onsite one, so the source isn't shared
+        let labels: Vec<&str> = jobs.iter().map(|job| job.label.as_str()).collect();
+        assert_eq!(labels, vec!["/dev/fakedst0p1", "/dev/fakedst1p1"]);
+    }
+}
@@ -1,12 +1,16 @@
-use std::{fs, path::Path};
+use std::{fs, path::Path, sync::Arc};
 
-use crate::run::{config::BackupConfig, utils::convert_to_byte_size};
-
-use super::{
-    command_output::command_output,
-    lsblk::{BlockDevice, Lsblk},
+use crate::run::{
+    config::{BackupConfig, ImageFormat},
+    image_metadata::ImageMetadata,
+    utils::available_bytes,
 };
 
+use super::{command_output::CommandRunner, lsblk::BlockDevice};
+
+/// The directory below which each destination filesystem is mounted at `<uuid>`, unless `mountpath` is set.
+pub const MOUNT_DIR: &str = "/run/dd_backup";
+
 /// Represents a filesystem associated with a block device.
 #[derive(Debug)]
 pub struct Filesystem {
@@ -16,10 +20,14 @@ pub struct Filesystem {
     pub device_path: String,
     /// The mount path for the filesystem.
     pub mountpath: String,
+    /// Whether `mount` created the mount path, which is removed again by `unmount`.
+    pub created_mountpath: bool,
     // The available size of the block device
     pub fsavail: Option<u64>,
     pub fsck_command: String,
     pub skip_fsck: bool,
+    /// The runner executing mount, unmount and fsck commands.
+    pub runner: Arc<dyn CommandRunner>,
 }
 
 impl Filesystem {
@@ -33,7 +41,8 @@ impl Filesystem {
     ///
     /// * `uuid` - The UUID of the filesystem.
     /// * `available_filesystems` - The list of available block devices to search for a matching UUID.
-    /// * `mountpath` - The optional mount path of the filesystem.
+    /// * `mountpath` - The optional mount path of the filesystem, `/run/dd_backup/<uuid>` by default.
+    /// * `runner` - The runner executing mount, unmount and fsck commands.
     ///
     /// # Returns
     ///
@@ -44,6 +53,7 @@ impl Filesystem {
         backup_config: &BackupConfig,
         available_filesystems: &[BlockDevice],
         mountpath: Option<String>,
+        runner: Arc<dyn CommandRunner>,
     ) -> Result<Option<Filesystem>, String> {
         let uuid_filtered_lsblk =
             Self::validate_uuid_uniq(&backup_config.uuid, available_filesystems)?;
@@ -53,17 +63,15 @@ impl Filesystem {
                 let filesystem = Filesystem {
                     blockdevice: blockdevice.clone(),
                     device_path: format!("/dev/{}", &blockdevice.name),
-                    mountpath: mountpath.unwrap_or("/mnt".to_string()),
-                    fsavail: blockdevice
-                        .fsavail
-                        .clone()
-                        .map(|fsavail| convert_to_byte_size(&fsavail).unwrap_or(None))
-                        .unwrap_or(None),
+                    mountpath: mountpath.unwrap_or(format!("{}/{}", MOUNT_DIR, backup_config.uuid)),
+                    created_mountpath: false,
+                    fsavail: blockdevice.fsavail,
                     fsck_command: backup_config
                         .fsck_command
                         .clone()
                         .unwrap_or("fsck -n".to_string()),
                     skip_fsck: backup_config.skip_fsck.unwrap_or(false),
+                    runner,
                 };
                 debug!("{:?}", filesystem);
                 Ok(Some(filesystem))
@@ -114,9 +122,12 @@ impl Filesystem {
     }
 
     /// Mounts the device.
+    /// The mount path is created if it doesn't exist, a non-empty directory is never mounted over,
+    /// so that no data already stored or mounted there is hidden.
     /// Returns `Ok(())` if the device is mounted successfully, otherwise returns an error message.
     pub fn mount(&mut self) -> Result<(), String> {
-        let output = command_output(
+        self.prepare_mountpath()?;
+        let output = self.runner.output(
             vec!["mount", &self.device_path, &self.mountpath],
             &format!(
                 "mount filesystem {} at {}",
@@ -133,6 +144,7 @@ impl Filesystem {
             );
             Ok(())
         } else {
+            self.remove_created_mountpath();
             Err(format!(
                 "Error mounting filesystem {} on {}",
                 self.device_path, self.mountpath
@@ -140,6 +152,63 @@ impl Filesystem {
         }
     }
 
+    /// Creates the mount path with its parents if it doesn't exist, readable by root only,
+    /// or makes sure it is an empty directory.
+    /// It is created with sudo like the mount, since the default mount path is below `/run`.
+    fn prepare_mountpath(&mut self) -> Result<(), String> {
+        let mountpath = Path::new(&self.mountpath);
+        if !mountpath.exists() {
+            let description = format!("create mount path {}", self.mountpath);
+            self.runner
+                .output(
+                    vec!["mkdir", "-p", &self.mountpath],
+                    &description,
+                    Some(true),
+                )
+                .and_then(|_| {
+                    self.runner.output(
+                        vec!["chmod", "700", &self.mountpath],
+                        &description,
+                        Some(true),
+                    )
+                })
+                .map_err(|e| format!("Failed to create mount path {}: {}", self.mountpath, e))?;
+            self.created_mountpath = true;
+            debug!("Created mount path {}", self.mountpath);
+            return Ok(());
+        }
+        let is_empty = fs::read_dir(mountpath)
+            .map_err(|e| format!("Failed to read mount path {}: {}", self.mountpath, e))?
+            .next()
+            .is_none();
+        match is_empty {
+            true => Ok(()),
+            false => Err(format!(
+                "Refusing to mount filesystem {} over the non-empty directory {}",
+                self.device_path, self.mountpath
+            )),
+        }
+    }
+
+    /// Removes the mount path if it was created by `mount`.
+    fn remove_created_mountpath(&mut self) {
+        if !self.created_mountpath {
+            return;
+        }
+        let removed = self.runner.output(
+            vec!["rmdir", &self.mountpath],
+            &format!("remove mount path {}", self.mountpath),
+            Some(true),
+        );
+        match removed {
+            Ok(_) => {
+                debug!("Removed mount path {}", self.mountpath);
+                self.created_mountpath = false;
+            }
+            Err(e) => warn!("Failed to remove mount path {}: {}", self.mountpath, e),
+        }
+    }
+
     /// Unmounts the device.
     /// Returns `Ok(())` if the device is unmounted successfully, otherwise returns an error message.
     pub fn unmount(&mut self) -> Result<(), String> {
@@ -149,9 +218,10 @@ impl Filesystem {
             .clone()
             .ok_or(self.mountpath.clone())?;
 
-        command_output(vec!["sync"], "execute sync", Some(false))?;
+        self.runner
+            .output(vec!["sync"], "execute sync", Some(false))?;
 
-        let output = command_output(
+        let output = self.runner.output(
             vec!["umount", &mountpoint],
             &format!("unmount filesystem {} at {}", self.device_path, &mountpoint),
             Some(true),
@@ -160,6 +230,7 @@ impl Filesystem {
         if output.status.success() {
             self.blockdevice.mountpoint = None;
             info!("Filesystem {} unmounted successfully", self.device_path);
+            self.remove_created_mountpath();
             Ok(())
         } else {
             Err(format!(
@@ -171,7 +242,7 @@ impl Filesystem {
         }
     }
 
-    /// Checks if the number of existing backups exceeds the specified number of copies.
+    /// Returns the number of backup files of the device in the backup directory, in any image format.
     pub fn present_number_of_copies(
         &self,
         suffix_file_name_pattern: &str,
@@ -184,7 +255,7 @@ impl Filesystem {
                         e.file_name()
                             .to_str()
                             .map(|s| s.to_string())
-                            .filter(|s| s.contains(suffix_file_name_pattern))
+                            .filter(|s| is_backup_file(s, suffix_file_name_pattern))
                     })
                 })
                 .collect::<Vec<String>>(),
@@ -194,49 +265,94 @@ impl Filesystem {
         backup_files.len() // >= self.backup_device.copies as usize
     }
 
-    /// Deletes the oldest backup file.
+    /// Deletes the oldest backup file, together with its layout files like `<image>.sfdisk`.
+    ///
+    /// Degraded images, with parts of their device unreadable, don't replace the last good image:
+    /// if the oldest image is the only one that isn't degraded, counting `keep` too, the oldest degraded
+    /// image is deleted instead, or none if there is no other. The image `keep`, like the one just written,
+    /// is never deleted.
     pub fn delete_oldest_backup(
         &self,
         suffix_file_name_pattern: &str,
         backup_dst_path: &str,
+        keep: Option<&str>,
     ) -> Result<(), String> {
-        let present_backup_files =
+        let mut present_backup_files =
             self.present_backup_files(suffix_file_name_pattern, backup_dst_path)?;
-        if let Some(oldest_file) = present_backup_files.iter().min_by_key(|&file_name| {
+        present_backup_files.retain(|file_name| Some(file_name.as_str()) != keep);
+        present_backup_files.sort_by_cached_key(|file_name| {
             let file_path = Path::new(backup_dst_path).join(file_name);
             if let Ok(metadata) = fs::metadata(file_path) {
                 if let Ok(created) = metadata.created() {
-                    return created;
+                    return (created, file_name.clone());
                 }
             }
-            // fallback value to ensure consistent ordering
-            std::time::UNIX_EPOCH
-        }) {
+            // fallback value to ensure consistent ordering, file names start with the date
+            (std::time::UNIX_EPOCH, file_name.clone())
+        });
+        let degraded: Vec<bool> = present_backup_files
+            .iter()
+            .map(|file_name| is_degraded(&format!("{}/{}", backup_dst_path, file_name)))
+            .collect();
+        let keeps_good_image =
+            keep.is_some_and(|keep| !is_degraded(&format!("{}/{}", backup_dst_path, keep)));
+        let good_images = degraded.iter().filter(|degraded| !**degraded).count();
+        let oldest = match degraded.first() {
+            Some(false) if good_images == 1 => {
+                let oldest_degraded = degraded.iter().position(|degraded| *degraded);
+                match (oldest_degraded, keeps_good_image) {
+                    (Some(_), _) => info!(
+                        "Keeping {}, the last backup file that isn't degraded",
+                        present_backup_files[0]
+                    ),
+                    (None, false) => warn!(
+                        "Keeping {} besides the configured copies, it is the last backup file that isn't degraded",
+                        present_backup_files[0]
+                    ),
+                    (None, true) => {}
+                }
+                oldest_degraded.or(keeps_good_image.then_some(0))
+            }
+            Some(_) => Some(0),
+            None => None,
+        };
+        if let Some(oldest_file) = oldest.map(|index| &present_backup_files[index]) {
             let file_path = format!("{}/{}", backup_dst_path, oldest_file);
             info!("Delete old back up file: {}", file_path);
-            fs::remove_file(&file_path)
-                .map_err(|e| format!("Failed to delete oldest backup file '{}': {}", file_path, e))
+            fs::remove_file(&file_path).map_err(|e| {
+                format!("Failed to delete oldest backup file '{}': {}", file_path, e)
+            })?;
+            self.delete_sidecar_files(oldest_file, backup_dst_path)
         } else {
             Ok(())
         }
     }
 
-    /// Returns the available space of the block device, converted to bytes, or None if the size is unavailable / readable.
-    pub fn available_space(&self) -> Result<Option<u64>, String> {
-        let device_uuid = self.blockdevice.uuid.clone();
-        // needs a new lsblk instance, since the filesystem size is only accessible if mounted
-        let lsblk = Lsblk::new()?;
-        let filesystem = lsblk
-            .available_filesystems
-            .iter()
-            .find(|fs| fs.uuid == device_uuid)
-            .unwrap();
+    /// Deletes the files stored next to a backup file, named like `<backup file>.<extension>`.
+    fn delete_sidecar_files(&self, backup_file: &str, backup_dst_path: &str) -> Result<(), String> {
+        let prefix = format!("{}.", backup_file);
+        let sidecar_files = fs::read_dir(backup_dst_path)
+            .map_err(|e| format!("Failed to read backup directory: {}", e))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|file_name| file_name.starts_with(&prefix));
+        for sidecar_file in sidecar_files {
+            let file_path = format!("{}/{}", backup_dst_path, sidecar_file);
+            info!("Delete old back up file: {}", file_path);
+            fs::remove_file(&file_path)
+                .map_err(|e| format!("Failed to delete backup file '{}': {}", file_path, e))?;
+        }
+        Ok(())
+    }
 
-        Ok(filesystem
-            .fsavail
-            .clone()
-            .map(|fsavail| convert_to_byte_size(&fsavail).unwrap_or(None))
-            .unwrap_or(None))
+    /// Returns the available space of the filesystem in bytes.
+    /// If mounted, it is read with `statvfs` from the mountpoint, since it changes while backups are written.
+    /// Otherwise the value read on startup is returned, which is `None` for unmounted filesystems.
+    pub fn available_space(&self) -> Result<Option<u64>, String> {
+        match &self.blockdevice.mountpoint {
+            Some(mountpoint) => available_bytes(mountpoint).map(Some),
+            None => Ok(self.fsavail),
+        }
     }
 
     fn present_backup_files(
@@ -251,7 +367,7 @@ impl Filesystem {
                     e.file_name()
                         .to_str()
                         .map(|s| s.to_string())
-                        .filter(|s| s.contains(suffix_file_name_pattern))
+                        .filter(|s| is_backup_file(s, suffix_file_name_pattern))
                 })
             })
             .collect::<Vec<String>>();
@@ -272,7 +388,7 @@ impl Filesystem {
                 let mut command_parts: Vec<&str> = fsck_command.split(' ').collect();
                 command_parts.push(self.device_path.as_str());
 
-                let output = command_output(command_parts, "check fs", Some(true))?;
+                let output = self.runner.output(command_parts, "check fs", Some(true))?;
 
                 if output.status.success() {
                     Ok(())
@@ -284,9 +400,30 @@ impl Filesystem {
     }
 }
 
+/// Returns whether `file_name` is a backup image named `<date>_<suffix_file_name_pattern>.<extension>`,
+/// with the extension of any image format, so that retention counts raw and converted images alike.
+fn is_backup_file(file_name: &str, suffix_file_name_pattern: &str) -> bool {
+    ImageFormat::ALL.iter().any(|format| {
+        file_name.ends_with(&format!(
+            "{}.{}",
+            suffix_file_name_pattern,
+            format.extension()
+        ))
+    })
+}
+
+/// Returns whether the image is degraded by unreadable parts of its device, recorded in its metadata.
+fn is_degraded(image: &str) -> bool {
+    ImageMetadata::read(image)
+        .is_ok_and(|metadata| metadata.as_ref().is_some_and(ImageMetadata::is_degraded))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::run::backup_run::command_output::fake::FakeCommandRunner;
+    use crate::run::extent::Extent;
+    use crate::run::image_metadata::Region;
 
     fn generate_test_filesystems() -> Vec<BlockDevice> {
         vec![
@@ -296,8 +433,9 @@ mod tests {
                 serial: Some("serial1".to_string()),
                 uuid: Some("uuid1".to_string()),
                 mountpoint: Some("/mnt/sda1".to_string()),
-                size: "100GB".to_string(),
-                fsavail: Some("50GB".to_string()),
+                size: 100_000_000_000,
+                fsavail: Some(50_000_000_000),
+                ..Default::default()
             },
             BlockDevice {
                 name: "sdb1".to_string(),
@@ -305,8 +443,9 @@ mod tests {
                 serial: Some("serial2".to_string()),
                 uuid: Some("uuid2".to_string()),
                 mountpoint: Some("/mnt/sdb1".to_string()),
-                size: "200GB".to_string(),
-                fsavail: Some("100GB".to_string()),
+                size: 200_000_000_000,
+                fsavail: Some(100_000_000_000),
+                ..Default::default()
             },
             BlockDevice {
                 name: "sdc1".to_string(),
@@ -314,8 +453,9 @@ mod tests {
                 serial: Some("serial3".to_string()),
                 uuid: Some("uuid2".to_string()), // Duplicate UUID
                 mountpoint: Some("/mnt/sdc1".to_string()),
-                size: "300GB".to_string(),
-                fsavail: Some("150GB".to_string()),
+                size: 300_000_000_000,
+                fsavail: Some(150_000_000_000),
+                ..Default::default()
             },
         ]
     }
@@ -337,6 +477,107 @@ mod tests {
         assert!(Filesystem::validate_present_uuid(uuid_filtered_lsblk).is_none());
     }
 
+    #[test]
+    fn test_delete_oldest_backup_keeps_last_good_image() {
+        let dir = std::env::temp_dir().join(format!(
+            "dd_backup_filesystem_test_{}_degraded",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let backup_dst_path = dir.to_string_lossy().to_string();
+        let filesystem = Filesystem {
+            blockdevice: generate_test_filesystems().remove(0),
+            device_path: "/dev/sda1".to_string(),
+            mountpath: backup_dst_path.clone(),
+            created_mountpath: false,
+            fsavail: None,
+            fsck_command: String::new(),
+            skip_fsck: true,
+            runner: Arc::new(FakeCommandRunner::new()),
+        };
+        let good = "2023-01-01_desktop_Model_SRC1.img";
+        let degraded = "2023-01-02_desktop_Model_SRC1.img";
+        fs::write(dir.join(good), b"image").unwrap();
+        fs::write(dir.join(degraded), b"image").unwrap();
+        // the new copy with `copies: 1` was read from a failing device
+        ImageMetadata {
+            source: "/dev/sdb".to_string(),
+            size: 5,
+            source_size: None,
+            regions: vec![Region::full(0, 5)],
+            unreadable: vec![Extent::new(0, 5)],
+        }
+        .write(&format!("{}/{}", backup_dst_path, degraded))
+        .unwrap();
+
+        let result =
+            filesystem.delete_oldest_backup("desktop_Model_SRC1", &backup_dst_path, Some(degraded));
+        let good_present = dir.join(good).exists();
+        let degraded_present = dir.join(degraded).exists();
+        // a new good copy replaces the old one
+        fs::remove_file(ImageMetadata::path(&format!(
+            "{}/{}",
+            backup_dst_path, degraded
+        )))
+        .unwrap();
+        let replaced =
+            filesystem.delete_oldest_backup("desktop_Model_SRC1", &backup_dst_path, Some(degraded));
+        let good_replaced = !dir.join(good).exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert!(good_present);
+        assert!(degraded_present);
+        assert_eq!(replaced, Ok(()));
+        assert!(good_replaced);
+    }
+
+    #[test]
+    fn test_mount_creates_and_removes_mountpath_with_sudo() {
+        let mountpath = std::env::temp_dir()
+            .join(format!(
+                "dd_backup_filesystem_test_{}_mount",
+                std::process::id()
+            ))
+            .join("DST-UUID")
+            .to_string_lossy()
+            .to_string();
+        let runner = Arc::new(FakeCommandRunner::new());
+        let mut filesystem = Filesystem {
+            blockdevice: BlockDevice {
+                mountpoint: None,
+                ..generate_test_filesystems().remove(0)
+            },
+            device_path: "/dev/sda1".to_string(),
+            mountpath: mountpath.clone(),
+            created_mountpath: false,
+            fsavail: None,
+            fsck_command: String::new(),
+            skip_fsck: true,
+            runner: runner.clone(),
+        };
+
+        filesystem.mount().unwrap();
+        let created = Path::new(&mountpath).is_dir();
+        filesystem.unmount().unwrap();
+
+        assert!(created);
+        assert!(!Path::new(&mountpath).exists());
+        assert_eq!(
+            runner.sudo_commands(),
+            vec![
+                format!("sudo mkdir -p {}", mountpath),
+                format!("sudo chmod 700 {}", mountpath),
+                format!("sudo mount /dev/sda1 {}", mountpath),
+                "sync".to_string(),
+                format!("sudo umount {}", mountpath),
+                format!("sudo rmdir {}", mountpath),
+            ]
+        );
+        fs::remove_dir(Path::new(&mountpath).parent().unwrap()).unwrap();
+    }
+
     #[test]
     fn test_validate_uuid_uniq() {
         let filesystems = generate_test_filesystems();
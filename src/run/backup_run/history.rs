@@ -0,0 +1,422 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local};
+use chrono_humanize::{Accuracy, HumanTime, Tense};
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use crate::run::config::Config;
+
+use super::backups::Backups;
+use super::device::Device;
+use super::progress::{ProgressEvent, ProgressObserver};
+
+/// The file in the config home holding the throughput of past copies, see `History`.
+pub const HISTORY_FILE: &str = "history.json";
+
+/// The number of past copies kept per device.
+const MAX_RUNS: usize = 10;
+
+/// The number of past copies needed to tell whether the throughput of a device has degraded.
+const MIN_RUNS_FOR_DEGRADATION: usize = 3;
+
+/// A copy reaching less than this fraction of the usual throughput of the device flags it as degraded.
+const DEGRADED_RATIO: f64 = 0.5;
+
+/// Copies measured for less seconds are not recorded, their throughput says little about the device.
+const MIN_RECORDED_SECONDS: f64 = 10.0;
+
+/// A past copy of a device, measured from its first progress report to its end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CopyRun {
+    /// The time the copy finished, in RFC 3339.
+    pub date: String,
+    /// The bytes copied while measuring.
+    pub bytes: u64,
+    /// The seconds measured.
+    pub seconds: f64,
+    /// The bytes of the whole copy, also those copied before a resumed copy, the expected size of the next one.
+    pub total_bytes: u64,
+}
+
+impl CopyRun {
+    /// Returns the throughput of the copy in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.seconds
+    }
+}
+
+/// The throughput of past copies per device, stored in `HISTORY_FILE`, to estimate the duration of backups
+/// and to notice drives getting slower. Devices are identified by their model, identifier and partition,
+/// see `key`, since device paths like `/dev/sdb` change between boots.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    pub devices: BTreeMap<String, Vec<CopyRun>>,
+}
+
+impl History {
+    /// Returns the path of the history file in the config home.
+    pub fn default_path() -> Result<PathBuf, String> {
+        Ok(Config::config_home_path()?.join(HISTORY_FILE))
+    }
+
+    /// Reads the history file, a missing one is an empty history.
+    pub fn read(path: &Path) -> Result<History, String> {
+        match path.exists() {
+            true => {
+                let mut file =
+                    File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Self::read_from(&mut file, path)
+            }
+            false => Ok(History::default()),
+        }
+    }
+
+    fn read_from(file: &mut File, path: &Path) -> Result<History, String> {
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        match content.trim().is_empty() {
+            true => Ok(History::default()),
+            false => serde_json::from_str(&content)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Records a copy of the device `key` in the history file, keeping its last `MAX_RUNS` copies.
+    /// The file is locked while it is updated, since jobs and other runs record their copies too.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(f64))`: The usual throughput of the device before this copy, if it has enough past copies.
+    /// - `Ok(None)`: If the device has too few past copies.
+    /// - `Err(String)`: If the history file could not be read or written.
+    pub fn record(path: &Path, key: &str, run: CopyRun) -> Result<Option<f64>, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .map_err(|e| format!("Failed to lock {}: {}", path.display(), e))?;
+        let mut history = Self::read_from(&mut file, path)?;
+        let usual = match history.devices.get(key) {
+            Some(runs) if runs.len() >= MIN_RUNS_FOR_DEGRADATION => history.bytes_per_second(key),
+            _ => None,
+        };
+        let runs = history.devices.entry(key.to_string()).or_default();
+        runs.push(run);
+        if runs.len() > MAX_RUNS {
+            runs.drain(..runs.len() - MAX_RUNS);
+        }
+        let content = serde_json::to_string_pretty(&history).map_err(|e| e.to_string())?;
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(usual)
+    }
+
+    /// Returns the usual throughput of the device `key` in bytes per second, the median of its past copies.
+    pub fn bytes_per_second(&self, key: &str) -> Option<f64> {
+        let mut rates: Vec<f64> = self
+            .devices
+            .get(key)?
+            .iter()
+            .map(CopyRun::bytes_per_second)
+            .collect();
+        rates.sort_by(f64::total_cmp);
+        match rates.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((rates[n / 2 - 1] + rates[n / 2]) / 2.0),
+            n => Some(rates[n / 2]),
+        }
+    }
+
+    /// Returns the estimated seconds to copy the device, from the size of its last copy, or its size
+    /// if unknown, and its usual throughput, limited by `bandwidth_limit`.
+    /// Returns `None` if the device was never copied before.
+    pub fn estimate(&self, device: &Device) -> Option<f64> {
+        let key = key(device);
+        let bytes_per_second = self.bytes_per_second(&key)?;
+        let bytes_per_second = match device.options.bandwidth_limit {
+            Some(limit) => bytes_per_second.min((limit * 1024 * 1024) as f64),
+            None => bytes_per_second,
+        };
+        let bytes = self
+            .devices
+            .get(&key)
+            .and_then(|runs| runs.last())
+            .map(|run| run.total_bytes)
+            .unwrap_or(device.total_size());
+        Some(bytes as f64 / bytes_per_second)
+    }
+}
+
+/// Returns the key of a device in the history: its model, identifier and partition suffix, like `Model_SRC1_part2`.
+pub fn key(device: &Device) -> String {
+    [
+        device.blockdevice.model.clone(),
+        Some(device.identifier_suffix()),
+        device.partition_suffix.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<String>>()
+    .join("_")
+    .replace(' ', "-")
+}
+
+/// Returns whether the throughput of the device is recorded: partition table images are too small
+/// and copies limited by `bandwidth_limit` don't show how fast it is.
+fn is_recorded(device: &Device) -> bool {
+    device.length.is_none() && device.options.bandwidth_limit.is_none()
+}
+
+/// Logs the estimated duration of the backups to each destination filesystem and of all of them,
+/// from the throughput of past copies, see `History::estimate`. Devices never copied before are named,
+/// the duration of their destination and the total are unknown then.
+///
+/// With `max_parallel` greater than 1 the total is the longest destination, or the sum of all of them
+/// shared by the parallel jobs if longer. A warning is logged if the backups are estimated to end
+/// after `finish_before`.
+pub fn log_estimates(
+    all_backups: &[Backups],
+    history: &History,
+    max_parallel: usize,
+    finish_before: Option<DateTime<Local>>,
+) {
+    let mut estimates = Vec::new();
+    for backups in all_backups {
+        let mut seconds = Some(0.0);
+        for backup_device in backups.backup_devices.iter().filter(|d| d.length.is_none()) {
+            match history.estimate(backup_device) {
+                Some(estimate) => seconds = seconds.map(|seconds| seconds + estimate),
+                None => {
+                    info!(
+                        "No past backups of {} to estimate its duration",
+                        backup_device.device_path
+                    );
+                    seconds = None;
+                }
+            }
+        }
+        match seconds {
+            Some(seconds) => info!(
+                "Estimated duration of the backups to {}: {}",
+                backups.dst_filesystem.device_path,
+                format_seconds(seconds)
+            ),
+            None => info!(
+                "Estimated duration of the backups to {}: unknown",
+                backups.dst_filesystem.device_path
+            ),
+        }
+        estimates.push(seconds);
+    }
+    let total = total_seconds(&estimates, max_parallel);
+    if estimates.len() > 1 {
+        info!(
+            "Estimated duration of all backups: {}",
+            total.map_or("unknown".to_string(), format_seconds)
+        );
+    }
+    let Some(total) = total else {
+        if let Some(finish_before) = finish_before {
+            info!(
+                "The end of the backups is unknown, it can't be checked against {}",
+                finish_before.format("%H:%M")
+            );
+        }
+        return;
+    };
+    let end = Local::now() + chrono::Duration::milliseconds((total * 1000.0) as i64);
+    if let Some(finish_before) = finish_before.filter(|finish_before| end > *finish_before) {
+        warn!(
+            "The backups are estimated to end at {}, after {}",
+            end.format("%H:%M"),
+            finish_before.format("%H:%M")
+        );
+    }
+}
+
+/// Returns the estimated seconds of all jobs running at most `max_parallel` at once,
+/// `None` if the estimate of any job is unknown.
+fn total_seconds(estimates: &[Option<f64>], max_parallel: usize) -> Option<f64> {
+    let estimates = estimates.iter().copied().collect::<Option<Vec<f64>>>()?;
+    let sum: f64 = estimates.iter().sum();
+    let longest = estimates.iter().copied().fold(0.0, f64::max);
+    Some(longest.max(sum / max_parallel.max(1) as f64))
+}
+
+fn format_seconds(seconds: f64) -> String {
+    HumanTime::from(chrono::Duration::seconds(seconds.round() as i64))
+        .to_text_en(Accuracy::Rough, Tense::Present)
+}
+
+/// The start of the measurement of a copy, its first progress report.
+struct Measurement {
+    bytes: u64,
+    started: Instant,
+}
+
+/// Records the throughput of every successful copy in the history, see `History::record`,
+/// and warns when a device got much slower than usual, which may be a failing drive.
+pub struct HistoryRecorder {
+    path: PathBuf,
+    /// The history keys of the recorded devices by their device path.
+    keys: HashMap<String, String>,
+    measurements: HashMap<String, Measurement>,
+}
+
+impl HistoryRecorder {
+    /// Creates a recorder of the copies of the devices of all backups into the history file at `path`.
+    pub fn new(path: PathBuf, all_backups: &[Backups]) -> HistoryRecorder {
+        let keys = all_backups
+            .iter()
+            .flat_map(|backups| &backups.backup_devices)
+            .filter(|backup_device| is_recorded(backup_device))
+            .map(|backup_device| (backup_device.device_path.clone(), key(backup_device)))
+            .collect();
+        HistoryRecorder {
+            path,
+            keys,
+            measurements: HashMap::new(),
+        }
+    }
+
+    fn record(&self, source: &str, key: &str, run: CopyRun) {
+        let bytes_per_second = run.bytes_per_second();
+        match History::record(&self.path, key, run) {
+            Ok(Some(usual)) if bytes_per_second < usual * DEGRADED_RATIO => warn!(
+                "{} was read with {}/s, much slower than its usual {}/s, the drive may be failing",
+                source,
+                format_bytes(bytes_per_second),
+                format_bytes(usual)
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to record the throughput of {}: {}", source, e),
+        }
+    }
+}
+
+impl ProgressObserver for HistoryRecorder {
+    fn on_event(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::BytesCopied { source, bytes, .. } if self.keys.contains_key(source) => {
+                self.measurements
+                    .entry(source.clone())
+                    .or_insert_with(|| Measurement {
+                        bytes: *bytes,
+                        started: Instant::now(),
+                    });
+            }
+            ProgressEvent::Finished {
+                source,
+                bytes,
+                success,
+                ..
+            } => {
+                let (Some(measurement), Some(key)) =
+                    (self.measurements.remove(source), self.keys.get(source))
+                else {
+                    return;
+                };
+                let seconds = measurement.started.elapsed().as_secs_f64();
+                if *success && seconds >= MIN_RECORDED_SECONDS {
+                    let run = CopyRun {
+                        date: Local::now().to_rfc3339(),
+                        bytes: bytes.saturating_sub(measurement.bytes),
+                        seconds,
+                        total_bytes: *bytes,
+                    };
+                    self.record(source, key, run);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    format!("{:.1} MiB", bytes / 1024.0 / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn run(mib_per_second: u64) -> CopyRun {
+        CopyRun {
+            date: "2023-06-15T22:00:00+02:00".to_string(),
+            bytes: mib_per_second * 1024 * 1024 * 100,
+            seconds: 100.0,
+            total_bytes: 64 * 1024 * 1024 * 1024,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_history_test_{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let usual: Vec<Option<f64>> = [100, 120, 80, 110, 40]
+            .into_iter()
+            .map(|mib_per_second| History::record(&path, "Model_SRC1", run(mib_per_second)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for _ in 0..MAX_RUNS {
+            History::record(&path, "Model_SRC2", run(200)).unwrap();
+        }
+        History::record(&path, "Model_SRC2", run(150)).unwrap();
+        let history = History::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mib = 1024.0 * 1024.0;
+        // the usual throughput is known from the third past copy on
+        assert_eq!(
+            usual,
+            vec![None, None, None, Some(100.0 * mib), Some(105.0 * mib)]
+        );
+        // the slow copy is below half of the usual throughput
+        assert!(run(40).bytes_per_second() < usual[4].unwrap() * DEGRADED_RATIO);
+        assert_eq!(history.bytes_per_second("Model_SRC1"), Some(100.0 * mib));
+        assert_eq!(history.devices["Model_SRC2"].len(), MAX_RUNS);
+        assert_eq!(history.devices["Model_SRC2"].last(), Some(&run(150)));
+        assert_eq!(History::read(&path), Ok(History::default()));
+    }
+
+    #[test]
+    fn test_total_seconds() {
+        assert_eq!(total_seconds(&[], 1), Some(0.0));
+        assert_eq!(
+            total_seconds(&[Some(600.0), Some(300.0), Some(300.0)], 1),
+            Some(1200.0)
+        );
+        assert_eq!(
+            total_seconds(&[Some(600.0), Some(300.0), Some(300.0)], 2),
+            Some(600.0)
+        );
+        assert_eq!(
+            total_seconds(&[Some(300.0), Some(300.0), Some(300.0)], 2),
+            Some(450.0)
+        );
+        // a destination with a device never copied before
+        assert_eq!(total_seconds(&[Some(600.0), None], 2), None);
+        assert_eq!(format_seconds(5400.0), "an hour");
+    }
+}
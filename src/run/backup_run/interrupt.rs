@@ -0,0 +1,105 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+/// The exit code of a run stopped by SIGINT or SIGTERM, like a shell reports a process killed by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// The number of SIGINT and SIGTERM signals received.
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the handler of SIGINT and SIGTERM.
+///
+/// The first signal only requests the interruption: the running copy is stopped and the destination
+/// is left consistent and unmounted, see `is_interrupted`. A second signal exits right away.
+pub fn install() -> Result<(), String> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only touches an atomic and calls the async-signal-safe `_exit`
+        unsafe { sigaction(signal, &action) }
+            .map_err(|e| format!("Failed to install the handler of {}: {}", signal, e))?;
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    if SIGNALS.fetch_add(1, Ordering::SeqCst) > 0 {
+        // SAFETY: `_exit` is async-signal-safe, unlike `std::process::exit`
+        unsafe { libc::_exit(EXIT_INTERRUPTED) };
+    }
+}
+
+/// Returns whether SIGINT or SIGTERM was received.
+pub fn is_interrupted() -> bool {
+    SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+/// Returns an error if SIGINT or SIGTERM was received.
+pub fn check() -> Result<(), String> {
+    match is_interrupted() {
+        true => Err("Interrupted by signal".to_string()),
+        false => Ok(()),
+    }
+}
+
+/// Fails reads once SIGINT or SIGTERM was received, which stops native copies between two reads.
+pub struct InterruptibleReader<'a, R> {
+    inner: R,
+    signals: &'a AtomicUsize,
+}
+
+impl<R: Read + Seek> InterruptibleReader<'static, R> {
+    pub fn new(inner: R) -> InterruptibleReader<'static, R> {
+        InterruptibleReader {
+            inner,
+            signals: &SIGNALS,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for InterruptibleReader<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        // not `ErrorKind::Interrupted`, which `read_exact` retries
+        match self.signals.load(Ordering::SeqCst) {
+            0 => self.inner.read(buffer),
+            _ => Err(io::Error::other("Interrupted by signal")),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for InterruptibleReader<'_, R> {
+    fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(seek)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_interruptible_reader() {
+        let signals = AtomicUsize::new(0);
+        let mut reader = InterruptibleReader {
+            inner: Cursor::new(vec![7u8; 16]),
+            signals: &signals,
+        };
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer).unwrap();
+        signals.fetch_add(1, Ordering::SeqCst);
+        let interrupted = reader.read_exact(&mut buffer).unwrap_err();
+
+        assert_eq!(buffer, [7u8; 8]);
+        assert_eq!(interrupted.to_string(), "Interrupted by signal");
+        assert!(!is_interrupted());
+    }
+}
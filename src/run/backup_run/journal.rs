@@ -0,0 +1,254 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{command_output::CommandRunner, destination_file};
+use crate::run::{
+    convert::DiskReader,
+    extent::{total_length, Extent},
+};
+
+/// The number of bytes copied between two syncs of the partial image and updates of the journal.
+pub const JOURNAL_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// The number of bytes before the confirmed offset compared with the device before resuming.
+const VERIFY_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Returns the path an image is written to until it is complete, `<image>.partial`.
+pub fn partial_path(image: &str) -> String {
+    format!("{}.partial", image)
+}
+
+/// The progress of an image being written into `<image>.partial`, stored as `<image>.partial.journal`.
+///
+/// The journal is only written after the partial image was synced, so that the bytes up to
+/// `confirmed_offset` survive a power loss.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    /// The path of the device being copied.
+    pub source: String,
+    /// The size of the image in bytes.
+    pub size: u64,
+    /// The number of bytes to copy, which tells the plans of native copies apart.
+    pub data_bytes: u64,
+    /// The offset in the image up to which the copied extents are synced.
+    pub confirmed_offset: u64,
+}
+
+impl Journal {
+    /// Returns the path of the journal of an image.
+    pub fn path(image: &str) -> String {
+        format!("{}.journal", partial_path(image))
+    }
+
+    /// Reads the journal of an image.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(Journal))`: If the image has a journal.
+    /// - `Ok(None)`: If it hasn't.
+    /// - `Err(String)`: If the journal is not readable.
+    pub fn read(image: &str) -> Result<Option<Journal>, String> {
+        let path = Self::path(image);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Writes the journal of an image through `runner` with sudo, replacing the previous one atomically.
+    pub fn write(&self, runner: &dyn CommandRunner, image: &str) -> Result<(), String> {
+        let content = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        destination_file::write(runner, &Self::path(image), content.as_bytes())
+    }
+
+    /// Removes the partial image and the journal of an image through `runner` with sudo, if present.
+    pub fn discard(runner: &dyn CommandRunner, image: &str) -> Result<(), String> {
+        for path in [partial_path(image), Self::path(image)] {
+            if Path::new(&path).exists() {
+                destination_file::remove(runner, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the journal belongs to a copy of the same device with the same plan.
+    pub fn matches(&self, source: &str, size: u64, extents: &[Extent]) -> bool {
+        self.source == source
+            && self.size == size
+            && self.data_bytes == total_length(extents)
+            && self.confirmed_offset <= size
+    }
+}
+
+/// Records the progress of a copy in the journal, syncing the partial image before each update.
+pub struct JournalWriter<'a> {
+    runner: &'a dyn CommandRunner,
+    image: String,
+    journal: Journal,
+}
+
+impl<'a> JournalWriter<'a> {
+    /// Starts journaling the copy into the partial image of `image`, writing the journal right away.
+    pub fn start(
+        runner: &'a dyn CommandRunner,
+        image: &str,
+        journal: Journal,
+    ) -> Result<JournalWriter<'a>, String> {
+        journal.write(runner, image)?;
+        Ok(JournalWriter {
+            runner,
+            image: image.to_string(),
+            journal,
+        })
+    }
+
+    /// Confirms that the image is written up to `offset` once `JOURNAL_INTERVAL` bytes were written
+    /// since the last update. The partial image is synced first, a failure only delays the confirmation.
+    pub fn update(&mut self, offset: u64) {
+        if offset < self.journal.confirmed_offset + JOURNAL_INTERVAL {
+            return;
+        }
+        let partial = partial_path(&self.image);
+        let synced = File::open(&partial).and_then(|file| file.sync_data());
+        if let Err(e) = synced {
+            debug!(
+                "Failed to sync {}, not confirming {}: {}",
+                partial, offset, e
+            );
+            return;
+        }
+        let journal = Journal {
+            confirmed_offset: offset,
+            ..self.journal.clone()
+        };
+        match journal.write(self.runner, &self.image) {
+            Ok(()) => self.journal = journal,
+            Err(e) => debug!("Failed to write the journal: {}", e),
+        }
+    }
+}
+
+/// Compares the last copied bytes before `confirmed_offset` in the partial image with the device,
+/// to make sure the device wasn't changed and the confirmed bytes reached the disk.
+///
+/// # Arguments
+///
+/// * `device` - The device being copied.
+/// * `partial` - The partial image.
+/// * `extents` - The extents of the device copied into the image.
+/// * `confirmed_offset` - The offset up to which the image is confirmed by the journal.
+///
+/// # Returns
+///
+/// - `Ok(true)`: If the bytes are equal.
+/// - `Ok(false)`: If they differ.
+/// - `Err(String)`: If the device or the partial image is not readable.
+pub fn verify_tail(
+    device: &mut dyn DiskReader,
+    partial: &mut File,
+    extents: &[Extent],
+    confirmed_offset: u64,
+) -> Result<bool, String> {
+    let start = confirmed_offset.saturating_sub(VERIFY_LENGTH);
+    let tail = Extent::new(start, confirmed_offset - start);
+    for extent in extents.iter().filter_map(|extent| extent.clip(&tail)) {
+        let mut expected = vec![0u8; extent.length as usize];
+        let mut written = vec![0u8; extent.length as usize];
+        device
+            .seek(SeekFrom::Start(extent.offset))
+            .and_then(|_| device.read_exact(&mut expected))
+            .map_err(|e| format!("Failed to read the device at {}: {}", extent.offset, e))?;
+        partial
+            .seek(SeekFrom::Start(extent.offset))
+            .and_then(|_| partial.read_exact(&mut written))
+            .map_err(|e| {
+                format!(
+                    "Failed to read the partial image at {}: {}",
+                    extent.offset, e
+                )
+            })?;
+        if expected != written {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::backup_run::command_output::fake::FakeCommandRunner;
+
+    #[test]
+    fn test_journal_and_verify_tail() {
+        let runner = FakeCommandRunner::new();
+        let dir =
+            std::env::temp_dir().join(format!("dd_backup_journal_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let device = dir.join("device");
+        let disk: Vec<u8> = (0..32 * 1024 * 1024).map(|i| (i % 253) as u8).collect();
+        fs::write(&device, &disk).unwrap();
+        let extents = vec![Extent::new(0, 20 * 1024 * 1024)];
+        // the copy was interrupted after 18 MiB, the last MiB didn't reach the disk
+        let mut partial_content = disk[..18 * 1024 * 1024].to_vec();
+        partial_content[17 * 1024 * 1024..].fill(0);
+        fs::write(partial_path(&image), &partial_content).unwrap();
+
+        let mut writer = JournalWriter::start(
+            &runner,
+            &image,
+            Journal {
+                source: device.to_string_lossy().to_string(),
+                size: disk.len() as u64,
+                data_bytes: 20 * 1024 * 1024,
+                confirmed_offset: 0,
+            },
+        )
+        .unwrap();
+        writer.update(1024 * 1024);
+        let not_confirmed = Journal::read(&image).unwrap().unwrap().confirmed_offset;
+        writer.update(JOURNAL_INTERVAL);
+        let journal = Journal::read(&image).unwrap().unwrap();
+
+        let mut device_file = File::open(&device).unwrap();
+        let mut partial = File::open(partial_path(&image)).unwrap();
+        let verified = verify_tail(&mut device_file, &mut partial, &extents, 17 * 1024 * 1024);
+        let unsynced = verify_tail(&mut device_file, &mut partial, &extents, 18 * 1024 * 1024);
+        Journal::discard(&runner, &image).unwrap();
+        let discarded = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(not_confirmed, 0);
+        assert_eq!(journal.confirmed_offset, JOURNAL_INTERVAL);
+        let journal = Journal {
+            confirmed_offset: 17 * 1024 * 1024,
+            ..journal
+        };
+        assert!(journal.matches(&journal.source, disk.len() as u64, &extents));
+        assert!(!journal.matches("/dev/sdb", disk.len() as u64, &extents));
+        assert!(!journal.matches(&journal.source, disk.len() as u64, &[]));
+        assert_eq!(verified, Ok(true));
+        assert_eq!(unsynced, Ok(false));
+        // only the device is left
+        assert_eq!(discarded, 1);
+        // the journal is written and removed with sudo
+        let journal_path = Journal::path(&image);
+        let commands = runner.sudo_commands();
+        assert!(commands[0].starts_with("sudo cp -- "), "{:?}", commands);
+        assert_eq!(
+            commands[1],
+            format!("sudo mv -f -- {}.tmp {}", journal_path, journal_path)
+        );
+        assert!(commands.contains(&format!("sudo rm -f -- {}", journal_path)));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::fd::AsRawFd,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use chrono::Local;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::kill,
+    unistd::{gethostname, Pid},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{command_output::CommandRunner, destination_file, interrupt};
+
+/// The file in the root directory of a destination filesystem locking it against runs on other hosts.
+pub const DESTINATION_LOCK_FILE: &str = ".dd_backup.lock";
+
+/// How long to sleep before trying a held lock again with `--wait`.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The run holding a lock, written into the lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    /// The boot id of the host, which tells a process apart from one with the same PID before a reboot.
+    pub boot_id: String,
+    /// The time the lock was acquired, in RFC 3339.
+    pub since: String,
+}
+
+impl LockOwner {
+    /// Returns the owner of locks acquired by this process.
+    pub fn current() -> LockOwner {
+        LockOwner {
+            pid: std::process::id(),
+            host: gethostname()
+                .map(|host| host.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            boot_id: fs::read_to_string("/proc/sys/kernel/random/boot_id")
+                .map(|boot_id| boot_id.trim().to_string())
+                .unwrap_or_default(),
+            since: Local::now().to_rfc3339(),
+        }
+    }
+
+    /// Returns whether the owner is a process of this host which is gone, like a killed run
+    /// or a run before a reboot. The processes of other hosts can't be checked.
+    fn is_stale(&self) -> bool {
+        let current = Self::current();
+        self.host == current.host
+            && (self.boot_id != current.boot_id
+                || kill(Pid::from_raw(self.pid as i32), None) == Err(Errno::ESRCH))
+    }
+
+    /// Reads the owner from a lock file, `None` if it is empty or not readable, like a file being written.
+    fn read(path: &Path) -> Option<LockOwner> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+
+    fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| e.to_string())
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PID {} on host {} since {}",
+            self.pid, self.host, self.since
+        )
+    }
+}
+
+/// Locks a destination filesystem against other runs on this host, before it is checked and mounted.
+///
+/// The lock file `<uuid>.lock` in the lock directory is locked with `flock`, so that the lock
+/// is released by the kernel when the run ends in any way. The file names the run holding it.
+#[derive(Debug)]
+pub struct HostLock {
+    _file: File,
+}
+
+impl HostLock {
+    /// Acquires the lock of the destination filesystem `uuid`.
+    ///
+    /// # Arguments
+    ///
+    /// * `lock_dir` - The directory of the lock files, created if needed.
+    /// * `uuid` - The UUID of the destination filesystem.
+    /// * `wait` - Waits until the lock is released instead of failing.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(HostLock)`: The lock, released when it is dropped.
+    /// - `Err(String)`: If the lock is held by another run, naming it, or the lock file is not writable.
+    pub fn acquire(lock_dir: &Path, uuid: &str, wait: bool) -> Result<HostLock, String> {
+        fs::create_dir_all(lock_dir).map_err(|e| format!("{}: {}", lock_dir.display(), e))?;
+        let path = lock_dir.join(format!("{}.lock", uuid));
+        let description = format!("Destination filesystem {}", uuid);
+        acquire(&description, wait, || {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                Ok(()) => {
+                    let owner = LockOwner::current().to_json()?;
+                    file.set_len(0)
+                        .and_then(|_| file.write_all(owner.as_bytes()))
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+                    Ok(Attempt::Acquired(HostLock { _file: file }))
+                }
+                Err(Errno::EWOULDBLOCK) => Ok(Attempt::Held(LockOwner::read(&path))),
+                Err(e) => Err(format!("Failed to lock {}: {}", path.display(), e)),
+            }
+        })
+    }
+}
+
+/// Locks a mounted destination filesystem against runs on other hosts, like a live system
+/// and the installed system taking turns with the same drive.
+///
+/// The lock file `DESTINATION_LOCK_FILE` is created exclusively and removed when the lock is dropped,
+/// both through the command runner with sudo, since the root directory of the filesystem is usually
+/// owned by root. A lock file left by a run of this host which is gone is stale and replaced.
+#[derive(Debug)]
+pub struct DestinationLock {
+    path: String,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl DestinationLock {
+    /// Acquires the lock of the destination filesystem mounted at `mountpoint`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(DestinationLock)`: The lock, released when it is dropped.
+    /// - `Err(String)`: If the lock is held by another run, naming it, or the lock file is not writable.
+    pub fn acquire(
+        runner: Arc<dyn CommandRunner>,
+        mountpoint: &str,
+        wait: bool,
+    ) -> Result<DestinationLock, String> {
+        let path = Path::new(mountpoint)
+            .join(DESTINATION_LOCK_FILE)
+            .to_string_lossy()
+            .to_string();
+        let description = format!("Destination filesystem at {}", mountpoint);
+        acquire(&description, wait, || {
+            let owner = LockOwner::current().to_json()?;
+            match destination_file::create_new(runner.as_ref(), &path, owner.as_bytes())? {
+                true => Ok(Attempt::Acquired(DestinationLock {
+                    path: path.clone(),
+                    runner: runner.clone(),
+                })),
+                false => match LockOwner::read(Path::new(&path)) {
+                    Some(owner) if owner.is_stale() => {
+                        warn!("Removing the stale lock {} of {}", path, owner);
+                        destination_file::remove(runner.as_ref(), &path)?;
+                        Ok(Attempt::Retry)
+                    }
+                    owner => Ok(Attempt::Held(owner)),
+                },
+            }
+        })
+    }
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        if let Err(e) = destination_file::remove(self.runner.as_ref(), &self.path) {
+            warn!("Failed to remove the lock {}: {}", self.path, e);
+        }
+    }
+}
+
+/// The outcome of an attempt to acquire a lock.
+enum Attempt<T> {
+    Acquired(T),
+    /// The lock is held by the owner, which is unknown while its lock file is being written.
+    Held(Option<LockOwner>),
+    /// A stale lock was removed, the lock is tried again right away.
+    Retry,
+}
+
+/// Calls `try_acquire` until it returns the lock, sleeping in between if `wait` is set.
+fn acquire<T>(
+    description: &str,
+    wait: bool,
+    mut try_acquire: impl FnMut() -> Result<Attempt<T>, String>,
+) -> Result<T, String> {
+    let mut waiting = false;
+    loop {
+        let owner = match try_acquire()? {
+            Attempt::Acquired(lock) => return Ok(lock),
+            Attempt::Held(owner) => owner,
+            Attempt::Retry => continue,
+        };
+        let owner = owner.map_or("another run".to_string(), |owner| owner.to_string());
+        match (wait, waiting) {
+            (false, _) => {
+                return Err(format!(
+                    "{} is locked by {}, use --wait to wait for it",
+                    description, owner
+                ))
+            }
+            (true, false) => {
+                info!("{} is locked by {}, waiting for it", description, owner);
+                waiting = true;
+            }
+            (true, true) => {}
+        }
+        interrupt::check()?;
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process::Command};
+
+    use super::*;
+    use crate::run::backup_run::command_output::fake::FakeCommandRunner;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "dd_backup_lock_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_host_lock() {
+        let dir = lock_dir("host");
+
+        let lock = HostLock::acquire(&dir, "DST-UUID", false).unwrap();
+        let held = HostLock::acquire(&dir, "DST-UUID", false).unwrap_err();
+        let other = HostLock::acquire(&dir, "OTHER-UUID", false);
+        drop(lock);
+        let released = HostLock::acquire(&dir, "DST-UUID", false);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            held.contains(&format!("locked by PID {} on host", std::process::id())),
+            "{}",
+            held
+        );
+        assert!(other.is_ok());
+        assert!(released.is_ok());
+    }
+
+    #[test]
+    fn test_destination_lock() {
+        let dir = lock_dir("destination");
+        let mountpoint = dir.to_string_lossy().to_string();
+        let path = dir.join(DESTINATION_LOCK_FILE);
+        let runner = Arc::new(FakeCommandRunner::new());
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+
+        let lock = DestinationLock::acquire(runner.clone(), &mountpoint, false).unwrap();
+        let held = DestinationLock::acquire(runner.clone(), &mountpoint, false).unwrap_err();
+        drop(lock);
+        let released = !path.exists();
+        // a run of this host which was killed
+        let killed = LockOwner {
+            pid: exited.id(),
+            ..LockOwner::current()
+        };
+        fs::write(&path, killed.to_json().unwrap()).unwrap();
+        let replaced = DestinationLock::acquire(runner.clone(), &mountpoint, false).map(|lock| {
+            let owner = LockOwner::read(Path::new(&lock.path));
+            drop(lock);
+            owner
+        });
+        // a run of another host can't be checked
+        let other_host = LockOwner {
+            host: "otherhost".to_string(),
+            ..killed
+        };
+        fs::write(&path, other_host.to_json().unwrap()).unwrap();
+        let foreign = DestinationLock::acquire(runner.clone(), &mountpoint, false).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(held.contains("locked by PID"), "{}", held);
+        assert!(released);
+        assert_eq!(replaced.unwrap().unwrap().pid, std::process::id());
+        assert!(foreign.contains("on host otherhost"), "{}", foreign);
+        // the lock file is created and removed with sudo
+        let path = path.to_string_lossy();
+        let commands = runner.sudo_commands();
+        assert!(
+            commands[0].starts_with("sudo sh -c set -C;"),
+            "{:?}",
+            commands
+        );
+        assert!(
+            commands[0].ends_with(&format!(" {}", path)),
+            "{:?}",
+            commands
+        );
+        assert!(commands.contains(&format!("sudo rm -f -- {}", path)));
+    }
+}
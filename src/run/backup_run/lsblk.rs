@@ -1,9 +1,13 @@
-use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json;
 
-use super::command_output::command_output;
+use crate::run::utils::convert_to_byte_size;
+
+use super::{command_output::CommandRunner, sysfs::Sysfs};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct BlockDevice {
     /// The name of the block device.
     pub name: String,
@@ -15,10 +19,94 @@ pub struct BlockDevice {
     pub uuid: Option<String>,
     /// The mount point of the block device.
     pub mountpoint: Option<String>,
-    /// The size of the block device.
-    pub size: String,
-    // The available size of the block device
-    pub fsavail: Option<String>,
+    /// The size of the block device in bytes.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub size: u64,
+    /// The available space of the filesystem in bytes, only known if mounted.
+    #[serde(default, deserialize_with = "deserialize_optional_size")]
+    pub fsavail: Option<u64>,
+    /// The world wide name of the block device.
+    #[serde(default)]
+    pub wwn: Option<String>,
+    /// The name of the parent device, like `sda` for the partition `sda1`.
+    #[serde(default)]
+    pub pkname: Option<String>,
+    /// The type of the block device, like `disk`, `part` or `loop`.
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
+    /// The UUID of the partition table entry.
+    #[serde(default)]
+    pub partuuid: Option<String>,
+    /// The label of the filesystem.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The type of the filesystem, like `ext4` or `swap`.
+    #[serde(default)]
+    pub fstype: Option<String>,
+    /// The name of the partition table entry.
+    #[serde(default)]
+    pub partlabel: Option<String>,
+    /// The number of the partition, like `2` for `sda2`.
+    #[serde(default)]
+    pub partn: Option<u32>,
+    /// The partition type, a GUID for GPT or a hex byte like `0x82` for MBR.
+    #[serde(default)]
+    pub parttype: Option<String>,
+}
+
+impl BlockDevice {
+    /// Returns whether the block device is a partition.
+    pub fn is_partition(&self) -> bool {
+        self.kind.as_deref() == Some("part")
+    }
+
+    /// Derives the partition number from the trailing digits of the name, like `2` for `nvme0n1p2`.
+    fn partition_number_from_name(&self) -> Option<u32> {
+        let digits = self.name.len()
+            - self
+                .name
+                .trim_end_matches(|c: char| c.is_ascii_digit())
+                .len();
+        self.name[self.name.len() - digits..].parse().ok()
+    }
+}
+
+/// A size as reported by `lsblk`, either as number of bytes or as string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LsblkSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl LsblkSize {
+    /// Returns the size in bytes, a string is either a plain number of bytes (`lsblk -b` of older versions)
+    /// or a human readable size like `100G`.
+    fn bytes(self) -> Result<Option<u64>, String> {
+        match self {
+            LsblkSize::Bytes(bytes) => Ok(Some(bytes)),
+            LsblkSize::Text(text) => match text.trim().parse::<u64>() {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(_) => convert_to_byte_size(&text),
+            },
+        }
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    LsblkSize::deserialize(deserializer)?
+        .bytes()
+        .map_err(serde::de::Error::custom)?
+        .ok_or_else(|| serde::de::Error::custom("unknown size unit"))
+}
+
+fn deserialize_optional_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    match Option::<LsblkSize>::deserialize(deserializer)? {
+        Some(size) => size.bytes().map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,15 +126,59 @@ pub struct Lsblk {
 impl Lsblk {
     /// Creates a new instance of `Lsblk`.
     ///
+    /// The block devices are read from sysfs and the udev database, which gives exact byte sizes.
+    /// If sysfs is not available or can't be read, the `lsblk` command is used as fallback.
+    ///
+    /// # Arguments
+    ///
+    /// * `runner` - The runner executing the `lsblk` command for the fallback.
+    ///
+    /// Returns:
+    /// - `Ok(Lsblk)`: If the block devices were read from sysfs or `lsblk`.
+    /// - `Err(String)`: If both sysfs and `lsblk` failed.
+    pub fn new(runner: &dyn CommandRunner) -> Result<Lsblk, String> {
+        match Self::from_sysfs(Path::new("/")) {
+            Ok(lsblk) => Ok(lsblk),
+            Err(e) => {
+                warn!("{}, falling back to lsblk", e);
+                Self::from_lsblk(runner)
+            }
+        }
+    }
+
+    /// Creates a new instance of `Lsblk` from sysfs and the udev database below `root`.
+    pub fn from_sysfs(root: &Path) -> Result<Lsblk, String> {
+        let blockdevices = Sysfs::new(root)
+            .block_devices()
+            .map_err(|e| format!("Failed to read block devices from sysfs: {}", e))?;
+        Ok(Self::from_output(LsblkOutput { blockdevices }))
+    }
+
+    /// Creates a new instance of `Lsblk` from the output of the `lsblk` command.
+    ///
     /// It captures the output of the `lsblk` command, filters and stores the available devices
     /// and available filesystems.
     ///
+    /// # Arguments
+    ///
+    /// * `runner` - The runner executing the `lsblk` command.
+    ///
     /// Returns:
     /// - `Ok(Lsblk)`: If the `lsblk` command was successful and the output was parsed correctly.
     /// - `Err(String)`: If there was an error executing or parsing the `lsblk` command.
-    pub fn new() -> Result<Lsblk, String> {
-        let lsblk_output =
-            Self::capture_lsblk().map_err(|e| format!("Failed to read JSON from lsblk: {}", e))?;
+    pub fn from_lsblk(runner: &dyn CommandRunner) -> Result<Lsblk, String> {
+        let lsblk_output = Self::capture_lsblk(runner)
+            .map_err(|e| format!("Failed to read JSON from lsblk: {}", e))?;
+        Ok(Self::from_output(lsblk_output))
+    }
+
+    fn from_output(mut lsblk_output: LsblkOutput) -> Lsblk {
+        // `lsblk` doesn't report partition numbers before util-linux 2.39
+        for blockdevice in lsblk_output.blockdevices.iter_mut() {
+            if blockdevice.is_partition() && blockdevice.partn.is_none() {
+                blockdevice.partn = blockdevice.partition_number_from_name();
+            }
+        }
 
         let available_devices = Self::available_devices(&lsblk_output);
         let available_filesystems = Self::available_filesystems(&lsblk_output);
@@ -56,17 +188,13 @@ impl Lsblk {
             available_filesystems,
         };
         debug!("{:?}", lsblk);
-        Ok(lsblk)
+        lsblk
     }
 
-    /// Filters and returns the available devices from the lsblk output.
+    /// Returns the available devices from the lsblk output.
+    /// All devices are kept, since devices without serial can be identified otherwise.
     fn available_devices(lsblk_output: &LsblkOutput) -> Vec<BlockDevice> {
-        lsblk_output
-            .blockdevices
-            .iter()
-            .filter(|a| a.serial.is_some())
-            .cloned()
-            .collect()
+        lsblk_output.blockdevices.clone()
     }
 
     /// Filters and returns the available filesystems from the lsblk output.
@@ -84,13 +212,13 @@ impl Lsblk {
     /// Returns:
     /// - `Ok(LsblkOutput)`: If the lsblk command was successful and the JSON output was parsed correctly.
     /// - `Err(String)`: If there was an error executing or parsing the lsblk command.
-    fn capture_lsblk() -> Result<LsblkOutput, String> {
-        let output = command_output(
+    fn capture_lsblk(runner: &dyn CommandRunner) -> Result<LsblkOutput, String> {
+        let output = runner.output(
             vec![
                 "lsblk",
-                "-lJ",
+                "-lJb",
                 "-o",
-                "NAME,MODEL,SERIAL,SIZE,MOUNTPOINT,UUID,FSAVAIL",
+                "NAME,MODEL,SERIAL,SIZE,MOUNTPOINT,UUID,FSAVAIL,WWN,PKNAME,TYPE,PARTUUID,LABEL,FSTYPE,PARTLABEL,PARTTYPE",
             ],
             "execute lsblk",
             Some(false),
@@ -107,3 +235,56 @@ impl Lsblk {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_sizes() {
+        let lsblk_output: LsblkOutput = serde_json::from_str(
+            r#"{"blockdevices": [
+                {"name": "sda", "model": null, "serial": "s", "uuid": null, "mountpoint": null,
+                 "size": 256060514304, "fsavail": null},
+                {"name": "sdb", "model": null, "serial": null, "uuid": "u", "mountpoint": "/mnt",
+                 "size": "1000204886016", "fsavail": "4096", "type": "part", "pkname": "sdc"},
+                {"name": "sdc", "model": null, "serial": "t", "uuid": null, "mountpoint": null,
+                 "size": "1.5G", "fsavail": null}
+            ]}"#,
+        )
+        .unwrap();
+        let devices = lsblk_output.blockdevices;
+
+        assert_eq!(devices[0].size, 256060514304);
+        assert_eq!(devices[0].fsavail, None);
+        assert_eq!(devices[1].size, 1000204886016);
+        assert_eq!(devices[1].fsavail, Some(4096));
+        assert_eq!(devices[1].kind.as_deref(), Some("part"));
+        assert_eq!(devices[1].pkname.as_deref(), Some("sdc"));
+        assert_eq!(devices[2].size, 1610612736);
+    }
+
+    #[test]
+    fn test_partition_number_from_name() {
+        let lsblk = Lsblk::from_output(
+            serde_json::from_str(
+                r#"{"blockdevices": [
+                    {"name": "nvme0n1", "size": 1, "model": null, "serial": null, "uuid": null,
+                     "mountpoint": null, "type": "disk"},
+                    {"name": "nvme0n1p12", "size": 1, "model": null, "serial": null, "uuid": null,
+                     "mountpoint": null, "type": "part", "pkname": "nvme0n1"},
+                    {"name": "sdb3", "size": 1, "model": null, "serial": null, "uuid": null,
+                     "mountpoint": null, "type": "part", "pkname": "sdb"}
+                ]}"#,
+            )
+            .unwrap(),
+        );
+        let partn: Vec<Option<u32>> = lsblk
+            .available_devices
+            .iter()
+            .map(|device| device.partn)
+            .collect();
+
+        assert_eq!(partn, vec![None, Some(12), Some(3)]);
+    }
+}
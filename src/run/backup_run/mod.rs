@@ -1,15 +1,43 @@
 mod backup;
 mod backups;
-mod command_output;
-mod device;
+pub mod command_output;
+mod destination_file;
+pub mod device;
+#[cfg(test)]
+mod e2e_tests;
+mod executor;
+mod fan_out;
 mod filesystem;
-mod lsblk;
+mod history;
+pub mod interrupt;
+mod journal;
+mod lock;
+pub mod lsblk;
+mod progress;
+mod quarantine;
+mod rescue;
+mod sysfs;
+mod tee;
+mod throttle;
+mod used_blocks;
+
+use std::{path::PathBuf, sync::Arc};
 
 use super::backup_run::backups::Backups;
+use super::backup_run::command_output::{CommandRunner, SystemCommandRunner};
+use super::backup_run::fan_out::FanOut;
+use super::backup_run::history::{History, HistoryRecorder};
 use super::backup_run::lsblk::Lsblk;
-use super::config::{BackupDevice, Config};
+use super::backup_run::progress::{
+    LogFileProgress, Progress, ProgressEvent, RunStatus, StatusSocket, TerminalProgress,
+};
+use super::config::{
+    BackupDevice, Config, CopyOptions, DeviceIdentifier, ImageFormat, IoPriority, PartitionSelector,
+};
 use crate::run::config::BackupConfig;
+use crate::run::utils::next_time_of_day;
 
+use chrono::Local;
 use clap::Args;
 
 #[derive(Args, Debug)]
@@ -29,6 +57,35 @@ pub struct BackupArgs {
     #[clap(short, long)]
     /// The mount path of the destination filesystem, overwrites config value.
     pub mountpath: Option<String>,
+
+    #[clap(long)]
+    /// Appends progress events as JSON lines to the given file.
+    pub progress_log: Option<String>,
+
+    #[clap(long)]
+    /// Serves progress events as JSON lines on a unix socket at the given path.
+    pub status_socket: Option<String>,
+
+    #[clap(long, default_value = "false")]
+    /// Waits for destination filesystems locked by another run instead of skipping them.
+    pub wait: bool,
+
+    #[clap(short = 'j', long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    /// The maximum number of destination filesystems backed up at once, overwrites config value.
+    pub jobs: Option<usize>,
+
+    #[clap(long, default_value = "false")]
+    /// Reads a device configured on several connected destinations once for all of them, overwrites config value.
+    pub fan_out: bool,
+
+    #[clap(long)]
+    /// The time of day as HH:MM after which no further backups are started, overwrites config value.
+    pub start_before: Option<String>,
+
+    #[clap(long)]
+    /// The time of day as HH:MM by which the backups should be finished, warns if they are estimated
+    /// to take longer, overwrites config value.
+    pub finish_before: Option<String>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -48,14 +105,34 @@ pub struct SingleBackupArgs {
     /// The serial number of the source device to be backed up, single-back-up-only.
     pub source_serial: Option<String>,
 
+    #[clap(long, conflicts_with = "source_serial")]
+    /// Another identifier of the source device as <KIND>=<VALUE>, KIND is one of
+    /// serial, wwn, by_id, by_path, partuuid, fs_uuid or path, single-back-up-only.
+    pub source_id: Option<DeviceIdentifier>,
+
     #[clap(long, default_value = "./")]
     /// The destination path where the backup will be stored, single-back-up-only.
     pub destination_path: Option<String>,
 
+    #[clap(long = "partition")]
+    /// Backs up the selected partitions instead of the whole device, as <KIND>=<VALUE>,
+    /// KIND is one of number, partuuid, label, fstype or type, repeatable, single-back-up-only.
+    pub partitions: Vec<PartitionSelector>,
+
+    #[clap(long = "exclude-partition")]
+    /// Leaves the selected partitions out of the image of the whole device, as <KIND>=<VALUE>
+    /// like `--partition`, repeatable, single-back-up-only.
+    pub exclude_partitions: Vec<PartitionSelector>,
+
     #[clap(long, default_value = None)]
     /// The number of backup copies to maintain, single-back-up-only.
     pub copies: Option<usize>,
 
+    #[clap(long)]
+    /// Deletes the oldest copy before the backup if the destination is short of space for it,
+    /// single-back-up-only.
+    pub delete_oldest_when_short: bool,
+
     #[clap(long)]
     /// The name of the backup, single-back-up-only.
     pub name: Option<String>,
@@ -71,6 +148,37 @@ pub struct SingleBackupArgs {
     #[clap(long)]
     /// Flag to skip mounting, single-back-up-only.
     pub skip_mount: bool,
+
+    #[clap(long)]
+    /// Copies only the blocks used by ext2/3/4, XFS, FAT and NTFS filesystems into a sparse image,
+    /// single-back-up-only.
+    pub used_blocks_only: bool,
+
+    #[clap(long)]
+    /// Copies the device only up to the end of its last partition, single-back-up-only.
+    pub trim_to_last_partition: bool,
+
+    #[clap(long)]
+    /// The format of the image, one of raw, qcow2 or vmdk, single-back-up-only.
+    pub output_format: Option<ImageFormat>,
+
+    #[clap(long)]
+    /// Copies around read errors of a failing device, filling unreadable blocks with a marker,
+    /// single-back-up-only.
+    pub rescue: bool,
+
+    #[clap(long)]
+    /// The I/O scheduling class and level the device is read with, as <CLASS>=<LEVEL> or idle,
+    /// CLASS is one of realtime or best_effort, LEVEL from 0 to 7, single-back-up-only.
+    pub io_priority: Option<IoPriority>,
+
+    #[clap(long, allow_negative_numbers = true)]
+    /// The nice level from -20 to 19 the copy runs with, single-back-up-only.
+    pub nice: Option<i32>,
+
+    #[clap(long)]
+    /// The maximum number of MiB read from the device per second, single-back-up-only.
+    pub bandwidth_limit: Option<u64>,
 }
 
 /// Runs the backup process based on the provided command-line arguments.
@@ -88,16 +196,119 @@ pub struct SingleBackupArgs {
 /// An `Ok` variant if the backup process completes successfully, or an `Err` variant with an error message as `String`
 /// if an error occurs during the backup process.
 pub fn run(backup_args: &BackupArgs) -> Result<(), String> {
+    interrupt::install()?;
+    run_with_runner(backup_args, Arc::new(SystemCommandRunner), Lsblk::new, None)
+}
+
+/// Runs the backup process like `run`, executing all external commands with `runner`
+/// and reading the block devices with `read_block_devices`. The history of past copies is read from
+/// and recorded into `history_path`, `history.json` in the config home by default.
+fn run_with_runner(
+    backup_args: &BackupArgs,
+    runner: Arc<dyn CommandRunner>,
+    read_block_devices: fn(&dyn CommandRunner) -> Result<Lsblk, String>,
+    history_path: Option<PathBuf>,
+) -> Result<(), String> {
     let config = backup_args_to_config(backup_args)?;
-    let lsblk = Lsblk::new()?;
+    let lsblk = read_block_devices(runner.as_ref())?;
+    let progress = progress(backup_args)?;
+
+    let result = run_backups(
+        &config,
+        &lsblk,
+        backup_args,
+        &progress,
+        runner,
+        history_path,
+    );
+    let status = match &result {
+        _ if interrupt::is_interrupted() => RunStatus::Interrupted,
+        Ok(()) => RunStatus::Completed,
+        Err(_) => RunStatus::Failed,
+    };
+    progress.emit(ProgressEvent::RunFinished { status });
+    result
+}
 
+/// Runs the backups of all configured destinations, stopping once SIGINT or SIGTERM was received.
+///
+/// Backups to different destinations run concurrently, up to `--jobs` or `max_parallel` at once,
+/// see `Backups::job` for the backups that are never run at the same time. The backup of a disk holding
+/// another destination runs after the backups to it, a cycle of such backups fails the run, see `order_jobs`.
+/// With `--fan-out` or `fan_out`, destinations sharing a source device are backed up in one job,
+/// reading the device once for all of them, see `FanOut`.
+/// The durations of the backups are estimated from past runs before they start and the throughput
+/// of every copy is recorded for the next runs, see `History`.
+fn run_backups(
+    config: &Config,
+    lsblk: &Lsblk,
+    backup_args: &BackupArgs,
+    progress: &Progress,
+    runner: Arc<dyn CommandRunner>,
+    history_path: Option<PathBuf>,
+) -> Result<(), String> {
+    interrupt::check()?;
+    let mut all_backups = Vec::new();
     for backup_config in &config.backups {
-        if let Some(backups) = Backups::new(backup_config, &lsblk, backup_args, &config)? {
-            backups.run()?;
+        if let Some(backups) = Backups::new(
+            backup_config,
+            lsblk,
+            backup_args,
+            config,
+            progress,
+            Arc::clone(&runner),
+        )? {
+            all_backups.push(backups);
+        }
+    }
+
+    let max_parallel = backup_args.jobs.or(config.max_parallel).unwrap_or(1);
+    let finish_deadline = backup_args
+        .finish_before
+        .as_ref()
+        .or(config.finish_before.as_ref())
+        .map(|finish_before| next_time_of_day(finish_before, Local::now()))
+        .transpose()?;
+    let history_path = history_path.map_or_else(History::default_path, Ok);
+    match history_path.and_then(|path| Ok((History::read(&path)?, path))) {
+        Ok((history, path)) => {
+            history::log_estimates(&all_backups, &history, max_parallel, finish_deadline);
+            progress.subscribe(Box::new(HistoryRecorder::new(path, &all_backups)));
         }
+        Err(e) => warn!("Failed to read the backup history, no estimates: {}", e),
     }
 
-    Ok(())
+    match backup_args.fan_out || config.fan_out.unwrap_or(false) {
+        true => {
+            let jobs = FanOut::plan(all_backups)
+                .into_iter()
+                .map(FanOut::job)
+                .collect();
+            executor::run_jobs(jobs, max_parallel, FanOut::run)
+        }
+        false => {
+            let jobs = all_backups.into_iter().map(Backups::job).collect();
+            executor::run_jobs(jobs, max_parallel, Backups::run)
+        }
+    }
+}
+
+/// Creates the `Progress` dispatcher with the observers requested by the command-line arguments.
+///
+/// A progress bar is rendered if stderr is a terminal, events are appended to
+/// `--progress-log` and served on `--status-socket` if given.
+fn progress(backup_args: &BackupArgs) -> Result<Progress, String> {
+    let progress = Progress::new();
+    if TerminalProgress::is_available() {
+        progress.subscribe(Box::new(TerminalProgress::new()));
+    }
+    if let Some(progress_log) = &backup_args.progress_log {
+        progress.subscribe(Box::new(LogFileProgress::new(progress_log)?));
+    }
+    if let Some(status_socket) = &backup_args.status_socket {
+        StatusSocket::serve(status_socket, &progress)?;
+    }
+    Ok(progress)
 }
 
 /// Converts `BackupArgs` into a `Config` object.
@@ -120,20 +331,52 @@ fn backup_args_to_config(backup_args: &BackupArgs) -> Result<Config, String> {
         Some(file_config_args) => Config::new(&file_config_args.config_file_path),
         None => match &backup_args.single_backup_args {
             Some(single_backup_args) => {
-                let source_serial = single_backup_args.source_serial.clone().ok_or(
-                    "Source serial needs to be provided in single backup mode, like: `--source-serial x...x`",
-                )?;
+                let identifier = match &single_backup_args.source_id {
+                    Some(source_id) => source_id.clone(),
+                    None => DeviceIdentifier::Serial(single_backup_args.source_serial.clone().ok_or(
+                        "Source serial needs to be provided in single backup mode, like: `--source-serial x...x`",
+                    )?),
+                };
                 let destination_uuid = single_backup_args.destination_uuid.clone().ok_or(
                     "Destination UUID needs to be provided in single backup mode, like: `--destination-uuid x...x`",
                 )?;
 
                 let config = Config {
-                    mountpath: Some(backup_args.mountpath.clone().unwrap_or("/mnt".to_string())),
+                    mountpath: backup_args.mountpath.clone(),
+                    max_parallel: None,
+                    fan_out: None,
+                    start_before: None,
+                    finish_before: None,
                     backups: vec![BackupConfig {
                         backup_devices: vec![BackupDevice {
-                            serial: source_serial,
+                            identifier,
                             name: single_backup_args.name.clone(),
                             copies: single_backup_args.copies,
+                            delete_oldest_when_short: Some(
+                                single_backup_args.delete_oldest_when_short,
+                            ),
+                            partitions: match single_backup_args.partitions.is_empty() {
+                                true => None,
+                                false => Some(single_backup_args.partitions.clone()),
+                            },
+                            options: CopyOptions {
+                                used_blocks_only: Some(single_backup_args.used_blocks_only),
+                                trim_to_last_partition: Some(
+                                    single_backup_args.trim_to_last_partition,
+                                ),
+                                exclude_partitions: match single_backup_args
+                                    .exclude_partitions
+                                    .is_empty()
+                                {
+                                    true => None,
+                                    false => Some(single_backup_args.exclude_partitions.clone()),
+                                },
+                                output_format: single_backup_args.output_format,
+                                rescue: Some(single_backup_args.rescue),
+                                io_priority: single_backup_args.io_priority,
+                                nice: single_backup_args.nice,
+                                bandwidth_limit: single_backup_args.bandwidth_limit,
+                            },
                         }],
                         uuid: destination_uuid,
                         destination_path: single_backup_args.destination_path.clone(),
@@ -154,32 +397,63 @@ fn backup_args_to_config(backup_args: &BackupArgs) -> Result<Config, String> {
 
 #[cfg(test)]
 mod tests {
+    use crate::run::backup_run::command_output::fake::{FakeCommandRunner, FakeResponse};
     use crate::run::backup_run::{FileConfigArgs, SingleBackupArgs};
 
     use super::*;
 
+    fn runner() -> Arc<FakeCommandRunner> {
+        Arc::new(
+            FakeCommandRunner::new().script("lsblk", FakeResponse::ok(r#"{"blockdevices": []}"#)),
+        )
+    }
+
     #[test]
     fn test_run() {
+        let history_path =
+            std::env::temp_dir().join(format!("dd_backup_run_history_{}.json", std::process::id()));
         let valid_single_backup_args = SingleBackupArgs {
             destination_uuid: Some("some-uuid-which-does-not-exist".to_string()),
             destination_path: None,
             source_serial: Some("some-source-serial-which-does-not-exist".to_string()),
+            source_id: None,
+            partitions: vec![],
+            exclude_partitions: vec![],
             copies: None,
+            delete_oldest_when_short: false,
             name: None,
             fsck_command: "fsck -n".to_string(),
             skip_fsck: false,
             skip_mount: false,
+            used_blocks_only: false,
+            trim_to_last_partition: false,
+            output_format: None,
+            rescue: false,
+            io_priority: None,
+            nice: None,
+            bandwidth_limit: None,
         };
 
         let invalid_single_backup_args = SingleBackupArgs {
             destination_uuid: None,
             destination_path: None,
             source_serial: None,
+            source_id: None,
+            partitions: vec![],
+            exclude_partitions: vec![],
             copies: None,
+            delete_oldest_when_short: false,
             name: None,
             fsck_command: "fsck -n".to_string(),
             skip_fsck: false,
             skip_mount: false,
+            used_blocks_only: false,
+            trim_to_last_partition: false,
+            output_format: None,
+            rescue: false,
+            io_priority: None,
+            nice: None,
+            bandwidth_limit: None,
         };
         // Test when the command is `Run` and backup_run returns Ok(())
         let backup_args = BackupArgs {
@@ -187,8 +461,20 @@ mod tests {
             file_config_args: None,
             single_backup_args: Some(valid_single_backup_args),
             mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
         };
-        let result = run(&backup_args);
+        let result = run_with_runner(
+            &backup_args,
+            runner(),
+            Lsblk::from_lsblk,
+            Some(history_path.clone()),
+        );
         assert_eq!(result, Ok(()));
 
         // Test when config is not found
@@ -199,8 +485,20 @@ mod tests {
             }),
             single_backup_args: Some(invalid_single_backup_args.clone()),
             mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
         };
-        let result = run(&backup_args);
+        let result = run_with_runner(
+            &backup_args,
+            runner(),
+            Lsblk::from_lsblk,
+            Some(history_path.clone()),
+        );
         assert_eq!(
             result,
             Err("Failed to create Config struct object: No such file or directory (os error 2): /does/not/exist.json".to_string())
@@ -212,8 +510,20 @@ mod tests {
             file_config_args: None,
             single_backup_args: Some(invalid_single_backup_args),
             mountpath: None,
+            progress_log: None,
+            status_socket: None,
+            wait: false,
+            jobs: None,
+            fan_out: false,
+            start_before: None,
+            finish_before: None,
         };
-        let result = run(&backup_args);
+        let result = run_with_runner(
+            &backup_args,
+            runner(),
+            Lsblk::from_lsblk,
+            Some(history_path.clone()),
+        );
         assert_eq!(
             result,
             Err("Source serial needs to be provided in single backup mode, like: `--source-serial x...x`".to_string())
@@ -0,0 +1,454 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// The phases a backup passes through, reported with `ProgressEvent::PhaseChanged`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Fsck,
+    Mount,
+    Copy,
+    Unmount,
+}
+
+/// How a run of all configured backups ended, reported with `ProgressEvent::RunFinished`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// All destinations were processed, failed backups of single devices are logged only.
+    Completed,
+    /// The run was aborted by an error.
+    Failed,
+    /// The run was stopped by SIGINT or SIGTERM.
+    Interrupted,
+}
+
+/// A typed progress event emitted while backups are running.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A copy of `source` to `destination` has started.
+    Started {
+        source: String,
+        destination: String,
+        total_bytes: Option<u64>,
+    },
+    /// The backup of `target` entered a new phase.
+    PhaseChanged { target: String, phase: Phase },
+    /// Bytes copied so far, with the average rate in bytes per second and the estimated remaining time.
+    BytesCopied {
+        source: String,
+        bytes: u64,
+        total_bytes: Option<u64>,
+        rate: f64,
+        eta: Option<Duration>,
+    },
+    /// The copy of `source` to `destination` has finished, successfully or not.
+    Finished {
+        source: String,
+        destination: String,
+        bytes: u64,
+        elapsed: Duration,
+        success: bool,
+    },
+    /// The run of all configured backups has ended, the last event of a run.
+    RunFinished { status: RunStatus },
+}
+
+/// A consumer of progress events, like a progress bar, a log file or a status socket.
+pub trait ProgressObserver: Send {
+    /// Handles a single event, called in the order the events are emitted.
+    fn on_event(&mut self, event: &ProgressEvent);
+}
+
+/// Dispatches progress events to all subscribed observers.
+#[derive(Default)]
+pub struct Progress {
+    observers: Mutex<Vec<Box<dyn ProgressObserver>>>,
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let observers = self.observers.lock().map(|o| o.len()).unwrap_or(0);
+        f.debug_struct("Progress")
+            .field("observers", &observers)
+            .finish()
+    }
+}
+
+impl Progress {
+    /// Creates a new `Progress` instance without any observers.
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Subscribes an observer, which will receive all events emitted from now on.
+    pub fn subscribe(&self, observer: Box<dyn ProgressObserver>) {
+        if let Ok(mut observers) = self.observers.lock() {
+            observers.push(observer);
+        }
+    }
+
+    /// Subscribes a channel and returns its receiving end.
+    /// Events are dropped silently once the receiver is gone.
+    pub fn channel(&self) -> Receiver<ProgressEvent> {
+        let (sender, receiver) = channel();
+        self.subscribe(Box::new(ChannelProgress { sender }));
+        receiver
+    }
+
+    /// Emits an event to all observers.
+    pub fn emit(&self, event: ProgressEvent) {
+        trace!("{:?}", event);
+        if let Ok(mut observers) = self.observers.lock() {
+            for observer in observers.iter_mut() {
+                observer.on_event(&event);
+            }
+        }
+    }
+
+    /// Emits a `ProgressEvent::PhaseChanged` event.
+    pub fn phase(&self, target: &str, phase: Phase) {
+        self.emit(ProgressEvent::PhaseChanged {
+            target: target.to_string(),
+            phase,
+        });
+    }
+}
+
+/// Tracks the bytes copied by one copy and turns them into `BytesCopied` events with rate and ETA.
+pub struct CopyTracker<'a> {
+    progress: &'a Progress,
+    source: String,
+    destination: String,
+    total_bytes: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl<'a> CopyTracker<'a> {
+    /// Emits `ProgressEvent::Started` and returns a tracker for the copy.
+    pub fn start(
+        progress: &'a Progress,
+        source: &str,
+        destination: &str,
+        total_bytes: Option<u64>,
+    ) -> CopyTracker<'a> {
+        progress.emit(ProgressEvent::Started {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            total_bytes,
+        });
+        CopyTracker {
+            progress,
+            source: source.to_string(),
+            destination: destination.to_string(),
+            total_bytes,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Records the total number of bytes copied so far and emits `ProgressEvent::BytesCopied`.
+    pub fn update(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = match self.total_bytes {
+            Some(total) if rate > 0.0 => Some(Duration::from_secs_f64(
+                total.saturating_sub(bytes) as f64 / rate,
+            )),
+            _ => None,
+        };
+        self.progress.emit(ProgressEvent::BytesCopied {
+            source: self.source.clone(),
+            bytes,
+            total_bytes: self.total_bytes,
+            rate,
+            eta,
+        });
+    }
+
+    /// Emits `ProgressEvent::Finished` and returns the elapsed time.
+    pub fn finish(self, success: bool) -> Duration {
+        let elapsed = self.started.elapsed();
+        self.progress.emit(ProgressEvent::Finished {
+            source: self.source,
+            destination: self.destination,
+            bytes: self.bytes,
+            elapsed,
+            success,
+        });
+        elapsed
+    }
+}
+
+/// Parses the number of copied bytes from a `dd status=progress` line,
+/// like `1073741824 bytes (1.1 GB, 1.0 GiB) copied, 5 s, 215 MB/s`.
+pub fn parse_dd_progress(line: &str) -> Option<u64> {
+    let (bytes, rest) = line.trim().split_once(' ')?;
+    if rest.starts_with("bytes") {
+        bytes.parse::<u64>().ok()
+    } else {
+        None
+    }
+}
+
+/// Forwards events into an `mpsc` channel.
+struct ChannelProgress {
+    sender: Sender<ProgressEvent>,
+}
+
+impl ProgressObserver for ChannelProgress {
+    fn on_event(&mut self, event: &ProgressEvent) {
+        let _ = self.sender.send(event.clone());
+    }
+}
+
+/// Appends every event as one JSON line to a file.
+pub struct LogFileProgress {
+    file: File,
+}
+
+impl LogFileProgress {
+    /// Opens (or creates) the log file in append mode.
+    pub fn new(path: &str) -> Result<LogFileProgress, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open progress log {}: {}", path, e))?;
+        Ok(LogFileProgress { file })
+    }
+}
+
+impl ProgressObserver for LogFileProgress {
+    fn on_event(&mut self, event: &ProgressEvent) {
+        let line = serde_json::json!({
+            "time": chrono::Local::now().to_rfc3339(),
+            "event": event,
+        });
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            warn!("Failed to write progress log: {}", e);
+        }
+    }
+}
+
+/// Serves progress events as JSON lines to every client connected to a unix socket.
+pub struct StatusSocket;
+
+impl StatusSocket {
+    /// Binds the socket at `path`, replacing a stale socket file, and subscribes it to `progress`.
+    /// Clients are accepted and served from background threads until the process exits.
+    pub fn serve(path: &str, progress: &Progress) -> Result<(), String> {
+        if fs::metadata(path).is_ok() {
+            fs::remove_file(path)
+                .map_err(|e| format!("Failed to remove stale status socket {}: {}", path, e))?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Failed to bind status socket {}: {}", path, e))?;
+        info!("Serving progress events on {}", path);
+
+        let clients: Arc<Mutex<Vec<UnixStream>>> = Arc::new(Mutex::new(Vec::new()));
+        let accepting_clients = Arc::clone(&clients);
+        thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                if let Ok(mut clients) = accepting_clients.lock() {
+                    clients.push(stream);
+                }
+            }
+        });
+
+        let receiver = progress.channel();
+        thread::spawn(move || {
+            for event in receiver {
+                let line = format!("{}\n", serde_json::json!(event));
+                if let Ok(mut clients) = clients.lock() {
+                    clients.retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Renders a progress bar on stderr, redrawn on every `BytesCopied` event.
+pub struct TerminalProgress {
+    last_draw: Option<Instant>,
+}
+
+impl TerminalProgress {
+    const WIDTH: usize = 30;
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+    pub fn new() -> TerminalProgress {
+        TerminalProgress { last_draw: None }
+    }
+
+    /// Returns whether stderr is attached to a terminal.
+    pub fn is_available() -> bool {
+        unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+    }
+
+    fn render(bytes: u64, total_bytes: Option<u64>, rate: f64, eta: Option<Duration>) -> String {
+        let bar = match total_bytes {
+            Some(total) if total > 0 => {
+                let ratio = (bytes as f64 / total as f64).min(1.0);
+                let filled = (ratio * Self::WIDTH as f64) as usize;
+                format!(
+                    "[{}{}] {:>3}% {} / {}",
+                    "#".repeat(filled),
+                    " ".repeat(Self::WIDTH - filled),
+                    (ratio * 100.0) as u64,
+                    human_bytes(bytes),
+                    human_bytes(total)
+                )
+            }
+            _ => human_bytes(bytes),
+        };
+        let eta = eta
+            .map(|eta| format!(" ETA {}", human_duration(eta)))
+            .unwrap_or_default();
+        format!("{} {}/s{}", bar, human_bytes(rate as u64), eta)
+    }
+}
+
+impl ProgressObserver for TerminalProgress {
+    fn on_event(&mut self, event: &ProgressEvent) {
+        let mut stderr = std::io::stderr();
+        match event {
+            ProgressEvent::BytesCopied {
+                bytes,
+                total_bytes,
+                rate,
+                eta,
+                ..
+            } => {
+                if self
+                    .last_draw
+                    .is_some_and(|last| last.elapsed() < Self::REDRAW_INTERVAL)
+                {
+                    return;
+                }
+                self.last_draw = Some(Instant::now());
+                let _ = write!(
+                    stderr,
+                    "\r\x1b[2K{}",
+                    Self::render(*bytes, *total_bytes, *rate, *eta)
+                );
+            }
+            ProgressEvent::Finished { .. } => {
+                self.last_draw = None;
+                let _ = writeln!(stderr);
+            }
+            _ => {}
+        }
+        let _ = stderr.flush();
+    }
+}
+
+/// Formats a byte count with a binary unit, like `1.5 GiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration like `1h 2m 3s`.
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dd_progress() {
+        assert_eq!(
+            parse_dd_progress("1073741824 bytes (1.1 GB, 1.0 GiB) copied, 5 s, 215 MB/s"),
+            Some(1073741824)
+        );
+        assert_eq!(
+            parse_dd_progress("512 bytes copied, 0.0001 s, 5.1 MB/s"),
+            Some(512)
+        );
+        assert_eq!(parse_dd_progress("2048+0 records in"), None);
+        assert_eq!(parse_dd_progress(""), None);
+    }
+
+    #[test]
+    fn test_progress_channel() {
+        let progress = Progress::new();
+        let receiver = progress.channel();
+
+        let mut tracker = CopyTracker::start(&progress, "/dev/sda", "/mnt/a.img", Some(100));
+        progress.phase("/dev/sda", Phase::Copy);
+        tracker.update(50);
+        tracker.finish(true);
+
+        let events: Vec<ProgressEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[0], ProgressEvent::Started { .. }));
+        assert_eq!(
+            events[1],
+            ProgressEvent::PhaseChanged {
+                target: "/dev/sda".to_string(),
+                phase: Phase::Copy
+            }
+        );
+        assert!(matches!(
+            events[2],
+            ProgressEvent::BytesCopied {
+                bytes: 50,
+                total_bytes: Some(100),
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[3],
+            ProgressEvent::Finished {
+                bytes: 50,
+                success: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_human_units() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(human_duration(Duration::from_secs(3723)), "1h 2m 3s");
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDate;
+
+use crate::run::utils::current_date;
+
+/// The directory in a backup directory stale temporary files are moved to.
+pub const QUARANTINE_DIR: &str = "dd_backup_quarantine";
+
+/// The endings of the temporary files written while an image is copied, see `journal`.
+const TEMPORARY_ENDINGS: [&str; 3] = [".partial", ".partial.journal", ".partial.journal.tmp"];
+
+/// Moves the stale temporary files of interrupted backups in `backup_dir` into its `QUARANTINE_DIR`.
+///
+/// A temporary file is stale if its backup started on an earlier day, since partial images are only
+/// resumed on the day they were started, or if it is the journal of a partial image which is gone.
+/// Partial images of today are left to the backup of their device, which resumes or discards them.
+/// Files are moved instead of deleted, so that they can be looked at before removing them by hand.
+///
+/// # Arguments
+///
+/// * `backup_dir` - The directory the images are written to.
+/// * `dry_run` - Only logs the files which would be moved.
+///
+/// # Returns
+///
+/// - `Ok(Vec<String>)`: The names of the stale files.
+/// - `Err(String)`: If the backup directory is not readable or a file could not be moved.
+pub fn quarantine_stale_files(backup_dir: &str, dry_run: bool) -> Result<Vec<String>, String> {
+    let dir = Path::new(backup_dir);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let file_names: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read backup directory {}: {}", backup_dir, e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    let today = current_date();
+    let mut stale: Vec<String> = file_names
+        .iter()
+        .filter(|file_name| is_stale(file_name, &file_names, &today))
+        .cloned()
+        .collect();
+    stale.sort();
+
+    for file_name in &stale {
+        let quarantine_dir = dir.join(QUARANTINE_DIR);
+        match dry_run {
+            true => info!(
+                "[DRY RUN] would move the stale file {} to {}",
+                dir.join(file_name).display(),
+                quarantine_dir.display()
+            ),
+            false => {
+                fs::create_dir_all(&quarantine_dir)
+                    .map_err(|e| format!("{}: {}", quarantine_dir.display(), e))?;
+                let target = free_path(&quarantine_dir, file_name);
+                fs::rename(dir.join(file_name), &target).map_err(|e| {
+                    format!(
+                        "Failed to move {} to {}: {}",
+                        dir.join(file_name).display(),
+                        target.display(),
+                        e
+                    )
+                })?;
+                warn!(
+                    "Moved the stale file {} of an interrupted backup to {}",
+                    file_name,
+                    target.display()
+                );
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// Returns whether `file_name` is a stale temporary file among the `file_names` of its directory.
+fn is_stale(file_name: &str, file_names: &[String], today: &str) -> bool {
+    let Some(ending) = TEMPORARY_ENDINGS
+        .iter()
+        .find(|ending| file_name.ends_with(*ending))
+    else {
+        return false;
+    };
+    let date = file_name.get(..10).unwrap_or_default();
+    if NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+        // not named like an image of dd_backup
+        return false;
+    }
+    if date != today {
+        return true;
+    }
+    let partial = format!(
+        "{}.partial",
+        file_name.strip_suffix(ending).unwrap_or(file_name)
+    );
+    *ending != ".partial" && !file_names.contains(&partial)
+}
+
+/// Returns the path of `file_name` in `dir`, numbered like `<file_name>.1` if the name is taken.
+fn free_path(dir: &Path, file_name: &str) -> PathBuf {
+    let mut path = dir.join(file_name);
+    let mut number = 0;
+    while path.exists() {
+        number += 1;
+        path = dir.join(format!("{}.{}", file_name, number));
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantine_stale_files() {
+        let dir =
+            std::env::temp_dir().join(format!("dd_backup_quarantine_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(QUARANTINE_DIR)).unwrap();
+        let today = current_date();
+        let files = [
+            "2023-01-01_desktop_SRC1.img".to_string(),
+            "2023-01-02_desktop_SRC1.img.partial".to_string(),
+            "2023-01-02_desktop_SRC1.img.partial.journal".to_string(),
+            format!("{}_desktop_SRC1.img.partial", today),
+            format!("{}_desktop_SRC1.img.partial.journal", today),
+            format!("{}_laptop_SRC2.img.partial.journal", today),
+            "notes.partial".to_string(),
+        ];
+        for file_name in &files {
+            fs::write(dir.join(file_name), b"data").unwrap();
+        }
+        // a partial image of the same name was quarantined before
+        fs::write(
+            dir.join(QUARANTINE_DIR)
+                .join("2023-01-02_desktop_SRC1.img.partial"),
+            b"old",
+        )
+        .unwrap();
+        let backup_dir = dir.to_string_lossy().to_string();
+
+        let dry_run = quarantine_stale_files(&backup_dir, true).unwrap();
+        let dry_run_left = fs::read_dir(&dir).unwrap().count();
+        let stale = quarantine_stale_files(&backup_dir, false).unwrap();
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        let mut quarantined: Vec<String> = fs::read_dir(dir.join(QUARANTINE_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        quarantined.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let expected = vec![
+            "2023-01-02_desktop_SRC1.img.partial".to_string(),
+            "2023-01-02_desktop_SRC1.img.partial.journal".to_string(),
+            format!("{}_laptop_SRC2.img.partial.journal", today),
+        ];
+        assert_eq!(dry_run, expected);
+        assert_eq!(dry_run_left, files.len() + 1);
+        assert_eq!(stale, expected);
+        assert_eq!(
+            left,
+            vec![
+                "2023-01-01_desktop_SRC1.img".to_string(),
+                format!("{}_desktop_SRC1.img.partial", today),
+                format!("{}_desktop_SRC1.img.partial.journal", today),
+                QUARANTINE_DIR.to_string(),
+                "notes.partial".to_string(),
+            ]
+        );
+        assert_eq!(
+            quarantined,
+            vec![
+                "2023-01-02_desktop_SRC1.img.partial".to_string(),
+                "2023-01-02_desktop_SRC1.img.partial.1".to_string(),
+                "2023-01-02_desktop_SRC1.img.partial.journal".to_string(),
+                format!("{}_laptop_SRC2.img.partial.journal", today),
+            ]
+        );
+    }
+}
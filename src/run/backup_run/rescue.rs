@@ -0,0 +1,282 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use crate::run::extent::{complement, merge, total_length, Extent};
+
+/// The smallest block read on its own, the size of a page of the page cache the device is read through.
+pub const RESCUE_BLOCK_SIZE: usize = 4096;
+
+/// How often an unreadable block is read again before it is given up.
+const RETRIES: usize = 2;
+
+/// The pattern unreadable blocks are filled with in the image, to tell them apart from zeros.
+pub const BAD_BLOCK_MARKER: &[u8; 16] = b"DD_BACKUP BADBLK";
+
+/// Reads a failing device like `ddrescue`: a read failing with an I/O error is split into halves down to
+/// `RESCUE_BLOCK_SIZE`, those blocks are retried and filled with `BAD_BLOCK_MARKER` if they stay unreadable.
+///
+/// The reader expects reads inside of the device, like those of `copy_extents` and `write_image`.
+pub struct RescueReader<R> {
+    inner: R,
+    position: u64,
+    unreadable: Vec<Extent>,
+}
+
+impl<R: Read + Seek> RescueReader<R> {
+    pub fn new(inner: R) -> RescueReader<R> {
+        RescueReader {
+            inner,
+            position: 0,
+            unreadable: Vec::new(),
+        }
+    }
+
+    /// Returns the merged extents which could not be read and were filled with the marker.
+    pub fn unreadable(&self) -> Vec<Extent> {
+        merge(self.unreadable.clone())
+    }
+
+    /// Fills `buffer` from `offset`, splitting the read on I/O errors.
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        match self.try_read(offset, buffer) {
+            Ok(()) => Ok(()),
+            Err(e) if is_read_error(&e) && buffer.len() <= RESCUE_BLOCK_SIZE => {
+                self.retry_block(offset, buffer)
+            }
+            Err(e) if is_read_error(&e) => {
+                debug!(
+                    "Read error at {} of {} bytes, retrying in smaller blocks",
+                    offset,
+                    buffer.len()
+                );
+                let half = (buffer.len() / 2).div_ceil(RESCUE_BLOCK_SIZE) * RESCUE_BLOCK_SIZE;
+                let (first, second) = buffer.split_at_mut(half);
+                self.read_at(offset, first)?;
+                self.read_at(offset + half as u64, second)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Retries reading a single block, which is filled with the marker if it stays unreadable.
+    fn retry_block(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        for attempt in 1..=RETRIES {
+            match self.try_read(offset, buffer) {
+                Ok(()) => {
+                    debug!("Read the block at {} on retry {}", offset, attempt);
+                    return Ok(());
+                }
+                Err(e) if is_read_error(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        debug!(
+            "Giving up the block at {} of {} bytes",
+            offset,
+            buffer.len()
+        );
+        for (byte, marker) in buffer.iter_mut().zip(BAD_BLOCK_MARKER.iter().cycle()) {
+            *byte = *marker;
+        }
+        self.unreadable
+            .push(Extent::new(offset, buffer.len() as u64));
+        Ok(())
+    }
+
+    fn try_read(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(buffer)
+    }
+}
+
+impl<R: Read + Seek> Read for RescueReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.read_at(self.position, buffer)?;
+        self.position += buffer.len() as u64;
+        Ok(buffer.len())
+    }
+}
+
+impl<R: Read + Seek> Seek for RescueReader<R> {
+    fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(seek)?;
+        Ok(self.position)
+    }
+}
+
+/// Returns whether the error is a failed read of the medium, which is worth retrying in smaller blocks.
+fn is_read_error(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EIO) | Some(libc::ENODATA))
+}
+
+/// Returns the path of the map file of an image, listing its unreadable ranges.
+pub fn map_path(image: &str) -> String {
+    format!("{}.map", image)
+}
+
+/// Writes the map file of a rescued image, in the format of `ddrescue` mapfiles, so that `ddrescue`
+/// can retry the unreadable ranges later.
+///
+/// The read extents are finished (`+`), the unreadable extents are bad (`-`) and the rest of the image,
+/// not read like the unused blocks of used-blocks-only images, is non-tried (`?`).
+///
+/// # Arguments
+///
+/// * `image` - The path of the image, the map is written to `<image>.map`.
+/// * `source` - The device the image was read from.
+/// * `size` - The size of the image in bytes.
+/// * `read` - The extents of the device which were read.
+/// * `unreadable` - The merged extents which could not be read.
+pub fn write_map(
+    image: &str,
+    source: &str,
+    size: u64,
+    read: &[Extent],
+    unreadable: &[Extent],
+) -> Result<(), String> {
+    let image_extent = Extent::new(0, size);
+    let read = merge(read.to_vec());
+    let mut ranges: Vec<(Extent, char)> = complement(&read, &image_extent)
+        .into_iter()
+        .map(|extent| (extent, '?'))
+        .chain(unreadable.iter().map(|extent| (*extent, '-')))
+        .collect();
+    for extent in &read {
+        let finished = complement(unreadable, extent)
+            .into_iter()
+            .map(|extent| (extent, '+'));
+        ranges.extend(finished);
+    }
+    ranges.sort();
+
+    let mut map = String::new();
+    let _ = writeln!(map, "# Mapfile. Created by dd_backup");
+    let _ = writeln!(map, "# Source: {}", source);
+    let _ = writeln!(map, "# Unreadable: {} bytes", total_length(unreadable));
+    let _ = writeln!(map, "# current_pos  current_status  current_pass");
+    let _ = writeln!(map, "0x{:08X}     +               1", size);
+    let _ = writeln!(map, "#      pos        size  status");
+    for (extent, status) in ranges {
+        let _ = writeln!(
+            map,
+            "0x{:08X}  0x{:08X}  {}",
+            extent.offset, extent.length, status
+        );
+    }
+    let path = map_path(image);
+    fs::write(&path, map).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A disk failing reads which touch its bad blocks, flaky blocks fail on the first read only.
+    struct FailingDisk {
+        data: Vec<u8>,
+        bad: Vec<Extent>,
+        flaky: Vec<Extent>,
+        position: u64,
+    }
+
+    impl Read for FailingDisk {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let request = Extent::new(self.position, buffer.len() as u64);
+            let flaky = self
+                .flaky
+                .iter()
+                .position(|extent| extent.clip(&request).is_some());
+            if let Some(index) = flaky {
+                self.flaky.remove(index);
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            if self
+                .bad
+                .iter()
+                .any(|extent| extent.clip(&request).is_some())
+            {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            let start = (self.position as usize).min(self.data.len());
+            let length = buffer.len().min(self.data.len() - start);
+            buffer[..length].copy_from_slice(&self.data[start..start + length]);
+            self.position += length as u64;
+            Ok(length)
+        }
+    }
+
+    impl Seek for FailingDisk {
+        fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+            self.position =
+                crate::run::convert::seek_position(self.position, self.data.len() as u64, seek)?;
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn test_rescue_reader() {
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let disk = FailingDisk {
+            data: data.clone(),
+            bad: vec![Extent::new(100_000, 10), Extent::new(500_000, 8192)],
+            flaky: vec![Extent::new(800_000, 1)],
+            position: 0,
+        };
+        let mut reader = RescueReader::new(disk);
+        let mut read = vec![0u8; data.len()];
+        reader.read_exact(&mut read).unwrap();
+
+        // the bad bytes are rounded to the blocks containing them, the flaky block was read on retry
+        let unreadable = reader.unreadable();
+        assert_eq!(
+            unreadable,
+            vec![Extent::new(98_304, 4096), Extent::new(499_712, 4096 * 3)]
+        );
+        for extent in &unreadable {
+            let range = extent.offset as usize..extent.end() as usize;
+            assert_eq!(&read[range.start..range.start + 16], BAD_BLOCK_MARKER);
+            read[range.clone()].copy_from_slice(&data[range]);
+        }
+        assert!(read == data);
+
+        // other errors are not hidden
+        let mut past_end = vec![0u8; 16];
+        reader.seek(SeekFrom::Start(data.len() as u64)).unwrap();
+        assert!(reader.read_exact(&mut past_end).is_err());
+    }
+
+    #[test]
+    fn test_write_map() {
+        let image = std::env::temp_dir()
+            .join(format!("dd_backup_rescue_test_{}.img", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        write_map(
+            &image,
+            "/dev/sda",
+            0x10000,
+            &[Extent::new(0, 0x8000), Extent::new(0xC000, 0x4000)],
+            &[Extent::new(0x1000, 0x1000)],
+        )
+        .unwrap();
+
+        let map = fs::read_to_string(map_path(&image)).unwrap();
+        fs::remove_file(map_path(&image)).unwrap();
+        let ranges: Vec<&str> = map.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                "0x00010000     +               1",
+                "0x00000000  0x00001000  +",
+                "0x00001000  0x00001000  -",
+                "0x00002000  0x00006000  +",
+                "0x00008000  0x00004000  ?",
+                "0x0000C000  0x00004000  +",
+            ]
+        );
+        assert!(map.contains("# Unreadable: 4096 bytes"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::run::utils::available_bytes;
+
+use super::lsblk::BlockDevice;
+
+/// The unit of the `size` and `start` attributes in sysfs, independent of the logical block size.
+const SECTOR_SIZE: u64 = 512;
+
+/// Reads block devices from sysfs (`/sys/block`), the udev database
+/// (`/run/udev/data`) and the mount table (`/proc/self/mountinfo`).
+///
+/// All paths are resolved below `root`, which is `/` except for tests.
+#[derive(Debug)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Sysfs {
+    /// Creates a new `Sysfs` reader for the system mounted at `root`.
+    pub fn new(root: &Path) -> Sysfs {
+        Sysfs {
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Returns all disks and their partitions with a non-zero size.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Vec<BlockDevice>)`: The disks, each followed by its partitions.
+    /// - `Err(String)`: If `/sys/block` is not readable.
+    pub fn block_devices(&self) -> Result<Vec<BlockDevice>, String> {
+        let sys_block = self.root.join("sys/block");
+        let mut disk_names = Self::dir_names(&sys_block)
+            .map_err(|e| format!("{}: {}", sys_block.to_string_lossy(), e))?;
+        disk_names.sort();
+
+        let mountpoints = self.mountpoints();
+        let mut blockdevices = Vec::new();
+        for disk_name in disk_names {
+            let disk_path = sys_block.join(&disk_name);
+            let Some(disk) = self.block_device(&disk_path, &disk_name, None, &mountpoints) else {
+                continue;
+            };
+
+            let mut partition_names: Vec<String> = Self::dir_names(&disk_path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|name| disk_path.join(name).join("partition").exists())
+                .collect();
+            partition_names
+                .sort_by_key(|name| Self::read_u64(&disk_path.join(name).join("partition")));
+
+            let partitions: Vec<BlockDevice> = partition_names
+                .iter()
+                .filter_map(|name| {
+                    self.block_device(&disk_path.join(name), name, Some(&disk), &mountpoints)
+                })
+                .collect();
+            blockdevices.push(disk);
+            blockdevices.extend(partitions);
+        }
+        Ok(blockdevices)
+    }
+
+    /// Reads a single disk or partition (if `parent` is given) from its sysfs directory.
+    /// Returns `None` for devices without size, like detached loop devices.
+    fn block_device(
+        &self,
+        sys_path: &Path,
+        name: &str,
+        parent: Option<&BlockDevice>,
+        mountpoints: &HashMap<String, String>,
+    ) -> Option<BlockDevice> {
+        let size = Self::read_u64(&sys_path.join("size"))? * SECTOR_SIZE;
+        if size == 0 {
+            return None;
+        }
+        let dev = Self::read_string(&sys_path.join("dev"))?;
+        let udev = self.udev_properties(&dev);
+        let property = |key: &str| udev.get(key).cloned();
+
+        let kind = match parent {
+            Some(_) => "part",
+            None if name.starts_with("loop") => "loop",
+            None => "disk",
+        };
+        let (model, serial, wwn) = match parent {
+            Some(_) => (None, None, None),
+            None => (
+                Self::read_string(&sys_path.join("device/model")).or(property("ID_MODEL")),
+                property("ID_SERIAL_SHORT")
+                    .or_else(|| Self::read_string(&sys_path.join("serial")))
+                    .or_else(|| Self::read_string(&sys_path.join("device/serial"))),
+                property("ID_WWN_WITH_EXTENSION")
+                    .or(property("ID_WWN"))
+                    .or_else(|| Self::read_string(&sys_path.join("wwid"))),
+            ),
+        };
+        let mountpoint = mountpoints.get(&dev).cloned();
+        let fsavail = mountpoint
+            .as_ref()
+            .and_then(|mountpoint| available_bytes(mountpoint).ok());
+
+        Some(BlockDevice {
+            name: name.to_string(),
+            model,
+            serial,
+            uuid: property("ID_FS_UUID"),
+            mountpoint,
+            size,
+            fsavail,
+            wwn,
+            pkname: parent.map(|parent| parent.name.clone()),
+            kind: Some(kind.to_string()),
+            partuuid: property("ID_PART_ENTRY_UUID"),
+            label: property("ID_FS_LABEL"),
+            fstype: property("ID_FS_TYPE"),
+            partlabel: property("ID_PART_ENTRY_NAME"),
+            partn: parent
+                .and_then(|_| Self::read_u64(&sys_path.join("partition")).map(|n| n as u32)),
+            parttype: property("ID_PART_ENTRY_TYPE"),
+        })
+    }
+
+    /// Reads the udev properties (`E:KEY=VALUE` lines) of the device with the given `major:minor`.
+    fn udev_properties(&self, dev: &str) -> HashMap<String, String> {
+        fs::read_to_string(self.root.join("run/udev/data").join(format!("b{}", dev)))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.strip_prefix("E:"))
+            .filter_map(|property| property.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Returns the first mountpoint of each mounted device, keyed by `major:minor`.
+    fn mountpoints(&self) -> HashMap<String, String> {
+        let mut mountpoints = HashMap::new();
+        let mountinfo =
+            fs::read_to_string(self.root.join("proc/self/mountinfo")).unwrap_or_default();
+        for line in mountinfo.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() > 4 {
+                mountpoints
+                    .entry(fields[2].to_string())
+                    .or_insert_with(|| Self::unescape_mountinfo(fields[4]));
+            }
+        }
+        mountpoints
+    }
+
+    /// Decodes the octal escapes (`\040` for space) used in `/proc/self/mountinfo`.
+    fn unescape_mountinfo(field: &str) -> String {
+        let mut unescaped = Vec::new();
+        let bytes = field.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\\' && i + 3 < bytes.len() {
+                let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+                if let Ok(byte) = u8::from_str_radix(octal, 8) {
+                    unescaped.push(byte);
+                    i += 4;
+                    continue;
+                }
+            }
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&unescaped).to_string()
+    }
+
+    fn dir_names(path: &Path) -> Result<Vec<String>, std::io::Error> {
+        Ok(fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn read_string(path: &Path) -> Option<String> {
+        fs::read_to_string(path)
+            .ok()
+            .map(|content| content.trim().to_string())
+            .filter(|content| !content.is_empty())
+    }
+
+    fn read_u64(path: &Path) -> Option<u64> {
+        Self::read_string(path)?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn generate_test_root() -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("dd_backup_sysfs_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        write(&root, "sys/block/sda/dev", "8:0\n");
+        write(&root, "sys/block/sda/size", "1953525168\n");
+        write(&root, "sys/block/sda/device/model", "Samsung SSD 860 \n");
+        write(&root, "sys/block/sda/sda1/dev", "8:1\n");
+        write(&root, "sys/block/sda/sda1/size", "1048576\n");
+        write(&root, "sys/block/sda/sda1/partition", "1\n");
+        write(&root, "sys/block/sda/sda2/dev", "8:2\n");
+        write(&root, "sys/block/sda/sda2/size", "1952474767\n");
+        write(&root, "sys/block/sda/sda2/partition", "2\n");
+        write(&root, "sys/block/loop0/dev", "7:0\n");
+        write(&root, "sys/block/loop0/size", "0\n");
+        write(&root, "sys/block/nvme0n1/dev", "259:0\n");
+        write(&root, "sys/block/nvme0n1/size", "1000215216\n");
+        write(
+            &root,
+            "sys/block/nvme0n1/device/serial",
+            "S4EWNX0R123456  \n",
+        );
+        write(&root, "sys/block/nvme0n1/wwid", "eui.0025388b91b12345\n");
+        write(
+            &root,
+            "run/udev/data/b8:0",
+            "S:disk/by-id/ata-Samsung\nE:ID_SERIAL_SHORT=S3Z9NB0K123456\nE:ID_WWN=0x5002538e40a12345\n",
+        );
+        write(
+            &root,
+            "run/udev/data/b8:2",
+            "E:ID_FS_UUID=0b1c-uuid\nE:ID_FS_TYPE=ext4\nE:ID_FS_LABEL=home\nE:ID_PART_ENTRY_UUID=part-uuid-2\nE:ID_PART_ENTRY_TYPE=0x83\n",
+        );
+        write(
+            &root,
+            "proc/self/mountinfo",
+            "22 1 8:2 / /mnt/my\\040home rw,relatime shared:1 - ext4 /dev/sda2 rw\n",
+        );
+        root
+    }
+
+    #[test]
+    fn test_block_devices() {
+        let root = generate_test_root();
+        let devices = Sysfs::new(&root).block_devices().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["nvme0n1", "sda", "sda1", "sda2"]);
+
+        let nvme = &devices[0];
+        assert_eq!(nvme.size, 1000215216 * 512);
+        assert_eq!(nvme.serial.as_deref(), Some("S4EWNX0R123456"));
+        assert_eq!(nvme.wwn.as_deref(), Some("eui.0025388b91b12345"));
+        assert_eq!(nvme.kind.as_deref(), Some("disk"));
+
+        let sda = &devices[1];
+        assert_eq!(sda.size, 1953525168 * 512);
+        assert_eq!(sda.model.as_deref(), Some("Samsung SSD 860"));
+        assert_eq!(sda.serial.as_deref(), Some("S3Z9NB0K123456"));
+        assert_eq!(sda.wwn.as_deref(), Some("0x5002538e40a12345"));
+
+        let sda2 = &devices[3];
+        assert_eq!(sda2.kind.as_deref(), Some("part"));
+        assert_eq!(sda2.pkname.as_deref(), Some("sda"));
+        assert_eq!(sda2.serial, None);
+        assert_eq!(sda2.uuid.as_deref(), Some("0b1c-uuid"));
+        assert_eq!(sda2.fstype.as_deref(), Some("ext4"));
+        assert_eq!(sda2.label.as_deref(), Some("home"));
+        assert_eq!(sda2.partuuid.as_deref(), Some("part-uuid-2"));
+        assert_eq!(sda2.partn, Some(2));
+        assert_eq!(sda2.parttype.as_deref(), Some("0x83"));
+        assert_eq!(sda2.mountpoint.as_deref(), Some("/mnt/my home"));
+    }
+}
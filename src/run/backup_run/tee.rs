@@ -0,0 +1,211 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::SeekFrom,
+    os::unix::fs::FileExt,
+};
+
+use crate::run::{convert::DiskReader, extent::Extent};
+
+use super::{
+    command_output::CommandRunner,
+    journal::{partial_path, Journal, JournalWriter},
+};
+
+/// The number of bytes read at once and written to every target.
+const TEE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// A partial image a single read of a device is teed into, see `copy_teed`.
+pub struct TeeTarget<'a> {
+    /// The path of the image, written into its partial backup file.
+    pub image: String,
+    file: File,
+    journal: JournalWriter<'a>,
+    /// The error which stopped the writes to this target, the other targets continue.
+    pub error: Option<String>,
+}
+
+impl<'a> TeeTarget<'a> {
+    /// Opens the partial backup file of `image` with the size of the image and starts its journal.
+    /// The file of a resumed backup is kept, its bytes before the confirmed offset of `journal` are not written again.
+    pub fn open(
+        runner: &'a dyn CommandRunner,
+        image: &str,
+        journal: Journal,
+    ) -> Result<TeeTarget<'a>, String> {
+        let path = partial_path(image);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        file.set_len(journal.size)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(TeeTarget {
+            image: image.to_string(),
+            file,
+            journal: JournalWriter::start(runner, image, journal)?,
+            error: None,
+        })
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: u64) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.file.write_all_at(buffer, offset) {
+            self.fail(format!(
+                "Failed to write {} at {}: {}",
+                self.image, offset, e
+            ));
+        }
+    }
+
+    fn fail(&mut self, error: String) {
+        warn!("{}, continuing with the other destinations", error);
+        self.error = Some(error);
+    }
+}
+
+/// Copies the extents of a device into all targets, reading every block once.
+///
+/// A target failing to write, like a full or unplugged destination, is left behind with its error,
+/// while the others are written to the end. The journal of each target is updated like for a single copy.
+///
+/// # Arguments
+///
+/// * `source` - The device to read.
+/// * `targets` - The partial images to write.
+/// * `extents` - The extents of the device to copy, at the same offsets in the images.
+/// * `on_progress` - Called with the number of bytes copied so far.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The number of bytes read, also if some targets failed.
+/// - `Err(String)`: If reading failed, or all targets failed.
+pub fn copy_teed(
+    source: &mut dyn DiskReader,
+    targets: &mut [TeeTarget],
+    extents: &[Extent],
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, String> {
+    let mut buffer = vec![0u8; TEE_BUFFER_SIZE];
+    let mut copied = 0;
+    for extent in extents {
+        source
+            .seek(SeekFrom::Start(extent.offset))
+            .map_err(|e| format!("Failed to seek to {}: {}", extent.offset, e))?;
+        let mut offset = extent.offset;
+        while offset < extent.end() {
+            let chunk = ((extent.end() - offset) as usize).min(TEE_BUFFER_SIZE);
+            source
+                .read_exact(&mut buffer[..chunk])
+                .map_err(|e| format!("Failed to read at {}: {}", offset, e))?;
+            for target in targets.iter_mut() {
+                target.write(&buffer[..chunk], offset);
+            }
+            offset += chunk as u64;
+            copied += chunk as u64;
+            if let Some(error) = all_failed(targets) {
+                return Err(error);
+            }
+            for target in targets.iter_mut().filter(|target| target.error.is_none()) {
+                target.journal.update(offset);
+            }
+            on_progress(copied);
+        }
+    }
+    for target in targets.iter_mut().filter(|target| target.error.is_none()) {
+        if let Err(e) = target.file.sync_all() {
+            target.fail(format!("Failed to sync {}: {}", target.image, e));
+        }
+    }
+    match all_failed(targets) {
+        Some(error) => Err(error),
+        None => Ok(copied),
+    }
+}
+
+/// Returns the error of the first target if all targets failed.
+fn all_failed(targets: &[TeeTarget]) -> Option<String> {
+    match targets.iter().all(|target| target.error.is_some()) {
+        true => targets.first().and_then(|target| target.error.clone()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use super::*;
+    use crate::run::backup_run::command_output::fake::FakeCommandRunner;
+
+    #[test]
+    fn test_copy_teed() {
+        let runner = FakeCommandRunner::new();
+        let dir = std::env::temp_dir().join(format!("dd_backup_tee_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let device: Vec<u8> = (0..3 * TEE_BUFFER_SIZE).map(|i| (i % 251) as u8).collect();
+        let size = device.len() as u64;
+        let journal = Journal {
+            source: "/dev/fakesrc0".to_string(),
+            size,
+            data_bytes: size - 1024,
+            confirmed_offset: 0,
+        };
+        let images: Vec<String> = ["first", "second"]
+            .iter()
+            .map(|name| {
+                dir.join(format!("{}.img", name))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        let mut targets: Vec<TeeTarget> = images
+            .iter()
+            .map(|image| TeeTarget::open(&runner, image, journal.clone()).unwrap())
+            .collect();
+        // a destination which can't be written to, like a read-only one
+        let read_only = dir.join("read_only.img").to_string_lossy().to_string();
+        fs::write(partial_path(&read_only), b"").unwrap();
+        targets.push(TeeTarget {
+            file: File::open(partial_path(&read_only)).unwrap(),
+            ..TeeTarget::open(&runner, &dir.join("unused.img").to_string_lossy(), journal).unwrap()
+        });
+        let extents = vec![Extent::new(1024, size - 1024)];
+        let mut progress = Vec::new();
+
+        let copied = copy_teed(
+            &mut Cursor::new(device.clone()),
+            &mut targets,
+            &extents,
+            &mut |bytes| progress.push(bytes),
+        );
+        let failed = copy_teed(
+            &mut Cursor::new(device.clone()),
+            &mut targets[2..],
+            &extents,
+            &mut |_| {},
+        );
+        let written: Vec<Vec<u8>> = images
+            .iter()
+            .map(|image| fs::read(partial_path(image)).unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied, Ok(size - 1024));
+        assert_eq!(progress.last(), Some(&(size - 1024)));
+        let mut expected = device.clone();
+        expected[..1024].fill(0);
+        assert!(written.iter().all(|image| *image == expected));
+        assert!(targets[..2].iter().all(|target| target.error.is_none()));
+        assert!(targets[2]
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("Failed to write"));
+        assert_eq!(failed, Err(targets[2].error.clone().unwrap()));
+    }
+}
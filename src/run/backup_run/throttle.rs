@@ -0,0 +1,222 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::errno::Errno;
+
+use crate::run::config::{CopyOptions, IoPriority};
+
+use super::interrupt;
+
+/// The longest single sleep of a `ThrottledReader`, so that SIGINT and SIGTERM are noticed soon.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// Selects a thread or process by its id in the `ioprio_get` and `ioprio_set` syscalls.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// The bit position of the class in an I/O priority of the `ioprio_set` syscall.
+const IOPRIO_CLASS_SHIFT: u8 = 13;
+
+/// Returns the commands `dd` is prefixed with to run with the I/O priority and nice level of the options,
+/// like `ionice -c 2 -n 7 nice -n 10`. It is empty if neither is set.
+pub fn command_prefix(options: &CopyOptions) -> Vec<String> {
+    let mut prefix = Vec::new();
+    if let Some(io_priority) = options.io_priority {
+        prefix.extend([
+            "ionice".to_string(),
+            "-c".to_string(),
+            io_priority.class().to_string(),
+        ]);
+        if let Some(level) = io_priority.level() {
+            prefix.extend(["-n".to_string(), level.to_string()]);
+        }
+    }
+    if let Some(nice) = options.nice {
+        prefix.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+    }
+    prefix
+}
+
+/// The previous I/O priority and nice level of the current thread, set for a native copy by `apply`
+/// and restored when dropped, since the thread runs further backups afterwards.
+pub struct Scheduling {
+    thread_id: libc::pid_t,
+    io_priority: Option<libc::c_int>,
+    nice: Option<libc::c_int>,
+}
+
+impl Scheduling {
+    /// Applies the I/O priority and nice level of the options to the current thread.
+    /// Failures are logged only, the copy runs with the previous priority then.
+    pub fn apply(options: &CopyOptions) -> Scheduling {
+        let thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        let io_priority = options.io_priority.and_then(|io_priority| {
+            let previous = get_io_priority(thread_id)
+                .and_then(|previous| {
+                    set_io_priority(thread_id, io_priority_value(io_priority)).map(|()| previous)
+                })
+                .map_err(|e| warn!("Failed to set the I/O priority {:?}: {}", io_priority, e));
+            previous.ok()
+        });
+        let nice = options.nice.and_then(|nice| {
+            let previous = get_nice(thread_id)
+                .and_then(|previous| set_nice(thread_id, nice).map(|()| previous))
+                .map_err(|e| warn!("Failed to set the nice level {}: {}", nice, e));
+            previous.ok()
+        });
+        Scheduling {
+            thread_id,
+            io_priority,
+            nice,
+        }
+    }
+}
+
+impl Drop for Scheduling {
+    fn drop(&mut self) {
+        if let Some(io_priority) = self.io_priority {
+            if let Err(e) = set_io_priority(self.thread_id, io_priority) {
+                warn!("Failed to restore the I/O priority: {}", e);
+            }
+        }
+        if let Some(nice) = self.nice {
+            if let Err(e) = set_nice(self.thread_id, nice) {
+                warn!("Failed to restore the nice level: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the value of an I/O priority for the `ioprio_set` syscall, its class followed by its level.
+fn io_priority_value(io_priority: IoPriority) -> libc::c_int {
+    let class = libc::c_int::from(io_priority.class()) << IOPRIO_CLASS_SHIFT;
+    class | libc::c_int::from(io_priority.level().unwrap_or(0))
+}
+
+fn get_io_priority(thread_id: libc::pid_t) -> Result<libc::c_int, Errno> {
+    let io_priority = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, thread_id) };
+    Errno::result(io_priority).map(|io_priority| io_priority as libc::c_int)
+}
+
+fn set_io_priority(thread_id: libc::pid_t, io_priority: libc::c_int) -> Result<(), Errno> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            thread_id,
+            io_priority,
+        )
+    };
+    Errno::result(result).map(drop)
+}
+
+fn get_nice(thread_id: libc::pid_t) -> Result<libc::c_int, Errno> {
+    // -1 is a valid nice level, errors are told apart by errno
+    Errno::clear();
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, thread_id as libc::id_t) };
+    match (nice, Errno::last()) {
+        (-1, errno) if errno != Errno::UnknownErrno => Err(errno),
+        (nice, _) => Ok(nice),
+    }
+}
+
+fn set_nice(thread_id: libc::pid_t, nice: libc::c_int) -> Result<(), Errno> {
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, thread_id as libc::id_t, nice) };
+    Errno::result(result).map(drop)
+}
+
+/// Reads at most `bytes_per_second` on average, sleeping after reads which got ahead of the limit.
+/// Without limit it reads like its inner reader. Seeking over unread regions doesn't count.
+pub struct ThrottledReader<R> {
+    inner: R,
+    bytes_per_second: Option<u64>,
+    started: Option<Instant>,
+    read: u64,
+}
+
+impl<R: Read + Seek> ThrottledReader<R> {
+    /// Creates a reader limited to `mib_per_second` MiB per second, if given.
+    pub fn new(inner: R, mib_per_second: Option<u64>) -> ThrottledReader<R> {
+        ThrottledReader {
+            inner,
+            bytes_per_second: mib_per_second.map(|limit| limit * 1024 * 1024),
+            started: None,
+            read: 0,
+        }
+    }
+
+    /// Sleeps until the bytes read so far are within the limit, or SIGINT or SIGTERM was received.
+    fn throttle(&self, bytes_per_second: u64) {
+        let Some(started) = self.started else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.read as f64 / bytes_per_second as f64);
+        while !interrupt::is_interrupted() {
+            let elapsed = started.elapsed();
+            if elapsed >= due {
+                break;
+            }
+            thread::sleep((due - elapsed).min(MAX_SLEEP));
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for ThrottledReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.started.get_or_insert_with(Instant::now);
+        let read = self.inner.read(buffer)?;
+        self.read += read as u64;
+        if let Some(bytes_per_second) = self.bytes_per_second {
+            self.throttle(bytes_per_second);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for ThrottledReader<R> {
+    fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(seek)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_command_prefix() {
+        let options = CopyOptions {
+            io_priority: Some(IoPriority::BestEffort(7)),
+            nice: Some(10),
+            ..CopyOptions::default()
+        };
+        assert_eq!(
+            command_prefix(&options).join(" "),
+            "ionice -c 2 -n 7 nice -n 10"
+        );
+        let options = CopyOptions {
+            io_priority: Some(IoPriority::Idle),
+            ..CopyOptions::default()
+        };
+        assert_eq!(command_prefix(&options).join(" "), "ionice -c 3");
+        assert!(command_prefix(&CopyOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_throttled_reader() {
+        let mut reader = ThrottledReader::new(Cursor::new(vec![7u8; 1024 * 1024]), Some(4));
+        let mut buffer = vec![0u8; 256 * 1024];
+        let started = Instant::now();
+        for _ in 0..4 {
+            reader.read_exact(&mut buffer).unwrap();
+        }
+
+        // 1 MiB at 4 MiB per second
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert_eq!(buffer, vec![7u8; 256 * 1024]);
+    }
+}
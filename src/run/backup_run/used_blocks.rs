@@ -0,0 +1,326 @@
+use std::fs::{File, OpenOptions};
+
+use crate::run::{
+    allocation::allocated_extents,
+    convert::DiskReader,
+    extent::{complement, copy_extents, from_offset, merge, Extent},
+    image_metadata::{CopyMode, ImageMetadata, Region},
+    partition_table::PartitionTable,
+    swap::SwapSignature,
+};
+
+/// Plans an image of a device copied natively, with used blocks only and without excluded partitions.
+///
+/// A whole device is split at its partitions: excluded partitions are left out, the blocks allocated by
+/// the filesystem of each other partition are copied if `used_blocks_only` is set, partitions with unknown
+/// filesystems and the space outside of partitions are copied completely.
+/// A partition, or a device without partition table, is treated as a single filesystem.
+///
+/// # Arguments
+///
+/// * `device_path` - The device to read, it must be readable by the current user.
+/// * `size` - The size of the image in bytes, the size of the device unless it is trimmed.
+/// * `is_whole_device` - Whether the device may hold a partition table.
+/// * `used_blocks_only` - Whether only the blocks allocated by filesystems are copied.
+/// * `excluded_partitions` - The numbers of the partitions left out of the image.
+///
+/// # Returns
+///
+/// - `Ok(ImageMetadata)`: The regions of the image.
+/// - `Err(String)`: If the device is not readable.
+pub fn plan(
+    device_path: &str,
+    size: u64,
+    is_whole_device: bool,
+    used_blocks_only: bool,
+    excluded_partitions: &[u32],
+) -> Result<ImageMetadata, String> {
+    let mut device = File::open(device_path).map_err(|e| {
+        format!(
+            "Failed to open {} for native imaging (needs to run as root): {}",
+            device_path, e
+        )
+    })?;
+    let device_extent = Extent::new(0, size);
+
+    let table = match is_whole_device {
+        true => PartitionTable::read(&mut device).unwrap_or_else(|e| {
+            warn!("{}: {}, copying it as one filesystem", device_path, e);
+            None
+        }),
+        false => None,
+    };
+    let mut partitions: Vec<(Extent, Option<u32>)> = match &table {
+        Some(table) => table
+            .partitions
+            .iter()
+            .filter(|partition| !partition.is_extended())
+            .filter_map(|partition| {
+                Extent::new(
+                    partition.start * table.sector_size,
+                    partition.size * table.sector_size,
+                )
+                .clip(&device_extent)
+                .map(|extent| (extent, Some(partition.number)))
+            })
+            .collect(),
+        None => vec![(device_extent, None)],
+    };
+    partitions.sort();
+
+    let partition_extents = partitions.iter().map(|(extent, _)| *extent).collect();
+    let mut regions: Vec<Region> = complement(&merge(partition_extents), &device_extent)
+        .into_iter()
+        .map(|gap| Region::full(gap.offset, gap.length))
+        .collect();
+    for (partition, number) in partitions {
+        let region = if number.is_some_and(|number| excluded_partitions.contains(&number)) {
+            excluded_region(&mut device, partition, device_path)
+        } else if used_blocks_only {
+            used_blocks_region(&mut device, partition, device_path)
+        } else {
+            Region::full(partition.offset, partition.length)
+        };
+        regions.push(region);
+    }
+    regions.sort_by_key(|region| region.offset);
+
+    Ok(ImageMetadata {
+        source: device_path.to_string(),
+        size,
+        source_size: None,
+        unreadable: Vec::new(),
+        regions,
+    })
+}
+
+/// Returns the region of a partition with the blocks allocated by its filesystem,
+/// or the whole partition if its filesystem isn't supported.
+fn used_blocks_region(device: &mut File, partition: Extent, device_path: &str) -> Region {
+    match allocated_extents(device, partition.offset, partition.length) {
+        Ok(Some((kind, extents))) => Region {
+            offset: partition.offset,
+            length: partition.length,
+            copy: CopyMode::UsedBlocks,
+            filesystem: Some(kind.name().to_string()),
+            extents,
+            swap: None,
+        },
+        Ok(None) => {
+            info!(
+                "No supported filesystem at byte {} of {}, copying it completely",
+                partition.offset, device_path
+            );
+            Region::full(partition.offset, partition.length)
+        }
+        Err(e) => {
+            warn!(
+                "{} at byte {} of {}, copying it completely",
+                e, partition.offset, device_path
+            );
+            Region::full(partition.offset, partition.length)
+        }
+    }
+}
+
+/// Returns the region of an excluded partition, keeping the signature of its swap area if it has one.
+fn excluded_region(device: &mut File, partition: Extent, device_path: &str) -> Region {
+    let swap = SwapSignature::read(device, partition.offset).unwrap_or_else(|e| {
+        warn!(
+            "{} at byte {} of {}, not recording a swap signature",
+            e, partition.offset, device_path
+        );
+        None
+    });
+    info!(
+        "Excluding the {} bytes at byte {} of {}{}",
+        partition.length,
+        partition.offset,
+        device_path,
+        match &swap {
+            Some(swap) => format!(", a swap area with UUID {}", swap.uuid),
+            None => String::new(),
+        }
+    );
+    Region::excluded(partition.offset, partition.length, swap)
+}
+
+/// Copies the data extents of the planned image from the device into a sparse image.
+///
+/// # Arguments
+///
+/// * `metadata` - The planned image, see `plan`.
+/// * `device` - The device to read, `metadata.source`.
+/// * `image_path` - The path of the image to create.
+/// * `from` - The offset to copy from, the image exists already if it is not 0 and the copy is resumed.
+/// * `on_progress` - Called with the total number of bytes copied so far.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The number of bytes copied.
+/// - `Err(String)`: If reading the device or writing the image failed.
+pub fn copy(
+    metadata: &ImageMetadata,
+    device: &mut dyn DiskReader,
+    image_path: &str,
+    from: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, String> {
+    let mut image = OpenOptions::new()
+        .write(true)
+        .create_new(from == 0)
+        .open(image_path)
+        .map_err(|e| format!("{}: {}", image_path, e))?;
+    image
+        .set_len(metadata.size)
+        .map_err(|e| format!("{}: {}", image_path, e))?;
+
+    let extents = from_offset(&metadata.data_extents(), from);
+    copy_extents(device, &mut image, &extents, on_progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    use super::*;
+    use crate::run::partition_table::tests::dos_disk;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "dd_backup_used_blocks_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_plan_and_copy_used_blocks() {
+        // a disk with a FAT partition, an unknown partition and unpartitioned space
+        let mut disk = dos_disk(
+            65536,
+            &[(0x0C, 2048, 32768, false), (0x83, 34816, 2048, false)],
+        );
+        let fat_offset = 2048 * 512;
+        disk[fat_offset + 0x0B..fat_offset + 0x0D].copy_from_slice(&512u16.to_le_bytes());
+        disk[fat_offset + 0x0D] = 4;
+        disk[fat_offset + 0x0E..fat_offset + 0x10].copy_from_slice(&4u16.to_le_bytes());
+        disk[fat_offset + 0x10] = 1;
+        disk[fat_offset + 0x11..fat_offset + 0x13].copy_from_slice(&16u16.to_le_bytes());
+        disk[fat_offset + 0x13..fat_offset + 0x15].copy_from_slice(&32768u16.to_le_bytes());
+        disk[fat_offset + 0x16..fat_offset + 0x18].copy_from_slice(&32u16.to_le_bytes());
+        disk[fat_offset + 0x36..fat_offset + 0x3E].copy_from_slice(b"FAT16   ");
+        disk[fat_offset + 510..fat_offset + 512].copy_from_slice(&[0x55, 0xAA]);
+        // cluster 2 is used, data starts after 4 reserved sectors, 32 FAT sectors and 1 root dir sector
+        let fat = fat_offset + 4 * 512;
+        disk[fat + 4..fat + 6].copy_from_slice(&[0xFF, 0xFF]);
+        let data_start = fat_offset + 37 * 512;
+        disk[data_start..data_start + 2048].fill(0xAB);
+        // a free cluster with stale data isn't copied
+        disk[data_start + 2048..data_start + 4096].fill(0xCD);
+
+        let dir = test_dir("fat");
+        let source = dir.join("disk.raw").to_string_lossy().to_string();
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        fs::write(&source, &disk).unwrap();
+
+        let metadata = plan(&source, disk.len() as u64, true, true, &[]).unwrap();
+        let copies: Vec<(u64, CopyMode, Option<&str>)> = metadata
+            .regions
+            .iter()
+            .map(|region| (region.offset, region.copy, region.filesystem.as_deref()))
+            .collect();
+        assert_eq!(
+            copies,
+            vec![
+                (0, CopyMode::Full, None),
+                (fat_offset as u64, CopyMode::UsedBlocks, Some("vfat")),
+                (34816 * 512, CopyMode::Full, None),
+                (36864 * 512, CopyMode::Full, None),
+            ]
+        );
+        // partition table, FAT metadata, one cluster, the unknown partition and the unpartitioned space
+        assert_eq!(
+            metadata.data_bytes(),
+            fat_offset as u64 + 37 * 512 + 2048 + (65536 - 34816) * 512
+        );
+
+        let mut progress = 0;
+        let copied = copy(
+            &metadata,
+            &mut File::open(&source).unwrap(),
+            &image,
+            0,
+            &mut |bytes| progress = bytes,
+        )
+        .unwrap();
+        assert_eq!(copied, metadata.data_bytes());
+        assert_eq!(progress, copied);
+
+        let copied_image = fs::read(&image).unwrap();
+        let allocated = fs::metadata(&image).unwrap().blocks() * 512;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied_image.len(), disk.len());
+        assert_eq!(copied_image[..data_start + 2048], disk[..data_start + 2048]);
+        assert!(copied_image[data_start + 2048..data_start + 4096]
+            .iter()
+            .all(|&b| b == 0));
+        assert!(allocated < disk.len() as u64);
+    }
+
+    #[test]
+    fn test_plan_and_copy_excluded_swap() {
+        // a disk with a data partition and a swap partition
+        let mut disk = dos_disk(
+            16384,
+            &[(0x83, 2048, 4096, false), (0x82, 6144, 4096, false)],
+        );
+        let (data, swap) = (2048 * 512, 6144 * 512);
+        disk[data..swap].fill(0xAB);
+        disk[swap..swap + 4096 * 512].fill(0xEE);
+        let signature = SwapSignature {
+            page_size: 4096,
+            uuid: "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9".to_string(),
+            label: None,
+        };
+        disk[swap..swap + 4096].copy_from_slice(&signature.to_bytes(4096 * 512).unwrap());
+
+        let dir = test_dir("swap");
+        let source = dir.join("disk.raw").to_string_lossy().to_string();
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        fs::write(&source, &disk).unwrap();
+
+        let metadata = plan(&source, disk.len() as u64, true, false, &[2]).unwrap();
+        assert_eq!(
+            metadata.regions,
+            vec![
+                Region::full(0, data as u64),
+                Region::full(data as u64, 4096 * 512),
+                Region::excluded(swap as u64, 4096 * 512, Some(signature)),
+                Region::full(10240 * 512, 6144 * 512),
+            ]
+        );
+        assert_eq!(metadata.data_bytes(), (16384 - 4096) * 512);
+
+        copy(
+            &metadata,
+            &mut File::open(&source).unwrap(),
+            &image,
+            0,
+            &mut |_| {},
+        )
+        .unwrap();
+        let copied_image = fs::read(&image).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied_image[..swap], disk[..swap]);
+        assert!(copied_image[swap..swap + 4096 * 512]
+            .iter()
+            .all(|&b| b == 0));
+        assert_eq!(copied_image[swap + 4096 * 512..], disk[swap + 4096 * 512..]);
+    }
+}
@@ -1,14 +1,251 @@
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
+    fmt,
     fs::{self, File},
     path::PathBuf,
+    str::FromStr,
 };
 
+use crate::run::utils::parse_time_of_day;
+
+/// Identifies the source device of a backup.
+///
+/// In the configuration file it is given as one key of the backup device, like `"serial": "x...x"`.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceIdentifier {
+    /// The serial number of the whole device.
+    Serial(String),
+    /// The world wide name of the whole device.
+    Wwn(String),
+    /// A name in `/dev/disk/by-id/`.
+    ById(String),
+    /// A name in `/dev/disk/by-path/`.
+    ByPath(String),
+    /// The UUID of a partition table entry (PARTUUID).
+    Partuuid(String),
+    /// The UUID of a filesystem.
+    FsUuid(String),
+    /// A path to a device node or a regular file.
+    Path(String),
+}
+
+impl DeviceIdentifier {
+    /// The configuration keys of all identifier types.
+    pub const KINDS: [&'static str; 7] = [
+        "serial", "wwn", "by_id", "by_path", "partuuid", "fs_uuid", "path",
+    ];
+
+    /// Returns the configuration key of the identifier type, like `serial`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeviceIdentifier::Serial(_) => "serial",
+            DeviceIdentifier::Wwn(_) => "wwn",
+            DeviceIdentifier::ById(_) => "by_id",
+            DeviceIdentifier::ByPath(_) => "by_path",
+            DeviceIdentifier::Partuuid(_) => "partuuid",
+            DeviceIdentifier::FsUuid(_) => "fs_uuid",
+            DeviceIdentifier::Path(_) => "path",
+        }
+    }
+
+    /// Returns the identifying value.
+    pub fn value(&self) -> &str {
+        match self {
+            DeviceIdentifier::Serial(value)
+            | DeviceIdentifier::Wwn(value)
+            | DeviceIdentifier::ById(value)
+            | DeviceIdentifier::ByPath(value)
+            | DeviceIdentifier::Partuuid(value)
+            | DeviceIdentifier::FsUuid(value)
+            | DeviceIdentifier::Path(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for DeviceIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.value())
+    }
+}
+
+impl FromStr for DeviceIdentifier {
+    type Err = String;
+
+    /// Parses an identifier of the form `<kind>=<value>`, like `wwn=0x5002538e40a12345`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once('=')
+            .ok_or(format!("Expected <kind>=<value>, got '{}'", s))?;
+        let value = value.to_string();
+        match kind {
+            "serial" => Ok(DeviceIdentifier::Serial(value)),
+            "wwn" => Ok(DeviceIdentifier::Wwn(value)),
+            "by_id" => Ok(DeviceIdentifier::ById(value)),
+            "by_path" => Ok(DeviceIdentifier::ByPath(value)),
+            "partuuid" => Ok(DeviceIdentifier::Partuuid(value)),
+            "fs_uuid" => Ok(DeviceIdentifier::FsUuid(value)),
+            "path" => Ok(DeviceIdentifier::Path(value)),
+            _ => Err(format!(
+                "Unknown identifier kind '{}', expected one of {}",
+                kind,
+                Self::KINDS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Selects partitions of a source device.
+///
+/// In the configuration file it is given as object with one key, like `{"number": 2}`.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionSelector {
+    /// The partition number, like `2` for `/dev/sda2`.
+    Number(u32),
+    /// The UUID of the partition table entry (PARTUUID).
+    Partuuid(String),
+    /// The name of the partition table entry or the label of the filesystem.
+    Label(String),
+    /// The type of the filesystem, like `ext4` or `ntfs`.
+    Fstype(String),
+    /// The partition type, a GUID for GPT or a hex byte like `82` for MBR.
+    Type(String),
+}
+
+impl FromStr for PartitionSelector {
+    type Err = String;
+
+    /// Parses a selector of the form `<kind>=<value>`, like `number=2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once('=')
+            .ok_or(format!("Expected <kind>=<value>, got '{}'", s))?;
+        match kind {
+            "number" => value
+                .parse()
+                .map(PartitionSelector::Number)
+                .map_err(|e| format!("Invalid partition number '{}': {}", value, e)),
+            "partuuid" => Ok(PartitionSelector::Partuuid(value.to_string())),
+            "label" => Ok(PartitionSelector::Label(value.to_string())),
+            "fstype" => Ok(PartitionSelector::Fstype(value.to_string())),
+            "type" => Ok(PartitionSelector::Type(value.to_string())),
+            _ => Err(format!(
+                "Unknown partition selector '{}', expected one of number, partuuid, label, fstype, type",
+                kind
+            )),
+        }
+    }
+}
+
+/// The file format of an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// A byte-by-byte copy of the device.
+    #[default]
+    Raw,
+    /// The QEMU copy-on-write format, version 2.
+    Qcow2,
+    /// The VMware monolithic sparse format.
+    Vmdk,
+}
+
+impl ImageFormat {
+    /// All formats, to find the images of a device in any of them.
+    pub const ALL: [ImageFormat; 3] = [ImageFormat::Raw, ImageFormat::Qcow2, ImageFormat::Vmdk];
+
+    /// Returns the file extension of images in this format, like `img` for raw images.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "img",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vmdk => "vmdk",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    /// Parses a format name, like `qcow2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ImageFormat::Raw),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            "vmdk" => Ok(ImageFormat::Vmdk),
+            _ => Err(format!(
+                "Unknown image format '{}', expected one of raw, qcow2, vmdk",
+                s
+            )),
+        }
+    }
+}
+
+/// The I/O scheduling class and level the copy of a device runs with, like `ionice`.
+///
+/// In the configuration file it is given as object with one key, like `{"best_effort": 7}`, or as `"idle"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoPriority {
+    /// Served before any other I/O, with a level from 0 (highest) to 7.
+    Realtime(u8),
+    /// Served in turns with other I/O, with a level from 0 (highest) to 7, the default class.
+    BestEffort(u8),
+    /// Served only when no other process needs the disk.
+    Idle,
+}
+
+impl IoPriority {
+    /// Returns the scheduling class, as number of `ionice -c` and the `ioprio_set` syscall.
+    pub fn class(&self) -> u8 {
+        match self {
+            IoPriority::Realtime(_) => 1,
+            IoPriority::BestEffort(_) => 2,
+            IoPriority::Idle => 3,
+        }
+    }
+
+    /// Returns the level within the class, the idle class has none.
+    pub fn level(&self) -> Option<u8> {
+        match self {
+            IoPriority::Realtime(level) | IoPriority::BestEffort(level) => Some(*level),
+            IoPriority::Idle => None,
+        }
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    /// Parses a priority of the form `<class>=<level>` or `idle`, like `best_effort=7`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "idle" {
+            return Ok(IoPriority::Idle);
+        }
+        let (class, level) = s
+            .split_once('=')
+            .ok_or(format!("Expected <class>=<level> or idle, got '{}'", s))?;
+        let level = level
+            .parse()
+            .map_err(|e| format!("Invalid I/O priority level '{}': {}", level, e))?;
+        match class {
+            "realtime" => Ok(IoPriority::Realtime(level)),
+            "best_effort" => Ok(IoPriority::BestEffort(level)),
+            _ => Err(format!(
+                "Unknown I/O priority class '{}', expected one of realtime, best_effort, idle",
+                class
+            )),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct BackupDevice {
-    /// The serial number of the device.
-    pub serial: String,
+    /// The identifier of the source device.
+    #[serde(flatten)]
+    pub identifier: DeviceIdentifier,
     /// An optional name for the device.
     pub name: Option<String>,
     /// The number of copies to be kept for this device.
@@ -17,6 +254,62 @@ pub struct BackupDevice {
     /// If set to a positive integer, the oldest copies will be deleted when the limit is reached.
     /// If set to 0, Config::validate_config will return Err(String).
     pub copies: Option<usize>,
+    /// Deletes the oldest copy before the backup if there isn't enough space for it next to the
+    /// present copies, like before the copies were only deleted after the backup was published.
+    ///
+    /// The new backup may fail after the oldest copy is gone. The last image which isn't degraded is never deleted.
+    #[serde(default)]
+    pub delete_oldest_when_short: Option<bool>,
+    /// The partitions to be backed up instead of the whole device.
+    ///
+    /// If set, one image per selected partition and a small image of the partition table are created.
+    /// If set to an empty list, Config::validate_config will return Err(String).
+    #[serde(default)]
+    pub partitions: Option<Vec<PartitionSelector>>,
+    /// Options on how the device is copied.
+    #[serde(flatten)]
+    pub options: CopyOptions,
+}
+
+/// Options on how a device is copied into its image, configured per device.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CopyOptions {
+    /// Copies only the blocks allocated by ext2/3/4, XFS, FAT and NTFS filesystems into a sparse image.
+    ///
+    /// Unknown filesystems and unpartitioned space are copied completely.
+    #[serde(default)]
+    pub used_blocks_only: Option<bool>,
+    /// Copies a whole device only up to the end of its last partition, followed by a relocated backup GPT.
+    ///
+    /// Devices without partition table are copied completely.
+    #[serde(default)]
+    pub trim_to_last_partition: Option<bool>,
+    /// The partitions of a whole device left out of its image, like swap, written as holes instead.
+    ///
+    /// The partition table is kept, swap partitions get their signature back on restore.
+    #[serde(default)]
+    pub exclude_partitions: Option<Vec<PartitionSelector>>,
+    /// The format the image is written in, defaults to raw.
+    ///
+    /// Partition table images are always raw.
+    #[serde(default)]
+    pub output_format: Option<ImageFormat>,
+    /// Copies around read errors of a failing device, like `ddrescue`, instead of aborting.
+    ///
+    /// Unreadable blocks are filled with a marker, listed in `<image>.map` and mark the image as degraded.
+    #[serde(default)]
+    pub rescue: Option<bool>,
+    /// The I/O scheduling class and level the device is read with, like `ionice`.
+    #[serde(default)]
+    pub io_priority: Option<IoPriority>,
+    /// The nice level from -20 to 19 the copy runs with, higher levels leave more CPU time to other processes.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// The maximum number of MiB read from the device per second.
+    ///
+    /// The device is copied natively then, instead of with `dd`.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
 }
 
 /// Represents the configuration for a single backup.
@@ -24,10 +317,9 @@ pub struct BackupDevice {
 pub struct BackupConfig {
     /// The list of devices to be backed up.
     ///
-    /// Strings are identifiers of whole devices.
-    /// The identifier can be the serial number or the wwn (world wide name).
+    /// Each device is identified by a `DeviceIdentifier`, like the serial number or the wwn (world wide name).
     /// Since some devices may not have a serial number or even have duplicated serial numbers,
-    /// the identifier serves as a unique identifier for the device.
+    /// other identifiers like a `/dev/disk/by-id/` name or a PARTUUID can be used instead.
     pub backup_devices: Vec<BackupDevice>,
 
     /// The UUID of the destination backup filesystem or partition.
@@ -62,6 +354,18 @@ pub struct Config {
     /// The path on which the destination filesystem will be mounted.
     /// If not provided, the default mount path will be used.
     pub mountpath: Option<String>,
+    /// The maximum number of destination filesystems backed up at once.
+    /// If not provided, the destinations are backed up one after another.
+    pub max_parallel: Option<usize>,
+    /// Whether a device configured on several connected destinations is read once for all of them.
+    /// If not provided, the device is read for each destination.
+    pub fan_out: Option<bool>,
+    /// The time of day as `HH:MM` after which no further backups are started, like `06:00`.
+    /// The running backups finish. If not provided, all backups are started.
+    pub start_before: Option<String>,
+    /// The time of day as `HH:MM` by which the backups should be finished, like `07:00`.
+    /// A warning is logged before the backups if they are estimated to take longer, see `History`.
+    pub finish_before: Option<String>,
 }
 
 impl Config {
@@ -103,7 +407,7 @@ impl Config {
         }
     }
 
-    /// Validates the configuration to ensure unique UUIDs and serial numbers.
+    /// Validates the configuration to ensure unique UUIDs and device identifiers.
     ///
     /// # Arguments
     ///
@@ -116,6 +420,16 @@ impl Config {
     pub fn validate_config(config: Result<Config, String>) -> Result<Config, String> {
         let config = config?;
 
+        if config.max_parallel == Some(0) {
+            return Err("Invalid max_parallel. Must be greater than 0.".to_string());
+        }
+        for time in [&config.start_before, &config.finish_before]
+            .into_iter()
+            .flatten()
+        {
+            parse_time_of_day(time)?;
+        }
+
         // Check for unique UUIDs
         let uuids: HashSet<&String> = config.backups.iter().map(|backup| &backup.uuid).collect();
         if uuids.len() != config.backups.len() {
@@ -123,27 +437,34 @@ impl Config {
         }
 
         for backup in &config.backups {
-            // Check for unique serial numbers within each backup
-            let serials: HashSet<&String> = backup
+            // Check for unique device identifiers within each backup
+            let identifiers: HashSet<&DeviceIdentifier> = backup
                 .backup_devices
                 .iter()
-                .map(|device| &device.serial)
+                .map(|device| &device.identifier)
                 .collect();
-            if serials.len() != backup.backup_devices.len() {
+            if identifiers.len() != backup.backup_devices.len() {
                 return Err(format!(
-                    "Duplicate serial number found in backup with UUID '{}'",
+                    "Duplicate device identifier found in backup with UUID '{}'",
                     backup.uuid
                 ));
             }
 
             // Check if the number of copies is specified and greater than 0
             for device in &backup.backup_devices {
+                if device.partitions.as_ref().is_some_and(|p| p.is_empty()) {
+                    return Err(format!(
+                        "Empty partitions for device with {}. Omit it to back up the whole device.",
+                        device.identifier
+                    ));
+                }
+                Self::validate_copy_options(device)?;
                 if let Some(copies) = device.copies {
                     if copies == 0 {
                         return Err(format!(
-                        "Invalid number of copies for device with serial '{}'. Must be greater than 0.",
-                        device.serial
-                    ));
+                            "Invalid number of copies for device with {}. Must be greater than 0.",
+                            device.identifier
+                        ));
                     }
                 }
             }
@@ -152,6 +473,34 @@ impl Config {
         Ok(config)
     }
 
+    /// Validates the throttling options of a device: the I/O priority level, the nice level and the bandwidth limit.
+    fn validate_copy_options(device: &BackupDevice) -> Result<(), String> {
+        let options = &device.options;
+        if options
+            .io_priority
+            .and_then(|p| p.level())
+            .is_some_and(|level| level > 7)
+        {
+            return Err(format!(
+                "Invalid io_priority for device with {}. The level must be between 0 and 7.",
+                device.identifier
+            ));
+        }
+        if options.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+            return Err(format!(
+                "Invalid nice for device with {}. Must be between -20 and 19.",
+                device.identifier
+            ));
+        }
+        if options.bandwidth_limit == Some(0) {
+            return Err(format!(
+                "Invalid bandwidth_limit for device with {}. Must be greater than 0.",
+                device.identifier
+            ));
+        }
+        Ok(())
+    }
+
     /// Returns the default path to the configuration file.
     ///
     /// # Returns
@@ -212,14 +561,20 @@ mod tests {
     #[test]
     fn test_validate_config_success() {
         let device1 = BackupDevice {
-            serial: "device1".to_string(),
+            identifier: DeviceIdentifier::Serial("device1".to_string()),
             copies: Some(1),
+            delete_oldest_when_short: None,
             name: None,
+            partitions: None,
+            options: CopyOptions::default(),
         };
         let device2 = BackupDevice {
-            serial: "device2".to_string(),
+            identifier: DeviceIdentifier::Serial("device2".to_string()),
             copies: Some(1),
+            delete_oldest_when_short: None,
             name: None,
+            partitions: None,
+            options: CopyOptions::default(),
         };
         let backup1 = BackupConfig {
             uuid: "backup1".to_string(),
@@ -240,6 +595,10 @@ mod tests {
         let config = Config {
             backups: vec![backup1, backup2],
             mountpath: Some("/mnt".to_string()),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
         };
         assert!(Config::validate_config(Ok(config)).is_ok());
     }
@@ -247,9 +606,12 @@ mod tests {
     #[test]
     fn test_validate_config_duplicate_uuids() {
         let device = BackupDevice {
-            serial: "device".to_string(),
+            identifier: DeviceIdentifier::Serial("device".to_string()),
             copies: Some(1),
+            delete_oldest_when_short: None,
             name: None,
+            partitions: None,
+            options: CopyOptions::default(),
         };
         let backup1 = BackupConfig {
             uuid: "backup".to_string(),
@@ -270,6 +632,10 @@ mod tests {
         let config = Config {
             backups: vec![backup1, backup2],
             mountpath: Some("/mnt".to_string()),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
         };
         assert!(Config::validate_config(Ok(config)).is_err());
     }
@@ -277,9 +643,12 @@ mod tests {
     #[test]
     fn test_validate_config_duplicate_serials() {
         let device = BackupDevice {
-            serial: "device".to_string(),
+            identifier: DeviceIdentifier::Serial("device".to_string()),
             copies: Some(1),
+            delete_oldest_when_short: None,
             name: None,
+            partitions: None,
+            options: CopyOptions::default(),
         };
         let backup = BackupConfig {
             uuid: "backup".to_string(),
@@ -292,6 +661,10 @@ mod tests {
         let config = Config {
             backups: vec![backup],
             mountpath: Some("/mnt".to_string()),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
         };
         assert!(Config::validate_config(Ok(config)).is_err());
     }
@@ -299,9 +672,12 @@ mod tests {
     #[test]
     fn test_validate_config_zero_copies() {
         let device = BackupDevice {
-            serial: "device".to_string(),
+            identifier: DeviceIdentifier::Serial("device".to_string()),
             copies: Some(0),
+            delete_oldest_when_short: None,
             name: None,
+            partitions: None,
+            options: CopyOptions::default(),
         };
         let backup = BackupConfig {
             uuid: "backup".to_string(),
@@ -314,7 +690,184 @@ mod tests {
         let config = Config {
             backups: vec![backup],
             mountpath: Some("/mnt".to_string()),
+            max_parallel: None,
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
         };
         assert!(Config::validate_config(Ok(config)).is_err());
     }
+
+    #[test]
+    fn test_validate_config_zero_max_parallel() {
+        let config = Config {
+            backups: vec![],
+            mountpath: None,
+            max_parallel: Some(0),
+            fan_out: None,
+            start_before: None,
+            finish_before: None,
+        };
+        assert_eq!(
+            Config::validate_config(Ok(config)),
+            Err("Invalid max_parallel. Must be greater than 0.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_device_identifier_config_keys() {
+        let devices: Vec<BackupDevice> = serde_json::from_str(
+            r#"[
+                {"serial": "S3Z9NB0K123456", "name": "desktop", "copies": 2},
+                {"wwn": "0x5002538e40a12345", "name": null, "copies": null},
+                {"by_id": "nvme-eui.0025388b91b12345"},
+                {"partuuid": "0b1c6f3e-02"},
+                {"path": "/var/lib/images/vm.raw"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            devices[0].identifier,
+            DeviceIdentifier::Serial("S3Z9NB0K123456".to_string())
+        );
+        assert_eq!(devices[0].copies, Some(2));
+        assert_eq!(
+            devices[1].identifier,
+            DeviceIdentifier::Wwn("0x5002538e40a12345".to_string())
+        );
+        assert_eq!(
+            devices[2].identifier,
+            DeviceIdentifier::ById("nvme-eui.0025388b91b12345".to_string())
+        );
+        assert_eq!(
+            devices[3].identifier,
+            DeviceIdentifier::Partuuid("0b1c6f3e-02".to_string())
+        );
+        assert_eq!(devices[4].name, None);
+        assert_eq!(
+            "path=/var/lib/images/vm.raw".parse::<DeviceIdentifier>(),
+            Ok(devices[4].identifier.clone())
+        );
+        assert!("label=foo".parse::<DeviceIdentifier>().is_err());
+    }
+
+    #[test]
+    fn test_partition_selectors() {
+        let device: BackupDevice = serde_json::from_str(
+            r#"{"serial": "S3Z9", "partitions": [{"number": 2}, {"label": "home"}, {"fstype": "ntfs"}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            device.partitions,
+            Some(vec![
+                PartitionSelector::Number(2),
+                PartitionSelector::Label("home".to_string()),
+                PartitionSelector::Fstype("ntfs".to_string()),
+            ])
+        );
+        assert_eq!(
+            "partuuid=0b1c6f3e-02".parse::<PartitionSelector>(),
+            Ok(PartitionSelector::Partuuid("0b1c6f3e-02".to_string()))
+        );
+        assert!("number=two".parse::<PartitionSelector>().is_err());
+
+        let device: BackupDevice = serde_json::from_str(
+            r#"{"serial": "S3Z9", "exclude_partitions": [{"fstype": "swap"}, {"type": "82"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            device.options.exclude_partitions,
+            Some(vec![
+                PartitionSelector::Fstype("swap".to_string()),
+                PartitionSelector::Type("82".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_throttling_options() {
+        let device: BackupDevice = serde_json::from_str(
+            r#"{"serial": "S3Z9", "io_priority": {"best_effort": 7}, "nice": 10, "bandwidth_limit": 50}"#,
+        )
+        .unwrap();
+        assert_eq!(device.options.io_priority, Some(IoPriority::BestEffort(7)));
+        assert_eq!(device.options.nice, Some(10));
+        assert_eq!(device.options.bandwidth_limit, Some(50));
+        let device: BackupDevice =
+            serde_json::from_str(r#"{"serial": "S3Z9", "io_priority": "idle"}"#).unwrap();
+        assert_eq!(device.options.io_priority, Some(IoPriority::Idle));
+        assert_eq!(
+            "realtime=0".parse::<IoPriority>(),
+            Ok(IoPriority::Realtime(0))
+        );
+        assert!("best_effort".parse::<IoPriority>().is_err());
+
+        let config = |options: CopyOptions, start_before: Option<&str>| Config {
+            backups: vec![BackupConfig {
+                uuid: "backup".to_string(),
+                backup_devices: vec![BackupDevice {
+                    options,
+                    ..device.clone()
+                }],
+                destination_path: None,
+                fsck_command: None,
+                skip_fsck: None,
+                skip_mount: None,
+            }],
+            mountpath: None,
+            max_parallel: None,
+            fan_out: None,
+            start_before: start_before.map(|time| time.to_string()),
+            finish_before: None,
+        };
+        assert!(Config::validate_config(Ok(config(
+            CopyOptions {
+                io_priority: Some(IoPriority::Realtime(7)),
+                nice: Some(-20),
+                bandwidth_limit: Some(1),
+                ..CopyOptions::default()
+            },
+            Some("06:00")
+        )))
+        .is_ok());
+        assert_eq!(
+            Config::validate_config(Ok(config(
+                CopyOptions {
+                    io_priority: Some(IoPriority::BestEffort(8)),
+                    ..CopyOptions::default()
+                },
+                None
+            ))),
+            Err("Invalid io_priority for device with serial: S3Z9. The level must be between 0 and 7.".to_string())
+        );
+        assert_eq!(
+            Config::validate_config(Ok(config(
+                CopyOptions {
+                    nice: Some(20),
+                    ..CopyOptions::default()
+                },
+                None
+            ))),
+            Err(
+                "Invalid nice for device with serial: S3Z9. Must be between -20 and 19."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            Config::validate_config(Ok(config(
+                CopyOptions {
+                    bandwidth_limit: Some(0),
+                    ..CopyOptions::default()
+                },
+                None
+            ))),
+            Err(
+                "Invalid bandwidth_limit for device with serial: S3Z9. Must be greater than 0."
+                    .to_string()
+            )
+        );
+        assert!(Config::validate_config(Ok(config(CopyOptions::default(), Some("6am")))).is_err());
+    }
 }
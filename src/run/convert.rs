@@ -0,0 +1,334 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use clap::Args;
+
+use super::{
+    config::ImageFormat,
+    extent::{merge, Extent},
+    image_metadata::ImageMetadata,
+    partition_table::read_at,
+    qcow2::{Qcow2Reader, Qcow2Writer},
+    vmdk::{VmdkReader, VmdkWriter},
+};
+
+/// The size of the clusters of converted images, only clusters holding data are written.
+pub const CLUSTER_SIZE: u64 = 64 * 1024;
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// The path to the image to convert, a raw, qcow2 or VMDK image.
+    pub image: String,
+
+    #[clap(short, long)]
+    /// The format to convert to, one of qcow2 or vmdk.
+    pub format: ImageFormat,
+
+    #[clap(short, long)]
+    /// The path of the converted image, defaults to the image with the extension of the format.
+    pub output: Option<String>,
+}
+
+/// Writes the clusters of a virtual disk into an image file of a specific format.
+pub trait ImageWriter {
+    /// Writes the cluster with the given index, clusters are written in ascending order.
+    fn write_cluster(&mut self, index: u64, data: &[u8]) -> Result<(), String>;
+
+    /// Writes the remaining metadata of the image and syncs it.
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+/// Reads the virtual disk of an image, at any offset.
+pub trait DiskReader: Read + Seek {}
+
+impl<T: Read + Seek> DiskReader for T {}
+
+/// Runs the `convert` subcommand.
+///
+/// Used-blocks-only images are converted using their metadata, so that unused blocks are not read.
+///
+/// # Returns
+///
+/// - `Ok(())`: If the image was converted.
+/// - `Err(String)`: If the image is not readable, the output exists or writing failed.
+pub fn run(convert_args: &ConvertArgs) -> Result<(), String> {
+    let image = &convert_args.image;
+    if convert_args.format == ImageFormat::Raw {
+        return Err("Images are converted to qcow2 or vmdk, not to raw".to_string());
+    }
+    let output = convert_args
+        .output
+        .clone()
+        .unwrap_or_else(|| output_path(image, convert_args.format));
+    let (mut source, size, source_format) = open_image(image)?;
+    let extents = match (source_format, ImageMetadata::read(image)?) {
+        (ImageFormat::Raw, Some(metadata)) => metadata.data_extents(),
+        _ => vec![Extent::new(0, size)],
+    };
+
+    info!(
+        "Converting {} to {:?} image {}",
+        image, convert_args.format, output
+    );
+    write_image(
+        convert_args.format,
+        source.as_mut(),
+        size,
+        &extents,
+        &[],
+        &output,
+        &mut |_| {},
+    )?;
+    info!("Converted {} to {}", image, output);
+    Ok(())
+}
+
+/// Returns the path of the converted image, with the extension of the raw image replaced.
+fn output_path(image: &str, format: ImageFormat) -> String {
+    let stem = image
+        .strip_suffix(&format!(".{}", ImageFormat::Raw.extension()))
+        .unwrap_or(image);
+    format!("{}.{}", stem, format.extension())
+}
+
+/// Writes a virtual disk read from `source` as image in the given format, streaming it cluster by cluster.
+///
+/// Only the clusters overlapping `extents` or `patches` are read, clusters holding only zeros are left
+/// unallocated in the image.
+///
+/// # Arguments
+///
+/// * `format` - The format of the image, qcow2 or vmdk.
+/// * `source` - The device or the virtual disk of an image to read.
+/// * `size` - The size of the virtual disk in bytes.
+/// * `extents` - The extents of `source` holding data, everything else is written as zeros.
+/// * `patches` - Bytes written over the data read from `source` at the given offsets, like a relocated GPT.
+/// * `output` - The path of the image, which must not exist.
+/// * `on_progress` - Called with the total number of bytes read so far.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The number of bytes read from `source`.
+/// - `Err(String)`: If reading or writing failed.
+pub fn write_image(
+    format: ImageFormat,
+    source: &mut dyn DiskReader,
+    size: u64,
+    extents: &[Extent],
+    patches: &[(u64, Vec<u8>)],
+    output: &str,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, String> {
+    let mut writer: Box<dyn ImageWriter> = match format {
+        ImageFormat::Qcow2 => Box::new(Qcow2Writer::create(output, size)?),
+        ImageFormat::Vmdk => Box::new(VmdkWriter::create(output, size)?),
+        ImageFormat::Raw => return Err("Raw images are not converted".to_string()),
+    };
+
+    let disk = Extent::new(0, size);
+    let patch_extents = patches
+        .iter()
+        .map(|(offset, bytes)| Extent::new(*offset, bytes.len() as u64));
+    let ranges: Vec<Extent> = merge(extents.iter().copied().chain(patch_extents).collect())
+        .into_iter()
+        .filter_map(|range| range.clip(&disk))
+        .collect();
+
+    let mut buffer = vec![0u8; CLUSTER_SIZE as usize];
+    let mut read = 0;
+    // the first range which may overlap the current cluster, clusters are visited in ascending order
+    let mut first_range = 0;
+    let mut last_cluster: Option<u64> = None;
+    for range in &ranges {
+        for index in range.offset / CLUSTER_SIZE..=(range.end() - 1) / CLUSTER_SIZE {
+            if last_cluster.is_some_and(|last_cluster| last_cluster >= index) {
+                continue;
+            }
+            last_cluster = Some(index);
+            let cluster = Extent::new(index * CLUSTER_SIZE, CLUSTER_SIZE);
+            while ranges[first_range].end() <= cluster.offset {
+                first_range += 1;
+            }
+
+            buffer.fill(0);
+            for data in ranges[first_range..]
+                .iter()
+                .take_while(|range| range.offset < cluster.end())
+                .filter_map(|range| range.clip(&cluster))
+            {
+                let start = (data.offset - cluster.offset) as usize;
+                source
+                    .seek(SeekFrom::Start(data.offset))
+                    .and_then(|_| {
+                        source.read_exact(&mut buffer[start..start + data.length as usize])
+                    })
+                    .map_err(|e| format!("Failed to read at {}: {}", data.offset, e))?;
+                read += data.length;
+            }
+            for (offset, bytes) in patches {
+                let patch = Extent::new(*offset, bytes.len() as u64);
+                if let Some(overlap) = patch.clip(&cluster) {
+                    let start = (overlap.offset - cluster.offset) as usize;
+                    let patch_start = (overlap.offset - offset) as usize;
+                    buffer[start..start + overlap.length as usize].copy_from_slice(
+                        &bytes[patch_start..patch_start + overlap.length as usize],
+                    );
+                }
+            }
+
+            if buffer.iter().any(|&b| b != 0) {
+                writer.write_cluster(index, &buffer)?;
+            }
+            on_progress(read);
+        }
+    }
+    writer.finish()?;
+    Ok(read)
+}
+
+/// Opens the virtual disk of an image, its format is detected by its first bytes.
+///
+/// # Returns
+///
+/// - `Ok((Box<dyn DiskReader>, u64, ImageFormat))`: The reader of the virtual disk, its size and the format.
+/// - `Err(String)`: If the image is not readable or uses unsupported features.
+pub fn open_image(image: &str) -> Result<(Box<dyn DiskReader>, u64, ImageFormat), String> {
+    let mut file = File::open(image).map_err(|e| format!("{}: {}", image, e))?;
+    let magic = read_at(&mut file, 0, 4)?;
+    match magic.as_deref() {
+        Some(b"QFI\xfb") => {
+            let reader = Qcow2Reader::new(file).map_err(|e| format!("{}: {}", image, e))?;
+            let size = reader.size();
+            Ok((Box::new(reader), size, ImageFormat::Qcow2))
+        }
+        Some(b"KDMV") => {
+            let reader = VmdkReader::new(file).map_err(|e| format!("{}: {}", image, e))?;
+            let size = reader.size();
+            Ok((Box::new(reader), size, ImageFormat::Vmdk))
+        }
+        _ => {
+            let size = fs::metadata(image)
+                .map_err(|e| format!("{}: {}", image, e))?
+                .len();
+            Ok((Box::new(file), size, ImageFormat::Raw))
+        }
+    }
+}
+
+/// Returns the position after seeking in a virtual disk of `size` bytes.
+pub fn seek_position(position: u64, size: u64, seek: SeekFrom) -> io::Result<u64> {
+    match seek {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(delta) => size.checked_add_signed(delta),
+        SeekFrom::Current(delta) => position.checked_add_signed(delta),
+    }
+    .ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Seek before the start of the image",
+    ))
+}
+
+/// Writes `bytes` at `offset` of `file`.
+pub fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+/// Returns whether the image is raw, judged by its extension, other formats can't be restored directly.
+pub fn is_raw_image(image: &str) -> bool {
+    !ImageFormat::ALL
+        .iter()
+        .filter(|format| **format != ImageFormat::Raw)
+        .any(|format| {
+            Path::new(image)
+                .extension()
+                .is_some_and(|extension| extension == format.extension())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{qcow2::tests::read_qcow2, vmdk::tests::read_vmdk};
+
+    #[test]
+    fn test_write_image() {
+        let dir =
+            std::env::temp_dir().join(format!("dd_backup_convert_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // a disk of 3 MiB and 5 bytes with data in the first, a late and the last cluster
+        let size = 3 * 1024 * 1024 + 5;
+        let mut disk = vec![0u8; size];
+        disk[0..512].fill(1);
+        disk[70_000..200_000].fill(2);
+        disk[size - 5..].fill(3);
+        // garbage outside of the data extents isn't copied
+        disk[1024 * 1024..2 * 1024 * 1024].fill(4);
+        let source_path = dir.join("disk.img");
+        fs::write(&source_path, &disk).unwrap();
+        let extents = vec![
+            Extent::new(0, 1024 * 1024),
+            Extent::new(2 * 1024 * 1024, 1024 * 1024 + 5),
+        ];
+        disk[1024 * 1024..2 * 1024 * 1024].fill(0);
+        // a patch over the first bytes
+        let patches = vec![(256, vec![9u8; 16])];
+        disk[256..272].fill(9);
+
+        for (format, read_image) in [
+            (ImageFormat::Qcow2, read_qcow2 as fn(&[u8]) -> Vec<u8>),
+            (ImageFormat::Vmdk, read_vmdk),
+        ] {
+            let output = output_path(&source_path.to_string_lossy(), format);
+            let mut progress = 0;
+            let read = write_image(
+                format,
+                &mut File::open(&source_path).unwrap(),
+                size as u64,
+                &extents,
+                &patches,
+                &output,
+                &mut |bytes| progress = bytes,
+            )
+            .unwrap();
+            assert_eq!(read, 2 * 1024 * 1024 + 5);
+            assert_eq!(progress, read);
+
+            let image = fs::read(&output).unwrap();
+            // zero clusters aren't allocated
+            assert!(image.len() < 1024 * 1024, "{:?}: {}", format, image.len());
+            let converted = read_image(&image);
+            assert!(converted[..size] == disk[..], "{:?} differs", format);
+            assert!(converted[size..].iter().all(|&b| b == 0));
+
+            // the readers see the same virtual disk, rounded up to sectors for VMDK
+            let (mut reader, reader_size, detected) = open_image(&output).unwrap();
+            assert_eq!(detected, format);
+            let mut read_back = Vec::new();
+            reader.read_to_end(&mut read_back).unwrap();
+            assert_eq!(read_back.len() as u64, reader_size);
+            assert!(read_back[..size] == disk[..], "{:?} reader differs", format);
+            reader.seek(SeekFrom::Start(70_000)).unwrap();
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes).unwrap();
+            assert_eq!(bytes, [2; 4]);
+            assert!(write_image(
+                format,
+                &mut File::open(&source_path).unwrap(),
+                1,
+                &[],
+                &[],
+                &output,
+                &mut |_| {}
+            )
+            .is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(is_raw_image("/mnt/2023-06-15_desktop.img"));
+        assert!(!is_raw_image("/mnt/2023-06-15_desktop.qcow2"));
+    }
+}
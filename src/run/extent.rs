@@ -0,0 +1,190 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::convert::DiskReader;
+
+/// The size of the buffer used to copy extents.
+const COPY_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// A range of bytes on a device or in an image, serialized as `[offset, length]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "[u64; 2]", into = "[u64; 2]")]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Extent {
+    pub fn new(offset: u64, length: u64) -> Extent {
+        Extent { offset, length }
+    }
+
+    /// Returns the first byte after the extent.
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    /// Returns the part of the extent inside of `bounds`, if any.
+    pub fn clip(&self, bounds: &Extent) -> Option<Extent> {
+        let offset = self.offset.max(bounds.offset);
+        let end = self.end().min(bounds.end());
+        (offset < end).then(|| Extent::new(offset, end - offset))
+    }
+}
+
+impl From<[u64; 2]> for Extent {
+    fn from([offset, length]: [u64; 2]) -> Extent {
+        Extent { offset, length }
+    }
+}
+
+impl From<Extent> for [u64; 2] {
+    fn from(extent: Extent) -> [u64; 2] {
+        [extent.offset, extent.length]
+    }
+}
+
+/// Sorts the extents and merges overlapping and adjacent ones, empty extents are dropped.
+pub fn merge(mut extents: Vec<Extent>) -> Vec<Extent> {
+    extents.retain(|extent| extent.length > 0);
+    extents.sort();
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last) if extent.offset <= last.end() => {
+                last.length = last.end().max(extent.end()) - last.offset;
+            }
+            _ => merged.push(extent),
+        }
+    }
+    merged
+}
+
+/// Returns the parts of `bounds` not covered by any of the merged `extents`.
+pub fn complement(extents: &[Extent], bounds: &Extent) -> Vec<Extent> {
+    let mut gaps = Vec::new();
+    let mut position = bounds.offset;
+    for extent in extents.iter().filter_map(|extent| extent.clip(bounds)) {
+        if extent.offset > position {
+            gaps.push(Extent::new(position, extent.offset - position));
+        }
+        position = position.max(extent.end());
+    }
+    if position < bounds.end() {
+        gaps.push(Extent::new(position, bounds.end() - position));
+    }
+    gaps
+}
+
+/// Returns the number of bytes covered by the extents.
+pub fn total_length(extents: &[Extent]) -> u64 {
+    extents.iter().map(|extent| extent.length).sum()
+}
+
+/// Returns the offset reached after copying the first `bytes` bytes of the extents in order.
+pub fn offset_after(extents: &[Extent], bytes: u64) -> u64 {
+    let mut remaining = bytes;
+    for extent in extents {
+        if remaining <= extent.length {
+            return extent.offset + remaining;
+        }
+        remaining -= extent.length;
+    }
+    extents.last().map_or(0, Extent::end)
+}
+
+/// Returns the parts of the extents from `offset` on.
+pub fn from_offset(extents: &[Extent], offset: u64) -> Vec<Extent> {
+    extents
+        .iter()
+        .filter_map(|extent| extent.clip(&Extent::new(offset, u64::MAX - offset)))
+        .collect()
+}
+
+/// Copies the extents from `source` to the same offsets in `destination`, leaving the bytes in between untouched.
+///
+/// # Arguments
+///
+/// * `source` - The device or image to read from.
+/// * `destination` - The image or device to write to.
+/// * `extents` - The extents to copy.
+/// * `on_progress` - Called with the total number of bytes copied so far.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The number of bytes copied.
+/// - `Err(String)`: If reading or writing failed.
+pub fn copy_extents(
+    source: &mut dyn DiskReader,
+    destination: &mut File,
+    extents: &[Extent],
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<u64, String> {
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+    for extent in extents {
+        source
+            .seek(SeekFrom::Start(extent.offset))
+            .and_then(|_| destination.seek(SeekFrom::Start(extent.offset)))
+            .map_err(|e| format!("Failed to seek to {}: {}", extent.offset, e))?;
+        let mut remaining = extent.length;
+        while remaining > 0 {
+            let chunk = (remaining as usize).min(COPY_BUFFER_SIZE);
+            source
+                .read_exact(&mut buffer[..chunk])
+                .map_err(|e| format!("Failed to read at {}: {}", extent.end() - remaining, e))?;
+            destination
+                .write_all(&buffer[..chunk])
+                .map_err(|e| format!("Failed to write at {}: {}", extent.end() - remaining, e))?;
+            remaining -= chunk as u64;
+            copied += chunk as u64;
+            on_progress(copied);
+        }
+    }
+    destination
+        .sync_all()
+        .map_err(|e| format!("Failed to sync: {}", e))?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_and_complement() {
+        let merged = merge(vec![
+            Extent::new(100, 50),
+            Extent::new(0, 10),
+            Extent::new(10, 10),
+            Extent::new(120, 100),
+            Extent::new(500, 0),
+        ]);
+        assert_eq!(merged, vec![Extent::new(0, 20), Extent::new(100, 120)]);
+        assert_eq!(total_length(&merged), 140);
+
+        assert_eq!(
+            complement(&merged, &Extent::new(0, 300)),
+            vec![Extent::new(20, 80), Extent::new(220, 80)]
+        );
+        assert_eq!(
+            complement(&merged, &Extent::new(110, 50)),
+            Vec::<Extent>::new()
+        );
+        assert_eq!(
+            serde_json::to_string(&merged).unwrap(),
+            "[[0,20],[100,120]]"
+        );
+        assert_eq!(
+            from_offset(&merged, 10),
+            vec![Extent::new(10, 10), Extent::new(100, 120)]
+        );
+        assert_eq!(offset_after(&merged, 20), 20);
+        assert_eq!(offset_after(&merged, 30), 110);
+        assert_eq!(offset_after(&merged, 500), 220);
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use clap::Args;
+
+use super::{
+    backup_run::{
+        command_output::{CommandRunner, SystemCommandRunner},
+        device::Device,
+        lsblk::Lsblk,
+    },
+    config::{DeviceIdentifier, ImageFormat},
+    convert::{open_image, DiskReader, CLUSTER_SIZE},
+    extent::{total_length, Extent},
+    image_metadata::{CopyMode, ImageMetadata},
+    partition_table::PartitionTable,
+};
+
+/// The size of the chunks read from the image.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Args, Debug)]
+pub struct ExtractArgs {
+    /// The path to the image of a whole device, a raw, qcow2 or VMDK image.
+    pub image: String,
+
+    /// The file to write the partition to, which must not exist.
+    #[clap(required_unless_present = "target", conflicts_with = "target")]
+    pub output: Option<String>,
+
+    #[clap(long)]
+    /// The number of the partition to extract, like `2` for `/dev/sda2`.
+    pub partition: u32,
+
+    #[clap(long)]
+    /// The device to write the partition to instead of a file, as <KIND>=<VALUE>, KIND is one of
+    /// serial, wwn, by_id, by_path, partuuid, fs_uuid or path.
+    pub target: Option<DeviceIdentifier>,
+
+    #[clap(short = 'n', long, default_value = "false")]
+    /// Prints what would be written without writing it.
+    pub dry_run: bool,
+}
+
+/// Runs the `extract` subcommand.
+pub fn run(extract_args: &ExtractArgs) -> Result<(), String> {
+    extract(extract_args, Arc::new(SystemCommandRunner), Lsblk::new)
+}
+
+/// Writes a single partition of an image of a whole device to a file or a device.
+///
+/// The partition is found in the partition table of the image. Of used-blocks-only images only the
+/// used blocks are read, the rest stays a hole in the output file or untouched on the device.
+/// In output files chunks of zeros are skipped, so that they stay sparse.
+///
+/// # Arguments
+///
+/// * `extract_args` - The image, the partition, the output file or target device and whether to do a dry run.
+/// * `runner` - The runner reading the block devices.
+/// * `read_block_devices` - Reads the available block devices to find the target device.
+///
+/// # Returns
+///
+/// - `Ok(())`: If the partition was written, or would have been on a dry run.
+/// - `Err(String)`: If the partition is not found, was excluded from the image, the target is mounted
+///   or too small, or writing failed.
+fn extract(
+    extract_args: &ExtractArgs,
+    runner: Arc<dyn CommandRunner>,
+    read_block_devices: fn(&dyn CommandRunner) -> Result<Lsblk, String>,
+) -> Result<(), String> {
+    let image = &extract_args.image;
+    let (mut reader, _, format) = open_image(image)?;
+    let table = PartitionTable::read(&mut reader)?
+        .ok_or(format!("No partition table found in {}", image))?;
+    let partition = table
+        .partitions
+        .iter()
+        .find(|partition| partition.number == extract_args.partition && !partition.is_extended())
+        .ok_or(format!(
+            "Partition {} not found in {}",
+            extract_args.partition, image
+        ))?;
+    let bounds = Extent::new(
+        partition.start * table.sector_size,
+        partition.size * table.sector_size,
+    );
+
+    let metadata = match format {
+        ImageFormat::Raw => ImageMetadata::read(image)?,
+        _ => None,
+    };
+    let extents = match &metadata {
+        Some(metadata) => {
+            let is_excluded = metadata
+                .regions
+                .iter()
+                .any(|region| region.copy == CopyMode::Excluded && region.offset == bounds.offset);
+            if is_excluded {
+                return Err(format!(
+                    "Partition {} was excluded from {}, it holds no data",
+                    extract_args.partition, image
+                ));
+            }
+            metadata
+                .data_extents()
+                .iter()
+                .filter_map(|extent| extent.clip(&bounds))
+                .collect()
+        }
+        None => vec![bounds],
+    };
+
+    let (destination_path, mut destination, skips_zeros) = match &extract_args.target {
+        Some(target) => {
+            let lsblk = read_block_devices(runner.as_ref())?;
+            let (blockdevice, device_path) =
+                Device::validate_identifier(target, &lsblk.available_devices)?;
+            if Device::is_device_or_partition_mounted(&device_path)? {
+                return Err(format!("Target {} is mounted", device_path));
+            }
+            if blockdevice.size < bounds.length && blockdevice.kind.as_deref() != Some("file") {
+                return Err(format!(
+                    "Target {} of {} bytes is too small for partition {} of {} bytes",
+                    device_path, blockdevice.size, extract_args.partition, bounds.length
+                ));
+            }
+            if extract_args.dry_run {
+                info!(
+                    "[DRY RUN] extract would write {} bytes of partition {} of {} to {}",
+                    total_length(&extents),
+                    extract_args.partition,
+                    image,
+                    device_path
+                );
+                return Ok(());
+            }
+            let device = OpenOptions::new()
+                .write(true)
+                .open(&device_path)
+                .map_err(|e| format!("{}: {}", device_path, e))?;
+            (device_path, device, false)
+        }
+        None => {
+            let output = extract_args
+                .output
+                .clone()
+                .ok_or("Either an output file or a target device is needed")?;
+            if extract_args.dry_run {
+                info!(
+                    "[DRY RUN] extract would write partition {} of {} with {} bytes to {}",
+                    extract_args.partition, image, bounds.length, output
+                );
+                return Ok(());
+            }
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&output)
+                .map_err(|e| format!("{}: {}", output, e))?;
+            file.set_len(bounds.length)
+                .map_err(|e| format!("{}: {}", output, e))?;
+            (output, file, true)
+        }
+    };
+
+    let written = copy_partition(
+        reader.as_mut(),
+        &mut destination,
+        &extents,
+        bounds.offset,
+        skips_zeros,
+    )?;
+    info!(
+        "Extracted partition {} of {} bytes of {} to {}, {} bytes written",
+        extract_args.partition, bounds.length, image, destination_path, written
+    );
+    Ok(())
+}
+
+/// Copies the extents of a partition from the image to the start of `destination`.
+///
+/// # Arguments
+///
+/// * `source` - The virtual disk of the image.
+/// * `destination` - The output file or device.
+/// * `extents` - The extents of the partition holding data, as offsets in the image.
+/// * `partition_offset` - The offset of the partition in the image, written to the start of `destination`.
+/// * `skips_zeros` - Whether chunks of zeros are skipped, for output files which read as zeros there.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The number of bytes written.
+/// - `Err(String)`: If reading or writing failed.
+fn copy_partition(
+    source: &mut dyn DiskReader,
+    destination: &mut File,
+    extents: &[Extent],
+    partition_offset: u64,
+    skips_zeros: bool,
+) -> Result<u64, String> {
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut written = 0;
+    for extent in extents {
+        let mut offset = extent.offset;
+        while offset < extent.end() {
+            let chunk = ((extent.end() - offset) as usize).min(CHUNK_SIZE);
+            source
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| source.read_exact(&mut buffer[..chunk]))
+                .map_err(|e| format!("Failed to read at {}: {}", offset, e))?;
+            // zeros are checked per cluster, so that small files in a large chunk keep the holes around them
+            let block_size = match skips_zeros {
+                true => CLUSTER_SIZE as usize,
+                false => chunk,
+            };
+            for (index, block) in buffer[..chunk].chunks(block_size).enumerate() {
+                if skips_zeros && block.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                let position = offset - partition_offset + (index * block_size) as u64;
+                destination
+                    .seek(SeekFrom::Start(position))
+                    .and_then(|_| destination.write_all(block))
+                    .map_err(|e| format!("Failed to write at {}: {}", position, e))?;
+                written += block.len() as u64;
+            }
+            offset += chunk as u64;
+        }
+    }
+    destination
+        .sync_all()
+        .map_err(|e| format!("Failed to sync: {}", e))?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::MetadataExt, path::PathBuf};
+
+    use super::*;
+    use crate::run::{
+        backup_run::command_output::fake::{FakeCommandRunner, FakeResponse},
+        convert::write_image,
+        image_metadata::Region,
+        partition_table::tests::{gpt_disk, EFI_SYSTEM, LINUX_FILESYSTEM},
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_extract_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn runner() -> Arc<FakeCommandRunner> {
+        Arc::new(
+            FakeCommandRunner::new().script("lsblk", FakeResponse::ok(r#"{"blockdevices": []}"#)),
+        )
+    }
+
+    fn extract_args(image: &str, partition: u32, output: &str) -> ExtractArgs {
+        ExtractArgs {
+            image: image.to_string(),
+            output: Some(output.to_string()),
+            partition,
+            target: None,
+            dry_run: false,
+        }
+    }
+
+    /// Returns a disk with a 1 MiB partition 1 holding data at its start and end,
+    /// and a 9 MiB partition 2 holding data in its middle only.
+    fn disk() -> Vec<u8> {
+        let mut disk = gpt_disk(
+            12 * 2048,
+            &[
+                (EFI_SYSTEM, 2048, 4095, "EFI System"),
+                (LINUX_FILESYSTEM, 4096, 22527, "home"),
+            ],
+        );
+        disk[2048 * 512..2049 * 512].fill(1);
+        disk[4095 * 512..4096 * 512].fill(2);
+        disk[8192 * 512..8200 * 512].fill(3);
+        disk
+    }
+
+    #[test]
+    fn test_extract_to_sparse_file() {
+        let dir = test_dir("file");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let disk = disk();
+        fs::write(&image, &disk).unwrap();
+        let output = dir.join("home.img").to_string_lossy().to_string();
+
+        extract(
+            &extract_args(&image, 2, &output),
+            runner(),
+            Lsblk::from_lsblk,
+        )
+        .unwrap();
+
+        let extracted = fs::read(&output).unwrap();
+        let allocated = fs::metadata(&output).unwrap().blocks() * 512;
+        let again = extract(
+            &extract_args(&image, 2, &output),
+            runner(),
+            Lsblk::from_lsblk,
+        );
+        let missing = extract(
+            &extract_args(&image, 5, &output),
+            runner(),
+            Lsblk::from_lsblk,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(extracted == disk[4096 * 512..22528 * 512]);
+        // only the data in the middle is allocated
+        assert!(allocated < 1024 * 1024, "{}", allocated);
+        assert!(again.is_err());
+        assert!(missing.unwrap_err().contains("Partition 5 not found"));
+    }
+
+    #[test]
+    fn test_extract_from_qcow2_to_target() {
+        let dir = test_dir("qcow2");
+        let raw = dir.join("disk.img");
+        let disk = disk();
+        fs::write(&raw, &disk).unwrap();
+        let image = dir.join("disk.qcow2").to_string_lossy().to_string();
+        write_image(
+            ImageFormat::Qcow2,
+            &mut File::open(&raw).unwrap(),
+            disk.len() as u64,
+            &[Extent::new(0, disk.len() as u64)],
+            &[],
+            &image,
+            &mut |_| {},
+        )
+        .unwrap();
+        let target = dir.join("target.raw");
+        fs::write(&target, vec![9u8; 1024 * 1024]).unwrap();
+        let extract_args = ExtractArgs {
+            image,
+            output: None,
+            partition: 1,
+            target: Some(DeviceIdentifier::Path(target.to_string_lossy().to_string())),
+            dry_run: false,
+        };
+
+        extract(&extract_args, runner(), Lsblk::from_lsblk).unwrap();
+
+        let extracted = fs::read(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        // the zeros of the partition are written to devices too
+        assert!(extracted == disk[2048 * 512..4096 * 512]);
+    }
+
+    #[test]
+    fn test_extract_used_blocks_and_excluded() {
+        let dir = test_dir("sparse");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let mut disk = disk();
+        // garbage in the unused blocks of partition 1 is not extracted
+        disk[3000 * 512..3001 * 512].fill(7);
+        fs::write(&image, &disk).unwrap();
+        ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: disk.len() as u64,
+            source_size: None,
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 2048 * 512),
+                Region {
+                    offset: 2048 * 512,
+                    length: 2048 * 512,
+                    copy: CopyMode::UsedBlocks,
+                    filesystem: Some("vfat".to_string()),
+                    extents: vec![Extent::new(2048 * 512, 512), Extent::new(4095 * 512, 512)],
+                    swap: None,
+                },
+                Region::excluded(4096 * 512, 18432 * 512, None),
+                Region::full(22528 * 512, disk.len() as u64 - 22528 * 512),
+            ],
+        }
+        .write(&image)
+        .unwrap();
+        let output = dir.join("efi.img").to_string_lossy().to_string();
+
+        extract(
+            &extract_args(&image, 1, &output),
+            runner(),
+            Lsblk::from_lsblk,
+        )
+        .unwrap();
+
+        let extracted = fs::read(&output).unwrap();
+        let excluded = extract(
+            &extract_args(&image, 2, &dir.join("home.img").to_string_lossy()),
+            runner(),
+            Lsblk::from_lsblk,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        disk[3000 * 512..3001 * 512].fill(0);
+        assert!(extracted == disk[2048 * 512..4096 * 512]);
+        assert!(excluded.unwrap_err().contains("was excluded"));
+    }
+}
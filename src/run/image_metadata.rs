@@ -0,0 +1,198 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    extent::{merge, total_length, Extent},
+    swap::SwapSignature,
+};
+
+/// How the bytes of a region were copied into the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyMode {
+    /// Every byte of the region was copied.
+    Full,
+    /// Only the blocks allocated by the filesystem were copied, the rest is a hole in the image.
+    UsedBlocks,
+    /// The region is an excluded partition, nothing was copied and it is a hole in the image.
+    Excluded,
+}
+
+/// A range of the source device and how it was copied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub offset: u64,
+    pub length: u64,
+    pub copy: CopyMode,
+    /// The filesystem whose allocation was read, for `CopyMode::UsedBlocks`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<String>,
+    /// The copied extents, for `CopyMode::UsedBlocks`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extents: Vec<Extent>,
+    /// The signature of the swap area in the region, recreated on restore, for `CopyMode::Excluded`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<SwapSignature>,
+}
+
+impl Region {
+    /// Creates a region copied completely.
+    pub fn full(offset: u64, length: u64) -> Region {
+        Region {
+            offset,
+            length,
+            copy: CopyMode::Full,
+            filesystem: None,
+            extents: Vec::new(),
+            swap: None,
+        }
+    }
+
+    /// Creates a region of an excluded partition, with the signature of its swap area if it has one.
+    pub fn excluded(offset: u64, length: u64, swap: Option<SwapSignature>) -> Region {
+        Region {
+            offset,
+            length,
+            copy: CopyMode::Excluded,
+            filesystem: swap.as_ref().map(|_| "swap".to_string()),
+            extents: Vec::new(),
+            swap,
+        }
+    }
+
+    /// Returns the extents of the region holding data in the image.
+    pub fn data_extents(&self) -> Vec<Extent> {
+        match self.copy {
+            CopyMode::Full => vec![Extent::new(self.offset, self.length)],
+            CopyMode::UsedBlocks => self.extents.clone(),
+            CopyMode::Excluded => Vec::new(),
+        }
+    }
+}
+
+/// Describes how an image was written, stored next to it as `<image>.json`.
+///
+/// Images without this file are full copies of their source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// The path of the device the image was taken from.
+    pub source: String,
+    /// The size of the image in bytes.
+    pub size: u64,
+    /// The size of the device in bytes, if the image was trimmed to the end of its last partition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_size: Option<u64>,
+    /// The regions of the image in ascending order, covering it completely.
+    pub regions: Vec<Region>,
+    /// The extents of the device which could not be read, filled with a marker in the image.
+    ///
+    /// An image with unreadable extents is degraded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unreadable: Vec<Extent>,
+}
+
+impl ImageMetadata {
+    /// Returns the path of the metadata file of an image.
+    pub fn path(image: &str) -> String {
+        format!("{}.json", image)
+    }
+
+    /// Reads the metadata of an image.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(ImageMetadata))`: If the image has a metadata file.
+    /// - `Ok(None)`: If it hasn't, like images of full copies.
+    /// - `Err(String)`: If the metadata file is not readable.
+    pub fn read(image: &str) -> Result<Option<ImageMetadata>, String> {
+        let path = Self::path(image);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Writes the metadata file of an image.
+    pub fn write(&self, image: &str) -> Result<(), String> {
+        let path = Self::path(image);
+        let content = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(&path, content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Returns whether any region was copied partially.
+    pub fn is_sparse(&self) -> bool {
+        self.regions
+            .iter()
+            .any(|region| region.copy != CopyMode::Full)
+    }
+
+    /// Returns whether parts of the device could not be read, so that the image is not a good copy.
+    pub fn is_degraded(&self) -> bool {
+        !self.unreadable.is_empty()
+    }
+
+    /// Returns the merged extents holding data in the image.
+    pub fn data_extents(&self) -> Vec<Extent> {
+        merge(
+            self.regions
+                .iter()
+                .flat_map(|region| region.data_extents())
+                .collect(),
+        )
+    }
+
+    /// Returns the number of bytes holding data in the image.
+    pub fn data_bytes(&self) -> u64 {
+        total_length(&self.data_extents())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: 4096,
+            source_size: None,
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 1024),
+                Region {
+                    offset: 1024,
+                    length: 3072,
+                    copy: CopyMode::UsedBlocks,
+                    filesystem: Some("ext".to_string()),
+                    extents: vec![Extent::new(1024, 1024), Extent::new(3072, 512)],
+                    swap: None,
+                },
+            ],
+        };
+        let image = std::env::temp_dir()
+            .join(format!(
+                "dd_backup_metadata_test_{}.img",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string();
+
+        assert_eq!(ImageMetadata::read(&image), Ok(None));
+        metadata.write(&image).unwrap();
+        let read = ImageMetadata::read(&image).unwrap();
+        fs::remove_file(ImageMetadata::path(&image)).unwrap();
+
+        assert_eq!(read, Some(metadata.clone()));
+        assert!(metadata.is_sparse());
+        assert_eq!(
+            metadata.data_extents(),
+            vec![Extent::new(0, 2048), Extent::new(3072, 512)]
+        );
+        assert_eq!(metadata.data_bytes(), 2560);
+    }
+}
@@ -0,0 +1,343 @@
+use std::fmt::Write as _;
+
+use clap::Args;
+use serde::Serialize;
+
+use super::{
+    config::ImageFormat,
+    convert::{open_image, DiskReader},
+    extent::Extent,
+    image_metadata::{CopyMode, ImageMetadata},
+    partition_table::PartitionTable,
+    superblock::{probe, FilesystemInfo},
+};
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// The path to the image, a raw, qcow2 or VMDK image.
+    pub image: String,
+
+    #[clap(long, default_value = "false")]
+    /// Prints the result as JSON instead of a table.
+    pub json: bool,
+}
+
+/// What an image contains, read without mounting it.
+#[derive(Debug, Serialize)]
+pub struct ImageInspection {
+    pub image: String,
+    pub format: ImageFormat,
+    /// The size of the virtual disk in bytes.
+    pub size: u64,
+    pub partition_table: Option<TableInfo>,
+    pub partitions: Vec<PartitionInfo>,
+    /// The filesystem of an image without partition table, like the image of a partition.
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableInfo {
+    /// `gpt` or `dos`, like the `label` of `sfdisk --dump`.
+    pub label: String,
+    pub id: String,
+    pub sector_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionInfo {
+    pub number: u32,
+    /// The byte offset of the partition in the image.
+    pub offset: u64,
+    /// The size of the partition in bytes.
+    pub size: u64,
+    /// The type GUID for GPT, the type byte for MBR.
+    pub type_id: String,
+    pub uuid: Option<String>,
+    pub name: Option<String>,
+    /// Whether the partition was left out of the image with `exclude_partitions`.
+    pub excluded: bool,
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+/// Runs the `inspect` subcommand, printing the partitions and filesystems of an image.
+pub fn run(inspect_args: &InspectArgs) -> Result<(), String> {
+    let inspection = inspect(&inspect_args.image)?;
+    match inspect_args.json {
+        true => println!(
+            "{}",
+            serde_json::to_string_pretty(&inspection).map_err(|e| e.to_string())?
+        ),
+        false => print!("{}", inspection.to_table()),
+    }
+    Ok(())
+}
+
+/// Reads the partition table of an image and probes the superblocks of its partitions.
+///
+/// The image is read at the offsets needed only, so that qcow2 and VMDK images are read without conversion.
+/// Partitions excluded from sparse images are marked by their metadata, their filesystem was not copied.
+///
+/// # Returns
+///
+/// - `Ok(ImageInspection)`: The partitions and filesystems of the image.
+/// - `Err(String)`: If the image or its partition table is not readable.
+pub fn inspect(image: &str) -> Result<ImageInspection, String> {
+    let (mut reader, size, format) = open_image(image)?;
+    let excluded: Vec<Extent> = match ImageMetadata::read(image)? {
+        Some(metadata) if format == ImageFormat::Raw => metadata
+            .regions
+            .iter()
+            .filter(|region| region.copy == CopyMode::Excluded)
+            .map(|region| Extent::new(region.offset, region.length))
+            .collect(),
+        _ => vec![],
+    };
+
+    // a FAT boot sector looks like an MBR, so a filesystem at the start rules out a partition table
+    let filesystem = probe_or_warn(reader.as_mut(), 0, size);
+    let table = match filesystem {
+        Some(_) => None,
+        None => PartitionTable::read(&mut reader)?,
+    };
+    let Some(table) = table else {
+        return Ok(ImageInspection {
+            image: image.to_string(),
+            format,
+            size,
+            partition_table: None,
+            partitions: vec![],
+            filesystem,
+        });
+    };
+    let partitions = table
+        .partitions
+        .iter()
+        .map(|partition| {
+            let offset = partition.start * table.sector_size;
+            let length = partition.size * table.sector_size;
+            let is_excluded = excluded.iter().any(|region| region.offset == offset);
+            PartitionInfo {
+                number: partition.number,
+                offset,
+                size: length,
+                type_id: partition.type_id.clone(),
+                uuid: partition.uuid.clone(),
+                name: partition.name.clone(),
+                excluded: is_excluded,
+                filesystem: match partition.is_extended() || is_excluded {
+                    true => None,
+                    false => probe_or_warn(reader.as_mut(), offset, length),
+                },
+            }
+        })
+        .collect();
+    Ok(ImageInspection {
+        image: image.to_string(),
+        format,
+        size,
+        partition_table: Some(TableInfo {
+            label: table.label.name().to_string(),
+            id: table.id.clone(),
+            sector_size: table.sector_size,
+        }),
+        partitions,
+        filesystem: None,
+    })
+}
+
+/// Probes the superblock at `offset`, a broken filesystem is logged and shown without filesystem.
+fn probe_or_warn(
+    mut reader: &mut dyn DiskReader,
+    offset: u64,
+    length: u64,
+) -> Option<FilesystemInfo> {
+    probe(&mut reader, offset, length).unwrap_or_else(|e| {
+        warn!("Failed to probe the filesystem at {}: {}", offset, e);
+        None
+    })
+}
+
+impl ImageInspection {
+    /// Formats the inspection as a table with one row per partition.
+    pub fn to_table(&self) -> String {
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "image: {} ({:?}, {} bytes)",
+            self.image, self.format, self.size
+        );
+        match &self.partition_table {
+            Some(info) => {
+                let _ = writeln!(
+                    table,
+                    "partition table: {}, id {}, sector size {}",
+                    info.label, info.id, info.sector_size
+                );
+            }
+            None => {
+                let _ = writeln!(table, "partition table: none");
+            }
+        }
+        let _ = writeln!(
+            table,
+            "{:<3} {:>14} {:>14} {:<36} {:<16} {:<12} {:<36} {:<16} {:>14}",
+            "NR", "OFFSET", "SIZE", "TYPE", "NAME", "FSTYPE", "UUID", "LABEL", "USED"
+        );
+        let rows = self
+            .partitions
+            .iter()
+            .map(|partition| {
+                (
+                    partition.number.to_string(),
+                    partition.offset,
+                    partition.size,
+                    partition.type_id.as_str(),
+                    partition.name.as_deref(),
+                    partition.excluded,
+                    partition.filesystem.as_ref(),
+                )
+            })
+            .chain(self.filesystem.iter().map(|filesystem| {
+                (
+                    "-".to_string(),
+                    0,
+                    self.size,
+                    "-",
+                    None,
+                    false,
+                    Some(filesystem),
+                )
+            }));
+        for (number, offset, size, type_id, name, excluded, filesystem) in rows {
+            let fstype = match (excluded, filesystem) {
+                (true, _) => "(excluded)",
+                (false, Some(filesystem)) => filesystem.fstype.as_str(),
+                (false, None) => "-",
+            };
+            let _ = writeln!(
+                table,
+                "{:<3} {:>14} {:>14} {:<36} {:<16} {:<12} {:<36} {:<16} {:>14}",
+                number,
+                offset,
+                size,
+                type_id,
+                name.unwrap_or("-"),
+                fstype,
+                filesystem.and_then(|f| f.uuid.as_deref()).unwrap_or("-"),
+                filesystem.and_then(|f| f.label.as_deref()).unwrap_or("-"),
+                filesystem
+                    .and_then(|f| f.used)
+                    .map_or("-".to_string(), |used| used.to_string()),
+            );
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::run::{
+        allocation::tests::fat_image,
+        image_metadata::Region,
+        partition_table::tests::{gpt_disk, EFI_SYSTEM, LINUX_FILESYSTEM},
+        swap::SwapSignature,
+    };
+
+    #[test]
+    fn test_inspect() {
+        let dir =
+            std::env::temp_dir().join(format!("dd_backup_inspect_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+
+        // a FAT partition, a swap partition and an excluded partition
+        let mut disk = gpt_disk(
+            40 * 2048 + 34,
+            &[
+                (EFI_SYSTEM, 2048, 34815, "EFI System"),
+                (LINUX_FILESYSTEM, 34816, 36863, "swap"),
+                (LINUX_FILESYSTEM, 36864, 38911, "tmp"),
+            ],
+        );
+        let mut fat = fat_image(&[2]);
+        fat[0x2B..0x36].copy_from_slice(b"EFI        ");
+        disk[2048 * 512..2048 * 512 + fat.len()].copy_from_slice(&fat);
+        let swap = SwapSignature {
+            page_size: 4096,
+            uuid: "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9".to_string(),
+            label: None,
+        }
+        .to_bytes(1024 * 1024)
+        .unwrap();
+        disk[34816 * 512..34816 * 512 + swap.len()].copy_from_slice(&swap);
+        fs::write(&image, &disk).unwrap();
+        ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: disk.len() as u64,
+            source_size: None,
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 36864 * 512),
+                Region::excluded(36864 * 512, 2048 * 512, None),
+                Region::full(38912 * 512, disk.len() as u64 - 38912 * 512),
+            ],
+        }
+        .write(&image)
+        .unwrap();
+
+        let inspection = inspect(&image).unwrap();
+        let table = inspection.to_table();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(inspection.format, ImageFormat::Raw);
+        assert_eq!(inspection.partition_table.as_ref().unwrap().label, "gpt");
+        let partitions = &inspection.partitions;
+        assert_eq!(partitions.len(), 3);
+        assert_eq!(
+            (partitions[0].offset, partitions[0].size),
+            (2048 * 512, 16 * 1024 * 1024)
+        );
+        let fat = partitions[0].filesystem.as_ref().unwrap();
+        assert_eq!(
+            (fat.fstype.as_str(), fat.label.as_deref()),
+            ("vfat", Some("EFI"))
+        );
+        assert_eq!(
+            partitions[1].filesystem.as_ref().unwrap().uuid.as_deref(),
+            Some("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9")
+        );
+        assert!(partitions[2].excluded && partitions[2].filesystem.is_none());
+        assert!(table.contains("partition table: gpt"), "{}", table);
+        assert!(table
+            .lines()
+            .any(|line| line.starts_with("2 ") && line.contains("swap")));
+        assert!(table
+            .lines()
+            .any(|line| line.starts_with("3 ") && line.contains("(excluded)")));
+        assert!(serde_json::to_string(&inspection)
+            .unwrap()
+            .contains("\"fstype\":\"vfat\""));
+    }
+
+    #[test]
+    fn test_inspect_partition_image() {
+        let image = std::env::temp_dir()
+            .join(format!(
+                "dd_backup_inspect_test_{}_part1.img",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&image, fat_image(&[2, 3])).unwrap();
+
+        let inspection = inspect(&image).unwrap();
+        fs::remove_file(&image).unwrap();
+
+        assert!(inspection.partition_table.is_none());
+        assert_eq!(inspection.filesystem.unwrap().fstype, "vfat");
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fs::{self, File},
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::{Args, Subcommand};
+
+use super::{
+    backup_run::{
+        command_output::{CommandRunner, SystemCommandRunner},
+        device::Device,
+        lsblk::Lsblk,
+    },
+    config::DeviceIdentifier,
+    partition_table::{relocate_gpt, Label, PartitionTable},
+};
+
+#[derive(Args, Debug)]
+pub struct LayoutArgs {
+    #[command(subcommand)]
+    pub command: LayoutCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LayoutCommands {
+    /// Prints the partition table saved with an image, like `sfdisk --dump`
+    List {
+        /// The path to the image, its `.head.bin` file is read if present.
+        image: String,
+    },
+    /// Writes the partition table saved with an image to a device, which may be of another size
+    Restore(RestoreLayoutArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RestoreLayoutArgs {
+    /// The path to the image, its `.head.bin` and `.tail.bin` files are read if present.
+    pub image: String,
+
+    #[clap(long)]
+    /// The device to write the partition table to as <KIND>=<VALUE>, KIND is one of
+    /// serial, wwn, by_id, by_path, partuuid, fs_uuid or path.
+    pub target: DeviceIdentifier,
+
+    #[clap(short = 'n', long, default_value = "false")]
+    /// Prints the commands writing the partition table without running them.
+    pub dry_run: bool,
+}
+
+/// A range of a file to be written to the target device.
+#[derive(Debug, PartialEq, Eq)]
+struct LayoutWrite {
+    /// The file to read from its start.
+    source: PathBuf,
+    /// The number of bytes to write.
+    length: u64,
+    /// The byte offset on the target device.
+    offset: u64,
+    /// Whether `source` was created for this restore and is removed afterwards.
+    is_temporary: bool,
+}
+
+/// Runs the `layout` subcommand.
+pub fn run(layout_args: &LayoutArgs) -> Result<(), String> {
+    match &layout_args.command {
+        LayoutCommands::List { image } => {
+            let table = read_partition_table(image)?;
+            print!("{}", table.to_sfdisk(image));
+            Ok(())
+        }
+        LayoutCommands::Restore(restore_args) => {
+            restore(restore_args, Arc::new(SystemCommandRunner), Lsblk::new)
+        }
+    }
+}
+
+/// Reads the partition table saved with an image, from `<image>.head.bin` or the start of the image.
+///
+/// # Returns
+///
+/// - `Ok(PartitionTable)`: The partition table of the image.
+/// - `Err(String)`: If the image is not readable or has no partition table.
+pub fn read_partition_table(image: &str) -> Result<PartitionTable, String> {
+    let head_path = head_path(image);
+    let mut head = File::open(&head_path).map_err(|e| format!("{}: {}", head_path, e))?;
+    PartitionTable::read(&mut head)?.ok_or(format!("No partition table found in {}", head_path))
+}
+
+/// Writes the partition table saved with an image to the target device.
+///
+/// The first MiB of the image is written to the start of the device. For GPT the backup GPT is
+/// written to the end of the device, taken from `<image>.tail.bin` if the device has the size of the
+/// original one, and relocated to the end of the device otherwise.
+///
+/// # Arguments
+///
+/// * `restore_args` - The image, the target device and whether to do a dry run.
+/// * `runner` - The runner executing the `dd` commands.
+/// * `read_block_devices` - Reads the available block devices to find the target device.
+///
+/// # Returns
+///
+/// - `Ok(())`: If the partition table was written, or would have been on a dry run.
+/// - `Err(String)`: If the target is not found, is mounted, too small or writing failed.
+fn restore(
+    restore_args: &RestoreLayoutArgs,
+    runner: Arc<dyn CommandRunner>,
+    read_block_devices: fn(&dyn CommandRunner) -> Result<Lsblk, String>,
+) -> Result<(), String> {
+    let lsblk = read_block_devices(runner.as_ref())?;
+    let (blockdevice, device_path) =
+        Device::validate_identifier(&restore_args.target, &lsblk.available_devices)?;
+    if blockdevice.is_partition() {
+        return Err(format!(
+            "Target {} is a partition, the partition table is written to whole devices only",
+            device_path
+        ));
+    }
+    if Device::is_device_or_partition_mounted(&device_path)? {
+        return Err(format!("Target {} is mounted", device_path));
+    }
+
+    let writes = layout_writes(&restore_args.image, blockdevice.size)?;
+    let result = writes.iter().try_for_each(|write| {
+        let input_file_arg = format!("if={}", write.source.to_string_lossy());
+        let output_file_arg = format!("of={}", device_path);
+        let count_arg = format!("count={}", write.length);
+        let seek_arg = format!("seek={}", write.offset);
+        let command_parts = vec![
+            "dd",
+            &input_file_arg,
+            &output_file_arg,
+            "iflag=count_bytes",
+            &count_arg,
+            "oflag=seek_bytes",
+            &seek_arg,
+            "conv=notrunc,fsync",
+        ];
+        match restore_args.dry_run {
+            true => {
+                info!(
+                    "[DRY RUN] layout restore would run with command: {}",
+                    command_parts.join(" ")
+                );
+                Ok(())
+            }
+            false => runner
+                .output(command_parts, "write partition table", Some(true))
+                .map(|_| ()),
+        }
+    });
+
+    for write in writes.iter().filter(|write| write.is_temporary) {
+        let _ = fs::remove_file(&write.source);
+    }
+    result?;
+    if !restore_args.dry_run {
+        info!(
+            "Restored partition table of {} to {}, the kernel may need to re-read it (`partprobe {}`)",
+            restore_args.image, device_path, device_path
+        );
+    }
+    Ok(())
+}
+
+/// Returns the writes restoring the partition table saved with `image` to a device of `target_size` bytes.
+fn layout_writes(image: &str, target_size: u64) -> Result<Vec<LayoutWrite>, String> {
+    let table = read_partition_table(image)?;
+    if table.end_of_last_partition() > target_size {
+        return Err(format!(
+            "Target of {} bytes is too small for the partitions of {}, which end at byte {}",
+            target_size,
+            image,
+            table.end_of_last_partition()
+        ));
+    }
+
+    let head_path = head_path(image);
+    let mut head = Vec::new();
+    File::open(&head_path)
+        .and_then(|file| {
+            file.take(Device::PARTITION_TABLE_LENGTH)
+                .read_to_end(&mut head)
+        })
+        .map_err(|e| format!("{}: {}", head_path, e))?;
+    let head_length = (head.len() as u64).min(target_size);
+
+    let tail_path = format!("{}.tail.bin", image);
+    let tail_length = fs::metadata(&tail_path).map(|metadata| metadata.len()).ok();
+    match (table.label, tail_length) {
+        (Label::Dos, _) => Ok(vec![LayoutWrite {
+            source: PathBuf::from(head_path),
+            length: head_length,
+            offset: 0,
+            is_temporary: false,
+        }]),
+        (Label::Gpt, Some(tail_length)) if table.disk_size == Some(target_size) => Ok(vec![
+            LayoutWrite {
+                source: PathBuf::from(head_path),
+                length: head_length,
+                offset: 0,
+                is_temporary: false,
+            },
+            LayoutWrite {
+                source: PathBuf::from(tail_path),
+                length: tail_length,
+                offset: target_size - tail_length,
+                is_temporary: false,
+            },
+        ]),
+        (Label::Gpt, _) => {
+            let relocated = relocate_gpt(&mut Cursor::new(&head), target_size)?
+                .ok_or(format!("No GPT found in {}", head_path))?;
+            info!(
+                "Relocating the backup GPT of {} from byte {} to byte {}",
+                image,
+                table.disk_size.unwrap_or_default(),
+                target_size
+            );
+            let header_offset = relocated.header_offset as usize;
+            head[header_offset..header_offset + relocated.header.len()]
+                .copy_from_slice(&relocated.header);
+
+            let head_source = temporary_file("head.bin", &head[..head_length as usize])?;
+            let tail_source = temporary_file("tail.bin", &relocated.tail)?;
+            Ok(vec![
+                LayoutWrite {
+                    source: head_source,
+                    length: head_length,
+                    offset: 0,
+                    is_temporary: true,
+                },
+                LayoutWrite {
+                    source: tail_source,
+                    length: relocated.tail.len() as u64,
+                    offset: relocated.tail_offset,
+                    is_temporary: true,
+                },
+            ])
+        }
+    }
+}
+
+/// Returns `<image>.head.bin` if present, the image itself otherwise, like partition table images.
+fn head_path(image: &str) -> String {
+    let head_path = format!("{}.head.bin", image);
+    match Path::new(&head_path).exists() {
+        true => head_path,
+        false => image.to_string(),
+    }
+}
+
+fn temporary_file(name: &str, content: &[u8]) -> Result<PathBuf, String> {
+    let path =
+        std::env::temp_dir().join(format!("dd_backup_layout_{}_{}", std::process::id(), name));
+    fs::write(&path, content).map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::backup_run::command_output::fake::{FakeCommandRunner, FakeResponse};
+    use crate::run::partition_table::tests::{dos_disk, gpt_disk};
+
+    const LINUX_FILESYSTEM: [u8; 16] = [
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ];
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_layout_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Saves the image of `disk` like a backup does, with `.head.bin` and `.tail.bin` files.
+    fn save_image(dir: &Path, disk: &[u8]) -> String {
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let length = Device::PARTITION_TABLE_LENGTH as usize;
+        fs::write(&image, disk).unwrap();
+        fs::write(format!("{}.head.bin", image), &disk[..length]).unwrap();
+        fs::write(format!("{}.tail.bin", image), &disk[disk.len() - length..]).unwrap();
+        image
+    }
+
+    /// Applies the writes to an empty disk of `size` bytes like `dd` would.
+    fn apply(writes: &[LayoutWrite], size: u64) -> Vec<u8> {
+        let mut disk = vec![0u8; size as usize];
+        for write in writes {
+            let content = fs::read(&write.source).unwrap();
+            let offset = write.offset as usize;
+            disk[offset..offset + write.length as usize]
+                .copy_from_slice(&content[..write.length as usize]);
+            if write.is_temporary {
+                fs::remove_file(&write.source).unwrap();
+            }
+        }
+        disk
+    }
+
+    #[test]
+    fn test_layout_writes_gpt() {
+        let dir = test_dir("gpt");
+        let disk = gpt_disk(8192, &[(LINUX_FILESYSTEM, 2048, 6143, "data")]);
+        let image = save_image(&dir, &disk);
+
+        // same size, head and tail are written as saved
+        let writes = layout_writes(&image, disk.len() as u64).unwrap();
+        assert_eq!(writes.len(), 2);
+        assert!(!writes[1].is_temporary);
+        assert_eq!(apply(&writes, disk.len() as u64), disk);
+
+        // bigger disk, the backup GPT is relocated to its end
+        let writes = layout_writes(&image, 16384 * 512).unwrap();
+        let restored = apply(&writes, 16384 * 512);
+        let table = PartitionTable::read(&mut Cursor::new(&restored))
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.disk_size, Some(16384 * 512));
+        assert_eq!(table.last_lba, Some(16384 - 34));
+        assert_eq!(table.partitions[0].end(), 6144);
+
+        // too small disk
+        assert!(layout_writes(&image, 6000 * 512)
+            .unwrap_err()
+            .contains("too small"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_layout_writes_dos_from_partition_table_image() {
+        let dir = test_dir("dos");
+        let disk = dos_disk(4096, &[(0x83, 2048, 2048, true)]);
+        let image = dir.join("disk_ptable.img").to_string_lossy().to_string();
+        fs::write(&image, &disk[..Device::PARTITION_TABLE_LENGTH as usize]).unwrap();
+
+        assert_eq!(
+            read_partition_table(&image).unwrap().partitions[0].start,
+            2048
+        );
+        let writes = layout_writes(&image, 8192 * 512).unwrap();
+        assert_eq!(
+            writes,
+            vec![LayoutWrite {
+                source: PathBuf::from(&image),
+                length: Device::PARTITION_TABLE_LENGTH,
+                offset: 0,
+                is_temporary: false,
+            }]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_writes_with_dd() {
+        let dir = test_dir("restore");
+        let disk = gpt_disk(8192, &[(LINUX_FILESYSTEM, 2048, 6143, "data")]);
+        let image = save_image(&dir, &disk);
+        let target = dir.join("target.img");
+        fs::write(&target, vec![0u8; disk.len()]).unwrap();
+        let restore_args = RestoreLayoutArgs {
+            image: image.clone(),
+            target: DeviceIdentifier::Path(target.to_string_lossy().to_string()),
+            dry_run: false,
+        };
+        let runner = Arc::new(
+            FakeCommandRunner::new().script("lsblk", FakeResponse::ok(r#"{"blockdevices": []}"#)),
+        );
+
+        restore(&restore_args, runner.clone(), Lsblk::from_lsblk).unwrap();
+
+        let target = target.to_string_lossy();
+        assert_eq!(
+            runner.commands_of(&["dd"]),
+            vec![
+                format!(
+                    "dd if={}.head.bin of={} iflag=count_bytes count=1048576 oflag=seek_bytes seek=0 conv=notrunc,fsync",
+                    image, target
+                ),
+                format!(
+                    "dd if={}.tail.bin of={} iflag=count_bytes count=1048576 oflag=seek_bytes seek=3145728 conv=notrunc,fsync",
+                    image, target
+                ),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,30 @@
+mod allocation;
 pub mod backup_run;
 mod config;
+mod convert;
+mod extent;
+mod extract;
+mod image_metadata;
+mod inspect;
+mod layout;
+mod mount_image;
+mod partition_table;
+mod qcow2;
+mod restore;
+mod superblock;
+mod swap;
 pub mod utils;
+mod vmdk;
 
 use clap::{Parser, Subcommand};
 
 use self::backup_run::{run as backup_run, BackupArgs};
+use self::convert::{run as convert_run, ConvertArgs};
+use self::extract::{run as extract_run, ExtractArgs};
+use self::inspect::{run as inspect_run, InspectArgs};
+use self::layout::{run as layout_run, LayoutArgs};
+use self::mount_image::{mount_run, umount_run, MountImageArgs, UmountImageArgs};
+use self::restore::{run as restore_run, RestoreArgs};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -17,7 +37,21 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Perform the backups
-    Run(BackupArgs),
+    Run(Box<BackupArgs>),
+    /// List or restore the partition table saved with an image
+    Layout(LayoutArgs),
+    /// Write an image back to a device
+    Restore(RestoreArgs),
+    /// Convert a raw image to qcow2 or VMDK
+    Convert(ConvertArgs),
+    /// List the partitions and filesystems of an image without mounting it
+    Inspect(InspectArgs),
+    /// Mount the partitions of an image read-only to browse their files
+    MountImage(MountImageArgs),
+    /// Unmount an image mounted with mount-image
+    UmountImage(UmountImageArgs),
+    /// Write a single partition of an image to a file or device
+    Extract(ExtractArgs),
 }
 
 /// Runs the backup process.
@@ -35,5 +69,26 @@ pub fn run() -> Result<(), String> {
         Commands::Run(backup_args) => {
             backup_run(backup_args).map_err(|e| format!("Failed to run backups: {}", e))
         }
+        Commands::Layout(layout_args) => {
+            layout_run(layout_args).map_err(|e| format!("Failed to run layout: {}", e))
+        }
+        Commands::Restore(restore_args) => {
+            restore_run(restore_args).map_err(|e| format!("Failed to restore image: {}", e))
+        }
+        Commands::Convert(convert_args) => {
+            convert_run(convert_args).map_err(|e| format!("Failed to convert image: {}", e))
+        }
+        Commands::Inspect(inspect_args) => {
+            inspect_run(inspect_args).map_err(|e| format!("Failed to inspect image: {}", e))
+        }
+        Commands::MountImage(mount_args) => {
+            mount_run(mount_args).map_err(|e| format!("Failed to mount image: {}", e))
+        }
+        Commands::UmountImage(umount_args) => {
+            umount_run(umount_args).map_err(|e| format!("Failed to unmount image: {}", e))
+        }
+        Commands::Extract(extract_args) => {
+            extract_run(extract_args).map_err(|e| format!("Failed to extract partition: {}", e))
+        }
     }
 }
@@ -0,0 +1,393 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    backup_run::command_output::{CommandRunner, SystemCommandRunner},
+    config::ImageFormat,
+    inspect::inspect,
+    superblock::FilesystemInfo,
+};
+
+/// The file in the mount directory recording what `umount-image` tears down.
+const STATE_FILE: &str = ".dd_backup_mount.json";
+/// Filesystems which have no files to browse.
+const UNMOUNTABLE_FSTYPES: [&str; 2] = ["swap", "crypto_LUKS"];
+
+#[derive(Args, Debug)]
+pub struct MountImageArgs {
+    /// The path to the raw image to mount.
+    pub image: String,
+
+    /// The directory to mount the partitions in, each in a subdirectory named by its number and label.
+    pub directory: String,
+}
+
+#[derive(Args, Debug)]
+pub struct UmountImageArgs {
+    /// The directory the image was mounted in with `mount-image`.
+    pub directory: String,
+}
+
+/// The loop device and the mountpoints of a mounted image, saved in the mount directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MountState {
+    image: String,
+    loop_device: String,
+    /// The mountpoints in the order they were mounted.
+    mountpoints: Vec<String>,
+}
+
+impl MountState {
+    fn path(directory: &str) -> PathBuf {
+        Path::new(directory).join(STATE_FILE)
+    }
+
+    fn read(directory: &str) -> Result<Option<MountState>, String> {
+        let path = Self::path(directory);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn write(&self, directory: &str) -> Result<(), String> {
+        let path = Self::path(directory);
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Runs the `mount-image` subcommand.
+pub fn mount_run(mount_args: &MountImageArgs) -> Result<(), String> {
+    mount(mount_args, Arc::new(SystemCommandRunner))
+}
+
+/// Runs the `umount-image` subcommand.
+pub fn umount_run(umount_args: &UmountImageArgs) -> Result<(), String> {
+    umount(&umount_args.directory, Arc::new(SystemCommandRunner))
+}
+
+/// Mounts the partitions of an image read-only for browsing their files.
+///
+/// The image is attached to a read-only loop device with partition scanning, each partition with a
+/// filesystem is mounted read-only at `<directory>/<number>` or `<directory>/<number>_<label>`.
+/// An image without partition table, like the image of a partition, is mounted as number 0.
+/// The loop device and mountpoints are recorded in `<directory>/.dd_backup_mount.json`,
+/// if mounting fails everything mounted so far is torn down again.
+///
+/// # Arguments
+///
+/// * `mount_args` - The image and the directory to mount it in.
+/// * `runner` - The runner executing `losetup` and `mount`.
+///
+/// # Returns
+///
+/// - `Ok(())`: If at least one filesystem was mounted.
+/// - `Err(String)`: If the image is not raw, the directory holds a mounted image, or mounting failed.
+fn mount(mount_args: &MountImageArgs, runner: Arc<dyn CommandRunner>) -> Result<(), String> {
+    let image = &mount_args.image;
+    let directory = &mount_args.directory;
+    let inspection = inspect(image)?;
+    if inspection.format != ImageFormat::Raw {
+        return Err(format!(
+            "{} is a {:?} image, loop devices need raw images, convert it like: `qemu-img convert -O raw {} <image>.img`",
+            image, inspection.format, image
+        ));
+    }
+    if MountState::read(directory)?.is_some() {
+        return Err(format!(
+            "An image is already mounted in {}, unmount it first with `dd_backup umount-image {}`",
+            directory, directory
+        ));
+    }
+    let filesystems: Vec<(u32, &FilesystemInfo)> = match inspection.partition_table {
+        Some(_) => inspection
+            .partitions
+            .iter()
+            .filter_map(|partition| {
+                partition
+                    .filesystem
+                    .as_ref()
+                    .map(|filesystem| (partition.number, filesystem))
+            })
+            .collect(),
+        None => inspection
+            .filesystem
+            .iter()
+            .map(|filesystem| (0, filesystem))
+            .collect(),
+    };
+    let (mountable, unmountable): (Vec<_>, Vec<_>) = filesystems
+        .into_iter()
+        .partition(|(_, filesystem)| !UNMOUNTABLE_FSTYPES.contains(&filesystem.fstype.as_str()));
+    for (number, filesystem) in unmountable {
+        info!(
+            "Skipping partition {} of {}, it holds {}",
+            number, image, filesystem.fstype
+        );
+    }
+    if mountable.is_empty() {
+        return Err(format!("No mountable filesystem found in {}", image));
+    }
+    fs::create_dir_all(directory).map_err(|e| format!("{}: {}", directory, e))?;
+
+    let output = runner.output(
+        vec![
+            "losetup",
+            "--find",
+            "--show",
+            "--read-only",
+            "--partscan",
+            image,
+        ],
+        &format!("attach {} to a loop device", image),
+        Some(true),
+    )?;
+    let loop_device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if loop_device.is_empty() {
+        return Err(format!("losetup printed no loop device for {}", image));
+    }
+    let mut state = MountState {
+        image: image.clone(),
+        loop_device: loop_device.clone(),
+        mountpoints: vec![],
+    };
+    state.write(directory)?;
+
+    for (number, filesystem) in mountable {
+        let device = match number {
+            0 => loop_device.clone(),
+            number => format!("{}p{}", loop_device, number),
+        };
+        let mountpoint = Path::new(directory)
+            .join(mountpoint_name(number, filesystem))
+            .to_string_lossy()
+            .to_string();
+        let mounted = fs::create_dir_all(&mountpoint)
+            .map_err(|e| format!("{}: {}", mountpoint, e))
+            .and_then(|_| {
+                runner.output(
+                    vec![
+                        "mount",
+                        "-o",
+                        mount_options(&filesystem.fstype),
+                        &device,
+                        &mountpoint,
+                    ],
+                    &format!("mount {} at {}", device, mountpoint),
+                    Some(true),
+                )
+            });
+        if let Err(e) = mounted {
+            let _ = fs::remove_dir(&mountpoint);
+            if let Err(teardown_error) = umount(directory, runner.clone()) {
+                warn!("Failed to unmount {}: {}", directory, teardown_error);
+            }
+            return Err(e);
+        }
+        info!(
+            "Mounted partition {} of {} at {}",
+            number, image, mountpoint
+        );
+        state.mountpoints.push(mountpoint);
+        state.write(directory)?;
+    }
+    Ok(())
+}
+
+/// Unmounts an image mounted with `mount-image`: the mountpoints in reverse order,
+/// then the loop device is detached and the state file is removed.
+///
+/// # Returns
+///
+/// - `Ok(())`: If everything was unmounted.
+/// - `Err(String)`: If no image is mounted in the directory or unmounting failed.
+fn umount(directory: &str, runner: Arc<dyn CommandRunner>) -> Result<(), String> {
+    let mut state = MountState::read(directory)?
+        .ok_or(format!("No image mounted with dd_backup in {}", directory))?;
+    while let Some(mountpoint) = state.mountpoints.last().cloned() {
+        runner.output(
+            vec!["umount", &mountpoint],
+            &format!("unmount {}", mountpoint),
+            Some(true),
+        )?;
+        if let Err(e) = fs::remove_dir(&mountpoint) {
+            warn!("Failed to remove mountpoint {}: {}", mountpoint, e);
+        }
+        state.mountpoints.pop();
+        state.write(directory)?;
+    }
+    runner.output(
+        vec!["losetup", "--detach", &state.loop_device],
+        &format!("detach loop device {}", state.loop_device),
+        Some(true),
+    )?;
+    let path = MountState::path(directory);
+    fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!(
+        "Unmounted {} and detached {}",
+        state.image, state.loop_device
+    );
+    Ok(())
+}
+
+/// Returns the name of the mountpoint of a partition, its number followed by its label, if any.
+fn mountpoint_name(number: u32, filesystem: &FilesystemInfo) -> String {
+    match &filesystem.label {
+        Some(label) => format!(
+            "{}_{}",
+            number,
+            label.replace(|c: char| c == '/' || c.is_whitespace(), "-")
+        ),
+        None => number.to_string(),
+    }
+}
+
+/// Returns the read-only mount options, journals are not replayed since the loop device is read-only.
+fn mount_options(fstype: &str) -> &'static str {
+    match fstype {
+        "ext3" | "ext4" => "ro,noload",
+        "xfs" => "ro,norecovery",
+        _ => "ro",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{
+        allocation::tests::fat_image,
+        backup_run::command_output::fake::{FakeCommandRunner, FakeResponse},
+        partition_table::tests::{gpt_disk, EFI_SYSTEM, LINUX_FILESYSTEM},
+        swap::SwapSignature,
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_mount_image_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Writes a disk image with two FAT partitions, the second one labeled, and a swap partition.
+    fn disk_image(dir: &Path) -> String {
+        let mut disk = gpt_disk(
+            34 * 2048 + 2048 + 34,
+            &[
+                (EFI_SYSTEM, 2048, 34815, "EFI System"),
+                (LINUX_FILESYSTEM, 34816, 36863, "swap"),
+                (LINUX_FILESYSTEM, 36864, 69631, "data"),
+            ],
+        );
+        let fat = fat_image(&[2]);
+        disk[2048 * 512..2048 * 512 + fat.len()].copy_from_slice(&fat);
+        let mut labeled = fat.clone();
+        labeled[0x2B..0x36].copy_from_slice(b"MY DATA    ");
+        disk[36864 * 512..36864 * 512 + fat.len()].copy_from_slice(&labeled);
+        let swap = SwapSignature {
+            page_size: 4096,
+            uuid: "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9".to_string(),
+            label: None,
+        }
+        .to_bytes(1024 * 1024)
+        .unwrap();
+        disk[34816 * 512..34816 * 512 + swap.len()].copy_from_slice(&swap);
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        fs::write(&image, &disk).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_mount_and_umount_image() {
+        let dir = test_dir("mount");
+        let image = disk_image(&dir);
+        let directory = dir.join("mnt").to_string_lossy().to_string();
+        let runner = Arc::new(
+            FakeCommandRunner::new().script("losetup --find", FakeResponse::ok("/dev/loop7\n")),
+        );
+        let mount_args = MountImageArgs {
+            image: image.clone(),
+            directory: directory.clone(),
+        };
+
+        mount(&mount_args, runner.clone()).unwrap();
+
+        let state = MountState::read(&directory).unwrap().unwrap();
+        assert_eq!(state.loop_device, "/dev/loop7");
+        assert_eq!(
+            state.mountpoints,
+            vec![
+                format!("{}/1", directory),
+                format!("{}/3_MY-DATA", directory)
+            ]
+        );
+        assert!(Path::new(&directory).join("3_MY-DATA").is_dir());
+        assert!(mount(&mount_args, runner.clone())
+            .unwrap_err()
+            .contains("already mounted"));
+
+        umount(&directory, runner.clone()).unwrap();
+
+        let remaining: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            runner.commands_of(&["losetup", "mount", "umount"]),
+            vec![
+                format!("losetup --find --show --read-only --partscan {}", image),
+                format!("mount -o ro /dev/loop7p1 {}/1", directory),
+                format!("mount -o ro /dev/loop7p3 {}/3_MY-DATA", directory),
+                format!("umount {}/3_MY-DATA", directory),
+                format!("umount {}/1", directory),
+                "losetup --detach /dev/loop7".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_image_tears_down_on_failure() {
+        let dir = test_dir("failure");
+        let image = disk_image(&dir);
+        let directory = dir.join("mnt").to_string_lossy().to_string();
+        let runner = Arc::new(
+            FakeCommandRunner::new()
+                .script("losetup --find", FakeResponse::ok("/dev/loop7\n"))
+                .script(
+                    "mount -o ro /dev/loop7p3",
+                    FakeResponse::fail("wrong fs type"),
+                ),
+        );
+        let mount_args = MountImageArgs {
+            image,
+            directory: directory.clone(),
+        };
+
+        let result = mount(&mount_args, runner.clone());
+
+        let remaining: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.unwrap_err().contains("wrong fs type"));
+        assert!(remaining.is_empty());
+        assert_eq!(
+            runner.commands_of(&["umount", "losetup"])[1..],
+            [
+                format!("umount {}/1", directory),
+                "losetup --detach /dev/loop7".to_string()
+            ]
+        );
+    }
+}
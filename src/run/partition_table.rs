@@ -0,0 +1,858 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// The sector sizes probed for a GPT header, the first one is used for MBR-only disks.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_PROTECTIVE_TYPE: u8 = 0xEE;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// The largest GPT partition entry array read, 128 entries of 128 bytes take only 16 KiB.
+const MAX_GPT_ENTRIES_LENGTH: u64 = 1024 * 1024;
+
+/// The kind of a partition table, named like the `label` of `sfdisk --dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Label {
+    Gpt,
+    Dos,
+}
+
+impl Label {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Label::Gpt => "gpt",
+            Label::Dos => "dos",
+        }
+    }
+}
+
+/// A single partition of a partition table, with start and size in sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// The number of the partition, like `2` for `/dev/sda2`.
+    pub number: u32,
+    /// The first sector of the partition.
+    pub start: u64,
+    /// The number of sectors of the partition.
+    pub size: u64,
+    /// The partition type, a GUID for GPT or a hex byte like `83` for MBR.
+    pub type_id: String,
+    /// The unique GUID of the partition, GPT only.
+    pub uuid: Option<String>,
+    /// The name of the partition, GPT only.
+    pub name: Option<String>,
+    /// The attribute flags of the partition, GPT only.
+    pub attributes: u64,
+    /// Whether the partition is marked as bootable, MBR only.
+    pub bootable: bool,
+}
+
+impl Partition {
+    /// Returns the first sector after the partition.
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Returns whether the partition is an MBR extended partition, containing the logical partitions.
+    pub fn is_extended(&self) -> bool {
+        MBR_EXTENDED_TYPES
+            .iter()
+            .any(|type_byte| self.type_id == format!("{:x}", type_byte))
+    }
+}
+
+/// A GPT or MBR partition table, parsed natively from the first sectors of a disk or image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub label: Label,
+    /// The disk GUID for GPT, the disk signature like `0x1234abcd` for MBR.
+    pub id: String,
+    pub sector_size: u64,
+    /// The first usable sector, GPT only.
+    pub first_lba: Option<u64>,
+    /// The last usable sector, GPT only.
+    pub last_lba: Option<u64>,
+    /// The size in bytes of the disk the GPT was written for, derived from the location of the backup header.
+    pub disk_size: Option<u64>,
+    /// The size in bytes of the backup GPT at the end of the disk, its entries and header, GPT only.
+    pub backup_length: Option<u64>,
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Reads the partition table from the start of `reader`, a disk, an image or the saved head of a disk.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(PartitionTable))`: If a GPT or MBR partition table was found.
+    /// - `Ok(None)`: If there is no partition table.
+    /// - `Err(String)`: If reading failed or the GPT is corrupted.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Option<PartitionTable>, String> {
+        let mbr = match read_at(reader, 0, 512)? {
+            Some(mbr) if mbr[510..512] == MBR_SIGNATURE => mbr,
+            _ => return Ok(None),
+        };
+        let primary_entries = mbr_entries(&mbr);
+
+        if primary_entries
+            .iter()
+            .any(|entry| entry.type_byte == MBR_PROTECTIVE_TYPE)
+        {
+            for sector_size in SECTOR_SIZES {
+                if let Some(header) = read_at(reader, sector_size, GPT_HEADER_SIZE)? {
+                    if &header[0..8] == GPT_SIGNATURE {
+                        return Self::read_gpt(reader, sector_size, &header).map(Some);
+                    }
+                }
+            }
+            return Err("Protective MBR found, but no GPT header".to_string());
+        }
+
+        Self::read_dos(reader, &mbr, primary_entries).map(Some)
+    }
+
+    fn read_gpt<R: Read + Seek>(
+        reader: &mut R,
+        sector_size: u64,
+        header_bytes: &[u8],
+    ) -> Result<PartitionTable, String> {
+        let header = GptHeader::parse(header_bytes)?;
+        let entries = read_at(
+            reader,
+            header.entries_lba * sector_size,
+            header.entries_length(),
+        )?
+        .ok_or("GPT partition entries are truncated")?;
+        if crc32(&entries) != header.entries_crc {
+            return Err("GPT partition entries checksum mismatch".to_string());
+        }
+
+        let partitions = entries
+            .chunks(header.entry_size as usize)
+            .enumerate()
+            .filter(|(_, entry)| entry[0..16].iter().any(|&b| b != 0))
+            .map(|(i, entry)| {
+                let first = u64_le(&entry[32..40]);
+                let last = u64_le(&entry[40..48]);
+                if last < first {
+                    return Err(format!(
+                        "GPT partition {} ends at sector {} before it starts at {}",
+                        i + 1,
+                        last,
+                        first
+                    ));
+                }
+                let name: Vec<u16> = entry[56..128.min(entry.len())]
+                    .chunks(2)
+                    .map(u16_le)
+                    .take_while(|&c| c != 0)
+                    .collect();
+                let name = String::from_utf16_lossy(&name);
+                Ok(Partition {
+                    number: i as u32 + 1,
+                    start: first,
+                    size: last + 1 - first,
+                    type_id: format_guid(&entry[0..16]),
+                    uuid: Some(format_guid(&entry[16..32])),
+                    name: (!name.is_empty()).then_some(name),
+                    attributes: u64_le(&entry[48..56]),
+                    bootable: false,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(PartitionTable {
+            label: Label::Gpt,
+            id: format_guid(&header.disk_guid),
+            sector_size,
+            first_lba: Some(header.first_usable_lba),
+            last_lba: Some(header.last_usable_lba),
+            disk_size: Some((header.backup_lba + 1) * sector_size),
+            backup_length: Some(
+                ((header.entries_length() as u64).div_ceil(sector_size) + 1) * sector_size,
+            ),
+            partitions,
+        })
+    }
+
+    fn read_dos<R: Read + Seek>(
+        reader: &mut R,
+        mbr: &[u8],
+        primary_entries: Vec<MbrEntry>,
+    ) -> Result<PartitionTable, String> {
+        let sector_size = SECTOR_SIZES[0];
+        let mut partitions = Vec::new();
+        let mut extended_start = None;
+        for (i, entry) in primary_entries.iter().enumerate() {
+            if entry.type_byte == 0 {
+                continue;
+            }
+            if MBR_EXTENDED_TYPES.contains(&entry.type_byte) {
+                extended_start = Some(entry.start);
+            }
+            partitions.push(entry.partition(i as u32 + 1, 0));
+        }
+
+        // logical partitions, chained by extended boot records inside the extended partition
+        if let Some(extended_start) = extended_start {
+            let mut ebr_offset = 0;
+            let mut number = 5;
+            let mut visited = HashSet::new();
+            loop {
+                let ebr_lba = extended_start + ebr_offset;
+                if !visited.insert(ebr_lba) {
+                    warn!(
+                        "Extended boot records loop back to sector {}, logical partitions from {} on are missing",
+                        ebr_lba, number
+                    );
+                    break;
+                }
+                let Some(ebr) = read_at(reader, ebr_lba * sector_size, 512)? else {
+                    warn!(
+                        "Extended boot record at sector {} is not readable, logical partitions from {} on are missing",
+                        ebr_lba, number
+                    );
+                    break;
+                };
+                if ebr[510..512] != MBR_SIGNATURE {
+                    break;
+                }
+                let entries = mbr_entries(&ebr);
+                if entries[0].type_byte != 0 {
+                    partitions.push(entries[0].partition(number, ebr_lba));
+                    number += 1;
+                }
+                if entries[1].type_byte == 0 || entries[1].start == 0 {
+                    break;
+                }
+                ebr_offset = entries[1].start;
+            }
+        }
+
+        Ok(PartitionTable {
+            label: Label::Dos,
+            id: format!(
+                "0x{:08x}",
+                u32::from_le_bytes([mbr[440], mbr[441], mbr[442], mbr[443]])
+            ),
+            sector_size,
+            first_lba: None,
+            last_lba: None,
+            disk_size: None,
+            backup_length: None,
+            partitions,
+        })
+    }
+
+    /// Returns the first byte after the last partition, or after the partition table itself for GPT.
+    pub fn end_of_last_partition(&self) -> u64 {
+        let last_sector = self
+            .partitions
+            .iter()
+            .map(|partition| partition.end())
+            .max()
+            .unwrap_or(0)
+            .max(self.first_lba.unwrap_or(1));
+        last_sector * self.sector_size
+    }
+
+    /// Returns the smallest size of a disk holding all partitions, with room for the backup GPT after them.
+    pub fn trimmed_size(&self) -> u64 {
+        self.end_of_last_partition() + self.backup_length.unwrap_or(0)
+    }
+
+    /// Formats the partition table like `sfdisk --dump` does, partitions are named after `device`.
+    pub fn to_sfdisk(&self, device: &str) -> String {
+        let mut dump = String::new();
+        let _ = writeln!(dump, "label: {}", self.label.name());
+        let _ = writeln!(dump, "label-id: {}", self.id);
+        let _ = writeln!(dump, "device: {}", device);
+        let _ = writeln!(dump, "unit: sectors");
+        if let (Some(first_lba), Some(last_lba)) = (self.first_lba, self.last_lba) {
+            let _ = writeln!(dump, "first-lba: {}", first_lba);
+            let _ = writeln!(dump, "last-lba: {}", last_lba);
+        }
+        let _ = writeln!(dump, "sector-size: {}", self.sector_size);
+        let _ = writeln!(dump);
+
+        let separator = match device.ends_with(|c: char| c.is_ascii_digit()) {
+            true => "p",
+            false => "",
+        };
+        for partition in &self.partitions {
+            let _ = write!(
+                dump,
+                "{}{}{} : start={:>12}, size={:>12}, type={}",
+                device,
+                separator,
+                partition.number,
+                partition.start,
+                partition.size,
+                partition.type_id
+            );
+            if let Some(uuid) = &partition.uuid {
+                let _ = write!(dump, ", uuid={}", uuid);
+            }
+            if let Some(name) = &partition.name {
+                let _ = write!(dump, ", name=\"{}\"", name.replace('"', "\\\""));
+            }
+            if partition.attributes != 0 {
+                let _ = write!(dump, ", attrs=\"{}\"", gpt_attributes(partition.attributes));
+            }
+            if partition.bootable {
+                let _ = write!(dump, ", bootable");
+            }
+            let _ = writeln!(dump);
+        }
+        dump
+    }
+}
+
+/// The GPT header fields needed to read and relocate a GPT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GptHeader {
+    bytes: Vec<u8>,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Vec<u8>,
+    entries_lba: u64,
+    entries_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    fn parse(bytes: &[u8]) -> Result<GptHeader, String> {
+        if bytes.len() < GPT_HEADER_SIZE || &bytes[0..8] != GPT_SIGNATURE {
+            return Err("No GPT header signature".to_string());
+        }
+        let header_size = u32_le(&bytes[12..16]) as usize;
+        if !(GPT_HEADER_SIZE..=bytes.len()).contains(&header_size) {
+            return Err(format!("Invalid GPT header size {}", header_size));
+        }
+        let mut header = bytes[..header_size].to_vec();
+        let header_crc = u32_le(&header[16..20]);
+        header[16..20].copy_from_slice(&[0; 4]);
+        if crc32(&header) != header_crc {
+            return Err("GPT header checksum mismatch".to_string());
+        }
+
+        let entry_size = u32_le(&header[84..88]);
+        if entry_size < 128 {
+            return Err(format!("Invalid GPT entry size {}", entry_size));
+        }
+        let entries_count = u32_le(&header[80..84]);
+        let entries_length = entries_count as u64 * entry_size as u64;
+        if entries_length > MAX_GPT_ENTRIES_LENGTH {
+            return Err(format!(
+                "GPT partition entries of {} bytes exceed {} bytes",
+                entries_length, MAX_GPT_ENTRIES_LENGTH
+            ));
+        }
+        Ok(GptHeader {
+            backup_lba: u64_le(&header[32..40]),
+            first_usable_lba: u64_le(&header[40..48]),
+            last_usable_lba: u64_le(&header[48..56]),
+            disk_guid: header[56..72].to_vec(),
+            entries_lba: u64_le(&header[72..80]),
+            entries_count,
+            entry_size,
+            entries_crc: u32_le(&header[88..92]),
+            bytes: header,
+        })
+    }
+
+    fn entries_length(&self) -> usize {
+        self.entries_count as usize * self.entry_size as usize
+    }
+
+    /// Serializes the header with the given locations, recomputing the header checksum.
+    fn to_bytes(
+        &self,
+        current_lba: u64,
+        backup_lba: u64,
+        last_usable_lba: u64,
+        entries_lba: u64,
+    ) -> Vec<u8> {
+        let mut bytes = self.bytes.clone();
+        bytes[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        bytes[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        bytes[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
+        bytes[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        bytes[16..20].copy_from_slice(&[0; 4]);
+        let crc = crc32(&bytes);
+        bytes[16..20].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// A GPT relocated to a disk of another size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocatedGpt {
+    /// The primary header, to be written at `header_offset`.
+    pub header: Vec<u8>,
+    pub header_offset: u64,
+    /// The backup partition entries followed by the backup header, to be written at `tail_offset`
+    /// up to the end of the disk.
+    pub tail: Vec<u8>,
+    pub tail_offset: u64,
+}
+
+impl RelocatedGpt {
+    /// Writes the primary header and the backup GPT to a disk or image, which holds the rest of the GPT.
+    pub fn write_to<W: Write + Seek>(&self, writer: &mut W) -> Result<(), String> {
+        for (offset, bytes) in [
+            (self.header_offset, &self.header),
+            (self.tail_offset, &self.tail),
+        ] {
+            writer
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| writer.write_all(bytes))
+                .map_err(|e| format!("Failed to write GPT at {}: {}", offset, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Relocates the GPT found at the start of `reader` to a disk of `disk_size` bytes.
+///
+/// The primary header is updated with the new location of the backup GPT and the new last usable sector,
+/// the backup GPT (entries and header) is created for the end of the new disk.
+///
+/// # Returns
+///
+/// - `Ok(Some(RelocatedGpt))`: If a GPT was found and all partitions fit on the new disk.
+/// - `Ok(None)`: If there is no GPT, like for MBR partitioned disks.
+/// - `Err(String)`: If the GPT is corrupted or the partitions don't fit.
+pub fn relocate_gpt<R: Read + Seek>(
+    reader: &mut R,
+    disk_size: u64,
+) -> Result<Option<RelocatedGpt>, String> {
+    let Some(table) = PartitionTable::read(reader)? else {
+        return Ok(None);
+    };
+    if table.label != Label::Gpt {
+        return Ok(None);
+    }
+    let sector_size = table.sector_size;
+    let header = GptHeader::parse(
+        &read_at(reader, sector_size, sector_size as usize)?.ok_or("GPT header is truncated")?,
+    )?;
+    let entries = read_at(
+        reader,
+        header.entries_lba * sector_size,
+        header.entries_length(),
+    )?
+    .ok_or("GPT partition entries are truncated")?;
+
+    let disk_sectors = disk_size / sector_size;
+    let entries_sectors = (header.entries_length() as u64).div_ceil(sector_size);
+    let backup_lba = disk_sectors
+        .checked_sub(1)
+        .ok_or("Disk is too small for a GPT")?;
+    let backup_entries_lba = backup_lba
+        .checked_sub(entries_sectors)
+        .ok_or("Disk is too small for a GPT")?;
+    let last_usable_lba = backup_entries_lba
+        .checked_sub(1)
+        .ok_or("Disk is too small for a GPT")?;
+    let last_partition_sector = table
+        .partitions
+        .iter()
+        .map(|partition| partition.end() - 1)
+        .max()
+        .unwrap_or(0);
+    if last_partition_sector > last_usable_lba {
+        return Err(format!(
+            "Partitions end at sector {}, but the disk of {} bytes ends at usable sector {}",
+            last_partition_sector, disk_size, last_usable_lba
+        ));
+    }
+
+    let primary = header.to_bytes(1, backup_lba, last_usable_lba, header.entries_lba);
+    let backup = header.to_bytes(backup_lba, 1, last_usable_lba, backup_entries_lba);
+
+    let mut tail = vec![0u8; ((entries_sectors + 1) * sector_size) as usize];
+    tail[..entries.len()].copy_from_slice(&entries);
+    let backup_header_offset = (entries_sectors * sector_size) as usize;
+    tail[backup_header_offset..backup_header_offset + backup.len()].copy_from_slice(&backup);
+
+    Ok(Some(RelocatedGpt {
+        header: primary,
+        header_offset: sector_size,
+        tail,
+        tail_offset: backup_entries_lba * sector_size,
+    }))
+}
+
+/// A primary or logical partition entry of an MBR or EBR.
+struct MbrEntry {
+    bootable: bool,
+    type_byte: u8,
+    start: u64,
+    size: u64,
+}
+
+impl MbrEntry {
+    fn partition(&self, number: u32, base_lba: u64) -> Partition {
+        Partition {
+            number,
+            start: base_lba + self.start,
+            size: self.size,
+            type_id: format!("{:x}", self.type_byte),
+            uuid: None,
+            name: None,
+            attributes: 0,
+            bootable: self.bootable,
+        }
+    }
+}
+
+fn mbr_entries(sector: &[u8]) -> Vec<MbrEntry> {
+    (0..4)
+        .map(|i| {
+            let entry = &sector[446 + i * 16..446 + (i + 1) * 16];
+            MbrEntry {
+                bootable: entry[0] == 0x80,
+                type_byte: entry[4],
+                start: u32_le(&entry[8..12]) as u64,
+                size: u32_le(&entry[12..16]) as u64,
+            }
+        })
+        .collect()
+}
+
+/// Reads `length` bytes at `offset`, or `None` if the reader ends before.
+pub fn read_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: usize,
+) -> Result<Option<Vec<u8>>, String> {
+    reader
+        .seek(SeekFrom::Start(offset))
+        .map_err(|e| format!("Failed to seek to {}: {}", offset, e))?;
+    let mut buffer = vec![0u8; length];
+    let mut read = 0;
+    while read < length {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => return Ok(None),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("Failed to read at {}: {}", offset, e)),
+        }
+    }
+    Ok(Some(buffer))
+}
+
+/// Formats a GUID stored in the mixed-endian on-disk format.
+pub fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32_le(&bytes[0..4]),
+        u16_le(&bytes[4..6]),
+        u16_le(&bytes[6..8]),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+/// Names the GPT attribute bits like `sfdisk` does.
+fn gpt_attributes(attributes: u64) -> String {
+    let mut names = Vec::new();
+    if attributes & 1 != 0 {
+        names.push("RequiredPartition".to_string());
+    }
+    if attributes & 2 != 0 {
+        names.push("NoBlockIOProtocol".to_string());
+    }
+    if attributes & 4 != 0 {
+        names.push("LegacyBIOSBootable".to_string());
+    }
+    let guid_specific: Vec<String> = (48..64)
+        .filter(|bit| attributes & (1 << bit) != 0)
+        .map(|bit| bit.to_string())
+        .collect();
+    if !guid_specific.is_empty() {
+        names.push(format!("GUID:{}", guid_specific.join(",")));
+    }
+    names.join(" ")
+}
+
+/// Computes the CRC-32 (IEEE 802.3) checksum used by GPT.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+pub fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub fn u64_le(bytes: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[0..8]);
+    u64::from_le_bytes(array)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    pub const EFI_SYSTEM: [u8; 16] = [
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ];
+    pub const LINUX_FILESYSTEM: [u8; 16] = [
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ];
+
+    /// Builds a disk image of `disk_sectors` 512 byte sectors with a GPT, partitions are
+    /// given as (type, first sector, last sector, name).
+    pub fn gpt_disk(disk_sectors: u64, partitions: &[([u8; 16], u64, u64, &str)]) -> Vec<u8> {
+        let mut disk = vec![0u8; (disk_sectors * 512) as usize];
+
+        // protective MBR
+        disk[446 + 4] = MBR_PROTECTIVE_TYPE;
+        disk[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        disk[446 + 12..446 + 16]
+            .copy_from_slice(&((disk_sectors - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+
+        let mut entries = vec![0u8; 128 * 128];
+        for (i, (type_guid, first, last, name)) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[0..16].copy_from_slice(type_guid);
+            entry[16..32].copy_from_slice(&[i as u8 + 1; 16]);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let mut header = vec![0u8; GPT_HEADER_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[56..72].copy_from_slice(&[0xAB; 16]);
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let header = GptHeader {
+            bytes: header,
+            backup_lba: 0,
+            first_usable_lba: 34,
+            last_usable_lba: 0,
+            disk_guid: vec![0xAB; 16],
+            entries_lba: 2,
+            entries_count: 128,
+            entry_size: 128,
+            entries_crc: crc32(&entries),
+        };
+        let backup_lba = disk_sectors - 1;
+        let primary = header.to_bytes(1, backup_lba, disk_sectors - 34, 2);
+        let backup = header.to_bytes(backup_lba, 1, disk_sectors - 34, disk_sectors - 33);
+
+        disk[512..512 + primary.len()].copy_from_slice(&primary);
+        disk[1024..1024 + entries.len()].copy_from_slice(&entries);
+        let backup_entries = ((disk_sectors - 33) * 512) as usize;
+        disk[backup_entries..backup_entries + entries.len()].copy_from_slice(&entries);
+        let backup_offset = (backup_lba * 512) as usize;
+        disk[backup_offset..backup_offset + backup.len()].copy_from_slice(&backup);
+        disk
+    }
+
+    /// Builds a disk image of `disk_sectors` 512 byte sectors with a MBR,
+    /// partitions are given as (type, first sector, sectors, bootable).
+    pub fn dos_disk(disk_sectors: u64, partitions: &[(u8, u32, u32, bool)]) -> Vec<u8> {
+        let mut disk = vec![0u8; (disk_sectors * 512) as usize];
+        disk[440..444].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        for (i, (type_byte, start, size, bootable)) in partitions.iter().enumerate() {
+            let entry = &mut disk[446 + i * 16..446 + (i + 1) * 16];
+            entry[0] = if *bootable { 0x80 } else { 0 };
+            entry[4] = *type_byte;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+        }
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        disk
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_read_gpt() {
+        let disk = gpt_disk(
+            8192,
+            &[
+                (EFI_SYSTEM, 2048, 4095, "EFI System"),
+                (LINUX_FILESYSTEM, 4096, 8158, "root"),
+            ],
+        );
+        let table = PartitionTable::read(&mut Cursor::new(&disk))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(table.label, Label::Gpt);
+        assert_eq!(table.sector_size, 512);
+        assert_eq!(table.last_lba, Some(8158));
+        assert_eq!(table.disk_size, Some(8192 * 512));
+        assert_eq!(table.partitions.len(), 2);
+        assert_eq!(table.partitions[1].start, 4096);
+        assert_eq!(table.partitions[1].size, 4063);
+        assert_eq!(table.end_of_last_partition(), 8159 * 512);
+        assert_eq!(table.backup_length, Some(33 * 512));
+        assert_eq!(table.trimmed_size(), 8192 * 512);
+        assert_eq!(
+            table.to_sfdisk("/dev/nvme0n1"),
+            "label: gpt
+label-id: ABABABAB-ABAB-ABAB-ABAB-ABABABABABAB
+device: /dev/nvme0n1
+unit: sectors
+first-lba: 34
+last-lba: 8158
+sector-size: 512
+
+/dev/nvme0n1p1 : start=        2048, size=        2048, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=01010101-0101-0101-0101-010101010101, name=\"EFI System\"
+/dev/nvme0n1p2 : start=        4096, size=        4063, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, uuid=02020202-0202-0202-0202-020202020202, name=\"root\"
+"
+        );
+
+        // a corrupted header is detected
+        let mut corrupted = disk.clone();
+        corrupted[512 + 40] ^= 1;
+        assert!(PartitionTable::read(&mut Cursor::new(&corrupted)).is_err());
+
+        // as is a partition ending before it starts
+        let inverted = gpt_disk(8192, &[(LINUX_FILESYSTEM, 4096, 2048, "root")]);
+        assert!(PartitionTable::read(&mut Cursor::new(&inverted))
+            .unwrap_err()
+            .contains("ends at sector 2048 before it starts at 4096"));
+    }
+
+    #[test]
+    fn test_gpt_header_rejects_oversized_entries() {
+        let disk = gpt_disk(8192, &[(LINUX_FILESYSTEM, 2048, 4095, "data")]);
+        let mut header = disk[512..512 + GPT_HEADER_SIZE].to_vec();
+        header[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        header[16..20].copy_from_slice(&[0; 4]);
+        let header_crc = crc32(&header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        assert!(GptHeader::parse(&header)
+            .unwrap_err()
+            .contains("GPT partition entries of"));
+    }
+
+    #[test]
+    fn test_read_dos() {
+        let disk = dos_disk(4096, &[(0x83, 2048, 1024, true), (0x82, 3072, 1024, false)]);
+        let table = PartitionTable::read(&mut Cursor::new(&disk))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(table.label, Label::Dos);
+        assert_eq!(table.trimmed_size(), 4096 * 512);
+        assert_eq!(
+            table.to_sfdisk("/dev/sda"),
+            "label: dos
+label-id: 0x1234abcd
+device: /dev/sda
+unit: sectors
+sector-size: 512
+
+/dev/sda1 : start=        2048, size=        1024, type=83, bootable
+/dev/sda2 : start=        3072, size=        1024, type=82
+"
+        );
+        assert!(PartitionTable::read(&mut Cursor::new(vec![0u8; 1024]))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_dos_stops_at_ebr_cycle() {
+        let mut disk = dos_disk(4096, &[(0x05, 1024, 2048, false)]);
+        // (sector of the EBR, logical partition start relative to it, next EBR offset)
+        for (ebr_lba, start, next) in [(1024u32, 1u32, 100u32), (1124, 1, 100)] {
+            let ebr = &mut disk[ebr_lba as usize * 512..(ebr_lba as usize + 1) * 512];
+            ebr[446 + 4] = 0x83;
+            ebr[446 + 8..446 + 12].copy_from_slice(&start.to_le_bytes());
+            ebr[446 + 12..446 + 16].copy_from_slice(&50u32.to_le_bytes());
+            ebr[462 + 4] = 0x05;
+            ebr[462 + 8..462 + 12].copy_from_slice(&next.to_le_bytes());
+            ebr[462 + 12..462 + 16].copy_from_slice(&51u32.to_le_bytes());
+            ebr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        }
+        let table = PartitionTable::read(&mut Cursor::new(&disk))
+            .unwrap()
+            .unwrap();
+
+        let numbers: Vec<u32> = table.partitions.iter().map(|p| p.number).collect();
+        assert_eq!(numbers, vec![1, 5, 6]);
+        assert_eq!(table.partitions[2].start, 1125);
+    }
+
+    #[test]
+    fn test_relocate_gpt() {
+        let disk = gpt_disk(8192, &[(LINUX_FILESYSTEM, 2048, 4095, "data")]);
+
+        // to a bigger disk
+        let relocated = relocate_gpt(&mut Cursor::new(&disk), 16384 * 512)
+            .unwrap()
+            .unwrap();
+        let mut target = Cursor::new(vec![0u8; 16384 * 512]);
+        target.get_mut()[..34 * 512].copy_from_slice(&disk[..34 * 512]);
+        relocated.write_to(&mut target).unwrap();
+        let target = target.into_inner();
+
+        let table = PartitionTable::read(&mut Cursor::new(&target))
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.last_lba, Some(16384 - 34));
+        assert_eq!(table.partitions[0].end(), 4096);
+        let backup = GptHeader::parse(&target[(16383 * 512) as usize..]).unwrap();
+        assert_eq!(u64_le(&backup.bytes[24..32]), 16383);
+        assert_eq!(backup.backup_lba, 1);
+        assert_eq!(backup.entries_lba, 16384 - 33);
+
+        // partitions must fit on a smaller disk, the trimmed size is the smallest one
+        let table = PartitionTable::read(&mut Cursor::new(&disk))
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.trimmed_size(), 4129 * 512);
+        assert!(relocate_gpt(&mut Cursor::new(&disk), 4129 * 512).is_ok());
+        assert!(relocate_gpt(&mut Cursor::new(&disk), 4128 * 512).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use super::{
+    allocation::{u32_be, u64_be},
+    convert::{seek_position, write_at, ImageWriter, CLUSTER_SIZE},
+    partition_table::read_at,
+};
+
+const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+const QCOW2_VERSION: u32 = 2;
+const CLUSTER_BITS: u32 = CLUSTER_SIZE.trailing_zeros();
+/// The number of 8 byte entries of an L1 or L2 table or of the refcount table per cluster.
+const TABLE_ENTRIES: u64 = CLUSTER_SIZE / 8;
+/// The number of 16 bit refcounts per refcount block.
+const REFCOUNT_ENTRIES: u64 = CLUSTER_SIZE / 2;
+/// Marks a table entry of a cluster with a refcount of exactly one, which may be written in place.
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+/// Marks an L2 entry of a compressed cluster.
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Marks an L2 entry of a cluster reading as zeros, qcow2 version 3 only.
+const QCOW_OFLAG_ZERO: u64 = 1;
+/// The bits of a table entry holding the offset of the table or cluster.
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+
+/// Writes a qcow2 image sequentially: the header and the L1 table are reserved at the start,
+/// data clusters and their L2 tables are appended, the refcounts are appended at the end.
+///
+/// Clusters which are never written are unallocated and read as zeros.
+pub struct Qcow2Writer {
+    file: File,
+    path: String,
+    size: u64,
+    l1: Vec<u64>,
+    l2: Vec<u64>,
+    /// The index of the L1 entry the buffered L2 table belongs to.
+    l2_index: Option<u64>,
+    /// The next free cluster of the image file.
+    next_cluster: u64,
+}
+
+impl Qcow2Writer {
+    /// Creates a qcow2 image of a virtual disk of `size` bytes at `path`, which must not exist.
+    pub fn create(path: &str, size: u64) -> Result<Qcow2Writer, String> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let l1_size = size.div_ceil(CLUSTER_SIZE * TABLE_ENTRIES);
+        let l1_clusters = (l1_size * 8).div_ceil(CLUSTER_SIZE).max(1);
+        Ok(Qcow2Writer {
+            file,
+            path: path.to_string(),
+            size,
+            l1: vec![0; l1_size as usize],
+            l2: vec![0; TABLE_ENTRIES as usize],
+            l2_index: None,
+            next_cluster: 1 + l1_clusters,
+        })
+    }
+
+    /// Appends a cluster to the image file and returns its offset.
+    fn append_cluster(&mut self, data: &[u8]) -> Result<u64, String> {
+        let offset = self.next_cluster * CLUSTER_SIZE;
+        write_at(&mut self.file, offset, data).map_err(|e| format!("{}: {}", self.path, e))?;
+        self.next_cluster += 1;
+        Ok(offset)
+    }
+
+    /// Appends the buffered L2 table, if any, and links it in the L1 table.
+    fn flush_l2(&mut self) -> Result<(), String> {
+        if let Some(l1_index) = self.l2_index.take() {
+            let table = to_be_bytes(&self.l2);
+            let offset = self.append_cluster(&table)?;
+            self.l1[l1_index as usize] = offset | QCOW_OFLAG_COPIED;
+            self.l2.fill(0);
+        }
+        Ok(())
+    }
+
+    /// Appends the refcount blocks and the refcount table, covering every cluster of the file including themselves.
+    ///
+    /// Returns the offset and the number of clusters of the refcount table.
+    fn write_refcounts(&mut self) -> Result<(u64, u64), String> {
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let clusters = self.next_cluster + blocks + table_clusters;
+            let needed_blocks = clusters.div_ceil(REFCOUNT_ENTRIES);
+            let needed_table_clusters = needed_blocks.div_ceil(TABLE_ENTRIES);
+            if (needed_blocks, needed_table_clusters) == (blocks, table_clusters) {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table_clusters);
+        }
+
+        let clusters = self.next_cluster + blocks + table_clusters;
+        let mut table = vec![0u64; (table_clusters * TABLE_ENTRIES) as usize];
+        for (block, entry) in table.iter_mut().enumerate().take(blocks as usize) {
+            let mut refcounts = vec![0u8; CLUSTER_SIZE as usize];
+            let first_cluster = block as u64 * REFCOUNT_ENTRIES;
+            let count = clusters.saturating_sub(first_cluster).min(REFCOUNT_ENTRIES);
+            for refcount in refcounts.chunks_mut(2).take(count as usize) {
+                refcount.copy_from_slice(&1u16.to_be_bytes());
+            }
+            *entry = self.append_cluster(&refcounts)?;
+        }
+        let table_offset = self.next_cluster * CLUSTER_SIZE;
+        for chunk in to_be_bytes(&table).chunks(CLUSTER_SIZE as usize) {
+            self.append_cluster(chunk)?;
+        }
+        Ok((table_offset, table_clusters))
+    }
+
+    fn header(&self, refcount_table_offset: u64, refcount_table_clusters: u64) -> Vec<u8> {
+        let mut header = vec![0u8; CLUSTER_SIZE as usize];
+        header[0..4].copy_from_slice(QCOW2_MAGIC);
+        header[4..8].copy_from_slice(&QCOW2_VERSION.to_be_bytes());
+        header[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+        header[24..32].copy_from_slice(&self.size.to_be_bytes());
+        header[36..40].copy_from_slice(&(self.l1.len() as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&CLUSTER_SIZE.to_be_bytes());
+        header[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+        header[56..60].copy_from_slice(&(refcount_table_clusters as u32).to_be_bytes());
+        header
+    }
+}
+
+impl ImageWriter for Qcow2Writer {
+    fn write_cluster(&mut self, index: u64, data: &[u8]) -> Result<(), String> {
+        let l1_index = index / TABLE_ENTRIES;
+        if self.l2_index != Some(l1_index) {
+            self.flush_l2()?;
+            self.l2_index = Some(l1_index);
+        }
+        let offset = self.append_cluster(data)?;
+        self.l2[(index % TABLE_ENTRIES) as usize] = offset | QCOW_OFLAG_COPIED;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.flush_l2()?;
+        let (refcount_table_offset, refcount_table_clusters) = self.write_refcounts()?;
+        let l1 = to_be_bytes(&self.l1);
+        let header = self.header(refcount_table_offset, refcount_table_clusters);
+        write_at(&mut self.file, CLUSTER_SIZE, &l1)
+            .and_then(|_| write_at(&mut self.file, 0, &header))
+            .map_err(|e| format!("{}: {}", self.path, e))?;
+        self.file
+            .sync_all()
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+}
+
+/// Reads the virtual disk of a qcow2 image, loading one L2 table at a time.
+///
+/// Unallocated and zero clusters read as zeros, images with a backing file,
+/// encryption or compressed clusters are not supported.
+pub struct Qcow2Reader<R> {
+    reader: R,
+    cluster_size: u64,
+    size: u64,
+    l1: Vec<u64>,
+    /// The L1 index and the entries of the last L2 table read.
+    l2: Option<(u64, Vec<u64>)>,
+    position: u64,
+}
+
+impl<R: Read + Seek> Qcow2Reader<R> {
+    /// Opens the qcow2 image read by `reader`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Qcow2Reader)`: If the header and the L1 table were read.
+    /// - `Err(String)`: If it is no qcow2 image or it uses unsupported features.
+    pub fn new(mut reader: R) -> Result<Qcow2Reader<R>, String> {
+        let header = read_at(&mut reader, 0, 104)?.ok_or("qcow2 header is truncated")?;
+        if &header[0..4] != QCOW2_MAGIC {
+            return Err("No qcow2 image".to_string());
+        }
+        if u64_be(&header[8..]) != 0 {
+            return Err("qcow2 images with a backing file are not supported".to_string());
+        }
+        if u32_be(&header[32..]) != 0 {
+            return Err("Encrypted qcow2 images are not supported".to_string());
+        }
+        let cluster_bits = u32_be(&header[20..]);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(format!("Invalid qcow2 cluster bits {}", cluster_bits));
+        }
+        let l1_size = u32_be(&header[36..]) as usize;
+        let l1 = read_at(&mut reader, u64_be(&header[40..]), l1_size * 8)?
+            .ok_or("qcow2 L1 table is truncated")?;
+        Ok(Qcow2Reader {
+            reader,
+            cluster_size: 1 << cluster_bits,
+            size: u64_be(&header[24..]),
+            l1: l1.chunks(8).map(u64_be).collect(),
+            l2: None,
+            position: 0,
+        })
+    }
+
+    /// Returns the size of the virtual disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the offset in the image of the cluster with the given index, `None` if it reads as zeros.
+    fn cluster_offset(&mut self, index: u64) -> Result<Option<u64>, String> {
+        let entries = self.cluster_size / 8;
+        let l1_index = index / entries;
+        let Some(l2_offset) = self
+            .l1
+            .get(l1_index as usize)
+            .map(|entry| entry & OFFSET_MASK)
+        else {
+            return Ok(None);
+        };
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+        if self.l2.as_ref().map(|(index, _)| *index) != Some(l1_index) {
+            let table = read_at(&mut self.reader, l2_offset, self.cluster_size as usize)?
+                .ok_or("qcow2 L2 table is truncated")?;
+            self.l2 = Some((l1_index, table.chunks(8).map(u64_be).collect()));
+        }
+        let entry = self
+            .l2
+            .as_ref()
+            .map(|(_, l2)| l2[(index % entries) as usize]);
+        match entry {
+            Some(entry) if entry & QCOW_OFLAG_COMPRESSED != 0 => {
+                Err("Compressed qcow2 clusters are not supported".to_string())
+            }
+            Some(entry) if entry & QCOW_OFLAG_ZERO != 0 || entry & OFFSET_MASK == 0 => Ok(None),
+            Some(entry) => Ok(Some(entry & OFFSET_MASK)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for Qcow2Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let in_cluster = self.position % self.cluster_size;
+        let length = (buf.len() as u64)
+            .min(self.cluster_size - in_cluster)
+            .min(self.size - self.position) as usize;
+        match self
+            .cluster_offset(self.position / self.cluster_size)
+            .map_err(io::Error::other)?
+        {
+            Some(offset) => {
+                self.reader.seek(SeekFrom::Start(offset + in_cluster))?;
+                self.reader.read_exact(&mut buf[..length])?;
+            }
+            None => buf[..length].fill(0),
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for Qcow2Reader<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.size, position)?;
+        Ok(self.position)
+    }
+}
+
+fn to_be_bytes(entries: &[u64]) -> Vec<u8> {
+    entries
+        .iter()
+        .flat_map(|entry| entry.to_be_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Reads the virtual disk of a qcow2 image written by `Qcow2Writer`, checking its refcounts.
+    pub fn read_qcow2(image: &[u8]) -> Vec<u8> {
+        assert_eq!(&image[0..4], QCOW2_MAGIC);
+        let size = u64_be(&image[24..32]);
+        let l1_size = u32::from_be_bytes(image[36..40].try_into().unwrap()) as usize;
+        let l1_offset = u64_be(&image[40..48]) as usize;
+        let refcount_table_offset = u64_be(&image[48..56]) as usize;
+
+        // every cluster of the file is referenced exactly once
+        let clusters = image.len() as u64 / CLUSTER_SIZE;
+        assert_eq!(image.len() as u64 % CLUSTER_SIZE, 0);
+        for cluster in 0..clusters {
+            let block =
+                u64_be(&image[refcount_table_offset + 8 * (cluster / REFCOUNT_ENTRIES) as usize..])
+                    as usize;
+            let entry = block + 2 * (cluster % REFCOUNT_ENTRIES) as usize;
+            assert_eq!(u16::from_be_bytes([image[entry], image[entry + 1]]), 1);
+        }
+
+        let mut disk = vec![0u8; size as usize];
+        for l1_index in 0..l1_size {
+            let l2_offset = u64_be(&image[l1_offset + 8 * l1_index..]) & !QCOW_OFLAG_COPIED;
+            if l2_offset == 0 {
+                continue;
+            }
+            for l2_index in 0..TABLE_ENTRIES as usize {
+                let data_offset =
+                    u64_be(&image[l2_offset as usize + 8 * l2_index..]) & !QCOW_OFLAG_COPIED;
+                if data_offset == 0 {
+                    continue;
+                }
+                let offset = (l1_index * TABLE_ENTRIES as usize + l2_index) * CLUSTER_SIZE as usize;
+                let length = (CLUSTER_SIZE as usize).min(disk.len() - offset);
+                let cluster = read_at(&mut std::io::Cursor::new(image), data_offset, length)
+                    .unwrap()
+                    .unwrap();
+                disk[offset..offset + length].copy_from_slice(&cluster);
+            }
+        }
+        disk
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use clap::Args;
+
+use super::{
+    backup_run::{
+        command_output::{CommandRunner, SystemCommandRunner},
+        device::Device,
+        lsblk::Lsblk,
+    },
+    config::DeviceIdentifier,
+    convert::is_raw_image,
+    extent::copy_extents,
+    image_metadata::{CopyMode, ImageMetadata, Region},
+    partition_table::relocate_gpt,
+};
+
+/// The number of bytes wiped at the start of excluded partitions, covering the signatures of filesystems.
+const WIPE_LENGTH: u64 = 1024 * 1024;
+
+#[derive(Args, Debug)]
+pub struct RestoreArgs {
+    /// The path to the image to restore.
+    pub image: String,
+
+    #[clap(long)]
+    /// The device to write the image to as <KIND>=<VALUE>, KIND is one of
+    /// serial, wwn, by_id, by_path, partuuid, fs_uuid or path.
+    pub target: DeviceIdentifier,
+
+    #[clap(short = 'n', long, default_value = "false")]
+    /// Prints what would be written without writing it.
+    pub dry_run: bool,
+}
+
+/// Runs the `restore` subcommand.
+pub fn run(restore_args: &RestoreArgs) -> Result<(), String> {
+    restore(restore_args, Arc::new(SystemCommandRunner), Lsblk::new)
+}
+
+/// Writes an image to the target device.
+///
+/// Full images are written with `dd`. Images with used blocks only, as recorded in their metadata,
+/// are written natively and only their data extents are written, the rest of the target is left untouched.
+/// The start of excluded partitions is wiped, swap areas get their signature back.
+/// For images trimmed to the end of the last partition, the backup GPT is recreated at the end of the target.
+///
+/// # Arguments
+///
+/// * `restore_args` - The image, the target device and whether to do a dry run.
+/// * `runner` - The runner executing the `dd` command.
+/// * `read_block_devices` - Reads the available block devices to find the target device.
+///
+/// # Returns
+///
+/// - `Ok(())`: If the image was written, or would have been on a dry run.
+/// - `Err(String)`: If the target is not found, is mounted, too small, the image is not raw or writing failed.
+fn restore(
+    restore_args: &RestoreArgs,
+    runner: Arc<dyn CommandRunner>,
+    read_block_devices: fn(&dyn CommandRunner) -> Result<Lsblk, String>,
+) -> Result<(), String> {
+    let image = &restore_args.image;
+    if !is_raw_image(image) {
+        return Err(format!(
+            "{} is no raw image, convert it first, like: `qemu-img convert -O raw {} <image>.img`",
+            image, image
+        ));
+    }
+    let lsblk = read_block_devices(runner.as_ref())?;
+    let (blockdevice, device_path) =
+        Device::validate_identifier(&restore_args.target, &lsblk.available_devices)?;
+    if Device::is_device_or_partition_mounted(&device_path)? {
+        return Err(format!("Target {} is mounted", device_path));
+    }
+
+    let metadata = ImageMetadata::read(image)?;
+    let image_size = match &metadata {
+        Some(metadata) => metadata.size,
+        None => fs::metadata(image)
+            .map_err(|e| format!("{}: {}", image, e))?
+            .len(),
+    };
+    if blockdevice.size < image_size && blockdevice.kind.as_deref() != Some("file") {
+        return Err(format!(
+            "Target {} of {} bytes is too small for the image of {} bytes",
+            device_path, blockdevice.size, image_size
+        ));
+    }
+    let is_trimmed = metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.source_size.is_some());
+
+    match metadata.filter(|metadata| metadata.is_sparse()) {
+        Some(metadata) if restore_args.dry_run => {
+            info!(
+                "[DRY RUN] restore would write {} of {} bytes of {} to {}",
+                metadata.data_bytes(),
+                image_size,
+                image,
+                device_path
+            );
+            for region in excluded_regions(&metadata) {
+                info!(
+                    "[DRY RUN] restore would wipe the excluded partition at byte {}{}",
+                    region.offset,
+                    match &region.swap {
+                        Some(swap) => format!(" and recreate its swap area {}", swap.uuid),
+                        None => String::new(),
+                    }
+                );
+            }
+        }
+        Some(metadata) => {
+            let mut source = File::open(image).map_err(|e| format!("{}: {}", image, e))?;
+            let mut target = OpenOptions::new()
+                .write(true)
+                .open(&device_path)
+                .map_err(|e| format!("{}: {}", device_path, e))?;
+            let written = copy_extents(
+                &mut source,
+                &mut target,
+                &metadata.data_extents(),
+                &mut |_| {},
+            )?;
+            info!(
+                "Restored {} of {} bytes of {} to {}, unused blocks were left untouched",
+                written, image_size, image, device_path
+            );
+            restore_excluded_regions(&metadata, &mut target, &device_path)?;
+        }
+        None => {
+            let input_file_arg = format!("if={}", image);
+            let output_file_arg = format!("of={}", device_path);
+            let command_parts = vec![
+                "dd",
+                &input_file_arg,
+                &output_file_arg,
+                "bs=4M",
+                "conv=notrunc,fsync",
+                "status=progress",
+            ];
+            match restore_args.dry_run {
+                true => info!(
+                    "[DRY RUN] restore would run with command: {}",
+                    command_parts.join(" ")
+                ),
+                false => {
+                    runner.output(command_parts, "restore image", Some(true))?;
+                    info!("Restored {} to {}", image, device_path);
+                }
+            }
+        }
+    }
+
+    if is_trimmed {
+        let target_size = blockdevice.size.max(image_size);
+        match restore_args.dry_run {
+            true => info!(
+                "[DRY RUN] restore would recreate the GPT of the trimmed image for the {} bytes of {}",
+                target_size, device_path
+            ),
+            false => recreate_gpt(&device_path, target_size)?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the regions of the excluded partitions of an image.
+fn excluded_regions(metadata: &ImageMetadata) -> impl Iterator<Item = &Region> {
+    metadata
+        .regions
+        .iter()
+        .filter(|region| region.copy == CopyMode::Excluded)
+}
+
+/// Wipes the start of the excluded partitions on the target, so that no stale filesystem is found there,
+/// and recreates the signatures of excluded swap areas with their original UUID and label.
+///
+/// # Returns
+///
+/// - `Ok(())`: If all excluded partitions were wiped.
+/// - `Err(String)`: If writing failed.
+fn restore_excluded_regions(
+    metadata: &ImageMetadata,
+    target: &mut File,
+    device_path: &str,
+) -> Result<(), String> {
+    for region in excluded_regions(metadata) {
+        let mut start = vec![0u8; region.length.min(WIPE_LENGTH) as usize];
+        match &region.swap {
+            Some(swap) => {
+                let page = swap.to_bytes(region.length)?;
+                start[..page.len()].copy_from_slice(&page);
+                info!(
+                    "Recreated the swap area {} at byte {} of {}",
+                    swap.uuid, region.offset, device_path
+                );
+            }
+            None => warn!(
+                "The partition at byte {} of {} was excluded from the image, it needs to be formatted",
+                region.offset, device_path
+            ),
+        }
+        target
+            .seek(SeekFrom::Start(region.offset))
+            .and_then(|_| target.write_all(&start))
+            .map_err(|e| format!("Failed to write at {}: {}", region.offset, e))?;
+    }
+    target
+        .sync_all()
+        .map_err(|e| format!("{}: {}", device_path, e))
+}
+
+/// Relocates the backup GPT written with a trimmed image to the end of the target,
+/// so that the GPT is valid for the size of the target.
+///
+/// # Arguments
+///
+/// * `device_path` - The target the image was written to.
+/// * `target_size` - The size of the target in bytes.
+///
+/// # Returns
+///
+/// - `Ok(())`: If the GPT was relocated, or the target has an MBR which needs no relocation.
+/// - `Err(String)`: If the GPT is not readable or writing failed.
+fn recreate_gpt(device_path: &str, target_size: u64) -> Result<(), String> {
+    let mut target = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .map_err(|e| format!("{}: {}", device_path, e))?;
+    if let Some(relocated) = relocate_gpt(&mut target, target_size)? {
+        relocated.write_to(&mut target)?;
+        target
+            .sync_all()
+            .map_err(|e| format!("{}: {}", device_path, e))?;
+        info!(
+            "Recreated the backup GPT at the end of {}, the kernel may need to re-read it (`partprobe {}`)",
+            device_path, device_path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::run::{
+        backup_run::command_output::fake::{FakeCommandRunner, FakeResponse},
+        extent::Extent,
+        partition_table::{tests::gpt_disk, PartitionTable},
+        swap::SwapSignature,
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dd_backup_restore_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn runner() -> Arc<FakeCommandRunner> {
+        Arc::new(
+            FakeCommandRunner::new().script("lsblk", FakeResponse::ok(r#"{"blockdevices": []}"#)),
+        )
+    }
+
+    fn restore_args(image: &str, target: &Path) -> RestoreArgs {
+        RestoreArgs {
+            image: image.to_string(),
+            target: DeviceIdentifier::Path(target.to_string_lossy().to_string()),
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn test_restore_used_blocks_only() {
+        let dir = test_dir("used_blocks");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let target = dir.join("target.raw");
+        fs::write(&image, [[1u8; 1024], [0u8; 1024], [2u8; 1024]].concat()).unwrap();
+        fs::write(&target, [3u8; 3072]).unwrap();
+        ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: 3072,
+            source_size: None,
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 1024),
+                Region {
+                    offset: 1024,
+                    length: 2048,
+                    copy: CopyMode::UsedBlocks,
+                    filesystem: Some("ext".to_string()),
+                    extents: vec![Extent::new(2048, 1024)],
+                    swap: None,
+                },
+            ],
+        }
+        .write(&image)
+        .unwrap();
+        let runner = runner();
+
+        restore(
+            &restore_args(&image, &target),
+            runner.clone(),
+            Lsblk::from_lsblk,
+        )
+        .unwrap();
+
+        let restored = fs::read(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(restored, [[1u8; 1024], [3u8; 1024], [2u8; 1024]].concat());
+        assert!(runner.commands_of(&["dd"]).is_empty());
+    }
+
+    #[test]
+    fn test_restore_rejects_converted_image() {
+        let dir = test_dir("converted");
+        let image = dir.join("disk.qcow2").to_string_lossy().to_string();
+        let target = dir.join("target.raw");
+        fs::write(&image, b"QFI\xfb").unwrap();
+        fs::write(&target, [3u8; 1024]).unwrap();
+
+        let result = restore(&restore_args(&image, &target), runner(), Lsblk::from_lsblk);
+
+        let restored = fs::read(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.unwrap_err().contains("qemu-img convert -O raw"));
+        assert_eq!(restored, [3u8; 1024]);
+    }
+
+    #[test]
+    fn test_restore_full_image_with_dd() {
+        let dir = test_dir("full");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let target = dir.join("target.raw");
+        fs::write(&image, [1u8; 2048]).unwrap();
+        fs::write(&target, [0u8; 1024]).unwrap();
+        let runner = runner();
+
+        restore(
+            &restore_args(&image, &target),
+            runner.clone(),
+            Lsblk::from_lsblk,
+        )
+        .unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            runner.commands_of(&["dd"]),
+            vec![format!(
+                "dd if={} of={} bs=4M conv=notrunc,fsync status=progress",
+                image,
+                target.to_string_lossy()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_restore_trimmed_image_recreates_gpt() {
+        let dir = test_dir("trimmed");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let target = dir.join("target.raw");
+        // the image of a disk of 8192 sectors, trimmed after its partition and the backup GPT
+        fs::write(&image, gpt_disk(4129, &[([0xAF; 16], 2048, 4095, "data")])).unwrap();
+        fs::write(&target, vec![0u8; 8192 * 512]).unwrap();
+        ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: 4129 * 512,
+            source_size: Some(8192 * 512),
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 2048 * 512),
+                Region {
+                    offset: 2048 * 512,
+                    length: 2048 * 512,
+                    copy: CopyMode::UsedBlocks,
+                    filesystem: Some("ext".to_string()),
+                    extents: Vec::new(),
+                    swap: None,
+                },
+                Region::full(4096 * 512, 33 * 512),
+            ],
+        }
+        .write(&image)
+        .unwrap();
+
+        restore(&restore_args(&image, &target), runner(), Lsblk::from_lsblk).unwrap();
+
+        let table = PartitionTable::read(&mut File::open(&target).unwrap())
+            .unwrap()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(table.disk_size, Some(8192 * 512));
+        assert_eq!(table.last_lba, Some(8192 - 34));
+        assert_eq!(table.partitions[0].end(), 4096);
+    }
+
+    #[test]
+    fn test_restore_recreates_excluded_swap() {
+        let dir = test_dir("swap");
+        let image = dir.join("disk.img").to_string_lossy().to_string();
+        let target = dir.join("target.raw");
+        fs::write(&image, [vec![1u8; 4096], vec![0u8; 65536]].concat()).unwrap();
+        fs::write(&target, [0xCDu8; 4096 + 65536]).unwrap();
+        let signature = SwapSignature {
+            page_size: 4096,
+            uuid: "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9".to_string(),
+            label: Some("swap".to_string()),
+        };
+        ImageMetadata {
+            source: "/dev/sda".to_string(),
+            size: 4096 + 65536,
+            source_size: None,
+            unreadable: Vec::new(),
+            regions: vec![
+                Region::full(0, 4096),
+                Region::excluded(4096, 65536, Some(signature.clone())),
+            ],
+        }
+        .write(&image)
+        .unwrap();
+
+        restore(&restore_args(&image, &target), runner(), Lsblk::from_lsblk).unwrap();
+
+        let restored = fs::read(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(restored[..4096], [1u8; 4096]);
+        assert_eq!(
+            SwapSignature::read(&mut std::io::Cursor::new(&restored), 4096).unwrap(),
+            Some(signature)
+        );
+        // the rest of the swap area is wiped
+        assert!(restored[8192..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,324 @@
+use std::io::{Read, Seek};
+
+use serde::Serialize;
+
+use super::{
+    allocation::{allocated_extents, u32_be, u64_be},
+    extent::total_length,
+    partition_table::{read_at, u16_le, u32_le, u64_le},
+    swap::{format_uuid, SwapSignature},
+};
+
+const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+const BTRFS_SUPERBLOCK_OFFSET: u64 = 64 * 1024;
+const BTRFS_MAGIC: &[u8; 8] = b"_BHRfS_M";
+
+/// A filesystem or another signature found by its superblock, named like `blkid` does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FilesystemInfo {
+    /// The type of the filesystem, like `ext4`, `vfat` or `crypto_LUKS`.
+    pub fstype: String,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    /// The size of the filesystem in bytes.
+    pub size: Option<u64>,
+    /// The bytes in use, if the filesystem records them or its allocation is readable.
+    pub used: Option<u64>,
+}
+
+/// Reads the superblock at `offset` of `reader`, probing LUKS, swap, btrfs, XFS, ext2/3/4, NTFS and FAT.
+///
+/// # Arguments
+///
+/// * `reader` - The device or image holding the filesystem.
+/// * `offset` - The byte offset of the filesystem, like the start of a partition.
+/// * `length` - The number of bytes of the partition.
+///
+/// # Returns
+///
+/// - `Ok(Some(FilesystemInfo))`: The filesystem found.
+/// - `Ok(None)`: If no known superblock was found.
+/// - `Err(String)`: If reading failed.
+pub fn probe<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let probes: [Probe<R>; 6] = [luks, swap, btrfs, xfs, ext, ntfs_or_fat];
+    for probe in probes {
+        if let Some(info) = probe(reader, offset, length)? {
+            return Ok(Some(info));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads one kind of superblock at the given offset and length, `None` if it is not of that kind.
+type Probe<R> = fn(&mut R, u64, u64) -> Result<Option<FilesystemInfo>, String>;
+
+/// Returns the text of a fixed size field up to its first null byte, `None` if it is empty.
+fn text(bytes: &[u8]) -> Option<String> {
+    let text: Vec<u8> = bytes.iter().take_while(|&&b| b != 0).copied().collect();
+    let text = String::from_utf8_lossy(&text).trim_end().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+fn luks<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    _length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let header = match read_at(reader, offset, 512)? {
+        Some(header) if &header[0..6] == LUKS_MAGIC => header,
+        _ => return Ok(None),
+    };
+    let is_luks2 = u16::from_be_bytes([header[6], header[7]]) == 2;
+    Ok(Some(FilesystemInfo {
+        fstype: "crypto_LUKS".to_string(),
+        uuid: text(&header[0xA8..0xA8 + 40]),
+        label: match is_luks2 {
+            true => text(&header[0x18..0x18 + 48]),
+            false => None,
+        },
+        size: None,
+        used: None,
+    }))
+}
+
+fn swap<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    Ok(
+        SwapSignature::read(reader, offset)?.map(|signature| FilesystemInfo {
+            fstype: "swap".to_string(),
+            uuid: Some(signature.uuid),
+            label: signature.label,
+            size: Some(length),
+            used: None,
+        }),
+    )
+}
+
+fn btrfs<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    _length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let superblock = match read_at(reader, offset + BTRFS_SUPERBLOCK_OFFSET, 4096)? {
+        Some(superblock) if &superblock[0x40..0x48] == BTRFS_MAGIC => superblock,
+        _ => return Ok(None),
+    };
+    Ok(Some(FilesystemInfo {
+        fstype: "btrfs".to_string(),
+        uuid: Some(format_uuid(&superblock[0x20..0x30])),
+        label: text(&superblock[0x12B..0x12B + 256]),
+        size: Some(u64_le(&superblock[0x70..])),
+        used: Some(u64_le(&superblock[0x78..])),
+    }))
+}
+
+fn xfs<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    _length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let superblock = match read_at(reader, offset, 512)? {
+        Some(superblock) if &superblock[0..4] == b"XFSB" => superblock,
+        _ => return Ok(None),
+    };
+    let block_size = u32_be(&superblock[0x04..]) as u64;
+    let data_blocks = u64_be(&superblock[0x08..]);
+    let free_blocks = u64_be(&superblock[0x90..]);
+    Ok(Some(FilesystemInfo {
+        fstype: "xfs".to_string(),
+        uuid: Some(format_uuid(&superblock[0x20..0x30])),
+        label: text(&superblock[0x6C..0x6C + 12]),
+        size: Some(data_blocks * block_size),
+        used: Some(data_blocks.saturating_sub(free_blocks) * block_size),
+    }))
+}
+
+fn ext<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    _length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let superblock = match read_at(reader, offset + 1024, 1024)? {
+        Some(superblock) if u16_le(&superblock[0x38..]) == 0xEF53 => superblock,
+        _ => return Ok(None),
+    };
+    let feature_compat = u32_le(&superblock[0x5C..]);
+    let feature_incompat = u32_le(&superblock[0x60..]);
+    let is_64bit = feature_incompat & 0x80 != 0;
+    let high = |at: usize| match is_64bit {
+        true => (u32_le(&superblock[at..]) as u64) << 32,
+        false => 0,
+    };
+    let blocks_count = u32_le(&superblock[0x04..]) as u64 | high(0x150);
+    let free_blocks = u32_le(&superblock[0x0C..]) as u64 | high(0x158);
+    let block_size = 1024u64 << u32_le(&superblock[0x18..]);
+    // like blkid: extents, 64bit or flex_bg make ext4, a journal ext3
+    let fstype = match (feature_incompat & 0x2C0 != 0, feature_compat & 0x4 != 0) {
+        (true, _) => "ext4",
+        (false, true) => "ext3",
+        (false, false) => "ext2",
+    };
+    Ok(Some(FilesystemInfo {
+        fstype: fstype.to_string(),
+        uuid: Some(format_uuid(&superblock[0x68..0x78])),
+        label: text(&superblock[0x78..0x88]),
+        size: Some(blocks_count * block_size),
+        used: Some(blocks_count.saturating_sub(free_blocks) * block_size),
+    }))
+}
+
+/// NTFS and FAT don't record their used space, it is counted from their allocation,
+/// which is read like for used-blocks-only images.
+fn ntfs_or_fat<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    length: u64,
+) -> Result<Option<FilesystemInfo>, String> {
+    let Some(boot_sector) = read_at(reader, offset, 512)? else {
+        return Ok(None);
+    };
+    let bytes_per_sector = u16_le(&boot_sector[0x0B..]) as u64;
+    let mut info = if &boot_sector[3..11] == b"NTFS    " {
+        FilesystemInfo {
+            fstype: "ntfs".to_string(),
+            uuid: Some(format!("{:016X}", u64_le(&boot_sector[0x48..]))),
+            // the label of NTFS is stored in the $Volume file, not in the boot sector
+            label: None,
+            size: Some(u64_le(&boot_sector[0x28..]) * bytes_per_sector),
+            used: None,
+        }
+    } else if boot_sector[510..512] == [0x55, 0xAA]
+        && (&boot_sector[0x36..0x39] == b"FAT" || &boot_sector[0x52..0x55] == b"FAT")
+    {
+        // FAT32 has its extended boot record after the 28 byte longer BPB
+        let ebr = match &boot_sector[0x52..0x55] == b"FAT" {
+            true => 0x40,
+            false => 0x24,
+        };
+        let serial = u32_le(&boot_sector[ebr + 3..]);
+        let total_sectors = match u16_le(&boot_sector[0x13..]) {
+            0 => u32_le(&boot_sector[0x20..]) as u64,
+            sectors => sectors as u64,
+        };
+        FilesystemInfo {
+            fstype: "vfat".to_string(),
+            uuid: Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+            label: text(&boot_sector[ebr + 7..ebr + 18]).filter(|label| label != "NO NAME"),
+            size: Some(total_sectors * bytes_per_sector),
+            used: None,
+        }
+    } else {
+        return Ok(None);
+    };
+    match allocated_extents(reader, offset, length) {
+        Ok(Some((_, extents))) => info.used = Some(total_length(&extents)),
+        Ok(None) => {}
+        Err(e) => debug!(
+            "Used space of {} at {} not readable: {}",
+            info.fstype, offset, e
+        ),
+    }
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, process::Command};
+
+    use super::*;
+    use crate::run::allocation::tests::fat_image;
+
+    #[test]
+    fn test_probe_fat() {
+        let mut image = fat_image(&[2, 3, 10]);
+        image[0x27..0x2B].copy_from_slice(&0x1234_ABCDu32.to_le_bytes());
+        image[0x2B..0x36].copy_from_slice(b"BOOT       ");
+
+        let info = probe(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.fstype, "vfat");
+        assert_eq!(info.uuid.as_deref(), Some("1234-ABCD"));
+        assert_eq!(info.label.as_deref(), Some("BOOT"));
+        assert_eq!(info.size, Some(16 * 1024 * 1024));
+        // the reserved sectors, the FATs, the root directory and 3 clusters of 2 KiB
+        assert_eq!(info.used, Some((4 + 2 * 32 + 32) * 512 + 3 * 2048));
+    }
+
+    #[test]
+    fn test_probe_luks_and_btrfs() {
+        let mut luks = vec![0u8; 4096];
+        luks[0..6].copy_from_slice(LUKS_MAGIC);
+        luks[6..8].copy_from_slice(&2u16.to_be_bytes());
+        luks[0x18..0x1C].copy_from_slice(b"home");
+        luks[0xA8..0xA8 + 36].copy_from_slice(b"0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9");
+        let info = probe(&mut Cursor::new(&luks), 0, 4096).unwrap().unwrap();
+        assert_eq!(info.fstype, "crypto_LUKS");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9")
+        );
+        assert_eq!(info.label.as_deref(), Some("home"));
+
+        let mut btrfs = vec![0u8; 128 * 1024];
+        let superblock = BTRFS_SUPERBLOCK_OFFSET as usize;
+        btrfs[superblock + 0x20..superblock + 0x30].copy_from_slice(&[0xAB; 16]);
+        btrfs[superblock + 0x40..superblock + 0x48].copy_from_slice(BTRFS_MAGIC);
+        btrfs[superblock + 0x70..superblock + 0x78].copy_from_slice(&(1u64 << 30).to_le_bytes());
+        btrfs[superblock + 0x78..superblock + 0x80].copy_from_slice(&(1u64 << 20).to_le_bytes());
+        btrfs[superblock + 0x12B..superblock + 0x12F].copy_from_slice(b"root");
+        let info = probe(&mut Cursor::new(&btrfs), 0, btrfs.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.fstype, "btrfs");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("abababab-abab-abab-abab-abababababab")
+        );
+        assert_eq!((info.size, info.used), (Some(1 << 30), Some(1 << 20)));
+
+        assert_eq!(
+            probe(&mut Cursor::new(vec![0u8; 4096]), 0, 4096).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_probe_ext4() {
+        if Command::new("mkfs.ext4").arg("-V").output().is_err() {
+            eprintln!("mkfs.ext4 not found, skipping");
+            return;
+        }
+        let path =
+            std::env::temp_dir().join(format!("dd_backup_superblock_test_{}", std::process::id()));
+        fs::write(&path, vec![0u8; 8 * 1024 * 1024]).unwrap();
+        let status = Command::new("mkfs.ext4")
+            .args(["-q", "-F", "-L", "home"])
+            .args(["-U", "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9"])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let image = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let info = probe(&mut Cursor::new(&image), 0, image.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.fstype, "ext4");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9")
+        );
+        assert_eq!(info.label.as_deref(), Some("home"));
+        assert_eq!(info.size, Some(8 * 1024 * 1024));
+        assert!(info.used.unwrap() > 0 && info.used.unwrap() < 8 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,166 @@
+use std::io::{Read, Seek};
+
+use serde::{Deserialize, Serialize};
+
+use super::partition_table::{read_at, u32_le};
+
+/// The page sizes probed for a swap signature, which is stored at the end of the first page.
+const PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+const SWAP_SIGNATURE: &[u8; 10] = b"SWAPSPACE2";
+/// The offset of the swap header after the boot sector space.
+const HEADER_OFFSET: usize = 1024;
+const UUID_OFFSET: usize = HEADER_OFFSET + 12;
+const LABEL_OFFSET: usize = HEADER_OFFSET + 28;
+const LABEL_LENGTH: usize = 16;
+
+/// The identity of a Linux swap area, recreated on restore since the swap area itself isn't copied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwapSignature {
+    /// The page size the swap area was created for, the signature is at the end of the first page.
+    pub page_size: u64,
+    /// The UUID of the swap area, like `mkswap -U` takes it.
+    pub uuid: String,
+    /// The label of the swap area, like `mkswap -L` takes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl SwapSignature {
+    /// Reads the signature of a swap area at `offset` of `reader`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(SwapSignature))`: If a version 1 swap area, as created by `mkswap`, was found.
+    /// - `Ok(None)`: If there is no swap area.
+    /// - `Err(String)`: If reading failed.
+    pub fn read<R: Read + Seek>(
+        reader: &mut R,
+        offset: u64,
+    ) -> Result<Option<SwapSignature>, String> {
+        for page_size in PAGE_SIZES {
+            let Some(page) = read_at(reader, offset, page_size as usize)? else {
+                return Ok(None);
+            };
+            if &page[page.len() - SWAP_SIGNATURE.len()..] != SWAP_SIGNATURE {
+                continue;
+            }
+            if u32_le(&page[HEADER_OFFSET..HEADER_OFFSET + 4]) != 1 {
+                return Ok(None);
+            }
+            let label: Vec<u8> = page[LABEL_OFFSET..LABEL_OFFSET + LABEL_LENGTH]
+                .iter()
+                .take_while(|&&b| b != 0)
+                .copied()
+                .collect();
+            return Ok(Some(SwapSignature {
+                page_size,
+                uuid: format_uuid(&page[UUID_OFFSET..UUID_OFFSET + 16]),
+                label: (!label.is_empty()).then(|| String::from_utf8_lossy(&label).to_string()),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Returns the first page of a swap area of `length` bytes with this signature, like `mkswap` writes it.
+    pub fn to_bytes(&self, length: u64) -> Result<Vec<u8>, String> {
+        let pages = length / self.page_size;
+        if pages < 10 {
+            return Err(format!("Swap area of {} bytes is too small", length));
+        }
+        let mut page = vec![0u8; self.page_size as usize];
+        page[HEADER_OFFSET..HEADER_OFFSET + 4].copy_from_slice(&1u32.to_le_bytes());
+        let last_page = u32::try_from(pages - 1).unwrap_or(u32::MAX);
+        page[HEADER_OFFSET + 4..HEADER_OFFSET + 8].copy_from_slice(&last_page.to_le_bytes());
+        page[UUID_OFFSET..UUID_OFFSET + 16].copy_from_slice(&parse_uuid(&self.uuid)?);
+        if let Some(label) = &self.label {
+            let label = &label.as_bytes()[..label.len().min(LABEL_LENGTH)];
+            page[LABEL_OFFSET..LABEL_OFFSET + label.len()].copy_from_slice(label);
+        }
+        let signature_offset = page.len() - SWAP_SIGNATURE.len();
+        page[signature_offset..].copy_from_slice(SWAP_SIGNATURE);
+        Ok(page)
+    }
+}
+
+/// Formats 16 bytes as UUID in byte order, like `blkid` prints the UUID of a swap area or a filesystem.
+pub fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn parse_uuid(uuid: &str) -> Result<[u8; 16], String> {
+    let hex = uuid.replace('-', "");
+    let mut bytes = [0u8; 16];
+    if hex.len() != 32 {
+        return Err(format!("Invalid UUID {}", uuid));
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| format!("Invalid UUID {}", uuid))?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn test_swap_signature_roundtrip() {
+        let signature = SwapSignature {
+            page_size: 4096,
+            uuid: "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9".to_string(),
+            label: Some("swap".to_string()),
+        };
+        let mut swap = signature.to_bytes(1024 * 1024).unwrap();
+        assert_eq!(u32_le(&swap[1028..1032]), 255);
+        swap.resize(1024 * 1024, 0);
+
+        let mut disk = vec![0u8; 4096];
+        disk.extend(&swap);
+        assert_eq!(
+            SwapSignature::read(&mut Cursor::new(&disk), 4096).unwrap(),
+            Some(signature)
+        );
+        assert_eq!(
+            SwapSignature::read(&mut Cursor::new(&disk), 0).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_swap_signature_like_mkswap() {
+        if Command::new("mkswap").arg("-V").output().is_err() {
+            eprintln!("mkswap not found, skipping");
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("dd_backup_swap_test_{}", std::process::id()));
+        fs::write(&path, vec![0u8; 4 * 1024 * 1024]).unwrap();
+        let status = Command::new("mkswap")
+            .args(["-U", "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9", "-L", "swap"])
+            .arg(&path)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+
+        let swap = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let signature = SwapSignature::read(&mut Cursor::new(&swap), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(signature.uuid, "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9");
+        assert_eq!(signature.label.as_deref(), Some("swap"));
+        let page = signature.to_bytes(swap.len() as u64).unwrap();
+        assert_eq!(page, swap[..page.len()]);
+    }
+}
@@ -1,4 +1,5 @@
-use chrono::Local;
+use chrono::{DateTime, Days, Local, NaiveTime};
+use nix::sys::statvfs::statvfs;
 
 /// Returns the current date in the the form YYYY-MM-DD as a String
 pub fn current_date() -> String {
@@ -32,8 +33,33 @@ pub fn convert_to_byte_size(size_str: &str) -> Result<Option<u64>, String> {
     }
 }
 
+/// Returns the space in bytes available to unprivileged users on the filesystem mounted at `path`.
+pub fn available_bytes(path: &str) -> Result<u64, String> {
+    let stat = statvfs(path).map_err(|e| format!("Failed to statvfs {}: {}", path, e))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Parses a time of day of the form `HH:MM`, like `06:00`.
+pub fn parse_time_of_day(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| format!("Invalid time of day '{}', expected HH:MM: {}", time, e))
+}
+
+/// Returns the next point in time after `now` at the time of day `time` of the form `HH:MM`,
+/// today or tomorrow.
+pub fn next_time_of_day(time: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    let time = parse_time_of_day(time)?;
+    [now.date_naive(), now.date_naive() + Days::new(1)]
+        .into_iter()
+        .filter_map(|date| date.and_time(time).and_local_timezone(Local).earliest())
+        .find(|next| *next > now)
+        .ok_or(format!("Failed to find the next {} after {}", time, now))
+}
+
 #[cfg(test)]
 mod tests {
+    use chrono::NaiveDate;
+
     use super::*;
 
     #[test]
@@ -58,4 +84,29 @@ mod tests {
             Err("Error parsing unit size: invalid float literal".to_string())
         );
     }
+
+    #[test]
+    fn test_next_time_of_day() {
+        let now = NaiveDate::from_ymd_opt(2023, 6, 15)
+            .unwrap()
+            .and_hms_opt(22, 30, 0)
+            .unwrap()
+            .and_local_timezone(Local)
+            .unwrap();
+        let at = |day, hour, minute| {
+            NaiveDate::from_ymd_opt(2023, 6, day)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap()
+        };
+
+        assert_eq!(next_time_of_day("23:15", now), Ok(at(15, 23, 15)));
+        assert_eq!(next_time_of_day("06:00", now), Ok(at(16, 6, 0)));
+        assert_eq!(next_time_of_day("22:30", now), Ok(at(16, 22, 30)));
+        assert!(next_time_of_day("25:00", now)
+            .unwrap_err()
+            .starts_with("Invalid time of day '25:00', expected HH:MM"));
+    }
 }
@@ -0,0 +1,324 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::{
+    convert::{seek_position, write_at, ImageWriter, CLUSTER_SIZE},
+    partition_table::{read_at, u32_le, u64_le},
+};
+
+const SECTOR_SIZE: u64 = 512;
+const VMDK_MAGIC: &[u8; 4] = b"KDMV";
+const VMDK_VERSION: u32 = 1;
+/// Valid new line detection, no redundant grain directory, no compression.
+const VMDK_FLAGS: u32 = 1;
+/// The number of sectors per grain, the clusters of the image.
+const GRAIN_SECTORS: u64 = CLUSTER_SIZE / SECTOR_SIZE;
+/// The number of grain table entries per grain table.
+const GT_ENTRIES: u64 = 512;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+/// Grains and grain tables are compressed, as in stream optimized images.
+const VMDK_FLAG_COMPRESSED: u32 = 1 << 16;
+/// The grain table entry of a grain reading as zeros.
+const ZERO_GRAIN: u32 = 1;
+
+/// Writes a monolithic sparse VMDK image sequentially: the header, the descriptor, the grain directory and
+/// all grain tables are reserved at the start, grains are appended, each grain table is written when complete.
+///
+/// Grains which are never written are unallocated and read as zeros.
+pub struct VmdkWriter {
+    file: File,
+    path: String,
+    /// The sector of the first grain table, the others follow.
+    gt_offset: u64,
+    gt: Vec<u32>,
+    /// The index of the grain table buffered in `gt`.
+    gt_index: Option<u64>,
+    /// The next free sector of the image file.
+    next_sector: u64,
+}
+
+impl VmdkWriter {
+    /// Creates a VMDK image of a virtual disk of `size` bytes at `path`, which must not exist.
+    pub fn create(path: &str, size: u64) -> Result<VmdkWriter, String> {
+        let capacity = size.div_ceil(SECTOR_SIZE);
+        let gts = capacity.div_ceil(GRAIN_SECTORS * GT_ENTRIES);
+        let gd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+        let gd_sectors = (gts * 4).div_ceil(SECTOR_SIZE);
+        let gt_offset = gd_offset + gd_sectors;
+        let gt_sectors = GT_ENTRIES * 4 / SECTOR_SIZE;
+        let overhead = (gt_offset + gts * gt_sectors).div_ceil(GRAIN_SECTORS) * GRAIN_SECTORS;
+        if overhead + capacity > u32::MAX as u64 {
+            return Err(format!(
+                "{} bytes exceed the maximum size of a VMDK image",
+                size
+            ));
+        }
+
+        let mut header = vec![0u8; SECTOR_SIZE as usize];
+        header[0..4].copy_from_slice(VMDK_MAGIC);
+        header[4..8].copy_from_slice(&VMDK_VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&VMDK_FLAGS.to_le_bytes());
+        header[12..20].copy_from_slice(&capacity.to_le_bytes());
+        header[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+        header[28..36].copy_from_slice(&DESCRIPTOR_OFFSET.to_le_bytes());
+        header[36..44].copy_from_slice(&DESCRIPTOR_SECTORS.to_le_bytes());
+        header[44..48].copy_from_slice(&(GT_ENTRIES as u32).to_le_bytes());
+        header[56..64].copy_from_slice(&gd_offset.to_le_bytes());
+        header[64..72].copy_from_slice(&overhead.to_le_bytes());
+        header[73..77].copy_from_slice(b"\n \r\n");
+
+        let file_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or(path.to_string());
+        let descriptor = descriptor(capacity, &file_name);
+        if descriptor.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+            return Err(format!(
+                "File name {} is too long for a VMDK image",
+                file_name
+            ));
+        }
+        let gd: Vec<u8> = (0..gts)
+            .flat_map(|gt| ((gt_offset + gt * gt_sectors) as u32).to_le_bytes())
+            .collect();
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        write_at(&mut file, 0, &header)
+            .and_then(|_| {
+                write_at(
+                    &mut file,
+                    DESCRIPTOR_OFFSET * SECTOR_SIZE,
+                    descriptor.as_bytes(),
+                )
+            })
+            .and_then(|_| write_at(&mut file, gd_offset * SECTOR_SIZE, &gd))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(VmdkWriter {
+            file,
+            path: path.to_string(),
+            gt_offset,
+            gt: vec![0; GT_ENTRIES as usize],
+            gt_index: None,
+            next_sector: overhead,
+        })
+    }
+
+    /// Writes the buffered grain table, if any.
+    fn flush_gt(&mut self) -> Result<(), String> {
+        if let Some(gt_index) = self.gt_index.take() {
+            let table: Vec<u8> = self
+                .gt
+                .iter()
+                .flat_map(|entry| entry.to_le_bytes())
+                .collect();
+            let offset = (self.gt_offset + gt_index * GT_ENTRIES * 4 / SECTOR_SIZE) * SECTOR_SIZE;
+            write_at(&mut self.file, offset, &table)
+                .map_err(|e| format!("{}: {}", self.path, e))?;
+            self.gt.fill(0);
+        }
+        Ok(())
+    }
+}
+
+impl ImageWriter for VmdkWriter {
+    fn write_cluster(&mut self, index: u64, data: &[u8]) -> Result<(), String> {
+        let gt_index = index / GT_ENTRIES;
+        if self.gt_index != Some(gt_index) {
+            self.flush_gt()?;
+            self.gt_index = Some(gt_index);
+        }
+        write_at(&mut self.file, self.next_sector * SECTOR_SIZE, data)
+            .map_err(|e| format!("{}: {}", self.path, e))?;
+        self.gt[(index % GT_ENTRIES) as usize] = self.next_sector as u32;
+        self.next_sector += GRAIN_SECTORS;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.flush_gt()?;
+        // the grain tables of unwritten grains are holes, reading as zeros
+        self.file
+            .set_len(self.next_sector * SECTOR_SIZE)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+}
+
+/// Reads the virtual disk of a monolithic sparse VMDK image, loading one grain table at a time.
+///
+/// Unallocated and zero grains read as zeros, compressed (stream optimized) images are not supported.
+pub struct VmdkReader<R> {
+    reader: R,
+    grain_size: u64,
+    size: u64,
+    gt_entries: u64,
+    gd: Vec<u32>,
+    /// The index and the entries of the last grain table read.
+    gt: Option<(u64, Vec<u32>)>,
+    position: u64,
+}
+
+impl<R: Read + Seek> VmdkReader<R> {
+    /// Opens the VMDK image read by `reader`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(VmdkReader)`: If the header and the grain directory were read.
+    /// - `Err(String)`: If it is no sparse VMDK image or it is compressed.
+    pub fn new(mut reader: R) -> Result<VmdkReader<R>, String> {
+        let header =
+            read_at(&mut reader, 0, SECTOR_SIZE as usize)?.ok_or("VMDK header is truncated")?;
+        if &header[0..4] != VMDK_MAGIC {
+            return Err("No sparse VMDK image".to_string());
+        }
+        if u32_le(&header[8..]) & VMDK_FLAG_COMPRESSED != 0 {
+            return Err("Compressed VMDK images are not supported".to_string());
+        }
+        let capacity = u64_le(&header[12..]);
+        let grain_sectors = u64_le(&header[20..]);
+        let gt_entries = u32_le(&header[44..]) as u64;
+        let gd_offset = u64_le(&header[56..]);
+        if grain_sectors == 0 || gt_entries == 0 || gd_offset == u64::MAX {
+            return Err("Invalid or stream optimized VMDK header".to_string());
+        }
+        let gts = capacity.div_ceil(grain_sectors * gt_entries);
+        let gd = read_at(&mut reader, gd_offset * SECTOR_SIZE, (gts * 4) as usize)?
+            .ok_or("VMDK grain directory is truncated")?;
+        Ok(VmdkReader {
+            reader,
+            grain_size: grain_sectors * SECTOR_SIZE,
+            size: capacity * SECTOR_SIZE,
+            gt_entries,
+            gd: gd.chunks(4).map(u32_le).collect(),
+            gt: None,
+            position: 0,
+        })
+    }
+
+    /// Returns the size of the virtual disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the offset in the image of the grain with the given index, `None` if it reads as zeros.
+    fn grain_offset(&mut self, index: u64) -> Result<Option<u64>, String> {
+        let gt_index = index / self.gt_entries;
+        let gt_sector = self.gd.get(gt_index as usize).copied().unwrap_or(0) as u64;
+        if gt_sector == 0 {
+            return Ok(None);
+        }
+        if self.gt.as_ref().map(|(index, _)| *index) != Some(gt_index) {
+            let table = read_at(
+                &mut self.reader,
+                gt_sector * SECTOR_SIZE,
+                (self.gt_entries * 4) as usize,
+            )?
+            .ok_or("VMDK grain table is truncated")?;
+            self.gt = Some((gt_index, table.chunks(4).map(u32_le).collect()));
+        }
+        let entry = self
+            .gt
+            .as_ref()
+            .map_or(0, |(_, gt)| gt[(index % self.gt_entries) as usize]);
+        match entry {
+            0 | ZERO_GRAIN => Ok(None),
+            sector => Ok(Some(sector as u64 * SECTOR_SIZE)),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for VmdkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let in_grain = self.position % self.grain_size;
+        let length = (buf.len() as u64)
+            .min(self.grain_size - in_grain)
+            .min(self.size - self.position) as usize;
+        match self
+            .grain_offset(self.position / self.grain_size)
+            .map_err(io::Error::other)?
+        {
+            Some(offset) => {
+                self.reader.seek(SeekFrom::Start(offset + in_grain))?;
+                self.reader.read_exact(&mut buf[..length])?;
+            }
+            None => buf[..length].fill(0),
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for VmdkReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, self.size, position)?;
+        Ok(self.position)
+    }
+}
+
+/// Returns the embedded descriptor of a monolithic sparse image of `capacity` sectors.
+fn descriptor(capacity: u64, file_name: &str) -> String {
+    let cylinders = (capacity / (16 * 63)).clamp(1, 16383);
+    format!(
+        "# Disk DescriptorFile
+version=1
+CID=fffffffe
+parentCID=ffffffff
+createType=\"monolithicSparse\"
+
+# Extent description
+RW {} SPARSE \"{}\"
+
+# The Disk Data Base
+#DDB
+
+ddb.virtualHWVersion = \"4\"
+ddb.geometry.cylinders = \"{}\"
+ddb.geometry.heads = \"16\"
+ddb.geometry.sectors = \"63\"
+ddb.adapterType = \"ide\"
+",
+        capacity, file_name, cylinders
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Reads the virtual disk of a VMDK image written by `VmdkWriter`.
+    pub fn read_vmdk(image: &[u8]) -> Vec<u8> {
+        assert_eq!(&image[0..4], VMDK_MAGIC);
+        let capacity = u64::from_le_bytes(image[12..20].try_into().unwrap());
+        let gd_offset = u64::from_le_bytes(image[56..64].try_into().unwrap());
+        let descriptor = String::from_utf8_lossy(&image[512..512 + 1024]).to_string();
+        assert!(descriptor.contains(&format!("RW {} SPARSE", capacity)));
+
+        let mut disk = vec![0u8; (capacity * SECTOR_SIZE) as usize];
+        let gts = capacity.div_ceil(GRAIN_SECTORS * GT_ENTRIES);
+        for gt_index in 0..gts {
+            let gt = u32_le(&image[(gd_offset * SECTOR_SIZE + gt_index * 4) as usize..]) as u64;
+            for entry in 0..GT_ENTRIES {
+                let grain = u32_le(&image[(gt * SECTOR_SIZE + entry * 4) as usize..]) as u64;
+                if grain == 0 {
+                    continue;
+                }
+                let offset = ((gt_index * GT_ENTRIES + entry) * CLUSTER_SIZE) as usize;
+                let length = (CLUSTER_SIZE as usize).min(disk.len() - offset);
+                let start = (grain * SECTOR_SIZE) as usize;
+                disk[offset..offset + length].copy_from_slice(&image[start..start + length]);
+            }
+        }
+        disk
+    }
+}